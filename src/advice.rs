@@ -0,0 +1,81 @@
+//! Paging hints for the kernel with `madvise(2)`.
+
+use std::mem::MaybeUninit;
+
+use crate::error::WeightError;
+
+/// Hint about how the memory of an allocation will be used, applied with
+/// [`Allocation::advise`](crate::alloc::Allocation::advise).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// No special treatment, the default.
+    Normal,
+    /// Expect random access, so the kernel reads ahead less.
+    Random,
+    /// Expect sequential access, so the kernel reads ahead more aggressively.
+    Sequential,
+    /// Expect access soon, so the kernel should page the memory in.
+    WillNeed,
+    /// Drop the pages right away. The next access faults in zeroed pages, so the memory no
+    /// longer holds its fill pattern.
+    DontNeed,
+    /// Make the pages the first candidates for reclaim under memory pressure. Linux 5.4+.
+    Cold,
+    /// Reclaim the pages right away, writing them to swap, to deliberately trigger page
+    /// faults on the next access. Linux 5.4+.
+    PageOut,
+    /// Let transparent huge pages back the memory even where they're only used on request
+    /// (`MADV_HUGEPAGE`).
+    HugePage,
+    /// Keep transparent huge pages from backing the memory and from collapsing its pages
+    /// later (`MADV_NOHUGEPAGE`), see
+    /// [`WeightConfigBuilder::disable_thp`](crate::config::WeightConfigBuilder::disable_thp).
+    NoHugePage,
+}
+
+/// Applies `advice` to the whole pages inside `data`.
+pub(crate) fn advise(data: &mut [u8], advice: Advice) -> Result<(), WeightError> {
+    // SAFETY: initialized bytes are valid uninitialized bytes, and advice leaves them
+    // initialized, at worst replacing them with zeros.
+    advise_uninit(unsafe { &mut *(data as *mut [u8] as *mut [MaybeUninit<u8>]) }, advice)
+}
+
+/// Applies `advice` to the whole pages inside `data`, which doesn't have to be initialized.
+#[cfg(target_os = "linux")]
+pub(crate) fn advise_uninit(data: &mut [MaybeUninit<u8>], advice: Advice) -> Result<(), WeightError> {
+    let flag = match advice {
+        Advice::Normal => libc::MADV_NORMAL,
+        Advice::Random => libc::MADV_RANDOM,
+        Advice::Sequential => libc::MADV_SEQUENTIAL,
+        Advice::WillNeed => libc::MADV_WILLNEED,
+        Advice::DontNeed => libc::MADV_DONTNEED,
+        Advice::Cold => libc::MADV_COLD,
+        Advice::PageOut => libc::MADV_PAGEOUT,
+        Advice::HugePage => libc::MADV_HUGEPAGE,
+        Advice::NoHugePage => libc::MADV_NOHUGEPAGE,
+    };
+
+    let page_size = crate::system::page_size();
+    let start = (data.as_ptr() as usize).next_multiple_of(page_size);
+    let end = (data.as_ptr() as usize + data.len()) / page_size * page_size;
+    if end <= start {
+        return Ok(());
+    }
+    // SAFETY: the address range lies within `data`, which is exclusively borrowed so
+    // nothing observes the pages while MADV_DONTNEED replaces them with zeroed ones.
+    if unsafe { libc::madvise(start as *mut libc::c_void, end - start, flag) } != 0 {
+        return Err(WeightError::Unsupported(format!(
+            "Failed to apply {:?} advice: {}",
+            advice,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+/// Paging hints are only supported on Linux, elsewhere this only warns.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn advise_uninit(_data: &mut [MaybeUninit<u8>], advice: Advice) -> Result<(), WeightError> {
+    warn!("Ignoring {:?} advice, paging hints are only supported on Linux.", advice);
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Pinning worker threads to specific CPU cores (Linux and Windows).
+
+use crate::error::WeightError;
+
+/// Checks that `cpus` are usable as a list of cores to pin workers to.
+pub(crate) fn check_cpus(cpus: &[usize]) -> Result<(), WeightError> {
+    if cpus.is_empty() {
+        return Err(WeightError::InvalidConfig("At least one CPU core is required".to_string()));
+    }
+    if let Some(&cpu) = cpus.iter().find(|&&cpu| cpu >= MAX_CPUS) {
+        return Err(WeightError::InvalidConfig(format!(
+            "CPU core {} is out of range, expected at most {}",
+            cpu,
+            MAX_CPUS - 1
+        )));
+    }
+    if let Some(allowed) = allowed_cpus()
+        && let Some(&cpu) = cpus.iter().find(|&&cpu| !allowed.contains(&cpu))
+    {
+        return Err(WeightError::Unsupported(format!(
+            "CPU core {} isn't available to this process, available cores are {:?}",
+            cpu, allowed
+        )));
+    }
+    Ok(())
+}
+
+/// CPU cores the process may run on, `None` if they can't be determined.
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Option<Vec<usize>> {
+    // SAFETY: cpu_set_t is plain data that sched_getaffinity fills in.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        Some((0..MAX_CPUS).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect())
+    }
+}
+
+/// CPU cores the process may run on, `None` if they can't be determined.
+#[cfg(windows)]
+fn allowed_cpus() -> Option<Vec<usize>> {
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, GetProcessAffinityMask};
+
+    let mut process_mask = 0;
+    let mut system_mask = 0;
+    // SAFETY: GetCurrentProcess returns a pseudo handle that is always valid and both masks
+    // are valid for writes.
+    if unsafe { GetProcessAffinityMask(GetCurrentProcess(), &mut process_mask, &mut system_mask) } == 0 {
+        return None;
+    }
+    Some((0..MAX_CPUS).filter(|&cpu| process_mask & (1 << cpu) != 0).collect())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn allowed_cpus() -> Option<Vec<usize>> {
+    None
+}
+
+/// Fails if threads can't be pinned on this platform.
+pub(crate) fn check_supported() -> Result<(), WeightError> {
+    if cfg!(any(target_os = "linux", windows)) {
+        Ok(())
+    } else {
+        Err(unsupported())
+    }
+}
+
+fn unsupported() -> WeightError {
+    WeightError::Unsupported("Pinning threads to CPU cores is only supported on Linux and Windows".to_string())
+}
+
+/// Highest number of CPUs that fit in a CPU set.
+#[cfg(target_os = "linux")]
+const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+/// Highest number of CPUs that fit in a thread affinity mask.
+#[cfg(not(target_os = "linux"))]
+const MAX_CPUS: usize = usize::BITS as usize;
+
+/// Restricts the calling thread to `cpus`.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(cpus: &[usize]) -> Result<(), WeightError> {
+    // SAFETY: cpu_set_t is plain data, CPU_SET ignores CPUs that don't fit in the set and
+    // sched_setaffinity only reads the set.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(WeightError::Unsupported(format!(
+                "Failed to pin thread to CPUs {:?}: {}",
+                cpus,
+                std::io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Restricts the calling thread to `cpus`.
+#[cfg(windows)]
+pub(crate) fn pin_current_thread(cpus: &[usize]) -> Result<(), WeightError> {
+    use windows_sys::Win32::System::Threading::{GetCurrentThread, SetThreadAffinityMask};
+
+    let mask = cpus.iter().filter(|&&cpu| cpu < MAX_CPUS).fold(0, |mask, &cpu| mask | 1 << cpu);
+    // SAFETY: GetCurrentThread returns a pseudo handle that is always valid for the calling thread.
+    if unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) } == 0 {
+        return Err(WeightError::Unsupported(format!(
+            "Failed to pin thread to CPUs {:?}: {}",
+            cpus,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn pin_current_thread(_cpus: &[usize]) -> Result<(), WeightError> {
+    Err(unsupported())
+}
@@ -0,0 +1,3361 @@
+//! Allocating memory and keeping it occupied with background threads.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::advice::{self, Advice};
+use crate::affinity;
+use crate::buffer::{self, Buffer};
+use crate::checksum;
+use crate::config::{AccessPattern, AdaptiveInterval, Backend, ChurnOp, CsvTrace, DiskFlush, FillPattern, MmapFlags, OnCycle, Progress, Ramp, TouchMode, Watchdog, WeightConfig};
+use crate::error::WeightError;
+use crate::flush::FlushFile;
+use crate::lock::{self, Locked, LockedBuffer};
+use crate::numa;
+use crate::system::{self, PageFaults};
+use crate::trace::CsvFile;
+
+/// Default time to wait between the increment and decrement passes over the memory.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+/// Shortest time a paused worker sleeps before checking whether it was resumed, so a zero
+/// interval doesn't busy-wait.
+pub(crate) const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Fraction of an allocation that may be missing from physical memory for
+/// [`Allocation::wait_resident`] to consider it resident.
+pub const DEFAULT_RESIDENT_TOLERANCE: f64 = 0.05;
+/// Time between two checks of the resident set size while waiting for an allocation to
+/// become resident.
+const RESIDENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Time between two checks of whether a held allocation was stopped, see [`Allocation::hold`].
+const HOLD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How many bytes the initial fill processes between progress reports. Large enough that
+/// the callback doesn't slow down the fill.
+const PROGRESS_STEP: usize = 16 * 1024 * 1024;
+
+/// How many bytes [`allocate_with_progress`] fills between two progress updates, 1GB.
+pub const CHANNEL_PROGRESS_STEP: usize = 1_000_000_000;
+/// Fraction of the target that [`allocate_target_rss`] accepts the resident memory to be off by.
+pub const TARGET_RSS_TOLERANCE: f64 = 0.01;
+/// Smallest number of bytes [`allocate_target_rss`] accepts the resident memory to be off by,
+/// since allocators and the OS hand out memory in pages or larger blocks.
+pub const TARGET_RSS_MIN_TOLERANCE: usize = 1024 * 1024;
+/// How long a supervised churn worker waits after a panic before it's restarted.
+const WORKER_RESTART_DELAY: Duration = Duration::from_millis(100);
+/// Most times [`allocate_target_rss`] resizes the allocation before settling for what it has.
+pub const TARGET_RSS_MAX_ITERATIONS: usize = 8;
+/// Size from which [`Backend::Auto`] maps the memory directly on Unix, 64MiB.
+pub const AUTO_MMAP_THRESHOLD: usize = 64 * 1024 * 1024;
+/// Size from which [`Backend::Auto`] backs the memory with huge pages on Linux if enough are
+/// free, 1GiB, where they save the most TLB misses.
+pub const AUTO_HUGE_PAGES_THRESHOLD: usize = 1024 * 1024 * 1024;
+
+/// Snapshot of what an allocation is doing, returned by [`Allocation::stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Number of bytes held by the allocation.
+    pub allocated_bytes: usize,
+    /// Number of churn cycles completed, summed over all worker threads.
+    pub cycles_completed: u64,
+    /// Resident set size of the whole process in bytes, `None` if it can't be determined
+    /// on this platform.
+    pub resident_bytes: Option<usize>,
+    /// Fraction of one CPU spent churning since the allocation was made, summed over all
+    /// worker threads, e.g. 0.1 for 10% of one core.
+    pub cpu_fraction: f64,
+    /// Page faults of the whole process while the memory was allocated and filled, `None` if
+    /// they can't be counted on this platform or nothing was allocated.
+    pub fill_page_faults: Option<PageFaults>,
+    /// Page faults of the whole process since the memory was filled, `None` if they can't be
+    /// counted on this platform. Major faults while churning mean pages are being read back
+    /// from swap, so the memory isn't staying resident.
+    pub churn_page_faults: Option<PageFaults>,
+    /// Number of bytes locked in physical memory: all of them when the allocation is locked,
+    /// the part at the start that fit in the lock limit when only that could be locked and the
+    /// memory is churned instead, 0 when it isn't locked.
+    pub locked_bytes: usize,
+    /// Number of times a churn worker panicked and was restarted, always 0 unless
+    /// [`supervise`](crate::config::WeightConfigBuilder::supervise) is set.
+    pub worker_restarts: u64,
+    /// Number of bytes of the allocation backed by transparent huge pages, which the kernel
+    /// may also collapse its pages into in the background, changing how they fault and are
+    /// reclaimed. Read from `/proc/self/smaps` at most every [`THP_SAMPLE_INTERVAL`], see
+    /// [`Allocation::thp_bytes`] for a fresh count. `None` on platforms other than Linux.
+    pub thp_bytes: Option<usize>,
+}
+
+/// Cloneable handle to the statistics of an allocation, returned by
+/// [`Allocation::stats_handle`].
+#[derive(Debug, Clone)]
+pub struct StatsHandle {
+    bytes: Arc<AtomicUsize>,
+    control: Arc<Control>,
+}
+
+impl StatsHandle {
+    /// Returns a snapshot of the allocation's statistics, the same as [`Allocation::stats`].
+    pub fn stats(&self) -> Stats {
+        self.control.stats(self.bytes.load(Ordering::Relaxed))
+    }
+}
+
+/// Cloneable handle that stops an allocation from another thread, returned by
+/// [`Allocation::stop_handle`].
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+    control: Arc<Control>,
+}
+
+impl StopHandle {
+    /// Stops churning the memory and ends a [`hold`](Allocation::hold). The memory is
+    /// released by the churn thread, or when the allocation is dropped if it's locked.
+    pub fn stop(&self) {
+        self.control.stop();
+    }
+
+    /// Whether the allocation was stopped.
+    pub fn is_stopped(&self) -> bool {
+        !self.control.running.load(Ordering::SeqCst)
+    }
+}
+
+/// How long [`Stats::thp_bytes`] is reused before `/proc/self/smaps` is read again, which
+/// walks the page tables of every mapping of the process.
+pub const THP_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default number of cycle durations kept for [`Allocation::cycle_percentiles`].
+pub const DEFAULT_CYCLE_HISTORY: usize = 1024;
+
+/// Percentiles of the time recent churn cycles took, returned by
+/// [`Allocation::cycle_percentiles`]. A cycle's time is what its passes over the memory took,
+/// without the sleeps in between, so stalls such as pages being read back from swap show up
+/// as spikes in the upper percentiles that the mean hides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CyclePercentiles {
+    /// Number of cycles the percentiles are computed over.
+    pub samples: usize,
+    /// Median cycle time.
+    pub p50: Duration,
+    /// Time 90% of the cycles took at most.
+    pub p90: Duration,
+    /// Time 99% of the cycles took at most.
+    pub p99: Duration,
+    /// Longest cycle time.
+    pub max: Duration,
+}
+
+/// Maximum number of mismatched offsets recorded by [`Allocation::verify`].
+pub const MAX_REPORTED_MISMATCHES: usize = 1024;
+
+/// Result of checking an allocation for corruption with [`Allocation::verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Number of bytes that were checked.
+    pub checked_bytes: usize,
+    /// Number of bytes that didn't hold their expected value.
+    pub mismatch_count: usize,
+    /// Offsets of the first mismatched bytes in ascending order, at most
+    /// [`MAX_REPORTED_MISMATCHES`] of them.
+    pub mismatches: Vec<usize>,
+}
+
+impl Verification {
+    /// Whether every checked byte held its expected value.
+    pub fn is_ok(&self) -> bool {
+        self.mismatch_count == 0
+    }
+
+    pub(crate) fn merge(&mut self, other: Verification) {
+        self.checked_bytes += other.checked_bytes;
+        self.mismatch_count += other.mismatch_count;
+        self.mismatches.extend(other.mismatches);
+        self.mismatches.sort_unstable();
+        self.mismatches.truncate(MAX_REPORTED_MISMATCHES);
+    }
+}
+
+/// Request for a churn worker to verify its range, answered on the enclosed sender.
+type VerifyRequest = Sender<Verification>;
+
+/// Senders for asking each of the current churn workers of an allocation to verify its range.
+type Verifiers = Arc<Mutex<Vec<Sender<VerifyRequest>>>>;
+
+/// Request for the thread owning the memory of an allocation, handled after its churn
+/// workers have exited and answered on the enclosed sender once they've been restarted.
+enum Command {
+    /// Truncate the memory to this many bytes.
+    Shrink(usize, Reply),
+    /// Extend the memory to this many bytes, filled with the pattern, unless that would leave
+    /// less than the safety margin of available memory.
+    Grow(usize, FillPattern, usize, Reply),
+    /// Apply a paging hint to the memory.
+    Advise(Advice, Reply),
+    /// Write the pattern to all of the memory again, faulting it back in, and churn and verify
+    /// it with that pattern from then on.
+    Refill(FillPattern, Reply),
+    /// Touch every page of the memory so reclaimed pages are faulted back in.
+    Retouch(Reply),
+    /// Pin the workers to these CPU cores, one per worker.
+    SetCpus(Vec<usize>, Reply),
+    /// Send a copy of up to this many bytes from the start of the memory. Answered right away,
+    /// since the memory isn't changed.
+    Snapshot(usize, Sender<Vec<u8>>),
+    /// Write this range of the memory to a file at the path.
+    Dump(PathBuf, Range<usize>, Reply),
+    /// Write to every page of this range of the memory, faulting it in.
+    Fault(Range<usize>, Reply),
+    /// Call the function with this range of the memory. Answered right away by the function,
+    /// since the memory isn't changed.
+    Inspect(Range<usize>, Inspect),
+    /// Send where the memory is and wait until the receiver is disconnected, leaving it to the
+    /// handle until then while the workers are stopped.
+    Lend(Sender<Lent>, Receiver<()>, Reply),
+}
+
+/// The memory handed to the handle by [`Command::Lend`].
+struct Lent {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the pointer is only dereferenced by the handle while the background thread waits
+// for it to be done, so the memory is never accessed from two threads at once.
+unsafe impl Send for Lent {}
+
+/// Exclusive access to the memory of an allocation, handed out in chunks by
+/// [`Allocation::chunks_mut`]. The churn workers stay stopped until this is dropped, which
+/// waits until they're running again.
+pub struct ChunksMut<'a> {
+    data: &'a mut [u8],
+    chunk_size: usize,
+    /// Dropping the sender lets the background thread restart the workers, which it answers
+    /// on the receiver. `None` for memory that isn't churned.
+    release: Option<Release>,
+}
+
+impl Drop for ChunksMut<'_> {
+    fn drop(&mut self) {
+        if let Some((release, restarted)) = self.release.take() {
+            drop(release);
+            let _ = restarted.recv();
+        }
+    }
+}
+
+impl ChunksMut<'_> {
+    /// The memory in chunks of [`chunk_size`](Self::chunk_size) bytes, in order, the last one
+    /// shorter if the size isn't a multiple of it.
+    pub fn iter_mut(&mut self) -> std::slice::ChunksMut<'_, u8> {
+        self.data.chunks_mut(self.chunk_size)
+    }
+
+    /// The memory in chunks like [`iter_mut`](Self::iter_mut), for reading it.
+    pub fn iter(&self) -> std::slice::Chunks<'_, u8> {
+        self.data.chunks(self.chunk_size)
+    }
+
+    /// Size of the chunks in bytes, the
+    /// [`chunk_size`](crate::config::WeightConfigBuilder::chunk_size) of the config.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+}
+
+impl<'b> IntoIterator for &'b mut ChunksMut<'_> {
+    type Item = &'b mut [u8];
+    type IntoIter = std::slice::ChunksMut<'b, u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+type Reply = Sender<Result<(), WeightError>>;
+/// The sender ending a [`Command::Lend`] and the receiver of its reply.
+type Release = (Sender<()>, Receiver<Result<(), WeightError>>);
+
+/// Function called with part of the memory by [`Command::Inspect`], which answers for itself.
+type Inspect = Box<dyn FnOnce(&[u8]) + Send>;
+
+/// State shared between an allocation handle and its churn workers.
+#[derive(Debug)]
+pub(crate) struct Control {
+    pub(crate) running: AtomicBool,
+    pub(crate) cycles: AtomicU64,
+    /// Whether the workers should skip touching the memory until resumed.
+    pub(crate) paused: AtomicBool,
+    /// Whether the workers haven't started churning yet, see
+    /// [`WeightConfigBuilder::warmup_delay`](crate::config::WeightConfigBuilder::warmup_delay).
+    warming_up: AtomicBool,
+    /// When the warmup ends by itself, `None` if only [`Control::start_churn`] ends it.
+    warmup_end: Option<Instant>,
+    /// Time the workers sleep between passes in nanoseconds, see [`Allocation::set_interval`].
+    interval: AtomicU64,
+    /// When holding the memory ends, see
+    /// [`WeightConfigBuilder::max_runtime`](crate::config::WeightConfigBuilder::max_runtime).
+    deadline: Option<Instant>,
+    /// Whether the workers should exit so the owner thread can handle a [`Command`].
+    restart: AtomicBool,
+    /// Time spent in passes over the memory, summed over all workers.
+    busy_nanos: AtomicU64,
+    started: Instant,
+    /// Durations of the most recent cycles of all workers, oldest first.
+    cycle_times: Mutex<VecDeque<Duration>>,
+    /// How many cycle durations are kept.
+    cycle_history: usize,
+    /// Page faults taken while the memory was filled.
+    fill_faults: Option<PageFaults>,
+    /// Page faults of the process when the memory was filled, the start of the churn faults.
+    filled_faults: Option<PageFaults>,
+    /// Bytes of the memory that are locked in physical memory.
+    pub(crate) locked: AtomicUsize,
+    /// Panicked workers that were restarted, see [`churn_supervised`].
+    restarts: AtomicU64,
+    /// Addresses of the memory, to find its mappings in `/proc/self/smaps`.
+    region: Mutex<Range<usize>>,
+    /// The last count of bytes backed by transparent huge pages and when it was taken.
+    thp_sample: Mutex<Option<(Instant, Option<usize>)>>,
+    /// Held while a sleeping worker checks whether to wake up, see [`Control::sleep`].
+    wake: Mutex<()>,
+    /// Notified by [`Control::wake`].
+    woken: Condvar,
+}
+
+impl Control {
+    /// Creates the state for a new allocation that keeps the durations of its last
+    /// `cycle_history` cycles, right after its memory was filled taking `fill_faults`.
+    pub(crate) fn new(cycle_history: usize, fill_faults: Option<PageFaults>) -> Control {
+        Control {
+            running: AtomicBool::new(true),
+            cycles: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            warming_up: AtomicBool::new(false),
+            warmup_end: None,
+            interval: AtomicU64::new(0),
+            deadline: None,
+            restart: AtomicBool::new(false),
+            busy_nanos: AtomicU64::new(0),
+            started: Instant::now(),
+            cycle_times: Mutex::new(VecDeque::with_capacity(cycle_history)),
+            cycle_history,
+            fill_faults,
+            filled_faults: system::page_faults(),
+            locked: AtomicUsize::new(0),
+            restarts: AtomicU64::new(0),
+            region: Mutex::new(0..0),
+            thp_sample: Mutex::new(None),
+            wake: Mutex::new(()),
+            woken: Condvar::new(),
+        }
+    }
+
+    /// Starts out with the memory at the addresses of `data`.
+    pub(crate) fn with_region(self, data: &[u8]) -> Control {
+        self.set_region(data);
+        self
+    }
+
+    /// Records that the memory is now at the addresses of `data`, e.g. after growing moved it.
+    pub(crate) fn set_region(&self, data: &[u8]) {
+        let start = data.as_ptr() as usize;
+        *self.region.lock().unwrap_or_else(PoisonError::into_inner) = start..start + data.len();
+    }
+
+    /// Bytes of the memory backed by transparent huge pages, reusing the last count if it's
+    /// younger than [`THP_SAMPLE_INTERVAL`] unless `fresh` is set.
+    pub(crate) fn thp_bytes(&self, fresh: bool) -> Option<usize> {
+        let mut sample = self.thp_sample.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((taken, bytes)) = *sample
+            && !fresh
+            && taken.elapsed() < THP_SAMPLE_INTERVAL
+        {
+            return bytes;
+        }
+        let region = self.region.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        let bytes = system::transparent_huge_pages(region);
+        *sample = Some((Instant::now(), bytes));
+        bytes
+    }
+
+    /// Holds off churning for `delay` from now, forever if it doesn't fit in an [`Instant`],
+    /// until [`start_churn`](Control::start_churn) is called.
+    pub(crate) fn with_warmup(mut self, delay: Duration) -> Control {
+        self.warming_up = AtomicBool::new(!delay.is_zero());
+        self.warmup_end = Instant::now().checked_add(delay);
+        self
+    }
+
+    /// Starts out sleeping `interval` between passes.
+    pub(crate) fn with_interval(self, interval: Duration) -> Control {
+        self.set_interval(interval);
+        self
+    }
+
+    /// Time to sleep between passes.
+    pub(crate) fn interval(&self) -> Duration {
+        Duration::from_nanos(self.interval.load(Ordering::Relaxed))
+    }
+
+    /// Changes the time to sleep between passes, saturating at about 584 years. Sleeping
+    /// workers pick it up right away.
+    pub(crate) fn set_interval(&self, interval: Duration) {
+        self.interval.store(interval.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+        self.wake();
+    }
+
+    /// Ends holding the memory once `max_runtime` has elapsed from now.
+    pub(crate) fn with_max_runtime(mut self, max_runtime: Option<Duration>) -> Control {
+        self.deadline = max_runtime.and_then(|max_runtime| Instant::now().checked_add(max_runtime));
+        self
+    }
+
+    /// Ends the warmup, so the workers start churning.
+    pub(crate) fn start_churn(&self) {
+        self.warming_up.store(false, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Tells the workers to exit, waking them up if they're sleeping.
+    pub(crate) fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Tells the workers to exit so the owner thread can handle a [`Command`], waking them up
+    /// if they're sleeping.
+    pub(crate) fn request_restart(&self) {
+        self.restart.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Wakes up the sleeping workers to check whether they should still sleep, after changing
+    /// what they check.
+    pub(crate) fn wake(&self) {
+        // A worker holds the lock from checking until it waits, so it can't miss the change
+        drop(self.wake.lock().unwrap_or_else(PoisonError::into_inner));
+        self.woken.notify_all();
+    }
+
+    /// Sleeps for as long as `remaining` returns, which is asked again whenever the workers are
+    /// [woken](Self::wake), until it returns zero or the workers are told to exit.
+    pub(crate) fn sleep(&self, mut remaining: impl FnMut() -> Duration) {
+        let mut guard = self.wake.lock().unwrap_or_else(PoisonError::into_inner);
+        while self.churning() {
+            let timeout = remaining();
+            if timeout.is_zero() {
+                break;
+            }
+            guard = self.woken.wait_timeout(guard, timeout).unwrap_or_else(PoisonError::into_inner).0;
+        }
+    }
+
+    /// Whether the workers are still warming up.
+    pub(crate) fn warming_up(&self) -> bool {
+        if !self.warming_up.load(Ordering::SeqCst) {
+            return false;
+        }
+        if self.warmup_end.is_some_and(|end| Instant::now() >= end) {
+            self.start_churn();
+            return false;
+        }
+        true
+    }
+
+    /// Whether the memory should be left alone, because churning is paused or hasn't started.
+    pub(crate) fn idle(&self) -> bool {
+        self.paused.load(Ordering::SeqCst) || self.warming_up()
+    }
+
+    /// Records that the passes of a cycle over the memory took `busy`, dropping the oldest
+    /// duration once the history is full.
+    pub(crate) fn record_cycle_time(&self, busy: Duration) {
+        if self.cycle_history == 0 {
+            return;
+        }
+        let mut cycle_times = self.cycle_times.lock().unwrap_or_else(PoisonError::into_inner);
+        if cycle_times.len() == self.cycle_history {
+            cycle_times.pop_front();
+        }
+        cycle_times.push_back(busy);
+    }
+
+    pub(crate) fn cycle_percentiles(&self) -> Option<CyclePercentiles> {
+        let cycle_times = self.cycle_times.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut times: Vec<Duration> = cycle_times.iter().copied().collect();
+        drop(cycle_times);
+        times.sort_unstable();
+        let max = *times.last()?;
+        // Nearest rank, so every percentile is a duration that was measured
+        let percentile = |percent: usize| times[(times.len() * percent).div_ceil(100).max(1) - 1];
+        Some(CyclePercentiles {
+            samples: times.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max,
+        })
+    }
+
+    /// Whether the sync churn workers should keep going.
+    fn churning(&self) -> bool {
+        self.running.load(Ordering::SeqCst) && !self.restart.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn add_busy(&self, busy: Duration) {
+        self.busy_nanos.fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self, bytes: usize) -> Stats {
+        let busy = Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed));
+        Stats {
+            allocated_bytes: bytes,
+            cycles_completed: self.cycles.load(Ordering::Relaxed),
+            resident_bytes: system::resident_memory(),
+            cpu_fraction: busy.as_secs_f64() / self.started.elapsed().as_secs_f64(),
+            fill_page_faults: self.fill_faults,
+            churn_page_faults: system::page_faults_since(self.filled_faults),
+            locked_bytes: self.locked.load(Ordering::Relaxed),
+            worker_restarts: self.restarts.load(Ordering::Relaxed),
+            thp_bytes: self.thp_bytes(false),
+        }
+    }
+}
+
+/// Handle to a block of memory that is being kept occupied by a background thread.
+/// The background thread owns the memory exclusively, so churning it never takes a lock.
+/// Memory that is locked in physical RAM is held by the handle instead and isn't churned.
+///
+/// Dropping the handle stops the background thread and its workers, waits for them to exit
+/// and releases the memory, so no threads are left behind:
+///
+/// ```
+/// # use std::time::{Duration, Instant};
+/// # fn main() -> Result<(), weight::WeightError> {
+/// // Linux lists the threads of the process there, elsewhere both counts are `None`
+/// let threads = || std::fs::read_dir("/proc/self/task").map(|tasks| tasks.count()).ok();
+/// let before = threads();
+/// let allocation = weight::allocate(weight::WeightConfig::new("1MB").workers(2).build()?)?;
+/// if let (Some(before), Some(running)) = (before, threads()) {
+///     // The background thread and its two workers
+///     assert_eq!(running, before + 3);
+/// }
+///
+/// drop(allocation);
+/// // A joined thread can take a moment to disappear from the list
+/// let deadline = Instant::now() + Duration::from_secs(10);
+/// while threads() != before {
+///     assert!(Instant::now() < deadline, "threads were left behind");
+///     std::thread::sleep(Duration::from_millis(1));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Allocation {
+    /// Number of bytes held, shared with the watchdog, which may shrink the allocation.
+    bytes: Arc<AtomicUsize>,
+    committed: Option<usize>,
+    /// Resident set size of the process without the allocation.
+    baseline: Option<usize>,
+    control: Arc<Control>,
+    worker: Option<JoinHandle<()>>,
+    commands: Option<Sender<Command>>,
+    verifiers: Verifiers,
+    fill_pattern: FillPattern,
+    /// Size of the chunks the workers touch the memory in, and [`chunks_mut`](Self::chunks_mut) hands it out in.
+    chunk_size: usize,
+    /// Bytes of available memory growing has to leave.
+    safety_margin: usize,
+    /// Whether [`release_pages`](Self::release_pages) paused the churn, so
+    /// [`reacquire_pages`](Self::reacquire_pages) resumes it.
+    paused_for_release: bool,
+    locked: Option<LockedBuffer>,
+    watchdog: Option<Periodic>,
+    disk_flush: Option<Periodic>,
+    csv_trace: Option<Periodic>,
+    cpus: Option<Vec<usize>>,
+    /// Name of the shared memory segment backing the memory, see [`Backend::Shared`].
+    shared_name: Option<String>,
+    /// Bytes of the request that couldn't be reserved in best-effort mode.
+    shortfall: usize,
+}
+
+impl Allocation {
+    /// Number of bytes held by this allocation.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of requested bytes that couldn't be reserved, always 0 unless
+    /// [`best_effort`](crate::config::WeightConfigBuilder::best_effort) is set. The allocation
+    /// then holds [`bytes`](Self::bytes) out of `bytes() + shortfall()` requested bytes.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MB").best_effort(true).build()?)?;
+    /// assert_eq!(allocation.bytes() + allocation.shortfall(), 1_000_000);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shortfall(&self) -> usize {
+        self.shortfall
+    }
+
+    /// Number of bytes of the allocation that were resident in physical memory right after
+    /// allocating, measured from the growth of the process' resident set size. `None` if the
+    /// resident set size can't be determined on this platform.
+    pub fn committed_bytes(&self) -> Option<usize> {
+        self.committed
+    }
+
+    /// Name of the POSIX shared memory segment backing the allocation, e.g. `/weight-1234-0`,
+    /// `None` unless it uses [`Backend::Shared`]. Another process can open it with `shm_open`
+    /// and map it read-only to inspect the memory while it's being churned.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// # #[cfg(unix)]
+    /// # {
+    /// let config = weight::WeightConfig::new("1MB").backend(weight::config::Backend::Shared).build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// let name = allocation.shared_memory_name().unwrap().to_string();
+    /// # #[cfg(target_os = "linux")]
+    /// assert!(std::path::Path::new("/dev/shm").join(&name[1..]).exists());
+    /// drop(allocation);
+    /// # #[cfg(target_os = "linux")]
+    /// assert!(!std::path::Path::new("/dev/shm").join(&name[1..]).exists());
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shared_memory_name(&self) -> Option<&str> {
+        self.shared_name.as_deref()
+    }
+
+    /// Whether the memory is locked in physical RAM rather than kept occupied by churning it.
+    /// `false` when only part of it could be locked, see [`Stats::locked_bytes`].
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
+    /// Returns a snapshot of the allocation's statistics.
+    pub fn stats(&self) -> Stats {
+        self.control.stats(self.bytes())
+    }
+
+    /// Number of bytes of the memory backed by transparent huge pages right now, from
+    /// `AnonHugePages` in `/proc/self/smaps`, like [`Stats::thp_bytes`] but counted afresh.
+    /// Where the kernel collapses pages into huge pages in the background this can grow while
+    /// the allocation runs, which [`disable_thp`](crate::config::WeightConfigBuilder::disable_thp)
+    /// prevents. `None` on platforms other than Linux.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("8MiB").disable_thp(true).build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// if let Some(huge) = allocation.thp_bytes() {
+    ///     assert_eq!(huge, 0);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn thp_bytes(&self) -> Option<usize> {
+        self.control.thp_bytes(true)
+    }
+
+    /// Percentiles of how long the most recent cycles of all workers took, over the last
+    /// [`cycle_history`](crate::config::WeightConfigBuilder::cycle_history) cycles. `None`
+    /// until a cycle has completed, and always for locked memory, which isn't churned.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("1MB").interval(std::time::Duration::ZERO).build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// while allocation.stats().cycles_completed < 10 {
+    ///     std::thread::yield_now();
+    /// }
+    /// let percentiles = allocation.cycle_percentiles().unwrap();
+    /// assert!(percentiles.samples >= 10);
+    /// assert!(percentiles.p50 <= percentiles.p99 && percentiles.p99 <= percentiles.max);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cycle_percentiles(&self) -> Option<CyclePercentiles> {
+        self.control.cycle_percentiles()
+    }
+
+    /// Returns a handle that takes snapshots of the allocation's statistics from other
+    /// threads, e.g. to export them. Once the allocation is stopped the handle keeps
+    /// returning its last counters.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            bytes: Arc::clone(&self.bytes),
+            control: Arc::clone(&self.control),
+        }
+    }
+
+    /// Waits until the allocation is resident in physical memory, within
+    /// [`DEFAULT_RESIDENT_TOLERANCE`], or `timeout` elapses. Pages can be faulted in lazily, so
+    /// this lets a test harness start measuring only once the memory is really committed.
+    /// Returns whether the memory became resident, which is always false on platforms where
+    /// the resident set size can't be determined.
+    pub fn wait_resident(&self, timeout: Duration) -> bool {
+        self.wait_resident_within(timeout, DEFAULT_RESIDENT_TOLERANCE)
+    }
+
+    /// Waits like [`wait_resident`](Self::wait_resident) until all but a `tolerance` fraction
+    /// of the allocation is resident, e.g. `0.1` to accept 90% of it.
+    ///
+    /// The resident set size is that of the whole process, minus what it had resident before
+    /// allocating, so memory the process allocates or frees elsewhere skews it.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let allocation = weight::allocate(weight::WeightConfig::new("16MB").build()?)?;
+    /// let resident = allocation.wait_resident_within(Duration::from_secs(5), 0.1);
+    /// assert_eq!(resident, allocation.stats().resident_bytes.is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_resident_within(&self, timeout: Duration, tolerance: f64) -> bool {
+        if self.bytes() == 0 {
+            return true;
+        }
+        let Some(baseline) = self.baseline else {
+            return false;
+        };
+        let target = self.bytes() as f64 * (1.0 - tolerance.clamp(0.0, 1.0));
+        let deadline = Instant::now() + timeout;
+        loop {
+            let Some(resident) = system::resident_memory() else {
+                return false;
+            };
+            if resident.saturating_sub(baseline) as f64 >= target {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            thread::sleep(RESIDENT_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Returns a handle that stops the allocation from other threads, e.g. a Ctrl-C handler,
+    /// which ends a [`hold`](Self::hold).
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            control: Arc::clone(&self.control),
+        }
+    }
+
+    /// Blocks the calling thread while the memory is being churned, for daemons that have
+    /// nothing else to do after allocating. Returns once the allocation is stopped, by a
+    /// [`StopHandle`] or an `on_cycle` callback, or, with the `signal` feature on Unix, when
+    /// the process receives SIGTERM or SIGINT. With a
+    /// [`max_runtime`](crate::config::WeightConfigBuilder::max_runtime) it also returns, and
+    /// stops the churn, once that has elapsed since the memory was allocated. Cleanup such as
+    /// dropping the handle can run after it returns, and the returned [`Termination`] tells
+    /// the two apart for the exit status.
+    ///
+    /// With the `signal` feature the signals end the hold instead of terminating the process,
+    /// and a signal ends every hold in progress when it arrives. Once no hold is in progress,
+    /// and no [`ShutdownHandler`](crate::shutdown::ShutdownHandler) was installed, they
+    /// terminate the process again as if they weren't handled, and a later hold only ends for
+    /// signals that arrive while it's holding.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MB").build()?)?;
+    /// let stop = allocation.stop_handle();
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(Duration::from_millis(50));
+    ///     stop.stop();
+    /// });
+    /// assert_eq!(allocation.hold(), weight::Termination::Stopped);
+    /// assert!(allocation.stop_handle().is_stopped());
+    ///
+    /// let config = weight::WeightConfig::new("1MB").max_runtime(Duration::from_millis(50)).build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// assert_eq!(allocation.hold(), weight::Termination::MaxRuntime);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn hold(&self) -> Termination {
+        #[cfg(all(feature = "signal", unix))]
+        let signals = crate::shutdown::HoldSignals::register();
+        while self.control.running.load(Ordering::SeqCst) {
+            #[cfg(all(feature = "signal", unix))]
+            if signals.received() {
+                info!("Received a termination signal, no longer holding the allocation");
+                self.control.stop();
+                break;
+            }
+            let now = Instant::now();
+            if let Some(deadline) = self.control.deadline {
+                if now >= deadline {
+                    info!("Reached the maximum runtime, no longer holding the allocation");
+                    self.control.stop();
+                    return Termination::MaxRuntime;
+                }
+                thread::sleep(HOLD_POLL_INTERVAL.min(deadline - now));
+            } else {
+                thread::sleep(HOLD_POLL_INTERVAL);
+            }
+        }
+        Termination::Stopped
+    }
+
+    /// Stops touching the memory without releasing it, e.g. to observe how fast the OS
+    /// reclaims the pages. The workers keep sleeping until [`resume`](Self::resume) is called.
+    /// Has no effect on locked memory.
+    pub fn pause(&self) {
+        self.control.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes churning after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        self.control.paused.store(false, Ordering::SeqCst);
+        self.control.wake();
+    }
+
+    /// Whether churning is paused.
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::SeqCst)
+    }
+
+    /// Time the workers sleep between passes over the memory, see
+    /// [`set_interval`](Self::set_interval).
+    pub fn interval(&self) -> Duration {
+        self.control.interval()
+    }
+
+    /// Changes how long the workers sleep between passes over the memory without recreating
+    /// the allocation, e.g. from a control loop that raises or lowers the pressure. Sleeping
+    /// workers pick up the new interval right away, counted from the end of their last pass, so
+    /// lowering a long interval takes effect at once. [`Duration::ZERO`] churns continuously,
+    /// and the [`cpu_budget`](crate::config::WeightConfigBuilder::cpu_budget) and
+    /// [`adaptive_interval`](crate::config::WeightConfigBuilder::adaptive_interval) still
+    /// apply on top of it. Has no effect on locked memory.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// // Without the change not even the first cycle would complete within the deadline
+    /// let config = weight::WeightConfig::new("64KB").interval(Duration::from_secs(3600)).build()?;
+    /// let allocation = weight::allocate(config)?;
+    ///
+    /// allocation.set_interval(Duration::ZERO);
+    /// assert_eq!(allocation.interval(), Duration::ZERO);
+    /// let deadline = std::time::Instant::now() + Duration::from_secs(60);
+    /// while allocation.stats().cycles_completed < 100 {
+    ///     assert!(std::time::Instant::now() < deadline, "the new interval wasn't picked up");
+    ///     std::thread::yield_now();
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_interval(&self, interval: Duration) {
+        self.control.set_interval(interval);
+        debug!("Changed the churn interval to {:?}", interval);
+    }
+
+    /// Starts churning before the
+    /// [`warmup_delay`](crate::config::WeightConfigBuilder::warmup_delay) has elapsed, or at all
+    /// if the delay is [`Duration::MAX`]. Has no effect once churning has started.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("1MB").interval(Duration::ZERO).warmup_delay(Duration::MAX).build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// std::thread::sleep(Duration::from_millis(50));
+    /// assert!(allocation.is_warming_up());
+    /// assert_eq!(allocation.stats().cycles_completed, 0);
+    ///
+    /// allocation.start_churn();
+    /// while allocation.stats().cycles_completed == 0 {
+    ///     std::thread::yield_now();
+    /// }
+    /// assert!(!allocation.is_warming_up());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn start_churn(&self) {
+        self.control.start_churn();
+    }
+
+    /// Whether churning hasn't started yet because of the
+    /// [`warmup_delay`](crate::config::WeightConfigBuilder::warmup_delay).
+    pub fn is_warming_up(&self) -> bool {
+        self.control.warming_up()
+    }
+
+    /// Checks that every byte still holds the value it was filled with, detecting silent
+    /// memory corruption such as bad RAM or bit flips.
+    ///
+    /// Each churn worker checks its range at the end of its next cycle, when the increment has
+    /// been undone, so this blocks for up to one cycle. Ranges of workers that have exited
+    /// aren't checked.
+    pub fn verify(&self) -> Verification {
+        if let Some(locked) = &self.locked {
+            return verify_pattern(locked.data(), 0, self.fill_pattern);
+        }
+        let (reply, replies) = mpsc::channel();
+        for verifier in self.verifiers.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            let _ = verifier.send(reply.clone());
+        }
+        drop(reply);
+        let mut verification = Verification::default();
+        for range in replies {
+            verification.merge(range);
+        }
+        verification
+    }
+
+    /// CRC-32 of the whole memory, see [`checksum::crc32`], to snapshot its contents and
+    /// compare them later in a long-running residency test. The churn leaves every byte as it
+    /// found it at the end of a cycle, so a checksum that changes while nothing else wrote to
+    /// the memory, e.g. [`release_pages`](Self::release_pages) or growing it, means it was
+    /// corrupted. See [`checksum_range_with`](Self::checksum_range_with) for other hashes.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let allocation = weight::allocate(weight::WeightConfig::new("64KB").interval(std::time::Duration::ZERO).build()?)?;
+    /// let before = allocation.checksum()?;
+    /// while allocation.stats().cycles_completed < 3 {
+    ///     std::thread::yield_now();
+    /// }
+    /// assert_eq!(allocation.checksum()?, before);
+    /// // The default fill pattern holds the offset of each byte
+    /// let filled: Vec<u8> = (0..64_000).map(|i| i as u8).collect();
+    /// assert_eq!(before, weight::checksum::crc32(&filled));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn checksum(&self) -> Result<u32, WeightError> {
+        self.checksum_range_with(0..self.bytes(), checksum::crc32)
+    }
+
+    /// Calls `hash` with the bytes of the memory in `range` and returns its result, for any
+    /// hash or check that should see the memory in the state it was filled in. Like
+    /// [`dump_range_to`](Self::dump_range_to) the workers are paused after their current cycle
+    /// until `hash` returns, so it should be quick, and the memory isn't copied.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// # use std::hash::Hasher;
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MB").build()?)?;
+    /// let hash = allocation.checksum_range_with(0..4096, |data| {
+    ///     let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    ///     hasher.write(data);
+    ///     hasher.finish()
+    /// })?;
+    /// println!("First page hashes to {:#x}", hash);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn checksum_range_with<T: Send + 'static>(
+        &self,
+        range: Range<usize>,
+        hash: impl FnOnce(&[u8]) -> T + Send + 'static,
+    ) -> Result<T, WeightError> {
+        if range.start > range.end || range.end > self.bytes() {
+            return Err(WeightError::InvalidConfig(format!(
+                "Can't checksum bytes {}..{} of an allocation of {} bytes",
+                range.start,
+                range.end,
+                self.bytes()
+            )));
+        }
+        if let Some(locked) = &self.locked {
+            return Ok(hash(&locked.data()[range]));
+        }
+        // An empty allocation has no thread to ask
+        if range.is_empty() {
+            return Ok(hash(&[]));
+        }
+        let (reply, result) = mpsc::channel();
+        self.send_command(Command::Inspect(
+            range,
+            Box::new(move |data| {
+                let _ = reply.send(hash(data));
+            }),
+        ))?;
+        result.recv().map_err(|_| stopped())
+    }
+
+    /// Hands out the memory for custom processing, e.g. checksums, sampling or writes of its
+    /// own, in chunks of the configured
+    /// [`chunk_size`](crate::config::WeightConfigBuilder::chunk_size). Churning and reading
+    /// the memory from two threads at once would race, so the workers are stopped after their
+    /// current cycle, when every byte holds its fill value, and only started again once the
+    /// returned [`ChunksMut`] is dropped. Unlike [`pause`](Self::pause), which lets the
+    /// workers finish what they're doing and sleep, this waits until no worker can touch the
+    /// memory anymore. Keep it short, the memory isn't churned meanwhile.
+    ///
+    /// Writes stay in the memory and the churn carries on from them, so [`verify`](Self::verify)
+    /// reports every byte that no longer holds the fill pattern, unless it's written again with
+    /// [`refill`](Self::refill).
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("1MB").chunk_size(64 * 1024).build()?;
+    /// let mut allocation = weight::allocate(config)?;
+    /// let mut chunks = allocation.chunks_mut()?;
+    /// assert_eq!(chunks.iter().count(), 1_000_000usize.div_ceil(64 * 1024));
+    ///
+    /// // The default fill pattern holds the offset of each byte
+    /// let sum: u64 = chunks.iter().flatten().map(|&byte| u64::from(byte)).sum();
+    /// let expected: u64 = (0..1_000_000u64).map(|i| i % 256).sum();
+    /// assert_eq!(sum, expected);
+    ///
+    /// for chunk in &mut chunks {
+    ///     chunk[0] = chunk[0].wrapping_add(1);
+    /// }
+    /// drop(chunks);
+    /// assert_eq!(allocation.verify().mismatch_count, 1_000_000usize.div_ceil(64 * 1024));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn chunks_mut(&mut self) -> Result<ChunksMut<'_>, WeightError> {
+        let chunk_size = self.chunk_size;
+        if self.locked.is_some() {
+            // Locked memory isn't churned, so nothing else is accessing it
+            let data = self.locked.as_mut().map_or(&mut [][..], |locked| locked.data_mut());
+            return Ok(ChunksMut {
+                data,
+                chunk_size,
+                release: None,
+            });
+        }
+        // An empty allocation may have no thread to ask
+        if self.bytes() == 0 {
+            return Ok(ChunksMut {
+                data: &mut [],
+                chunk_size,
+                release: None,
+            });
+        }
+        let (lend, lent) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let (reply, restarted) = mpsc::channel();
+        self.send_command(Command::Lend(lend, released, reply))?;
+        let lent = lent.recv().map_err(|_| stopped())?;
+        // SAFETY: the background thread owns the memory and waits, with the workers stopped,
+        // until `release` is dropped along with the returned value. That borrows the handle
+        // mutably, so the memory can't be released or changed by it meanwhile either.
+        let data = unsafe { std::slice::from_raw_parts_mut(lent.ptr, lent.len) };
+        Ok(ChunksMut {
+            data,
+            chunk_size,
+            release: Some((release, restarted)),
+        })
+    }
+
+    /// Writes the whole memory to a new file at `path`, e.g. to capture the actual bytes after
+    /// [`verify`](Self::verify) found mismatches and examine them offline. See
+    /// [`dump_range_to`](Self::dump_range_to).
+    pub fn dump_to(&mut self, path: impl AsRef<Path>) -> Result<(), WeightError> {
+        self.dump_range_to(path, 0..self.bytes())
+    }
+
+    /// Writes the bytes of the memory in `range` to a new file at `path`, replacing the file
+    /// if it exists. The bytes are written straight from the allocation without copying them,
+    /// but the workers are paused until the write has finished, after their current cycle so
+    /// every byte holds its fill value again unless it was corrupted. Dumping generates file
+    /// I/O and uses disk space proportional to the size of the range.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let mut allocation = weight::allocate(weight::WeightConfig::new("1MB").build()?)?;
+    /// let path = std::env::temp_dir().join(format!("weight-dump-{}.bin", std::process::id()));
+    /// allocation.dump_range_to(&path, 1000..2000)?;
+    /// let dump = std::fs::read(&path).unwrap();
+    /// # std::fs::remove_file(&path).unwrap();
+    /// // The default fill pattern holds the offset of each byte
+    /// assert_eq!(dump.len(), 1000);
+    /// assert_eq!(dump[0], (1000 % 256) as u8);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dump_range_to(&mut self, path: impl AsRef<Path>, range: Range<usize>) -> Result<(), WeightError> {
+        if range.start > range.end || range.end > self.bytes() {
+            return Err(WeightError::InvalidConfig(format!(
+                "Can't dump bytes {}..{} of an allocation of {} bytes",
+                range.start,
+                range.end,
+                self.bytes()
+            )));
+        }
+        let path = path.as_ref().to_path_buf();
+        if let Some(locked) = &self.locked {
+            return dump(&locked.data()[range], &path);
+        }
+        let (reply, result) = mpsc::channel();
+        self.send_command(Command::Dump(path.clone(), range, reply))?;
+        result.recv().map_err(|_| stopped())??;
+        info!("Dumped allocation to {}", path.display());
+        Ok(())
+    }
+
+    /// Releases the memory after the first `bytes` back to the OS while the rest keeps being
+    /// churned. This blocks until the workers have finished their current pass and were
+    /// restarted on the smaller memory.
+    ///
+    /// Fails if `bytes` is larger than the allocation or the memory is locked.
+    pub fn shrink(&mut self, bytes: usize) -> Result<(), WeightError> {
+        if bytes > self.bytes() {
+            return Err(WeightError::InvalidConfig(format!(
+                "Can't shrink an allocation of {} bytes to {} bytes",
+                self.bytes(), bytes
+            )));
+        }
+        if self.is_locked() {
+            return Err(WeightError::Unsupported("Locked memory can't be shrunk".to_string()));
+        }
+        if bytes == self.bytes() {
+            return Ok(());
+        }
+        let (reply, result) = mpsc::channel();
+        self.send_command(Command::Shrink(bytes, reply))?;
+        result.recv().map_err(|_| stopped())??;
+        info!("Shrunk allocation from {} to {} bytes", self.bytes(), bytes);
+        self.bytes.store(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Extends the allocation to `bytes`, after which the new memory is filled, pre-touched and
+    /// churned along with the rest. This blocks until the memory has been filled and the workers
+    /// were restarted on all of it.
+    ///
+    /// The memory can move while it grows, which briefly takes up the old size on top of the
+    /// new one. Fails if `bytes` is smaller than the allocation, the additional memory can't be
+    /// reserved, or the memory is locked, backed by huge pages, placed on NUMA nodes or empty
+    /// from the start, which leaves no thread to churn it. Like allocating, growing fails with
+    /// [`WeightError::WouldExhaustMemory`] if the additional memory would leave less than the
+    /// [`safety_margin`](crate::config::WeightConfigBuilder::safety_margin) available.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let mut allocation = weight::allocate(weight::WeightConfig::new("1MB").build()?)?;
+    /// let before = allocation.stats();
+    /// allocation.grow(64_000_000)?;
+    /// let after = allocation.stats();
+    /// assert_eq!(after.allocated_bytes, 64_000_000);
+    /// if let (Some(before), Some(after)) = (before.resident_bytes, after.resident_bytes) {
+    ///     assert!(after > before);
+    /// }
+    /// assert!(allocation.grow(1_000_000).is_err());
+    ///
+    /// if let Some(available) = weight::system::effective_available_memory() {
+    ///     let Err(weight::WeightError::WouldExhaustMemory { bytes, .. }) = allocation.grow(64_000_000 + available) else {
+    ///         panic!("expected growing to be refused");
+    ///     };
+    ///     assert_eq!(bytes, available);
+    ///     assert_eq!(allocation.bytes(), 64_000_000);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn grow(&mut self, bytes: usize) -> Result<(), WeightError> {
+        if bytes < self.bytes() {
+            return Err(WeightError::InvalidConfig(format!(
+                "Can't grow an allocation of {} bytes to {} bytes",
+                self.bytes(), bytes
+            )));
+        }
+        if self.is_locked() {
+            return Err(WeightError::Unsupported("Locked memory can't grow".to_string()));
+        }
+        if bytes == self.bytes() {
+            return Ok(());
+        }
+        if self.worker.is_none() && self.commands.is_none() && self.bytes() == 0 {
+            return Err(WeightError::Unsupported(
+                "Allocations that were empty from the start have no thread to churn grown memory".to_string(),
+            ));
+        }
+        // Refused here without stopping the workers, and checked again by the owner thread
+        // right before it reserves the memory
+        system::check_safety_margin(bytes - self.bytes(), self.safety_margin)?;
+        let (reply, result) = mpsc::channel();
+        self.send_command(Command::Grow(bytes, self.fill_pattern, self.safety_margin, reply))?;
+        result.recv().map_err(|_| stopped())??;
+        info!("Grew allocation from {} to {} bytes", self.bytes(), bytes);
+        self.bytes.store(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Applies a paging hint to the memory with `madvise(2)`, e.g. [`Advice::PageOut`] to
+    /// deliberately evict it and trigger page faults. The churn workers are paused while the
+    /// hint is applied. On platforms other than Linux this only warns.
+    pub fn advise(&mut self, advice: Advice) -> Result<(), WeightError> {
+        if let Some(locked) = &mut self.locked {
+            return advice::advise(locked.data_mut(), advice);
+        }
+        if self.bytes() == 0 {
+            return Ok(());
+        }
+        let (reply, result) = mpsc::channel();
+        self.send_command(Command::Advise(advice, reply))?;
+        result.recv().map_err(|_| stopped())?
+    }
+
+    /// Hands the pages of the memory back to the OS with `MADV_DONTNEED`, dropping them from
+    /// the resident set while the allocation keeps its size and virtual mapping, e.g. to test
+    /// how an application reacts to memory becoming available. Churning is paused so the pages
+    /// aren't faulted right back in, until [`reacquire_pages`](Self::reacquire_pages) is called.
+    ///
+    /// Released pages read as zeros, so [`verify`](Self::verify) fails until they're
+    /// reacquired. Fails on platforms other than Linux and for locked memory.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let mut allocation = weight::allocate(weight::WeightConfig::new("64MB").build()?)?;
+    /// # #[cfg(target_os = "linux")]
+    /// # {
+    /// let resident = weight::system::process_memory().unwrap().resident_bytes;
+    /// allocation.release_pages()?;
+    /// let released = weight::system::process_memory().unwrap().resident_bytes;
+    /// assert!(released + 32_000_000 < resident);
+    ///
+    /// allocation.reacquire_pages()?;
+    /// assert!(weight::system::process_memory().unwrap().resident_bytes > released + 32_000_000);
+    /// assert!(allocation.verify().is_ok());
+    /// assert!(!allocation.is_paused());
+    ///
+    /// // Churning paused beforehand stays paused
+    /// allocation.pause();
+    /// allocation.release_pages()?;
+    /// allocation.reacquire_pages()?;
+    /// assert!(allocation.is_paused());
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn release_pages(&mut self) -> Result<(), WeightError> {
+        if !cfg!(target_os = "linux") {
+            return Err(WeightError::Unsupported("Releasing pages is only supported on Linux".to_string()));
+        }
+        if self.is_locked() {
+            return Err(WeightError::Unsupported("Locked memory can't be released".to_string()));
+        }
+        if self.bytes() == 0 {
+            return Ok(());
+        }
+        // Paused before the workers restart after the command, or they'd fault the pages back in
+        let was_paused = self.is_paused();
+        self.pause();
+        let (reply, result) = mpsc::channel();
+        let released = self
+            .send_command(Command::Advise(Advice::DontNeed, reply))
+            .and_then(|()| result.recv().map_err(|_| stopped())?);
+        if let Err(err) = released {
+            if !was_paused {
+                self.resume();
+            }
+            return Err(err);
+        }
+        self.paused_for_release |= !was_paused;
+        info!("Released the pages of {} bytes", self.bytes());
+        Ok(())
+    }
+
+    /// Faults the memory back in after [`release_pages`](Self::release_pages) by writing the
+    /// fill pattern to all of it again, then resumes churning if releasing paused it, a
+    /// [`pause`](Self::pause) from before releasing stays in effect. This blocks until the
+    /// memory is resident again.
+    pub fn reacquire_pages(&mut self) -> Result<(), WeightError> {
+        if self.is_locked() {
+            return Err(WeightError::Unsupported("Locked memory can't be reacquired".to_string()));
+        }
+        if self.bytes() > 0 {
+            let (reply, result) = mpsc::channel();
+            self.send_command(Command::Refill(self.fill_pattern, reply))?;
+            result.recv().map_err(|_| stopped())??;
+            info!("Reacquired the pages of {} bytes", self.bytes());
+        }
+        if std::mem::take(&mut self.paused_for_release) {
+            self.resume();
+        }
+        Ok(())
+    }
+
+    /// Rewrites all of the memory with `pattern` and makes it the pattern that
+    /// [`verify`](Self::verify), grown memory and restarted workers expect from then on, e.g. to
+    /// watch a change of the contents propagate to monitoring that hashes pages. The workers
+    /// finish their current cycle first, when every byte holds the old pattern, and start over
+    /// on the new one once it's written, so a verification never sees a mix of both. This
+    /// blocks until all of the memory is rewritten, which faults in pages that aren't resident.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// use weight::config::FillPattern;
+    ///
+    /// let config = weight::WeightConfig::new("1MB").interval(Duration::ZERO).workers(2).build()?;
+    /// let mut allocation = weight::allocate(config)?;
+    /// while allocation.stats().cycles_completed < 4 {
+    ///     std::thread::yield_now();
+    /// }
+    /// allocation.refill(FillPattern::Ones)?;
+    /// assert!(allocation.verify().is_ok());
+    /// assert_eq!(allocation.checksum()?, weight::checksum::crc32(&[0xFF; 1_000_000]));
+    ///
+    /// // The workers keep churning on the new pattern
+    /// let cycles = allocation.stats().cycles_completed;
+    /// while allocation.stats().cycles_completed < cycles + 4 {
+    ///     std::thread::yield_now();
+    /// }
+    /// assert!(allocation.verify().is_ok());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn refill(&mut self, pattern: FillPattern) -> Result<(), WeightError> {
+        if let Some(locked) = &mut self.locked {
+            // Locked memory isn't churned, so nothing else is writing to it
+            write_pattern(locked.data_mut(), 0, pattern);
+        } else if self.bytes() > 0 {
+            let (reply, result) = mpsc::channel();
+            self.send_command(Command::Refill(pattern, reply))?;
+            result.recv().map_err(|_| stopped())??;
+            info!("Refilled {} bytes with {:?}", self.bytes(), pattern);
+        }
+        self.fill_pattern = pattern;
+        Ok(())
+    }
+
+    /// Writes to every page of the `len` bytes from `start`, forcing the OS to back them with
+    /// physical memory, and returns how much the resident set of the process grew while doing
+    /// so, `None` if it can't be measured on this platform. This blocks until the workers have
+    /// finished their current pass.
+    ///
+    /// Meant for [lazy](crate::config::WeightConfigBuilder::lazy) allocations, where it takes
+    /// the copy-on-write faults of each page just like an application first writing to memory
+    /// it got from `calloc`. Pages that are resident already don't grow the resident set, like
+    /// the rest of the memory, which was filled when it was allocated.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("64MB").lazy(true).build()?;
+    /// let mut allocation = weight::allocate(config)?;
+    /// let grown = allocation.fault_range(0, 32_000_000)?;
+    /// # #[cfg(target_os = "linux")]
+    /// assert!(grown.unwrap() >= 30_000_000);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fault_range(&mut self, start: usize, len: usize) -> Result<Option<usize>, WeightError> {
+        let end = start.checked_add(len).filter(|&end| end <= self.bytes()).ok_or_else(|| {
+            WeightError::InvalidConfig(format!(
+                "Can't fault in {} bytes from {} of an allocation of {} bytes",
+                len,
+                start,
+                self.bytes()
+            ))
+        })?;
+        let before = system::resident_memory();
+        if let Some(locked) = &mut self.locked {
+            fault_in(&mut locked.data_mut()[start..end]);
+        } else if len > 0 {
+            let (reply, result) = mpsc::channel();
+            self.send_command(Command::Fault(start..end, reply))?;
+            result.recv().map_err(|_| stopped())??;
+        }
+        let grown = before
+            .zip(system::resident_memory())
+            .map(|(before, after)| after.saturating_sub(before));
+        debug!("Faulted in {} bytes from {}, the resident set grew by {:?}", len, start, grown);
+        Ok(grown)
+    }
+
+    /// CPU cores the workers are pinned to, one per worker, `None` if they aren't pinned to
+    /// individual cores.
+    pub fn cpu_affinity(&self) -> Option<&[usize]> {
+        self.cpus.as_deref()
+    }
+
+    /// Pins the workers to other CPU cores, see
+    /// [`WeightConfigBuilder::cpus`](crate::config::WeightConfigBuilder::cpus). This blocks until
+    /// the workers have finished their current pass and were restarted on the new cores.
+    ///
+    /// Fails if the number of cores doesn't match the number of workers, the workers are placed
+    /// on NUMA nodes, the memory is locked or pinning isn't supported on this platform.
+    pub fn set_cpu_affinity(&mut self, cpus: Vec<usize>) -> Result<(), WeightError> {
+        if self.is_locked() {
+            return Err(WeightError::Unsupported("Locked memory has no workers to pin".to_string()));
+        }
+        affinity::check_supported()?;
+        affinity::check_cpus(&cpus)?;
+        let (reply, result) = mpsc::channel();
+        self.send_command(Command::SetCpus(cpus.clone(), reply))?;
+        result.recv().map_err(|_| stopped())??;
+        info!("Pinned workers to CPUs {:?}", cpus);
+        self.cpus = Some(cpus);
+        Ok(())
+    }
+
+    fn send_command(&self, command: Command) -> Result<(), WeightError> {
+        let commands = self.commands.as_ref().ok_or_else(stopped)?;
+        send_command(commands, &self.control, command)
+    }
+
+    /// Signals the background thread to exit and waits for it to finish,
+    /// which releases the memory. Workers sleeping between passes are woken up, so this only
+    /// waits for the pass they're in, however long the interval. Once this returns no worker
+    /// touches the memory anymore:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("1MB").interval(Duration::ZERO).build()?;
+    /// let mut allocation = weight::allocate(config)?;
+    /// allocation.stop();
+    /// let cycles = allocation.stats().cycles_completed;
+    /// std::thread::sleep(Duration::from_millis(50));
+    /// assert_eq!(allocation.stats().cycles_completed, cycles);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stop(&mut self) {
+        // The watchdog and disk flush may be waiting for a command to be handled, so they're
+        // stopped first
+        drop(self.watchdog.take());
+        drop(self.disk_flush.take());
+        drop(self.csv_trace.take());
+        self.control.stop();
+        // Wakes up the owner thread if it's waiting for commands
+        self.commands = None;
+        if let Some(worker) = self.worker.take() {
+            // A panicking worker only stops churning its own range, the others keep going.
+            // Report it here instead of propagating the panic out of stop or drop.
+            if worker.join().is_err() {
+                warn!("A memory churn worker panicked, part of the memory was no longer being modified.");
+            }
+        }
+    }
+}
+
+/// Sends `command` to the thread owning the memory and tells the churn workers to exit
+/// so it gets handled.
+fn send_command(commands: &Sender<Command>, control: &Control, command: Command) -> Result<(), WeightError> {
+    let sent = commands.send(command).is_ok();
+    // The command is sent before the workers are told to exit, so the owner thread
+    // always finds it once they have
+    control.request_restart();
+    if sent { Ok(()) } else { Err(stopped()) }
+}
+
+fn stopped() -> WeightError {
+    WeightError::InvalidConfig("The allocation was already stopped".to_string())
+}
+
+/// The error for failing to start `thread`, e.g. because the process is at its thread limit.
+pub(crate) fn spawn_failed(thread: &str, err: std::io::Error) -> WeightError {
+    WeightError::SpawnFailed(format!("Failed to start {}: {}", thread, err))
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Background thread that calls a function every interval until it's dropped or the function
+/// returns false.
+struct Periodic {
+    cancel: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    /// Starts the thread, named `name` in the error if it can't be spawned.
+    fn spawn(name: &str, interval: Duration, mut tick: impl FnMut() -> bool + Send + 'static) -> Result<Periodic, WeightError> {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .spawn(move || {
+                // Sleeps for the interval and exits as soon as the sender is dropped
+                while let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(interval) {
+                    if !tick() {
+                        break;
+                    }
+                }
+            })
+            .map_err(|err| spawn_failed(name, err))?;
+        Ok(Periodic {
+            cancel: Some(cancel),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.cancel.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts checking that the allocation's `bytes` are resident on top of the `baseline`
+/// resident set size the process had without the allocation, asking the owner thread to touch
+/// the memory again through `commands` when they aren't. With a `headroom`, the allocation is
+/// also shrunk whenever less than that is available. Runs until the returned thread is dropped.
+/// Fails if the thread can't be started.
+fn spawn_watchdog(
+    watchdog: Watchdog,
+    bytes: Arc<AtomicUsize>,
+    baseline: usize,
+    headroom: Option<usize>,
+    control: Arc<Control>,
+    commands: Sender<Command>,
+) -> Result<Periodic, WeightError> {
+    Periodic::spawn("the watchdog thread", watchdog.interval, move || {
+        // Pausing is meant to let the OS reclaim the memory and the warmup to leave it as the
+        // fill left it, so don't fight either
+        if control.idle() {
+            return true;
+        }
+        if let Some(headroom) = headroom
+            && bytes.load(Ordering::Relaxed) > 0
+            && let Some(available) = system::effective_available_memory()
+            && available < headroom
+        {
+            let held = bytes.load(Ordering::Relaxed);
+            let target = held.saturating_sub(headroom - available);
+            warn!(
+                "Only {} bytes of memory are available, shrinking the allocation from {} to {} bytes to leave {} bytes free.",
+                available, held, target, headroom
+            );
+            let (reply, result) = mpsc::channel();
+            if send_command(&commands, &control, Command::Shrink(target, reply)).is_err()
+                || !matches!(result.recv(), Ok(Ok(())))
+            {
+                return false;
+            }
+            bytes.store(target, Ordering::Relaxed);
+        }
+        let Some(resident) = system::resident_memory() else {
+            return false;
+        };
+        let bytes = bytes.load(Ordering::Relaxed);
+        let resident = resident.saturating_sub(baseline).min(bytes);
+        if resident as f64 >= bytes as f64 * watchdog.threshold {
+            return true;
+        }
+        warn!(
+            "Only {} of {} bytes of the allocation are resident, touching the memory again.",
+            resident, bytes
+        );
+        let (reply, result) = mpsc::channel();
+        send_command(&commands, &control, Command::Retouch(reply)).is_ok() && result.recv().is_ok()
+    })
+}
+
+/// Starts writing snapshots of the memory to a temporary file as configured by `disk_flush`,
+/// getting them from the owner thread through `commands`. Write errors such as a full disk
+/// are only logged, so the churn keeps going, while failing to start the thread fails.
+fn spawn_disk_flush(disk_flush: DiskFlush, control: Arc<Control>, commands: Sender<Command>) -> Result<Option<Periodic>, WeightError> {
+    let mut file = match FlushFile::create() {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Failed to create a file for flushing memory to disk, disk flushing is disabled: {}", err);
+            return Ok(None);
+        }
+    };
+    info!("Flushing {} bytes to {} every {:?}", disk_flush.bytes, file.path().display(), disk_flush.interval);
+    Periodic::spawn("the disk flush thread", disk_flush.interval, move || {
+        // Copying the snapshot would touch the memory, which pausing and the warmup are meant to avoid
+        if control.idle() {
+            return true;
+        }
+        let (reply, snapshot) = mpsc::channel();
+        if send_command(&commands, &control, Command::Snapshot(disk_flush.bytes, reply)).is_err() {
+            return false;
+        }
+        let Ok(snapshot) = snapshot.recv() else {
+            return false;
+        };
+        if let Err(err) = file.write(&snapshot) {
+            warn!("Failed to flush {} bytes to {}: {}", snapshot.len(), file.path().display(), err);
+        }
+        true
+    })
+    .map(Some)
+}
+
+/// Starts appending the statistics of the allocation holding `bytes` to the CSV file of `trace`
+/// every interval, until the returned thread is dropped. Fails if the file can't be created or
+/// the thread can't be started, while failing to write a row only stops the trace.
+fn spawn_csv_trace(trace: CsvTrace, bytes: Arc<AtomicUsize>, control: Arc<Control>) -> Result<Periodic, WeightError> {
+    let path = trace.path;
+    let mut file = CsvFile::create(&path)
+        .map_err(|err| WeightError::Io(format!("Failed to create the CSV trace {}: {}", path.display(), err)))?;
+    info!("Tracing statistics to {} every {:?}", path.display(), trace.interval);
+    let mut sample = move || file.sample(&control.stats(bytes.load(Ordering::Relaxed)));
+    // The first row is taken right away, so even short runs have one
+    if let Err(err) = sample() {
+        return Err(WeightError::Io(format!("Failed to write the CSV trace {}: {}", path.display(), err)));
+    }
+    Periodic::spawn("the CSV trace thread", trace.interval, move || match sample() {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Failed to write the CSV trace {}, tracing is stopped: {}", path.display(), err);
+            false
+        }
+    })
+}
+
+/// Allocation that is released automatically after a fixed duration, created with [`allocate_for`].
+///
+/// Dropping the handle releases the memory right away.
+pub struct TimedAllocation {
+    bytes: usize,
+    cancel: Option<Sender<()>>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl TimedAllocation {
+    /// Number of bytes held by this allocation.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether the duration has elapsed and the memory has been released.
+    pub fn is_finished(&self) -> bool {
+        self.timer.as_ref().is_none_or(|timer| timer.is_finished())
+    }
+
+    /// Releases the memory before the duration has elapsed.
+    pub fn cancel(mut self) {
+        self.release();
+    }
+
+    /// Blocks until the duration has elapsed and the memory has been released.
+    pub fn wait(mut self) {
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+
+    fn release(&mut self) {
+        // Dropping the sender wakes up the timer thread, which then drops the allocation
+        drop(self.cancel.take());
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
+
+impl Drop for TimedAllocation {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Number of bytes processed at a time when not specified, one memory page as reported by
+/// [`system::page_size`]. That's 4KB on most systems, which gives good cache performance,
+/// but 16KB on Apple Silicon and some ARM servers.
+pub fn default_chunk_size() -> usize {
+    system::page_size()
+}
+
+/// Number of worker threads to churn memory with when not specified, one per logical CPU.
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Allocates `memory` with the default settings and keeps it occupied with background threads.
+/// See [`WeightConfig`] for the settings that can be changed with [`allocate`].
+pub fn allocate_memory(memory: &str) -> Result<Allocation, WeightError> {
+    // Unlike with `allocate` the span also covers parsing the size
+    span!("allocate", memory = memory);
+    keep_occupied(WeightConfig::new(memory).build()?)
+}
+
+/// Allocates each size in the comma-separated list `memory`, e.g. `1GB,2GB,512MB`, like
+/// [`allocate_memory`] and returns one handle per entry, in order. A single size without a
+/// comma gives a single handle.
+///
+/// All entries are checked before anything is allocated. An invalid one fails with
+/// [`WeightError::InvalidEntry`], which tells which entry it was.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let allocations = weight::allocate_memory_list("1MB, 2MB,512KB")?;
+/// let bytes: Vec<_> = allocations.iter().map(|allocation| allocation.bytes()).collect();
+/// assert_eq!(bytes, [1_000_000, 2_000_000, 512_000]);
+///
+/// let Err(weight::WeightError::InvalidEntry { index, entry, .. }) = weight::allocate_memory_list("1MB,2XB") else {
+///     panic!("expected an invalid entry");
+/// };
+/// assert_eq!((index, entry.as_str()), (1, "2XB"));
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_memory_list(memory: &str) -> Result<Vec<Allocation>, WeightError> {
+    let configs = memory
+        .split(',')
+        .enumerate()
+        .map(|(index, entry)| {
+            let entry = entry.trim();
+            WeightConfig::new(entry).build().map_err(|error| WeightError::InvalidEntry {
+                index,
+                entry: entry.to_string(),
+                error: Box::new(error),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    configs.into_iter().map(allocate).collect()
+}
+
+/// Total bytes held by all live allocations of the process, from [`allocate`] and every other
+/// way of allocating, including those in an [`AllocationRegistry`](crate::AllocationRegistry).
+/// It goes down as soon as memory is released, by dropping, shrinking or stopping an
+/// allocation, even when that happens while a panic unwinds.
+///
+/// Unlike the resident set size this is exactly what the crate means to hold, whether or not
+/// the pages are resident, and it's only an atomic load.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// assert_eq!(weight::total_allocated_bytes(), 0);
+/// let first = weight::allocate_memory("1MB")?;
+/// let mut second = weight::allocate_memory("2MB")?;
+/// assert_eq!(weight::total_allocated_bytes(), 3_000_000);
+/// drop(first);
+/// assert_eq!(weight::total_allocated_bytes(), 2_000_000);
+/// second.stop();
+/// assert_eq!(weight::total_allocated_bytes(), 0);
+/// # Ok(())
+/// # }
+/// ```
+pub fn total_allocated_bytes() -> usize {
+    buffer::held_bytes()
+}
+
+/// Allocates the size in the environment variable `var`, e.g. `WEIGHT_SIZE=2GB`, like
+/// [`allocate_memory`]. Fails with [`WeightError::InvalidConfig`] if the variable isn't set
+/// or isn't valid unicode, and with the parse error if it isn't a valid size.
+///
+/// This only looks at the environment, so callers that also take a size as an argument
+/// should only fall back to this when the argument is missing, the way the `weight` binary
+/// uses `WEIGHT_SIZE` only without `--memory` or a size argument.
+pub fn allocate_from_env(var: &str) -> Result<Allocation, WeightError> {
+    let memory = env::var(var).map_err(|err| match err {
+        env::VarError::NotPresent => {
+            WeightError::InvalidConfig(format!("Environment variable {} with the memory size isn't set", var))
+        }
+        env::VarError::NotUnicode(_) => {
+            WeightError::InvalidConfig(format!("Environment variable {} isn't valid unicode", var))
+        }
+    })?;
+    allocate_memory(memory.trim())
+}
+
+/// Allocates the memory described by `config` and keeps it occupied with background threads,
+/// or locks it in physical RAM when [`lock`](crate::config::WeightConfigBuilder::lock) is set.
+/// A zero byte allocation doesn't start any threads:
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// // Linux lists the threads of the process there, elsewhere both counts are `None`
+/// let threads = || std::fs::read_dir("/proc/self/task").map(|tasks| tasks.count()).ok();
+/// let before = threads();
+/// let allocation = weight::allocate(weight::WeightConfig::new("0B").build()?)?;
+/// assert_eq!(allocation.bytes(), 0);
+/// assert_eq!(threads(), before);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// If the threads keeping the memory occupied can't be started, e.g. because the process is
+/// at its thread limit, this fails with [`WeightError::SpawnFailed`] after releasing the memory
+/// again, rather than returning memory that nothing keeps resident.
+///
+/// With the `tracing` feature this runs in an `allocate` span with the requested `memory` and
+/// its size in `bytes`, and the churn workers emit a `churn_cycle` event after every cycle.
+pub fn allocate(config: WeightConfig) -> Result<Allocation, WeightError> {
+    span!("allocate", memory = config.memory.as_str(), bytes = config.bytes);
+    keep_occupied(config)
+}
+
+/// Result of [`allocate_target_rss`].
+pub struct TargetRss {
+    /// The allocation, resized until the resident memory matched the target.
+    pub allocation: Allocation,
+    /// How much the resident set of the process grew by the allocation in the end.
+    pub resident_bytes: usize,
+    /// How many times the allocation was resized after the first fill.
+    pub iterations: usize,
+    /// Whether the resident memory ended up within the tolerance of the target, rather than
+    /// running out of iterations.
+    pub converged: bool,
+}
+
+/// Allocates like [`allocate`], but treats the size of `config` as the amount of memory that
+/// should become resident rather than the size to request: allocator overhead, page alignment
+/// and memory the OS doesn't fault in can make the two differ. After the fill the growth of
+/// the resident set of the process is measured, and the allocation is grown or shrunk by the
+/// difference until it's within [`TARGET_RSS_TOLERANCE`] of the target, or
+/// [`TARGET_RSS_MIN_TOLERANCE`] bytes for small targets, for at most
+/// [`TARGET_RSS_MAX_ITERATIONS`] resizes.
+///
+/// The growth is measured against what the process had resident before allocating, so memory
+/// it allocates or frees elsewhere in the meantime skews it, as with
+/// [`wait_resident`](Allocation::wait_resident). Fails if the resident set size can't be
+/// determined on this platform, or for configs whose memory can't be resized or isn't
+/// faulted in, that is locked, [lazy](crate::config::WeightConfigBuilder::lazy), backed by
+/// huge pages or placed on NUMA nodes.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// # #[cfg(target_os = "linux")]
+/// # {
+/// let target = weight::allocate_target_rss(weight::WeightConfig::new("64MB").build()?)?;
+/// println!("{}B resident after {} adjustments", target.resident_bytes, target.iterations);
+/// assert!(target.converged);
+/// assert!(target.resident_bytes.abs_diff(64_000_000) <= weight::alloc::TARGET_RSS_MIN_TOLERANCE);
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_target_rss(mut config: WeightConfig) -> Result<TargetRss, WeightError> {
+    if config.lock || config.lazy || config.huge_pages || config.numa_nodes.is_some() || config.memory_backend.is_some() {
+        return Err(WeightError::InvalidConfig(
+            "Only allocations that are faulted in and can be resized can target a resident size".to_string(),
+        ));
+    }
+    // Mappings can't grow, so the choice is already made
+    if config.backend == Backend::Auto {
+        config.backend = Backend::Heap;
+    }
+    let target = config.bytes;
+    let tolerance = ((target as f64 * TARGET_RSS_TOLERANCE) as usize).max(TARGET_RSS_MIN_TOLERANCE);
+    let mut allocation = allocate(config)?;
+    let unsupported = || WeightError::Unsupported("Unable to determine the resident memory on this platform".to_string());
+    let baseline = allocation.baseline.ok_or_else(unsupported)?;
+    let mut iterations = 0;
+    loop {
+        let resident = system::resident_memory().ok_or_else(unsupported)?.saturating_sub(baseline);
+        let converged = resident.abs_diff(target) <= tolerance;
+        // When the difference rounds away there's no size that gets any closer
+        let bytes = if resident < target {
+            allocation.bytes().saturating_add(target - resident)
+        } else {
+            allocation.bytes().saturating_sub(resident - target)
+        };
+        if converged || iterations == TARGET_RSS_MAX_ITERATIONS || bytes == allocation.bytes() {
+            if !converged {
+                warn!(
+                    "{}B are resident after {} adjustments instead of the target of {}B",
+                    resident, iterations, target
+                );
+            }
+            info!("{}B resident for a target of {}B after {} adjustments", resident, target, iterations);
+            return Ok(TargetRss {
+                allocation,
+                resident_bytes: resident,
+                iterations,
+                converged,
+            });
+        }
+        debug!("{}B resident for a target of {}B, resizing to {}B", resident, target, bytes);
+        if bytes > allocation.bytes() {
+            allocation.grow(bytes)?;
+        } else {
+            allocation.shrink(bytes)?;
+        }
+        iterations += 1;
+    }
+}
+
+/// Allocates like [`allocate`] and sends the number of bytes filled so far to `progress` as the
+/// initial fill proceeds, every [`CHANNEL_PROGRESS_STEP`] bytes and once all of them are
+/// filled. With a [`Ramp`] every step is reported as well, even if it's smaller than that.
+/// The sender is dropped once the fill is done, or if the allocation fails, which closes the
+/// channel when it was the last one. A receiver that was dropped in the meantime doesn't
+/// affect the allocation.
+///
+/// Unlike [`on_progress`](crate::config::WeightConfigBuilder::on_progress) this can be
+/// received from anywhere, e.g. a dashboard thread or an async select loop. A progress
+/// callback set on `config` is still called.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use weight::config::Ramp;
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let ramp = Ramp { steps: 3, delay: Duration::from_millis(1), on_step: None };
+/// let config = weight::WeightConfig::new("3MB").ramp(ramp).build()?;
+/// let (progress, filled) = std::sync::mpsc::channel();
+/// let allocation = weight::allocate_with_progress(config, progress)?;
+/// // The channel is closed once the fill is done, so this ends
+/// assert_eq!(filled.iter().collect::<Vec<_>>(), [1_000_000, 2_000_000, 3_000_000]);
+/// assert_eq!(allocation.bytes(), 3_000_000);
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_with_progress(mut config: WeightConfig, progress: Sender<usize>) -> Result<Allocation, WeightError> {
+    let step = config
+        .ramp
+        .as_ref()
+        .map_or(CHANNEL_PROGRESS_STEP, |ramp| config.bytes.div_ceil(ramp.steps).clamp(1, CHANNEL_PROGRESS_STEP));
+    let mut callback = config.progress.take();
+    let mut next = step;
+    config.progress = Some(Progress(Box::new(move |filled, total| {
+        if let Some(callback) = &mut callback {
+            (callback.0)(filled, total);
+        }
+        if filled >= next || filled == total {
+            // The receiver may be gone, which only means nobody is watching anymore
+            let _ = progress.send(filled);
+            next = (filled / step + 1).saturating_mul(step);
+        }
+    })));
+    allocate(config)
+}
+
+fn keep_occupied(mut config: WeightConfig) -> Result<Allocation, WeightError> {
+    let requested = config.bytes;
+    let bytes = requested;
+    info!("Allocating {} bytes ({})", bytes, config.memory);
+    if bytes == 0 {
+        // There's nothing to keep occupied, so return an inert handle without any threads
+        return Ok(Allocation {
+            bytes: Arc::new(AtomicUsize::new(bytes)),
+            committed: Some(0),
+            baseline: system::resident_memory(),
+            control: Arc::new(
+                Control::new(config.cycle_history, None)
+                    .with_interval(config.interval)
+                    .with_max_runtime(config.max_runtime),
+            ),
+            worker: None,
+            commands: None,
+            verifiers: Verifiers::default(),
+            fill_pattern: config.fill_pattern,
+            chunk_size: config.chunk_size,
+            safety_margin: config.safety_margin,
+            paused_for_release: false,
+            locked: None,
+            watchdog: None,
+            disk_flush: None,
+            csv_trace: None,
+            cpus: None,
+            shared_name: None,
+            shortfall: 0,
+        });
+    }
+
+    let (mut data, fill) = allocate_buffer(&mut config)?;
+    let bytes = data.len();
+    let shortfall = requested - bytes;
+    let committed = fill.committed;
+    let shared_name = data.shared_name().map(String::from);
+    // What the process had resident before allocating
+    let baseline = system::resident_memory()
+        .zip(committed)
+        .map(|(resident, committed)| resident.saturating_sub(committed));
+
+    let control = Arc::new(
+        Control::new(config.cycle_history, fill.page_faults)
+            .with_region(&data)
+            .with_interval(config.interval)
+            .with_warmup(config.warmup_delay)
+            .with_max_runtime(config.max_runtime),
+    );
+    let watched = Arc::new(AtomicUsize::new(bytes));
+    let csv_trace = config
+        .csv_trace
+        .take()
+        .map(|trace| spawn_csv_trace(trace, Arc::clone(&watched), Arc::clone(&control)))
+        .transpose()?;
+    if config.lock {
+        match lock::lock_buffer(data, config.raise_lock_limit)? {
+            Locked::Full(locked) => {
+                control.locked.store(bytes, Ordering::Relaxed);
+                return Ok(Allocation {
+                    bytes: watched,
+                    committed,
+                    baseline,
+                    control,
+                    worker: None,
+                    commands: None,
+                    verifiers: Verifiers::default(),
+                    fill_pattern: config.fill_pattern,
+                    chunk_size: config.chunk_size,
+                    safety_margin: config.safety_margin,
+                    paused_for_release: false,
+                    locked: Some(locked),
+                    watchdog: None,
+                    disk_flush: None,
+                    csv_trace,
+                    cpus: None,
+                    shared_name,
+                    shortfall,
+                });
+            }
+            // The rest of the memory is churned like an unlocked allocation
+            Locked::Partial(partial) => {
+                control.locked.store(partial.locked_len(), Ordering::Relaxed);
+                data = partial;
+            }
+        }
+    }
+
+    let settings = ChurnSettings::new(&config);
+    let verifiers = Verifiers::default();
+    let (commands, received) = mpsc::channel();
+    let watchdog = config
+        .watchdog
+        .and_then(|watchdog| {
+            let Some(baseline) = baseline else {
+                warn!("The resident set size can't be determined on this platform, so the watchdog is disabled.");
+                return None;
+            };
+            Some(spawn_watchdog(
+                watchdog,
+                Arc::clone(&watched),
+                baseline,
+                config.headroom,
+                Arc::clone(&control),
+                commands.clone(),
+            ))
+        })
+        .transpose()?;
+    let disk_flush = config
+        .disk_flush
+        .map(|disk_flush| spawn_disk_flush(disk_flush, Arc::clone(&control), commands.clone()))
+        .transpose()?
+        .flatten();
+    let placement = Placement::new(config.workers, config.numa_nodes, config.cpus);
+    let cpus = placement.cpus.clone();
+    let worker = keep_modifying_data(
+        data,
+        Arc::clone(&control),
+        placement,
+        settings,
+        Arc::clone(&verifiers),
+        received,
+        config.on_cycle.take(),
+    )?;
+
+    Ok(Allocation {
+        bytes: watched,
+        committed,
+        baseline,
+        control,
+        worker: Some(worker),
+        commands: Some(commands),
+        verifiers,
+        fill_pattern: config.fill_pattern,
+        chunk_size: config.chunk_size,
+        safety_margin: config.safety_margin,
+        paused_for_release: false,
+        locked: None,
+        watchdog,
+        disk_flush,
+        csv_trace,
+        cpus,
+        shared_name,
+        shortfall,
+    })
+}
+
+/// What happened while filling a new buffer, returned by [`allocate_buffer`].
+pub(crate) struct Fill {
+    /// Bytes that were committed to physical memory, if that can be determined.
+    pub(crate) committed: Option<usize>,
+    /// Page faults of the process while allocating and filling, if they can be counted.
+    pub(crate) page_faults: Option<PageFaults>,
+}
+
+/// Reserves `bytes` with the backend of `config`, retrying according to its [`Retry`](crate::config::Retry) policy.
+fn reserve(config: &WeightConfig, bytes: usize) -> Result<Buffer, WeightError> {
+    let retry = config.retry;
+    let mut failed = 0;
+    loop {
+        let err = match reserve_once(config, bytes) {
+            Ok(data) => return Ok(data),
+            Err(err) => err,
+        };
+        failed += 1;
+        if failed >= retry.attempts {
+            // A single attempt fails with the plain error, as it did before retries existed
+            if retry.attempts == 1 {
+                return Err(err);
+            }
+            return Err(WeightError::RetriesExhausted {
+                attempts: failed,
+                error: Box::new(err),
+            });
+        }
+        let delay = retry.delay(failed);
+        debug!("Attempt {} of {} failed: {}, retrying in {:?}", failed, retry.attempts, err, delay);
+        thread::sleep(delay);
+    }
+}
+
+/// Reserves `bytes` with the backend of `config`, without retrying.
+fn reserve_once(config: &WeightConfig, bytes: usize) -> Result<Buffer, WeightError> {
+    if let Some(custom) = config.memory_backend {
+        return (custom.reserve)(bytes);
+    }
+    reserve_backend(config.backend, config.huge_pages, config.lazy, bytes)
+}
+
+/// Reserves `bytes` with `backend`, backed by huge pages or zeroed for a lazy allocation.
+fn reserve_backend(backend: Backend, huge_pages: bool, lazy: bool, bytes: usize) -> Result<Buffer, WeightError> {
+    match (huge_pages, backend) {
+        (false, Backend::Auto) => reserve_auto(lazy, bytes),
+        (true, Backend::Heap | Backend::Auto) => Buffer::huge_pages(bytes, MmapFlags::default()),
+        (true, Backend::Mmap(flags)) => Buffer::huge_pages(bytes, flags),
+        (false, Backend::Heap) if lazy => Buffer::heap_zeroed(bytes),
+        (false, Backend::Heap) => Buffer::heap(bytes),
+        (false, Backend::Mmap(flags)) => Buffer::mapped(bytes, flags),
+        (_, Backend::Shared) => Buffer::shared(bytes),
+        (_, Backend::VirtualAlloc(flags)) => Buffer::virtual_alloc(bytes, flags),
+    }
+}
+
+/// Backends [`Backend::Auto`] tries for `bytes` in order of preference, by name and whether
+/// they use huge pages. The heap comes last since it works everywhere.
+fn auto_backends(lazy: bool, bytes: usize) -> Vec<(&'static str, Backend, bool)> {
+    let mut backends = Vec::with_capacity(3);
+    if cfg!(target_os = "linux")
+        && !lazy
+        && bytes >= AUTO_HUGE_PAGES_THRESHOLD
+        && system::free_huge_pages().is_some_and(|free| free >= bytes)
+    {
+        backends.push(("huge pages", Backend::Mmap(MmapFlags::default()), true));
+    }
+    if cfg!(unix) && bytes >= AUTO_MMAP_THRESHOLD {
+        backends.push(("mmap", Backend::Mmap(MmapFlags::default()), false));
+    }
+    backends.push(("heap", Backend::Heap, false));
+    backends
+}
+
+/// Reserves `bytes` with the first of the [`auto_backends`] that works, logging which one it was.
+fn reserve_auto(lazy: bool, bytes: usize) -> Result<Buffer, WeightError> {
+    let mut backends = auto_backends(lazy, bytes).into_iter().peekable();
+    while let Some((name, backend, huge_pages)) = backends.next() {
+        match reserve_backend(backend, huge_pages, lazy, bytes) {
+            Ok(data) => {
+                info!("Chose the {} backend for {} bytes", name, bytes);
+                return Ok(data);
+            }
+            Err(err) if backends.peek().is_some() => debug!("The {} backend failed: {}, falling back", name, err),
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("the heap is always tried last")
+}
+
+/// Reserves as much of `bytes` as possible for a
+/// [`best_effort`](crate::config::WeightConfigBuilder::best_effort) config after reserving all
+/// of it failed with `err`. The largest size that can be reserved is searched for by halving
+/// the gap between the largest size that worked and the smallest that didn't until it's
+/// smaller than a page, which only takes a few dozen attempts since nothing is touched yet.
+/// Returns the buffer with its size, or fails with `err` if not even a page can be reserved.
+fn reserve_best_effort(config: &WeightConfig, bytes: usize, err: WeightError) -> Result<(Buffer, usize), WeightError> {
+    let page_size = system::page_size();
+    let (mut reserved, mut failed) = (None::<Buffer>, bytes);
+    let mut works = 0;
+    while failed - works > page_size {
+        let attempt = works + ((failed - works) / 2).next_multiple_of(page_size).min(failed - works - 1);
+        match reserve_once(config, attempt) {
+            Ok(data) => {
+                // Only the largest buffer is kept
+                reserved = Some(data);
+                works = attempt;
+            }
+            Err(_) => failed = attempt,
+        }
+    }
+    let data = reserved.ok_or(err)?;
+    warn!("Only {} of the requested {} bytes could be reserved.", works, bytes);
+    Ok((data, works))
+}
+
+/// Allocates and fills the buffer for `config`, returning it along with how much of it was
+/// committed to physical memory and the page faults that took. With
+/// [`best_effort`](crate::config::WeightConfigBuilder::best_effort) the buffer can be smaller
+/// than requested, which its length and `config.bytes` reflect afterwards.
+pub(crate) fn allocate_buffer(config: &mut WeightConfig) -> Result<(Buffer, Fill), WeightError> {
+    let mut requested = config.bytes;
+    span!("fill", bytes = requested);
+    // Checked before reserving anything, against what is available right now rather than
+    // when the size was parsed. A best effort takes whatever leaves the margin instead
+    match system::check_safety_margin(requested, config.safety_margin) {
+        Err(WeightError::WouldExhaustMemory { available, margin, .. }) if config.best_effort && available > margin => {
+            warn!("Only allocating {} of the requested {} bytes to leave {} bytes available.", available - margin, requested, margin);
+            requested = available - margin;
+        }
+        result => result?,
+    }
+    // Without this, exceeding `ulimit -v` only shows up as a failed allocation. A best effort
+    // takes whatever fits within the limits instead
+    if !config.best_effort {
+        system::check_resource_limits(requested)?;
+    }
+    let resident_before = system::resident_memory();
+    let faults_before = system::page_faults();
+    let (mut data, bytes) = match reserve(config, requested) {
+        Ok(data) => (data, requested),
+        Err(err) if config.best_effort => reserve_best_effort(config, requested, err)?,
+        Err(err) => return Err(err),
+    };
+    config.bytes = bytes;
+    data.set_zero_on_drop(config.zero_on_drop);
+    // Before the fill faults the pages in, so none of them starts out as a huge page
+    if config.disable_thp {
+        data.disable_thp()?;
+    }
+    if let Some(nodes) = &config.numa_nodes {
+        // Bind each worker's range to its node before the fill faults the pages in
+        let range_len = range_len(bytes, nodes.len(), config.chunk_size);
+        for (range, &node) in data.reserved_mut()[..bytes].chunks_mut(range_len).zip(nodes) {
+            numa::bind(range, node)?;
+        }
+    }
+    // Lazy memory comes zeroed from the allocator or the mapping, and is left untouched
+    if !config.lazy {
+        let throttle = config.fill_rate.map(Throttle::new);
+        fill(&mut data, bytes, config.fill_pattern, config.ramp.take(), throttle, config.progress.take())?;
+    }
+    let committed = resident_before
+        .zip(system::resident_memory())
+        .map(|(before, after)| after.saturating_sub(before).min(bytes));
+    let page_faults = system::page_faults_since(faults_before);
+    info!("Allocated {} bytes", bytes);
+    Ok((data, Fill { committed, page_faults }))
+}
+
+/// Allocates the memory described by `config` like [`allocate`] and releases it again once
+/// `duration` has elapsed. The returned handle can be used to release it earlier. Fails with
+/// [`WeightError::SpawnFailed`] after releasing the memory if the timer can't be started.
+pub fn allocate_for(config: WeightConfig, duration: Duration) -> Result<TimedAllocation, WeightError> {
+    let allocation = allocate(config)?;
+    let bytes = allocation.bytes();
+    let (cancel, cancelled) = mpsc::channel::<()>();
+    // If the thread can't be spawned the closure is dropped, which releases the memory
+    let timer = thread::Builder::new()
+        .spawn(move || {
+            // Returns on timeout or as soon as the sender is dropped
+            let _ = cancelled.recv_timeout(duration);
+            // Stops the churn threads and frees the memory
+            drop(allocation);
+        })
+        .map_err(|err| spawn_failed("the timer releasing the memory", err))?;
+
+    Ok(TimedAllocation {
+        bytes,
+        cancel: Some(cancel),
+        timer: Some(timer),
+    })
+}
+
+/// Why [`allocate_memory_blocking`] or [`Allocation::hold`] stopped holding the memory. The
+/// memory has been released, or stopped being churned, either way, so callers can map this to
+/// the exit status of the process while failures to allocate are returned as errors instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// The duration or [`max_runtime`](crate::config::WeightConfigBuilder::max_runtime)
+    /// elapsed, the run completed as planned.
+    MaxRuntime,
+    /// Stopped before that, by a stop channel, a [`StopHandle`], an `on_cycle` callback or a
+    /// termination signal.
+    Stopped,
+}
+
+/// Result of [`allocate_memory_blocking`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockingRun {
+    /// Statistics at the moment the memory was released.
+    pub stats: Stats,
+    /// Why the memory was released.
+    pub termination: Termination,
+}
+
+/// Allocates the memory described by `config` like [`allocate`], but churns it from the calling
+/// thread instead of a background thread that has to be kept alive. Blocks until `duration`
+/// or the [`max_runtime`](crate::config::WeightConfigBuilder::max_runtime) has elapsed,
+/// whichever is shorter, or `stop` receives a message or is disconnected, e.g. by a Ctrl-C
+/// handler, then releases the memory and returns the final statistics along with which of
+/// those ended the run.
+///
+/// With a single worker that isn't pinned to a NUMA node or CPU core, the default, no thread
+/// is started: the calling thread churns the memory itself and checks `stop` and the time
+/// after each cycle, so the run ends once the cycle it's in, including its sleeps for the
+/// interval, is complete. More workers, or a pinned one, which would pin the calling thread,
+/// are scoped threads instead, which have always exited by the time this returns, and the
+/// calling thread only waits. If one of them can't be started this fails with
+/// [`WeightError::SpawnFailed`] once the others have exited and the memory is released. The
+/// [`Watchdog`] and [`DiskFlush`] aren't supported, since they need a handle to talk to.
+///
+/// ```
+/// # use std::sync::mpsc;
+/// # use std::time::Duration;
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let (_stop, stopped) = mpsc::channel();
+/// let config = weight::WeightConfig::new("1MB").max_runtime(Duration::from_millis(50)).build()?;
+/// let run = weight::allocate_memory_blocking(config, None, &stopped)?;
+/// assert_eq!(run.stats.allocated_bytes, 1_000_000);
+/// let status = match run.termination {
+///     weight::Termination::MaxRuntime => 0,
+///     weight::Termination::Stopped => 1,
+/// };
+/// assert_eq!(status, 0);
+///
+/// // The single worker is the calling thread, so its callbacks run there
+/// let caller = std::thread::current().id();
+/// let (stop, stopped) = mpsc::channel();
+/// let config = weight::WeightConfig::new("1MB")
+///     .interval(Duration::from_millis(1))
+///     .on_cycle(move |stats| {
+///         assert_eq!(std::thread::current().id(), caller);
+///         if stats.cycles_completed == 3 {
+///             stop.send(()).unwrap();
+///         }
+///         std::ops::ControlFlow::Continue(())
+///     })
+///     .build()?;
+/// let run = weight::allocate_memory_blocking(config, None, &stopped)?;
+/// assert_eq!((run.stats.cycles_completed, run.termination), (3, weight::Termination::Stopped));
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_memory_blocking(
+    mut config: WeightConfig,
+    duration: Option<Duration>,
+    stop: &Receiver<()>,
+) -> Result<BlockingRun, WeightError> {
+    if config.watchdog.is_some() || config.disk_flush.is_some() {
+        return Err(WeightError::Unsupported(
+            "The watchdog and disk flushing aren't supported when allocating blocking".to_string(),
+        ));
+    }
+    span!("allocate", memory = config.memory.as_str(), bytes = config.bytes);
+    let bytes = config.bytes;
+    info!("Allocating {} bytes ({})", bytes, config.memory);
+    let duration = match (duration, config.max_runtime) {
+        (Some(duration), Some(max_runtime)) => Some(duration.min(max_runtime)),
+        (duration, max_runtime) => duration.or(max_runtime),
+    };
+    let wait = || match duration {
+        Some(duration) => match stop.recv_timeout(duration) {
+            Err(RecvTimeoutError::Timeout) => Termination::MaxRuntime,
+            _ => Termination::Stopped,
+        },
+        None => {
+            let _ = stop.recv();
+            Termination::Stopped
+        }
+    };
+    if bytes == 0 {
+        let termination = wait();
+        return Ok(BlockingRun {
+            stats: Control::new(config.cycle_history, None).stats(bytes),
+            termination,
+        });
+    }
+
+    let (mut data, fill) = allocate_buffer(&mut config)?;
+    let bytes = data.len();
+    let control = Control::new(config.cycle_history, fill.page_faults)
+        .with_region(&data)
+        .with_interval(config.interval)
+        .with_warmup(config.warmup_delay);
+    if config.lock {
+        match lock::lock_buffer(data, config.raise_lock_limit)? {
+            Locked::Full(locked) => {
+                control.locked.store(bytes, Ordering::Relaxed);
+                let termination = wait();
+                drop(locked);
+                return Ok(BlockingRun {
+                    stats: control.stats(bytes),
+                    termination,
+                });
+            }
+            Locked::Partial(partial) => {
+                control.locked.store(partial.locked_len(), Ordering::Relaxed);
+                data = partial;
+            }
+        }
+    }
+
+    let settings = ChurnSettings::new(&config);
+    let placement = Placement::new(config.workers, config.numa_nodes.take(), config.cpus.take());
+    let range_len = range_len(bytes, placement.workers, settings.chunk_size);
+    let on_cycle = config.on_cycle.take().map(Mutex::new);
+    let on_cycle = on_cycle.as_ref().map(|callback| CycleHook { callback, bytes });
+    let deadline = duration.and_then(|duration| Instant::now().checked_add(duration));
+    if placement.workers == 1 && !placement.pins() {
+        // The worker doesn't finish cycles while warming up, so the warmup is waited out here
+        if control.warming_up() {
+            let warmup = deadline.map_or(config.warmup_delay, |deadline| {
+                deadline.saturating_duration_since(Instant::now()).min(config.warmup_delay)
+            });
+            let termination = match stop.recv_timeout(warmup) {
+                Err(RecvTimeoutError::Timeout) if deadline.is_none_or(|deadline| Instant::now() < deadline) => None,
+                Err(RecvTimeoutError::Timeout) => Some(Termination::MaxRuntime),
+                _ => Some(Termination::Stopped),
+            };
+            if let Some(termination) = termination {
+                return Ok(BlockingRun {
+                    stats: control.stats(bytes),
+                    termination,
+                });
+            }
+            control.start_churn();
+        }
+        // Nothing can ask for verification without a handle
+        let (_, requests) = mpsc::channel();
+        let termination = Cell::new(Termination::Stopped);
+        let after_cycle = |control: &Control| {
+            if let Some(on_cycle) = on_cycle {
+                on_cycle.call(control);
+            }
+            match stop.try_recv() {
+                Err(TryRecvError::Empty) if deadline.is_none_or(|deadline| Instant::now() < deadline) => {}
+                Err(TryRecvError::Empty) => {
+                    termination.set(Termination::MaxRuntime);
+                    control.stop();
+                }
+                _ => control.stop(),
+            }
+        };
+        churn_supervised(&mut data, 0, 0, &control, &requests, settings, &after_cycle);
+        return Ok(BlockingRun {
+            stats: control.stats(bytes),
+            termination: termination.get(),
+        });
+    }
+    let termination = thread::scope(|scope| {
+        let (control, placement) = (&control, &placement);
+        for (i, range) in data.chunks_mut(range_len).enumerate() {
+            let spawned = thread::Builder::new().spawn_scoped(scope, move || {
+                placement.pin(i);
+                // Nothing can ask for verification without a handle
+                let (_, requests) = mpsc::channel();
+                let after_cycle = |control: &Control| {
+                    if let Some(on_cycle) = on_cycle {
+                        on_cycle.call(control);
+                    }
+                };
+                churn_supervised(range, i, i * range_len, control, &requests, settings, &after_cycle)
+            });
+            if let Err(err) = spawned {
+                // The workers that did start exit after their current cycle, before the scope ends
+                control.stop();
+                return Err(spawn_failed("a churn worker", err));
+            }
+        }
+        // Polled so the wait also ends when the on_cycle callback stops the churn
+        let mut termination = Termination::Stopped;
+        while control.running.load(Ordering::SeqCst) {
+            let timeout = deadline.map_or(PAUSED_POLL_INTERVAL, |deadline| {
+                deadline.saturating_duration_since(Instant::now()).min(PAUSED_POLL_INTERVAL)
+            });
+            match stop.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) if deadline.is_none_or(|deadline| Instant::now() < deadline) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    termination = Termination::MaxRuntime;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        // The workers finish the chunk they're on and exit before the scope ends
+        control.stop();
+        Ok(termination)
+    })?;
+    Ok(BlockingRun {
+        stats: control.stats(bytes),
+        termination,
+    })
+}
+
+/// Seed for the chunk order of [`allocate_deterministic`] when the config doesn't set one.
+pub const DETERMINISTIC_SEED: u64 = 0;
+
+/// Result of [`allocate_deterministic`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeterministicRun {
+    /// Statistics after the last cycle. Cycles are counted once per pass over the whole
+    /// allocation, so `cycles_completed` is the number of cycles that were run.
+    pub stats: Stats,
+    /// Check of the whole allocation against its fill pattern after the last cycle.
+    pub verification: Verification,
+}
+
+/// Allocates the memory described by `config` and churns it for exactly `cycles` cycles from
+/// the calling thread, without sleeping and with the chunk order seeded with
+/// [`DETERMINISTIC_SEED`] unless the config sets a seed. Every cycle undoes its own changes, so
+/// the memory ends up holding its fill pattern again, which the returned verification checks
+/// before the memory is released. Tests can assert on the outcome exactly, where the timing
+/// of the background workers makes that impossible.
+///
+/// The ranges of the configured workers are processed one after the other, the interval, CPU
+/// budget and full touch interval are ignored and an `on_cycle` callback can still end the
+/// run early. Locking, ramping, the [`Watchdog`] and [`DiskFlush`] aren't supported, since
+/// they don't churn the memory or depend on timing.
+///
+/// ```
+/// # use weight::config::AccessPattern;
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let config = weight::WeightConfig::new("1MB").access_pattern(AccessPattern::Random).build()?;
+/// let run = weight::allocate_deterministic(config, 3)?;
+/// assert_eq!(run.stats.cycles_completed, 3);
+/// assert!(run.verification.is_ok());
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_deterministic(mut config: WeightConfig, cycles: u64) -> Result<DeterministicRun, WeightError> {
+    if config.lock || config.ramp.is_some() || config.watchdog.is_some() || config.disk_flush.is_some() {
+        return Err(WeightError::Unsupported(
+            "Locking, ramping, the watchdog and disk flushing aren't supported in deterministic runs".to_string(),
+        ));
+    }
+    span!("allocate", memory = config.memory.as_str(), bytes = config.bytes);
+    let bytes = config.bytes;
+    info!("Allocating {} bytes ({}) for {} deterministic cycles", bytes, config.memory, cycles);
+    config.seed.get_or_insert(DETERMINISTIC_SEED);
+    let settings = ChurnSettings::new(&config);
+    let (mut data, fill) = allocate_buffer(&mut config)?;
+    let bytes = data.len();
+    let control = Control::new(config.cycle_history, fill.page_faults).with_region(&data);
+    let range_len = range_len(bytes, config.workers, settings.chunk_size);
+    let mut on_cycle = config.on_cycle.take();
+
+    for cycle in 0..cycles {
+        let busy = timed(&control, || {
+            for (i, range) in data.chunks_mut(range_len).enumerate() {
+                let order = settings.chunk_order(range.len(), i, cycle);
+                let order = order.as_deref();
+                match settings.touch_mode {
+                    TouchMode::ReadWrite => {
+                        modify(range, settings.chunk_size, settings.stride, order, |byte| *byte = settings.op.apply(*byte));
+                        modify(range, settings.chunk_size, settings.stride, order, |byte| *byte = settings.op.undo(*byte));
+                    }
+                    TouchMode::ReadOnly => read(range, settings.chunk_size, settings.stride, order),
+                }
+            }
+        });
+        control.cycles.fetch_add(1, Ordering::Relaxed);
+        control.record_cycle_time(busy);
+        if let Some(on_cycle) = &mut on_cycle
+            && (on_cycle.0)(&control.stats(bytes)).is_break()
+        {
+            info!("Churn stopped by the cycle callback");
+            break;
+        }
+    }
+
+    Ok(DeterministicRun {
+        stats: control.stats(bytes),
+        verification: verify_pattern(&data, 0, config.fill_pattern),
+    })
+}
+
+/// Paces the fill to a [`fill_rate`](crate::config::WeightConfigBuilder::fill_rate) by
+/// sleeping whenever more has been written than the rate allows for the time that passed.
+struct Throttle {
+    rate: u64,
+    /// Bytes written between checks, about a twentieth of a second's worth.
+    slice: usize,
+    started: Instant,
+    written: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Throttle {
+        Throttle {
+            rate,
+            slice: usize::try_from(rate / 20).unwrap_or(usize::MAX).clamp(system::page_size(), PROGRESS_STEP),
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+
+    /// Counts `bytes` more as written and sleeps until the rate has caught up with them.
+    fn wrote(&mut self, bytes: usize) {
+        self.written += bytes as u64;
+        let due = Duration::from_secs_f64(self.written as f64 / self.rate as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+
+    /// Starts counting afresh, so a pause such as a ramp delay isn't made up for with a burst.
+    fn restart(&mut self) {
+        self.started = Instant::now();
+        self.written = 0;
+    }
+}
+
+/// Fills `data` up to `bytes` with `pattern`, either in one go or step by step following `ramp`,
+/// at most as fast as `throttle` allows, reporting to `progress` every [`PROGRESS_STEP`] bytes.
+/// Fails if memory whose commit was deferred can't be committed.
+fn fill(
+    data: &mut Buffer,
+    bytes: usize,
+    pattern: FillPattern,
+    mut ramp: Option<Ramp>,
+    mut throttle: Option<Throttle>,
+    mut progress: Option<Progress>,
+) -> Result<(), WeightError> {
+    let step = ramp.as_ref().map_or(bytes, |ramp| bytes.div_ceil(ramp.steps)).max(1);
+    let mut start = 0;
+    while start < bytes {
+        let end = (start + step).min(bytes);
+        // Zeroing the memory first uses an optimized memset, then a single pass writes the
+        // pattern. This is several times faster than pushing byte by byte.
+        data.extend_to(end)?;
+        let mut offset = start;
+        while offset < end {
+            let next = (offset + PROGRESS_STEP).min(end);
+            match &mut throttle {
+                Some(throttle) => {
+                    for slice in (offset..next).step_by(throttle.slice) {
+                        let slice_end = (slice + throttle.slice).min(next);
+                        write_pattern(&mut data[slice..slice_end], slice, pattern);
+                        pre_touch(&mut data[slice..slice_end]);
+                        throttle.wrote(slice_end - slice);
+                    }
+                }
+                None => {
+                    write_pattern(&mut data[offset..next], offset, pattern);
+                    pre_touch(&mut data[offset..next]);
+                }
+            }
+            if let Some(progress) = &mut progress {
+                (progress.0)(next, bytes);
+            }
+            offset = next;
+        }
+
+        if let Some(ramp) = &mut ramp {
+            debug!("Ramp step allocated {} of {} bytes", end, bytes);
+            if let Some(on_step) = &mut ramp.on_step {
+                on_step(end);
+            }
+            if end < bytes {
+                thread::sleep(ramp.delay);
+                if let Some(throttle) = &mut throttle {
+                    throttle.restart();
+                }
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Writes `pattern` into `range`, which starts at byte `offset` of the allocation.
+pub(crate) fn write_pattern(range: &mut [u8], offset: usize, pattern: FillPattern) {
+    match pattern {
+        FillPattern::Zeros => range.fill(0x00),
+        FillPattern::Ones => range.fill(0xFF),
+        // Matching once per range instead of per byte keeps the common patterns fast
+        FillPattern::Index
+        | FillPattern::Alternating
+        | FillPattern::Random(_)
+        | FillPattern::WalkingOnes
+        | FillPattern::Address => {
+            for (i, byte) in range.iter_mut().enumerate() {
+                *byte = pattern_byte(pattern, offset + i);
+            }
+        }
+    }
+}
+
+/// The byte at `offset` of an allocation filled with `pattern`.
+fn pattern_byte(pattern: FillPattern, offset: usize) -> u8 {
+    match pattern {
+        FillPattern::Zeros => 0x00,
+        FillPattern::Ones => 0xFF,
+        FillPattern::Index => (offset % 256) as u8,
+        FillPattern::Alternating => if offset.is_multiple_of(2) { 0xAA } else { 0x55 },
+        FillPattern::Random(seed) => {
+            // Every 8 bytes come from the element of the seed's SplitMix64 sequence at that
+            // position, so any byte can be recomputed without generating the ones before it
+            let word = Rng(seed.wrapping_add(((offset / 8) as u64).wrapping_mul(RNG_INCREMENT))).next();
+            (word >> (offset % 8 * 8)) as u8
+        }
+        FillPattern::WalkingOnes => 1 << (offset % 8),
+        FillPattern::Address => (((offset - offset % 8) as u64) >> (offset % 8 * 8)) as u8,
+    }
+}
+
+/// Checks that `range`, which starts at byte `offset` of the allocation, holds `pattern`
+/// as written by [`write_pattern`].
+pub(crate) fn verify_pattern(range: &[u8], offset: usize, pattern: FillPattern) -> Verification {
+    let mut verification = Verification {
+        checked_bytes: range.len(),
+        ..Verification::default()
+    };
+    for (i, &byte) in range.iter().enumerate() {
+        if byte != pattern_byte(pattern, offset + i) {
+            verification.mismatch_count += 1;
+            if verification.mismatches.len() < MAX_REPORTED_MISMATCHES {
+                verification.mismatches.push(offset + i);
+            }
+        }
+    }
+    verification
+}
+
+/// Writes one byte in every page of `data` so the OS has to back all of it with
+/// physical memory right away, even with lazy allocation or overcommit.
+fn pre_touch(data: &mut [u8]) {
+    for byte in data.iter_mut().step_by(system::page_size()) {
+        // SAFETY: `byte` is a valid, exclusive reference. The volatile write makes sure the
+        // store isn't optimized away even though it writes back the same value.
+        unsafe { std::ptr::write_volatile(byte, *byte) };
+    }
+}
+
+/// Writes to every page `data` overlaps, including the last one when it doesn't start on a
+/// page boundary.
+fn fault_in(data: &mut [u8]) {
+    pre_touch(data);
+    if let Some(byte) = data.last_mut() {
+        // SAFETY: see pre_touch.
+        unsafe { std::ptr::write_volatile(byte, *byte) };
+    }
+}
+
+/// Settings shared by all churn workers of an allocation.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChurnSettings {
+    pub(crate) chunk_size: usize,
+    pub(crate) touch_mode: TouchMode,
+    pub(crate) fill_pattern: FillPattern,
+    pub(crate) access_pattern: AccessPattern,
+    pub(crate) op: ChurnOp,
+    /// Distance between two touched bytes, 1 to touch all of them.
+    pub(crate) stride: usize,
+    pub(crate) seed: u64,
+    /// Share of one CPU each worker may use, see [`WeightConfigBuilder::cpu_budget`].
+    ///
+    /// [`WeightConfigBuilder::cpu_budget`]: crate::config::WeightConfigBuilder::cpu_budget
+    pub(crate) cpu_budget: Option<f64>,
+    pub(crate) adaptive_interval: Option<AdaptiveInterval>,
+    /// How often a cycle is a full read-write pass over every byte, see
+    /// [`WeightConfigBuilder::full_touch_interval`].
+    ///
+    /// [`WeightConfigBuilder::full_touch_interval`]: crate::config::WeightConfigBuilder::full_touch_interval
+    pub(crate) full_touch_interval: Option<Duration>,
+    /// Whether passes start on ticks of the wall clock, see
+    /// [`WeightConfigBuilder::align_to_clock`].
+    ///
+    /// [`WeightConfigBuilder::align_to_clock`]: crate::config::WeightConfigBuilder::align_to_clock
+    pub(crate) align_to_clock: bool,
+    /// Whether panicking workers are restarted, see [`WeightConfigBuilder::supervise`].
+    ///
+    /// [`WeightConfigBuilder::supervise`]: crate::config::WeightConfigBuilder::supervise
+    pub(crate) supervise: bool,
+}
+
+impl ChurnSettings {
+    pub(crate) fn new(config: &WeightConfig) -> ChurnSettings {
+        ChurnSettings {
+            chunk_size: config.chunk_size,
+            touch_mode: config.touch_mode,
+            fill_pattern: config.fill_pattern,
+            access_pattern: config.access_pattern,
+            op: config.churn_op,
+            stride: config.stride.bytes(),
+            seed: config.seed.unwrap_or_else(|| RandomState::new().build_hasher().finish()),
+            cpu_budget: config.cpu_budget.map(|budget| budget / config.workers as f64),
+            adaptive_interval: config.adaptive_interval,
+            full_touch_interval: config.full_touch_interval,
+            align_to_clock: config.align_to_clock,
+            supervise: config.supervise,
+        }
+    }
+
+    /// How long a worker sleeps after a pass that took `busy`. That's the current `interval`,
+    /// adapted to the memory pressure if configured, or longer if needed to keep the worker
+    /// within its CPU budget. Aligned to the clock it's the time until the next tick instead.
+    pub(crate) fn pause_after(&self, interval: Duration, busy: Duration) -> Duration {
+        let interval = match self.adaptive_interval {
+            Some(adaptive) => adaptive.interval(system::memory_pressure(), interval),
+            None => interval,
+        };
+        let pause = match self.cpu_budget {
+            Some(budget) => interval.max(busy.mul_f64(((1.0 - budget) / budget).max(0.0))),
+            None => interval,
+        };
+        if !self.align_to_clock {
+            return pause;
+        }
+        if busy > interval {
+            debug!("A pass took {:?}, longer than the interval of {:?}, skipping to the next tick", busy, interval);
+        }
+        until_next_tick(interval, pause - interval)
+    }
+
+    /// Sleeps for [`pause_after`](Self::pause_after) a pass that took `busy`, or until the
+    /// workers are told to exit. An interval set meanwhile applies right away, as if it had been
+    /// set before the pass ended.
+    pub(crate) fn sleep_after(&self, control: &Control, busy: Duration) {
+        let ended = Instant::now();
+        let mut interval = control.interval();
+        let mut until = ended.checked_add(self.pause_after(interval, busy));
+        control.sleep(|| {
+            if control.interval() != interval {
+                interval = control.interval();
+                let pause = self.pause_after(interval, busy);
+                // Aligned to the clock the pause already counts from now
+                until = if self.align_to_clock { Instant::now() } else { ended }.checked_add(pause);
+            }
+            // A pause that doesn't fit in an Instant lasts until the workers are woken
+            until.map_or(Duration::MAX, |until| until.saturating_duration_since(Instant::now()))
+        });
+    }
+
+    /// The settings for the next cycle. That's a read-write pass over every byte if the full
+    /// touch interval has passed since `last_full_touch`, which is then reset, so every page is
+    /// written at least that often whatever the touch mode and stride, and these otherwise.
+    pub(crate) fn next_cycle(&self, last_full_touch: &mut Instant) -> ChurnSettings {
+        match self.full_touch_interval {
+            Some(interval) if last_full_touch.elapsed() >= interval => {
+                *last_full_touch = Instant::now();
+                debug!("Touching every byte in a full read-write pass");
+                ChurnSettings {
+                    touch_mode: TouchMode::ReadWrite,
+                    stride: 1,
+                    ..*self
+                }
+            }
+            _ => *self,
+        }
+    }
+
+    /// Order in which the worker of range number `range` visits the chunks of its `len`
+    /// bytes during `cycle`, `None` to walk them sequentially. The order only depends on
+    /// the seed, range and cycle, so it's reproducible.
+    pub(crate) fn chunk_order(&self, len: usize, range: usize, cycle: u64) -> Option<Vec<usize>> {
+        let chunks = len.div_ceil(self.chunk_size);
+        let order = match self.access_pattern {
+            AccessPattern::Sequential => return None,
+            AccessPattern::Reverse => (0..chunks).rev().collect(),
+            AccessPattern::Strided(step) => {
+                // Each pass over the strides starts one chunk further, so all are visited once
+                let step = step.max(1);
+                (0..step.min(chunks)).flat_map(|start| (start..chunks).step_by(step)).collect()
+            }
+            AccessPattern::Random => {
+                let mut rng = Rng(self.seed ^ ((range as u64) << 32) ^ cycle);
+                let mut order: Vec<usize> = (0..chunks).collect();
+                // Fisher-Yates shuffle
+                for i in (1..order.len()).rev() {
+                    let j = (rng.next() % (i as u64 + 1)) as usize;
+                    order.swap(i, j);
+                }
+                order
+            }
+        };
+        Some(order)
+    }
+}
+
+/// SplitMix64, a tiny PRNG that is plenty for shuffling the chunk order.
+struct Rng(u64);
+
+const RNG_INCREMENT: u64 = 0x9E37_79B9_7F4A_7C15;
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(RNG_INCREMENT);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// This function will keep modifying the data in the vector
+/// by adding 1 and then subtracting 1 from each element
+/// in the vector, or only reading it in [`TouchMode::ReadOnly`].
+/// This will keep the memory occupied
+/// and make it harder for the OS to move it to file cache or swap.
+///
+/// Fails if the thread or any of the workers it starts first can't be spawned, in which case
+/// the data has already been released. Workers that can't be restarted later stop the
+/// allocation, releasing the data, and fail the commands waiting for them.
+///
+/// The returned thread takes ownership of the data and splits it into one
+/// contiguous range per worker, aligned to the chunk size. Each range is exclusively owned by its own
+/// scoped worker thread, so no locking is needed. The data is dropped once
+/// `control.running` is set to false and all workers have exited.
+///
+/// The workers are pinned as described by `placement`.
+///
+/// To handle `commands` that change the data, the workers are told to exit with
+/// `control.restart` and started again afterwards. The senders for asking each worker to
+/// verify its range are kept in `verifiers`. The workers call `on_cycle`, if given, after
+/// each cycle.
+fn keep_modifying_data(
+    mut data: Buffer,
+    control: Arc<Control>,
+    mut placement: Placement,
+    mut settings: ChurnSettings,
+    verifiers: Verifiers,
+    commands: Receiver<Command>,
+    on_cycle: Option<OnCycle>,
+) -> Result<JoinHandle<()>, WeightError> {
+    let (started, start) = mpsc::channel();
+    // If the thread can't be spawned the closure is dropped, which releases the data
+    let spawned = thread::Builder::new().spawn(move || {
+        let on_cycle = on_cycle.map(Mutex::new);
+        // Commands are only answered once the workers have been restarted, so for example
+        // verifying right after shrinking reaches the new workers. Starting them the first
+        // time is answered the same way
+        let mut replies = vec![(started, Ok(()))];
+        while control.running.load(Ordering::SeqCst) {
+            if data.is_empty() {
+                // There's nothing to churn, so wait for a command or the handle to be stopped
+                verifiers.lock().unwrap_or_else(PoisonError::into_inner).clear();
+                send_replies(&mut replies);
+                match commands.recv() {
+                    Ok(command) => replies.extend(run_command(&mut data, &mut placement, &mut settings, command)),
+                    Err(_) => break,
+                }
+                control.locked.store(data.locked_len(), Ordering::Relaxed);
+                control.set_region(&data);
+                continue;
+            }
+            if let Err(err) = churn_workers(&mut data, &control, &placement, settings, &verifiers, &mut replies, on_cycle.as_ref()) {
+                warn!("{}, releasing the memory.", err);
+                control.stop();
+                break;
+            }
+            control.restart.store(false, Ordering::SeqCst);
+            replies.extend(
+                commands
+                    .try_iter()
+                    .filter_map(|command| run_command(&mut data, &mut placement, &mut settings, command)),
+            );
+            // Shrinking also shrinks the part of the memory that is locked
+            control.locked.store(data.locked_len(), Ordering::Relaxed);
+            control.set_region(&data);
+        }
+    });
+    let owner = spawned.map_err(|err| spawn_failed("the thread keeping the memory occupied", err))?;
+    match start.recv() {
+        Ok(Ok(())) => Ok(owner),
+        // The thread has released the data and exits
+        Ok(Err(err)) => {
+            let _ = owner.join();
+            Err(err)
+        }
+        Err(_) => {
+            let _ = owner.join();
+            Err(WeightError::SpawnFailed(
+                "The thread keeping the memory occupied exited before starting the churn workers".to_string(),
+            ))
+        }
+    }
+}
+
+/// Runs `command` and returns its reply along with the result to send, `None` if it was
+/// already answered. `settings` are those the workers are started with afterwards.
+fn run_command(
+    data: &mut Buffer,
+    placement: &mut Placement,
+    settings: &mut ChurnSettings,
+    command: Command,
+) -> Option<(Reply, Result<(), WeightError>)> {
+    match command {
+        Command::Shrink(bytes, reply) => {
+            data.truncate(bytes);
+            Some((reply, Ok(())))
+        }
+        Command::Grow(bytes, pattern, margin, reply) => Some((reply, grow(data, placement, bytes, pattern, margin))),
+        Command::Advise(advice, reply) => Some((reply, advice::advise(data, advice))),
+        Command::Refill(pattern, reply) => {
+            write_pattern(data, 0, pattern);
+            settings.fill_pattern = pattern;
+            Some((reply, Ok(())))
+        }
+        Command::Retouch(reply) => {
+            pre_touch(data);
+            Some((reply, Ok(())))
+        }
+        Command::SetCpus(cpus, reply) => Some((reply, placement.set_cpus(cpus))),
+        Command::Snapshot(bytes, reply) => {
+            let _ = reply.send(data[..bytes.min(data.len())].to_vec());
+            None
+        }
+        Command::Dump(path, range, reply) => Some((reply, dump(&data[range], &path))),
+        Command::Fault(range, reply) => {
+            fault_in(&mut data[range]);
+            Some((reply, Ok(())))
+        }
+        Command::Inspect(range, inspect) => {
+            inspect(&data[range]);
+            None
+        }
+        Command::Lend(lend, release, reply) => {
+            let lent = Lent {
+                ptr: data.as_mut_ptr(),
+                len: data.len(),
+            };
+            if lend.send(lent).is_ok() {
+                // Only fails once the handle is done with the memory and drops the sender
+                let _ = release.recv();
+            }
+            Some((reply, Ok(())))
+        }
+    }
+}
+
+/// Writes `data` to a new file at `path`.
+fn dump(data: &[u8], path: &Path) -> Result<(), WeightError> {
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(data)?;
+        file.sync_data()
+    };
+    write().map_err(|err| WeightError::Io(format!("Failed to dump the memory to {}: {}", path.display(), err)))
+}
+
+/// Extends `data` to `bytes`, filling and pre-touching the new memory like the initial fill.
+fn grow(data: &mut Buffer, placement: &Placement, bytes: usize, pattern: FillPattern, margin: usize) -> Result<(), WeightError> {
+    // Moving the memory would lose the binding of each range to its node
+    if placement.nodes.is_some() {
+        return Err(WeightError::Unsupported("Memory placed on NUMA nodes can't grow".to_string()));
+    }
+    let start = data.len();
+    system::check_safety_margin(bytes - start, margin)?;
+    data.reserve_to(bytes)?;
+    data.extend_to(bytes)?;
+    write_pattern(&mut data[start..], start, pattern);
+    pre_touch(&mut data[start..]);
+    Ok(())
+}
+
+fn send_replies(replies: &mut Vec<(Reply, Result<(), WeightError>)>) {
+    for (reply, result) in replies.drain(..) {
+        let _ = reply.send(result);
+    }
+}
+
+/// Number of churn workers of an allocation and where they run.
+#[derive(Debug)]
+pub(crate) struct Placement {
+    workers: usize,
+    /// NUMA node of each worker.
+    nodes: Option<Vec<usize>>,
+    /// CPU core of each worker.
+    cpus: Option<Vec<usize>>,
+}
+
+impl Placement {
+    /// Placement of `workers` workers, pinned to `nodes` or `cpus` if given. CPU cores are
+    /// ignored with a warning on platforms where threads can't be pinned.
+    pub(crate) fn new(workers: usize, nodes: Option<Vec<usize>>, cpus: Option<Vec<usize>>) -> Placement {
+        let cpus = cpus.filter(|_| match affinity::check_supported() {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("{}, the workers aren't pinned.", err);
+                false
+            }
+        });
+        Placement { workers, nodes, cpus }
+    }
+
+    fn set_cpus(&mut self, cpus: Vec<usize>) -> Result<(), WeightError> {
+        if self.nodes.is_some() {
+            return Err(WeightError::InvalidConfig(
+                "Workers placed on NUMA nodes can't be pinned to CPU cores".to_string(),
+            ));
+        }
+        if cpus.len() != self.workers {
+            return Err(WeightError::InvalidConfig(format!(
+                "Expected one CPU core for each of the {} workers, got {}",
+                self.workers,
+                cpus.len()
+            )));
+        }
+        self.cpus = Some(cpus);
+        Ok(())
+    }
+
+    /// Whether the workers are pinned to NUMA nodes or CPU cores.
+    fn pins(&self) -> bool {
+        self.nodes.is_some() || self.cpus.is_some()
+    }
+
+    /// Pins the calling thread, worker number `index`, to its NUMA node or CPU core. Failing to
+    /// do so only warns, since the worker can still churn its range.
+    pub(crate) fn pin(&self, index: usize) {
+        let result = match (&self.nodes, &self.cpus) {
+            (Some(nodes), _) => numa::pin_current_thread(nodes[index]),
+            (None, Some(cpus)) => affinity::pin_current_thread(&cpus[index..=index]),
+            (None, None) => Ok(()),
+        };
+        if let Err(err) = result {
+            warn!("{}", err);
+        }
+    }
+}
+
+/// Churns `data` with one scoped worker per range until `control` says to stop or restart.
+/// The `replies` to earlier commands are sent once the new workers can be reached. Fails with
+/// [`WeightError::SpawnFailed`], which the replies get instead, if a worker can't be started,
+/// after the workers that were have exited.
+fn churn_workers(
+    data: &mut Buffer,
+    control: &Control,
+    placement: &Placement,
+    settings: ChurnSettings,
+    verifiers: &Verifiers,
+    replies: &mut Vec<(Reply, Result<(), WeightError>)>,
+    on_cycle: Option<&Mutex<OnCycle>>,
+) -> Result<(), WeightError> {
+    let range_len = range_len(data.len(), placement.workers, settings.chunk_size);
+    let on_cycle = on_cycle.map(|callback| CycleHook { callback, bytes: data.len() });
+    let (senders, requests): (Vec<_>, Vec<_>) =
+        (0..data.len().div_ceil(range_len)).map(|_| mpsc::channel()).unzip();
+    *verifiers.lock().unwrap_or_else(PoisonError::into_inner) = senders;
+    thread::scope(|scope| {
+        for (i, (range, requests)) in data.chunks_mut(range_len).zip(requests).enumerate() {
+            let spawned = thread::Builder::new().spawn_scoped(scope, move || {
+                placement.pin(i);
+                let after_cycle = |control: &Control| {
+                    if let Some(on_cycle) = on_cycle {
+                        on_cycle.call(control);
+                    }
+                };
+                churn_supervised(range, i, i * range_len, control, &requests, settings, &after_cycle)
+            });
+            if let Err(err) = spawned {
+                // The workers that did start exit after their current cycle, before the scope ends
+                control.request_restart();
+                let err = spawn_failed("a churn worker", err);
+                for (reply, _) in replies.drain(..) {
+                    let _ = reply.send(Err(err.clone()));
+                }
+                return Err(err);
+            }
+        }
+        send_replies(replies);
+        Ok(())
+    })
+}
+
+/// The [`OnCycle`] callback of an allocation, shared by its churn workers.
+#[derive(Clone, Copy)]
+struct CycleHook<'a> {
+    callback: &'a Mutex<OnCycle>,
+    /// Size of the allocation the statistics are reported for.
+    bytes: usize,
+}
+
+impl CycleHook<'_> {
+    /// Calls the callback with the current statistics and stops the churn if it breaks.
+    fn call(self, control: &Control) {
+        // Gathered before locking, so only the callback itself runs under the lock
+        let stats = control.stats(self.bytes);
+        let flow = (self.callback.lock().unwrap_or_else(PoisonError::into_inner).0)(&stats);
+        if flow.is_break() {
+            info!("Churn stopped by the cycle callback");
+            control.stop();
+        }
+    }
+}
+
+/// Length of the range each of `workers` churns in a buffer of `bytes`, aligned to `chunk_size`.
+pub(crate) fn range_len(bytes: usize, workers: usize, chunk_size: usize) -> usize {
+    bytes.div_ceil(workers).next_multiple_of(chunk_size).max(1)
+}
+
+/// Runs [`churn`], and with [`ChurnSettings::supervise`] catches its panics, logs them and
+/// starts it over after rewriting the fill pattern of `range`, which the panic may have left
+/// halfway through a cycle. Without supervision a panic ends all churning of the allocation.
+fn churn_supervised(
+    range: &mut [u8],
+    index: usize,
+    offset: usize,
+    control: &Control,
+    requests: &Receiver<VerifyRequest>,
+    settings: ChurnSettings,
+    after_cycle: &dyn Fn(&Control),
+) {
+    if !settings.supervise {
+        return churn(range, index, offset, control, requests, settings, after_cycle);
+    }
+    loop {
+        let churned = panic::catch_unwind(AssertUnwindSafe(|| {
+            churn(range, index, offset, control, requests, settings, after_cycle)
+        }));
+        let Err(panic) = churned else {
+            return;
+        };
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        let restarts = control.restarts.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Churn worker {} panicked: {}, restarting it ({} restarts so far).", index, message, restarts);
+        write_pattern(range, offset, settings.fill_pattern);
+        // A worker that panics on every cycle shouldn't spin
+        thread::sleep(WORKER_RESTART_DELAY);
+    }
+}
+
+/// Keeps touching every byte in `range`, range number `index` which starts at byte `offset`
+/// of the allocation, until `control` says to stop or restart, sleeping for the interval after each
+/// pass. Verify requests are answered between cycles, when every byte holds its fill value,
+/// and `after_cycle` is called after each cycle.
+fn churn(
+    range: &mut [u8],
+    index: usize,
+    offset: usize,
+    control: &Control,
+    requests: &Receiver<VerifyRequest>,
+    settings: ChurnSettings,
+    after_cycle: &dyn Fn(&Control),
+) {
+    let chunk_size = settings.chunk_size;
+    let mut cycle = 0;
+    let mut last_full_touch = Instant::now();
+    while control.churning() {
+        if control.idle() {
+            // The memory is untouched while idle, so verify requests can still be answered
+            for reply in requests.try_iter() {
+                let _ = reply.send(verify_pattern(range, offset, settings.fill_pattern));
+            }
+            // Resuming, ending the warmup and stopping wake the worker, verify requests and the
+            // warmup delay running out are polled for
+            let paused = Instant::now();
+            control.sleep(|| {
+                if control.idle() {
+                    PAUSED_POLL_INTERVAL.saturating_sub(paused.elapsed())
+                } else {
+                    Duration::ZERO
+                }
+            });
+            continue;
+        }
+        let order = settings.chunk_order(range.len(), index, cycle);
+        let order = order.as_deref();
+        let pass_settings = settings.next_cycle(&mut last_full_touch);
+        // The time spent in the last pass and in all passes of the cycle
+        let (busy, cycle_busy) = match pass_settings.touch_mode {
+            TouchMode::ReadWrite => {
+                // Increment all bytes, or whatever the churn op does first
+                let increment = timed(control, || modify(range, chunk_size, pass_settings.stride, order, |byte| *byte = settings.op.apply(*byte)));
+
+                settings.sleep_after(control, increment);
+
+                // Decrement all bytes, undoing the first pass
+                let decrement = timed(control, || modify(range, chunk_size, pass_settings.stride, order, |byte| *byte = settings.op.undo(*byte)));
+                (decrement, increment + decrement)
+            }
+            TouchMode::ReadOnly => {
+                let busy = timed(control, || read(range, chunk_size, pass_settings.stride, order));
+                (busy, busy)
+            }
+        };
+        cycle += 1;
+        control.cycles.fetch_add(1, Ordering::Relaxed);
+        control.record_cycle_time(cycle_busy);
+        debug!("Churn cycle over {} bytes completed", range.len());
+        event!(
+            "churn_cycle",
+            range = index,
+            cycle = cycle,
+            bytes = range.len(),
+            duration_us = cycle_busy.as_micros() as u64
+        );
+        for reply in requests.try_iter() {
+            let _ = reply.send(verify_pattern(range, offset, settings.fill_pattern));
+        }
+        after_cycle(control);
+
+        settings.sleep_after(control, busy);
+    }
+}
+
+/// Time from now until the first multiple of `interval` since the Unix epoch that is more than
+/// `delay` away, so waiting for it lands on an aligned tick of the wall clock. Ticks that are
+/// already past are skipped. A zero `interval` has no ticks, and only `delay` is waited.
+fn until_next_tick(interval: Duration, delay: Duration) -> Duration {
+    let interval = interval.as_nanos();
+    if interval == 0 {
+        return delay;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let tick = ((now + delay.as_nanos()) / interval + 1) * interval;
+    Duration::from_nanos(u64::try_from(tick - now).unwrap_or(u64::MAX))
+}
+
+/// Runs `pass` and records how long it took in `control`.
+fn timed(control: &Control, pass: impl FnOnce()) -> Duration {
+    let started = Instant::now();
+    pass();
+    let busy = started.elapsed();
+    control.add_busy(busy);
+    busy
+}
+
+/// Applies `modify` to every `stride`th byte in `range`, visiting its chunks in `order` if
+/// given and sequentially otherwise.
+pub(crate) fn modify(range: &mut [u8], chunk_size: usize, stride: usize, order: Option<&[usize]>, modify: impl Fn(&mut u8)) {
+    match order {
+        Some(order) => {
+            for &chunk in order {
+                let start = chunk * chunk_size;
+                let end = (start + chunk_size).min(range.len());
+                range[start..end].iter_mut().step_by(stride).for_each(&modify);
+            }
+        }
+        None => {
+            for chunk in range.chunks_mut(chunk_size) {
+                chunk.iter_mut().step_by(stride).for_each(&modify);
+            }
+        }
+    }
+}
+
+/// Reads every `stride`th byte in `range` without modifying it, which keeps the pages in the
+/// working set without making them dirty. Visits the chunks like [`modify`].
+pub(crate) fn read(range: &[u8], chunk_size: usize, stride: usize, order: Option<&[usize]>) {
+    let read_chunk = |chunk: &[u8]| {
+        let sum = chunk.iter().step_by(stride).fold(0u64, |sum, &byte| sum.wrapping_add(byte as u64));
+        // black_box keeps the compiler from optimizing the reads away
+        std::hint::black_box(sum);
+    };
+    match order {
+        Some(order) => {
+            for &chunk in order {
+                let start = chunk * chunk_size;
+                read_chunk(&range[start..(start + chunk_size).min(range.len())]);
+            }
+        }
+        None => range.chunks(chunk_size).for_each(read_chunk),
+    }
+}
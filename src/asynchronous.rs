@@ -0,0 +1,352 @@
+//! An async variant of the allocation API for embedding in Tokio applications.
+//!
+//! The churn loop runs as a Tokio task that waits with `tokio::time::sleep` instead of
+//! blocking a thread, and runs the CPU-bound passes over the memory on the blocking pool.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::task::{self, JoinHandle};
+
+use crate::buffer::Buffer;
+use crate::config::{OnCycle, TouchMode, WeightConfig};
+use crate::error::WeightError;
+use crate::lock::{self, Locked, LockedBuffer};
+use crate::alloc::{self, ChurnSettings, Control, CyclePercentiles, Stats};
+
+/// Handle to a block of memory that is being kept occupied by a Tokio task.
+///
+/// Awaiting the handle waits until the churn task has finished, which happens after
+/// [`abort`](AsyncAllocation::abort). Dropping the handle stops the task after its current
+/// pass and releases the memory.
+pub struct AsyncAllocation {
+    bytes: usize,
+    committed: Option<usize>,
+    control: Arc<Control>,
+    task: Option<JoinHandle<()>>,
+    _locked: Option<LockedBuffer>,
+    shared_name: Option<String>,
+}
+
+impl AsyncAllocation {
+    /// Number of bytes held by the allocation.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of bytes committed to physical memory by the allocation, `None` if it can't be
+    /// determined on this platform.
+    pub fn committed_bytes(&self) -> Option<usize> {
+        self.committed
+    }
+
+    /// Name of the shared memory segment backing the allocation, like
+    /// [`Allocation::shared_memory_name`](crate::alloc::Allocation::shared_memory_name).
+    pub fn shared_memory_name(&self) -> Option<&str> {
+        self.shared_name.as_deref()
+    }
+
+    /// Returns a snapshot of the allocation's progress.
+    pub fn stats(&self) -> Stats {
+        self.control.stats(self.bytes)
+    }
+
+    /// Percentiles of how long the most recent cycles took, like
+    /// [`Allocation::cycle_percentiles`](crate::alloc::Allocation::cycle_percentiles).
+    pub fn cycle_percentiles(&self) -> Option<CyclePercentiles> {
+        self.control.cycle_percentiles()
+    }
+
+    /// Stops touching the memory without releasing it until [`resume`](Self::resume) is called.
+    /// Has no effect on locked memory.
+    pub fn pause(&self) {
+        self.control.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes churning after [`pause`](Self::pause).
+    pub fn resume(&self) {
+        self.control.paused.store(false, Ordering::SeqCst);
+        self.control.wake();
+    }
+
+    /// Whether churning is paused.
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::SeqCst)
+    }
+
+    /// Time the churn task sleeps between passes over the memory.
+    pub fn interval(&self) -> Duration {
+        self.control.interval()
+    }
+
+    /// Changes how long the churn task sleeps between passes, see
+    /// [`Allocation::set_interval`](crate::Allocation::set_interval).
+    pub fn set_interval(&self, interval: Duration) {
+        self.control.set_interval(interval);
+    }
+
+    /// Starts churning before the warmup delay has elapsed, see
+    /// [`Allocation::start_churn`](crate::Allocation::start_churn).
+    pub fn start_churn(&self) {
+        self.control.start_churn();
+    }
+
+    /// Whether churning hasn't started yet because of the warmup delay.
+    pub fn is_warming_up(&self) -> bool {
+        self.control.warming_up()
+    }
+
+    /// Stops the churn task and waits until it has released the memory.
+    pub async fn stop(mut self) {
+        self.control.stop();
+        if let Some(task) = self.task.take() {
+            join(task).await;
+        }
+    }
+
+    /// Aborts the churn task at its next await point without waiting for it. A pass that
+    /// is already running on the blocking pool finishes first, then the memory is released.
+    pub fn abort(&self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+impl Future for AsyncAllocation {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(task) = &mut self.get_mut().task else {
+            return Poll::Ready(());
+        };
+        match Pin::new(task).poll(cx) {
+            Poll::Ready(Err(err)) if err.is_panic() => {
+                warn!("A memory churn task panicked.");
+                Poll::Ready(())
+            }
+            Poll::Ready(_) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for AsyncAllocation {
+    fn drop(&mut self) {
+        self.control.stop();
+    }
+}
+
+/// Allocates `memory` with the default settings and keeps it occupied with a Tokio task.
+pub async fn allocate_memory_async(memory: &str) -> Result<AsyncAllocation, WeightError> {
+    allocate_async(WeightConfig::new(memory).build()?).await
+}
+
+/// Allocates the memory described by `config` like [`allocate`](crate::alloc::allocate),
+/// but fills it on the blocking pool and churns it from a Tokio task. Must be called from
+/// within a Tokio runtime.
+///
+/// NUMA placement and CPU pinning aren't supported, because the blocking pool threads can't be pinned,
+/// and neither are the [`Watchdog`](crate::config::Watchdog) and
+/// [`DiskFlush`](crate::config::DiskFlush).
+pub async fn allocate_async(mut config: WeightConfig) -> Result<AsyncAllocation, WeightError> {
+    if config.numa_nodes.is_some() {
+        return Err(WeightError::Unsupported(
+            "NUMA placement isn't supported by the async API".to_string(),
+        ));
+    }
+    if config.cpus.is_some() {
+        return Err(WeightError::Unsupported(
+            "Pinning workers to CPU cores isn't supported by the async API".to_string(),
+        ));
+    }
+    if config.watchdog.is_some() {
+        return Err(WeightError::Unsupported("The watchdog isn't supported by the async API".to_string()));
+    }
+    if config.disk_flush.is_some() {
+        return Err(WeightError::Unsupported("Disk flushing isn't supported by the async API".to_string()));
+    }
+    let bytes = config.bytes;
+    info!("Allocating {} bytes ({})", bytes, config.memory);
+    if bytes == 0 {
+        return Ok(AsyncAllocation {
+            bytes,
+            committed: Some(0),
+            control: Arc::new(Control::new(config.cycle_history, None).with_interval(config.interval)),
+            task: None,
+            _locked: None,
+            shared_name: None,
+        });
+    }
+
+    let settings = ChurnSettings::new(&config);
+    let workers = config.workers;
+    let (lock, raise_lock_limit) = (config.lock, config.raise_lock_limit);
+    let cycle_history = config.cycle_history;
+    let (interval, warmup_delay) = (config.interval, config.warmup_delay);
+    let on_cycle = config.on_cycle.take();
+    let (mut data, fill) = task::spawn_blocking(move || alloc::allocate_buffer(&mut config))
+        .await
+        .map_err(|_| WeightError::AllocationFailed(bytes))??;
+    let bytes = data.len();
+    let committed = fill.committed;
+    let shared_name = data.shared_name().map(String::from);
+    let control = Arc::new(
+        Control::new(cycle_history, fill.page_faults)
+            .with_region(&data)
+            .with_interval(interval)
+            .with_warmup(warmup_delay),
+    );
+
+    if lock {
+        match lock::lock_buffer(data, raise_lock_limit)? {
+            Locked::Full(locked) => {
+                control.locked.store(bytes, Ordering::Relaxed);
+                return Ok(AsyncAllocation {
+                    bytes,
+                    committed,
+                    control,
+                    task: None,
+                    _locked: Some(locked),
+                    shared_name,
+                });
+            }
+            Locked::Partial(partial) => {
+                control.locked.store(partial.locked_len(), Ordering::Relaxed);
+                data = partial;
+            }
+        }
+    }
+
+    let task = tokio::spawn(churn(data, Arc::clone(&control), workers, settings, on_cycle));
+    Ok(AsyncAllocation {
+        bytes,
+        committed,
+        control,
+        task: Some(task),
+        _locked: None,
+        shared_name,
+    })
+}
+
+/// Keeps touching every byte in `data` until `control.running` is false, like the churn
+/// threads of the sync API but sleeping without blocking a thread. Calls `on_cycle` after
+/// each cycle over all ranges.
+async fn churn(
+    mut data: Buffer,
+    control: Arc<Control>,
+    workers: usize,
+    settings: ChurnSettings,
+    mut on_cycle: Option<OnCycle>,
+) {
+    let range_len = alloc::range_len(data.len(), workers, settings.chunk_size);
+    let ranges = data.len().div_ceil(range_len) as u64;
+    let mut cycle = 0;
+    let mut last_full_touch = Instant::now();
+    while control.running.load(Ordering::SeqCst) {
+        if control.idle() {
+            tokio::time::sleep(control.interval().max(alloc::PAUSED_POLL_INTERVAL)).await;
+            continue;
+        }
+        let pass_settings = settings.next_cycle(&mut last_full_touch);
+        let busy;
+        let cycle_busy;
+        match pass_settings.touch_mode {
+            TouchMode::ReadWrite => {
+                // Increment all bytes, or whatever the churn op does first
+                let busy_increment;
+                (data, busy_increment) = timed(&control, ranges, pass(data, range_len, pass_settings, cycle, Pass::Apply)).await;
+
+                tokio::time::sleep(settings.pause_after(control.interval(), busy_increment)).await;
+
+                // Decrement all bytes, undoing the first pass
+                (data, busy) = timed(&control, ranges, pass(data, range_len, pass_settings, cycle, Pass::Undo)).await;
+                cycle_busy = busy_increment + busy;
+            }
+            TouchMode::ReadOnly => {
+                (data, busy) = timed(&control, ranges, pass(data, range_len, pass_settings, cycle, Pass::Read)).await;
+                cycle_busy = busy;
+            }
+        }
+        cycle += 1;
+        control.cycles.fetch_add(ranges, Ordering::Relaxed);
+        control.record_cycle_time(cycle_busy);
+        debug!("Churn cycle over {} bytes completed", data.len());
+        event!(
+            "churn_cycle",
+            cycle = cycle,
+            bytes = data.len(),
+            duration_us = cycle_busy.as_micros() as u64
+        );
+        if let Some(on_cycle) = &mut on_cycle
+            && (on_cycle.0)(&control.stats(data.len())).is_break()
+        {
+            info!("Churn stopped by the cycle callback");
+            control.stop();
+            break;
+        }
+
+        tokio::time::sleep(settings.pause_after(control.interval(), busy)).await;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Pass {
+    /// The first half of the churn op.
+    Apply,
+    /// The second half of the churn op, which undoes the first.
+    Undo,
+    Read,
+}
+
+/// Runs one pass over `data` on the blocking pool, with one scoped thread per range so
+/// the pass uses as many cores as the sync API does.
+async fn pass(mut data: Buffer, range_len: usize, settings: ChurnSettings, cycle: u64, pass: Pass) -> Buffer {
+    let result = task::spawn_blocking(move || {
+        thread::scope(|scope| {
+            for (i, range) in data.chunks_mut(range_len).enumerate() {
+                scope.spawn(move || {
+                    let order = settings.chunk_order(range.len(), i, cycle);
+                    match pass {
+                        Pass::Apply => alloc::modify(range, settings.chunk_size, settings.stride, order.as_deref(), |byte| {
+                            *byte = settings.op.apply(*byte)
+                        }),
+                        Pass::Undo => alloc::modify(range, settings.chunk_size, settings.stride, order.as_deref(), |byte| {
+                            *byte = settings.op.undo(*byte)
+                        }),
+                        Pass::Read => alloc::read(range, settings.chunk_size, settings.stride, order.as_deref()),
+                    }
+                });
+            }
+        });
+        data
+    })
+    .await;
+    match result {
+        Ok(data) => data,
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+/// Awaits a pass over `ranges` ranges in parallel and records the time each of them was busy
+/// in `control`.
+async fn timed(control: &Control, ranges: u64, pass: impl Future<Output = Buffer>) -> (Buffer, Duration) {
+    let started = Instant::now();
+    let data = pass.await;
+    let busy = started.elapsed();
+    control.add_busy(busy * ranges as u32);
+    (data, busy)
+}
+
+async fn join(task: JoinHandle<()>) {
+    if let Err(err) = task.await
+        && err.is_panic()
+    {
+        warn!("A memory churn task panicked.");
+    }
+}
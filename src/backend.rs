@@ -0,0 +1,135 @@
+//! Plugging in memory from elsewhere, e.g. GPU pinned memory or persistent memory, while
+//! keeping the filling, churning, verifying and statistics of a regular allocation.
+//!
+//! A [`MemoryBackend`] hands out the memory and the allocation does everything else with it
+//! through [`as_mut_slice`](MemoryBackend::as_mut_slice). [`HeapMemory`] and, on Unix,
+//! [`MappedMemory`] implement it for the memory of the built-in backends, as examples and to
+//! wrap in backends that only change part of the lifecycle.
+
+use crate::buffer;
+#[cfg(unix)]
+use crate::buffer::Mapping;
+#[cfg(unix)]
+use crate::config::MmapFlags;
+use crate::error::WeightError;
+
+/// Memory an allocation is made in, chosen with
+/// [`WeightConfigBuilder::memory_backend`](crate::config::WeightConfigBuilder::memory_backend).
+///
+/// The allocation calls [`allocate`](Self::allocate) for the requested size, more than once
+/// when [retrying](crate::config::WeightConfigBuilder::retry) or searching for the size that
+/// fits with [`best_effort`](crate::config::WeightConfigBuilder::best_effort), then
+/// [`touch`](Self::touch) once, fills the memory with its fill pattern and churns it from its
+/// worker threads, each of which gets its own part of the slice. When the allocation is
+/// dropped, [`free`](Self::free) is called before the backend itself is dropped.
+///
+/// ```
+/// use weight::backend::{HeapMemory, MemoryBackend};
+/// use weight::WeightError;
+///
+/// /// Heap memory that counts how often it was handed out.
+/// struct Counted(HeapMemory);
+///
+/// static ALLOCATED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+///
+/// impl MemoryBackend for Counted {
+///     fn allocate(bytes: usize) -> Result<Self, WeightError> {
+///         ALLOCATED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+///         HeapMemory::allocate(bytes).map(Counted)
+///     }
+///
+///     fn as_slice(&self) -> &[u8] {
+///         self.0.as_slice()
+///     }
+///
+///     fn as_mut_slice(&mut self) -> &mut [u8] {
+///         self.0.as_mut_slice()
+///     }
+/// }
+///
+/// # fn main() -> Result<(), WeightError> {
+/// let allocation = weight::allocate(weight::WeightConfig::new("1MB").memory_backend::<Counted>().build()?)?;
+/// assert_eq!(allocation.bytes(), 1_000_000);
+/// assert!(allocation.verify().is_ok());
+/// assert_eq!(ALLOCATED.load(std::sync::atomic::Ordering::Relaxed), 1);
+/// # Ok(())
+/// # }
+/// ```
+pub trait MemoryBackend: Send {
+    /// Reserves `bytes` of memory. The slices must be at least that long, the allocation only
+    /// uses the first `bytes` of them. Fails with
+    /// [`AllocationFailed`](WeightError::AllocationFailed) or another error when the memory
+    /// isn't available.
+    fn allocate(bytes: usize) -> Result<Self, WeightError>
+    where
+        Self: Sized;
+
+    /// The memory, for verifying and inspecting it.
+    fn as_slice(&self) -> &[u8];
+
+    /// The memory, for filling and churning it. It must be the same memory as
+    /// [`as_slice`](Self::as_slice), which may not move while the allocation exists.
+    fn as_mut_slice(&mut self) -> &mut [u8];
+
+    /// Prepares the memory to be filled, called once after allocating it, e.g. to pin it or
+    /// register it with a device. The fill writes every byte, which faults in memory that
+    /// isn't backed yet, so the default does nothing.
+    fn touch(&mut self) {}
+
+    /// Releases what dropping the backend doesn't, called once when the allocation is dropped,
+    /// e.g. to flush persistent memory or unregister it from a device. The memory isn't
+    /// accessed after this. The default does nothing.
+    fn free(&mut self) {}
+}
+
+/// Memory from the global allocator, as used by [`Backend::Heap`](crate::config::Backend::Heap).
+pub struct HeapMemory(Vec<u8>);
+
+impl MemoryBackend for HeapMemory {
+    fn allocate(bytes: usize) -> Result<Self, WeightError> {
+        // Zeroed memory comes straight from the OS without touching it, so the fill does that
+        buffer::zeroed_vec(bytes).map(HeapMemory)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// An anonymous private mapping, as used by [`Backend::Mmap`](crate::config::Backend::Mmap)
+/// with the default [`MmapFlags`]. Only available on Unix.
+///
+/// ```
+/// # #[cfg(unix)]
+/// # fn main() -> Result<(), weight::WeightError> {
+/// use weight::backend::MappedMemory;
+///
+/// let config = weight::WeightConfig::new("1MiB").memory_backend::<MappedMemory>().build()?;
+/// let allocation = weight::allocate(config)?;
+/// assert!(allocation.verify().is_ok());
+/// # Ok(())
+/// # }
+/// # #[cfg(not(unix))]
+/// # fn main() {}
+/// ```
+#[cfg(unix)]
+pub struct MappedMemory(Mapping);
+
+#[cfg(unix)]
+impl MemoryBackend for MappedMemory {
+    fn allocate(bytes: usize) -> Result<Self, WeightError> {
+        Mapping::private(bytes, MmapFlags::default()).map(MappedMemory)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
@@ -0,0 +1,91 @@
+//! Measuring memory bandwidth with the same passes the churn workers make.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::WeightConfig;
+use crate::error::WeightError;
+use crate::alloc::{self, ChurnSettings, Placement};
+
+/// Throughput of one kind of pass in GB/s (10^9 bytes per second) over all benchmark passes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    /// Throughput of the slowest pass.
+    pub min: f64,
+    /// Throughput of the fastest pass.
+    pub max: f64,
+    /// Mean throughput of all passes.
+    pub mean: f64,
+}
+
+impl Throughput {
+    fn new(bytes: usize, timings: &[Duration]) -> Throughput {
+        let rates: Vec<f64> = timings
+            .iter()
+            .map(|timing| bytes as f64 / timing.as_secs_f64().max(f64::MIN_POSITIVE) / 1e9)
+            .collect();
+        Throughput {
+            min: rates.iter().copied().fold(f64::INFINITY, f64::min),
+            max: rates.iter().copied().fold(0.0, f64::max),
+            mean: rates.iter().sum::<f64>() / rates.len() as f64,
+        }
+    }
+}
+
+/// Result of [`benchmark`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Benchmark {
+    /// Number of bytes each pass went over.
+    pub bytes: usize,
+    /// Number of increment and decrement passes that were timed.
+    pub passes: usize,
+    /// Throughput of the passes incrementing every byte.
+    pub increment: Throughput,
+    /// Throughput of the passes decrementing every byte.
+    pub decrement: Throughput,
+}
+
+/// Allocates the memory described by `config` and measures the memory bandwidth achieved by
+/// `passes` full increment and decrement passes over it, using the configured workers and
+/// their NUMA nodes or CPU cores, chunk size and access pattern. The memory is released again before returning, no churn
+/// threads are started and the interval and stride are ignored.
+pub fn benchmark(mut config: WeightConfig, passes: usize) -> Result<Benchmark, WeightError> {
+    if passes == 0 {
+        return Err(WeightError::InvalidConfig("Benchmark pass count must be at least 1".to_string()));
+    }
+    let settings = ChurnSettings::new(&config);
+    let (mut data, _) = alloc::allocate_buffer(&mut config)?;
+    let placement = Placement::new(config.workers, config.numa_nodes.take(), config.cpus.take());
+    let placement = &placement;
+    let bytes = data.len();
+    let range_len = alloc::range_len(bytes, config.workers, settings.chunk_size);
+
+    let mut increments = Vec::with_capacity(passes);
+    let mut decrements = Vec::with_capacity(passes);
+    for pass in 0..passes {
+        for (timings, modify) in [
+            (&mut increments, (|byte| *byte = byte.wrapping_add(1)) as fn(&mut u8)),
+            (&mut decrements, |byte| *byte = byte.wrapping_sub(1)),
+        ] {
+            let started = Instant::now();
+            thread::scope(|scope| {
+                for (i, range) in data.chunks_mut(range_len).enumerate() {
+                    scope.spawn(move || {
+                        placement.pin(i);
+                        let order = settings.chunk_order(range.len(), i, pass as u64);
+                        alloc::modify(range, settings.chunk_size, 1, order.as_deref(), modify);
+                    });
+                }
+            });
+            timings.push(started.elapsed());
+        }
+    }
+    debug!("Benchmarked {} passes over {} bytes", passes, bytes);
+
+    Ok(Benchmark {
+        bytes,
+        passes,
+        increment: Throughput::new(bytes, &increments),
+        decrement: Throughput::new(bytes, &decrements),
+    })
+}
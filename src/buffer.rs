@@ -0,0 +1,816 @@
+//! Memory that backs an allocation.
+
+#[cfg(unix)]
+use std::ffi::CString;
+use std::alloc::{self, Layout};
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::advice::{self, Advice};
+use crate::backend::MemoryBackend;
+use crate::config::{MmapFlags, VirtualAllocFlags};
+use crate::error::WeightError;
+use crate::lock;
+
+/// Bytes held by all live buffers of the process, see [`held_bytes`].
+static HELD_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Distinguishes the shared memory segments of several allocations in the same process.
+#[cfg(unix)]
+static NEXT_SEGMENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Memory that backs an allocation, either from the global allocator or mapped directly.
+pub(crate) struct Buffer {
+    memory: Memory,
+    /// Whether the memory is overwritten with zeros before it's released.
+    zero_on_drop: bool,
+    /// Bytes the buffer is meant to hold, counted in [`HELD_BYTES`].
+    held: usize,
+    /// Bytes at the start that are locked in physical memory, unlocked again when dropped.
+    locked: usize,
+    /// Whether transparent huge pages are kept from backing the memory, also after it moves.
+    thp_disabled: bool,
+}
+
+enum Memory {
+    /// Memory from the global allocator.
+    Heap(Vec<u8>),
+    /// Anonymous or shared memory mapping, backed by regular or huge pages.
+    #[cfg(unix)]
+    Mapped(Mapping),
+    /// Memory reserved and committed with `VirtualAlloc`.
+    #[cfg(windows)]
+    Virtual(VirtualMemory),
+    /// Memory from a [`MemoryBackend`] of the caller, of which the first `len` bytes are used.
+    Custom { backend: Box<dyn MemoryBackend>, len: usize },
+}
+
+impl Buffer {
+    fn new(memory: Memory, bytes: usize) -> Buffer {
+        HELD_BYTES.fetch_add(bytes, Ordering::Relaxed);
+        Buffer {
+            memory,
+            zero_on_drop: false,
+            held: bytes,
+            locked: 0,
+            thp_disabled: false,
+        }
+    }
+
+    /// Updates the bytes the buffer is meant to hold to `bytes`.
+    fn hold(&mut self, bytes: usize) {
+        if bytes > self.held {
+            HELD_BYTES.fetch_add(bytes - self.held, Ordering::Relaxed);
+        } else {
+            HELD_BYTES.fetch_sub(self.held - bytes, Ordering::Relaxed);
+        }
+        self.held = bytes;
+    }
+
+    /// Overwrite the memory with zeros before it's released, see
+    /// [`WeightConfigBuilder::zero_on_drop`](crate::config::WeightConfigBuilder::zero_on_drop).
+    pub(crate) fn set_zero_on_drop(&mut self, zero_on_drop: bool) {
+        self.zero_on_drop = zero_on_drop;
+    }
+
+    /// Bytes at the start of the buffer that are locked in physical memory, when only part of
+    /// it could be locked.
+    pub(crate) fn locked_len(&self) -> usize {
+        self.locked
+    }
+
+    /// Records that the first `len` bytes were locked with [`lock::lock`], so they're unlocked
+    /// again before the memory is released.
+    pub(crate) fn set_locked_len(&mut self, len: usize) {
+        self.locked = len;
+    }
+
+    /// Keeps transparent huge pages from backing the memory with `MADV_NOHUGEPAGE`, including
+    /// memory it's moved to when it grows. Does nothing on platforms other than Linux, which
+    /// don't have them.
+    pub(crate) fn disable_thp(&mut self) -> Result<(), WeightError> {
+        self.thp_disabled = true;
+        self.advise_thp()
+    }
+
+    fn advise_thp(&mut self) -> Result<(), WeightError> {
+        if !cfg!(target_os = "linux") {
+            return Ok(());
+        }
+        let memory: &mut [MaybeUninit<u8>] = match &mut self.memory {
+            Memory::Heap(data) => {
+                // SAFETY: the capacity of the Vec is allocated and exclusively borrowed, and
+                // its initialized bytes are valid uninitialized bytes.
+                unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr().cast(), data.capacity()) }
+            }
+            #[cfg(unix)]
+            Memory::Mapped(mapping) => mapping.as_uninit_mut(),
+            #[cfg(windows)]
+            Memory::Virtual(memory) => memory.as_uninit_mut(),
+            Memory::Custom { .. } => self.reserved_mut(),
+        };
+        advice::advise_uninit(memory, Advice::NoHugePage)
+    }
+
+    /// Reserves `bytes` from the global allocator without touching them.
+    pub(crate) fn heap(bytes: usize) -> Result<Buffer, WeightError> {
+        // try_reserve_exact returns an error instead of aborting the process when the
+        // memory isn't available, so callers can fall back to a smaller request.
+        let mut data = Vec::new();
+        data.try_reserve_exact(bytes)
+            .map_err(|_| WeightError::AllocationFailed(bytes))?;
+        Ok(Buffer::new(Memory::Heap(data), bytes))
+    }
+
+    /// Gets `bytes` of zeroed memory from the global allocator, which for large allocations
+    /// hands out fresh pages from the OS without touching them, unlike zeroing them afterwards.
+    pub(crate) fn heap_zeroed(bytes: usize) -> Result<Buffer, WeightError> {
+        zeroed_vec(bytes).map(|data| Buffer::new(Memory::Heap(data), bytes))
+    }
+
+    /// Maps `bytes` of anonymous memory with mmap(2) and the additional `flags`.
+    #[cfg(unix)]
+    pub(crate) fn mapped(bytes: usize, flags: MmapFlags) -> Result<Buffer, WeightError> {
+        Mapping::private(bytes, flags).map(|mapping| Buffer::new(Memory::Mapped(mapping), bytes))
+    }
+
+    /// Reserves `bytes` with `VirtualAlloc` and commits them right away, or as the buffer is
+    /// extended with [`defer_commit`](VirtualAllocFlags::defer_commit).
+    #[cfg(windows)]
+    pub(crate) fn virtual_alloc(bytes: usize, flags: VirtualAllocFlags) -> Result<Buffer, WeightError> {
+        VirtualMemory::new(bytes, flags).map(|memory| Buffer::new(Memory::Virtual(memory), bytes))
+    }
+
+    /// Reserves `bytes` with `VirtualAlloc`, which is only supported on Windows.
+    #[cfg(not(windows))]
+    pub(crate) fn virtual_alloc(_bytes: usize, _flags: VirtualAllocFlags) -> Result<Buffer, WeightError> {
+        Err(WeightError::Unsupported("The VirtualAlloc backend is only supported on Windows".to_string()))
+    }
+
+    /// Gets `bytes` from a custom backend `B` and prepares them with
+    /// [`touch`](MemoryBackend::touch).
+    pub(crate) fn custom<B: MemoryBackend + 'static>(bytes: usize) -> Result<Buffer, WeightError> {
+        let mut backend = B::allocate(bytes)?;
+        let len = backend.as_slice().len();
+        if len < bytes {
+            return Err(WeightError::Unsupported(format!(
+                "The memory backend returned {} bytes instead of {}",
+                len, bytes
+            )));
+        }
+        backend.touch();
+        Ok(Buffer::new(Memory::Custom { backend: Box::new(backend), len: bytes }, bytes))
+    }
+
+    /// Maps `bytes` of anonymous memory, which is only supported on Unix.
+    #[cfg(not(unix))]
+    pub(crate) fn mapped(_bytes: usize, _flags: MmapFlags) -> Result<Buffer, WeightError> {
+        Err(WeightError::Unsupported("The mmap backend is only supported on Unix".to_string()))
+    }
+
+    /// Maps `bytes` of a new POSIX shared memory segment, which is removed again when the
+    /// buffer is dropped.
+    #[cfg(unix)]
+    pub(crate) fn shared(bytes: usize) -> Result<Buffer, WeightError> {
+        let page_size = crate::system::page_size();
+        let mapped_len = bytes.checked_next_multiple_of(page_size).ok_or(WeightError::Overflow)?;
+        // Short enough for macOS, which limits names to 31 bytes
+        let name = format!("/weight-{}-{}", std::process::id(), NEXT_SEGMENT.fetch_add(1, Ordering::Relaxed));
+        Mapping::shared(bytes, mapped_len, page_size, &name)
+            .map(|mapping| Buffer::new(Memory::Mapped(mapping), bytes))
+            .map_err(|err| WeightError::Unsupported(format!("Failed to map {} bytes of shared memory at {}: {}", mapped_len, name, err)))
+    }
+
+    /// Maps `bytes` of shared memory, which is only supported on Unix.
+    #[cfg(not(unix))]
+    pub(crate) fn shared(_bytes: usize) -> Result<Buffer, WeightError> {
+        Err(WeightError::Unsupported("The shared memory backend is only supported on Unix".to_string()))
+    }
+
+    /// Name of the shared memory segment backing the buffer, `None` if it isn't shared.
+    pub(crate) fn shared_name(&self) -> Option<&str> {
+        match &self.memory {
+            Memory::Heap(_) | Memory::Custom { .. } => None,
+            #[cfg(unix)]
+            Memory::Mapped(mapping) => mapping.segment.as_ref().and_then(|segment| segment.name.to_str().ok()),
+            #[cfg(windows)]
+            Memory::Virtual(_) => None,
+        }
+    }
+
+    /// Maps `bytes` backed by huge pages with the additional `flags`, which requires huge
+    /// pages to be reserved in the kernel with `sysctl vm.nr_hugepages=<count>`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn huge_pages(bytes: usize, flags: MmapFlags) -> Result<Buffer, WeightError> {
+        let huge_page_size = huge_page_size().unwrap_or(2 * 1024 * 1024);
+        let mapped_len = bytes.checked_next_multiple_of(huge_page_size).ok_or(WeightError::Overflow)?;
+        Mapping::anonymous(bytes, mapped_len, 0, huge_page_size, libc::MAP_HUGETLB | map_flags(flags))
+            .map(|mapping| Buffer::new(Memory::Mapped(mapping), bytes))
+            .map_err(|err| {
+                WeightError::Unsupported(format!(
+                    "Failed to map {} bytes of huge pages: {}. Make sure enough huge pages are reserved, e.g. with `sysctl vm.nr_hugepages={}`",
+                    mapped_len,
+                    err,
+                    mapped_len / huge_page_size
+                ))
+            })
+    }
+
+    /// Maps `bytes` backed by huge pages, which is only supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn huge_pages(_bytes: usize, _flags: MmapFlags) -> Result<Buffer, WeightError> {
+        Err(WeightError::Unsupported("Huge pages are only supported on Linux".to_string()))
+    }
+
+    /// The memory reserved for the buffer that hasn't been touched yet.
+    pub(crate) fn reserved_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        match &mut self.memory {
+            Memory::Heap(data) => data.spare_capacity_mut(),
+            // A fresh mapping is all zero pages that haven't been faulted in yet
+            #[cfg(unix)]
+            Memory::Mapped(mapping) => mapping.as_uninit_mut(),
+            #[cfg(windows)]
+            Memory::Virtual(memory) => memory.as_uninit_mut(),
+            Memory::Custom { backend, len } => {
+                let data = &mut backend.as_mut_slice()[..*len];
+                // SAFETY: initialized bytes are valid uninitialized bytes, and only initialized
+                // bytes are written through the returned slice.
+                unsafe { &mut *(data as *mut [u8] as *mut [MaybeUninit<u8>]) }
+            }
+        }
+    }
+
+    /// Reserves memory for growing the buffer to `len` bytes without touching it. Mappings
+    /// have a fixed size and can't grow, and `VirtualAlloc` memory only within the address
+    /// space it reserved.
+    pub(crate) fn reserve_to(&mut self, len: usize) -> Result<(), WeightError> {
+        match &mut self.memory {
+            Memory::Heap(data) => data
+                .try_reserve_exact(len.saturating_sub(data.len()))
+                .map_err(|_| WeightError::AllocationFailed(len))?,
+            #[cfg(unix)]
+            Memory::Mapped(_) => return Err(WeightError::Unsupported("Mapped allocations can't grow".to_string())),
+            #[cfg(windows)]
+            Memory::Virtual(memory) => {
+                if len > memory.reserved_len {
+                    return Err(WeightError::Unsupported(format!(
+                        "VirtualAlloc allocations can't grow past the {} bytes they reserved",
+                        memory.reserved_len
+                    )));
+                }
+            }
+            Memory::Custom { .. } => {
+                return Err(WeightError::Unsupported("Allocations of custom backends can't grow".to_string()));
+            }
+        }
+        self.hold(self.held.max(len));
+        // Growing may have moved the memory to pages the advice wasn't applied to
+        if self.thp_disabled {
+            self.advise_thp()?;
+        }
+        Ok(())
+    }
+
+    /// Grows the buffer to `len` bytes, zeroing the new bytes. Only fails when `VirtualAlloc`
+    /// memory can't be committed.
+    pub(crate) fn extend_to(&mut self, len: usize) -> Result<(), WeightError> {
+        match &mut self.memory {
+            // resize zeroes the memory with an optimized memset
+            Memory::Heap(data) => data.resize(len, 0),
+            // Mappings and custom memory have their full length from the start
+            #[cfg(unix)]
+            Memory::Mapped(_) => {}
+            #[cfg(windows)]
+            Memory::Virtual(memory) => memory.extend_to(len)?,
+            Memory::Custom { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Shrinks the buffer to `len` bytes and returns the memory after it to the OS.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if self.zero_on_drop && len < self.len() {
+            zero(&mut self[len..]);
+        }
+        // Shrinking the heap can move the memory, so it's unlocked first and the part that
+        // is left is locked again afterwards
+        let locked = self.locked.min(len);
+        if self.locked > 0 {
+            lock::unlock(&self[..self.locked]);
+            self.locked = 0;
+        }
+        match &mut self.memory {
+            Memory::Heap(data) => {
+                data.truncate(len);
+                data.shrink_to_fit();
+            }
+            #[cfg(unix)]
+            Memory::Mapped(mapping) => mapping.truncate(len),
+            #[cfg(windows)]
+            Memory::Virtual(memory) => memory.truncate(len),
+            // The backend can't be asked to give memory back, so the rest just goes unused
+            Memory::Custom { len: used, .. } => *used = (*used).min(len),
+        }
+        self.hold(self.held.min(len));
+        if locked > 0 {
+            match lock::lock(&self[..locked]) {
+                Ok(()) => self.locked = locked,
+                Err(err) => warn!("{}, the memory is no longer partly locked.", err),
+            }
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.memory {
+            Memory::Heap(data) => data,
+            #[cfg(unix)]
+            Memory::Mapped(mapping) => mapping,
+            #[cfg(windows)]
+            Memory::Virtual(memory) => memory,
+            Memory::Custom { backend, len } => &backend.as_slice()[..*len],
+        }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.memory {
+            Memory::Heap(data) => data,
+            #[cfg(unix)]
+            Memory::Mapped(mapping) => mapping,
+            #[cfg(windows)]
+            Memory::Virtual(memory) => memory,
+            Memory::Custom { backend, len } => &mut backend.as_mut_slice()[..*len],
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.zero_on_drop {
+            zero(self);
+            debug!("Zeroed {} bytes before releasing them", self.len());
+        }
+        if self.locked > 0 {
+            lock::unlock(&self[..self.locked]);
+        }
+        if let Memory::Custom { backend, .. } = &mut self.memory {
+            backend.free();
+        }
+        // Runs while unwinding from a panic as well, so the count never includes dropped buffers
+        HELD_BYTES.fetch_sub(self.held, Ordering::Relaxed);
+    }
+}
+
+/// Gets `bytes` of zeroed memory from the global allocator without touching it.
+pub(crate) fn zeroed_vec(bytes: usize) -> Result<Vec<u8>, WeightError> {
+    if bytes == 0 {
+        return Ok(Vec::new());
+    }
+    let layout = Layout::array::<u8>(bytes).map_err(|_| WeightError::AllocationFailed(bytes))?;
+    // SAFETY: the layout has a non-zero size.
+    let ptr = unsafe { alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(WeightError::AllocationFailed(bytes));
+    }
+    // SAFETY: the pointer was allocated by the global allocator with the layout of `bytes`
+    // bytes, which are all initialized to zero.
+    Ok(unsafe { Vec::from_raw_parts(ptr, bytes, bytes) })
+}
+
+/// Bytes held by all live buffers of the process.
+pub(crate) fn held_bytes() -> usize {
+    HELD_BYTES.load(Ordering::Relaxed)
+}
+
+/// Overwrites `data` with zeros using volatile writes, which the compiler can't optimize away
+/// even though the memory is about to be freed.
+fn zero(data: &mut [u8]) {
+    // SAFETY: u64 has no invalid bit patterns, so viewing aligned bytes as words is sound.
+    let (head, words, tail) = unsafe { data.align_to_mut::<u64>() };
+    for byte in head.iter_mut().chain(tail) {
+        // SAFETY: `byte` is a valid, exclusive reference.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    // Whole words are written for speed, since this may have to clear gigabytes
+    for word in words {
+        // SAFETY: `word` is a valid, exclusive and aligned reference.
+        unsafe { std::ptr::write_volatile(word, 0) };
+    }
+}
+
+/// Memory mapping that is unmapped when dropped, either anonymous and private or of a shared
+/// memory segment.
+#[cfg(unix)]
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    mapped_len: usize,
+    page_size: usize,
+    /// Length of the inaccessible guard page right after the mapped length, 0 without one.
+    guard_len: usize,
+    segment: Option<Segment>,
+}
+
+/// POSIX shared memory segment, removed when dropped. Other processes that have it mapped
+/// keep their mapping.
+#[cfg(unix)]
+struct Segment {
+    name: CString,
+    /// Kept open to resize the segment when the mapping is truncated.
+    fd: OwnedFd,
+}
+
+#[cfg(unix)]
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: the name is a valid C string.
+        unsafe {
+            libc::shm_unlink(self.name.as_ptr());
+        }
+    }
+}
+
+// SAFETY: the mapping is exclusively owned, like the memory of a Vec.
+#[cfg(unix)]
+unsafe impl Send for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    /// Maps `bytes` of anonymous private memory with mmap(2) and the additional `flags`.
+    pub(crate) fn private(bytes: usize, flags: MmapFlags) -> Result<Mapping, WeightError> {
+        let page_size = crate::system::page_size();
+        let mapped_len = bytes.checked_next_multiple_of(page_size).ok_or(WeightError::Overflow)?;
+        let guard_len = if flags.guard_page { page_size } else { 0 };
+        Mapping::anonymous(bytes, mapped_len, guard_len, page_size, map_flags(flags))
+            .map_err(|err| WeightError::Unsupported(format!("Failed to map {} bytes: {}", mapped_len + guard_len, err)))
+    }
+
+    /// Maps `mapped_len` bytes of anonymous memory in pages of `page_size` with the additional
+    /// `flags`, of which the first `len` are usable, followed by `guard_len` inaccessible bytes.
+    fn anonymous(
+        len: usize,
+        mapped_len: usize,
+        guard_len: usize,
+        page_size: usize,
+        flags: libc::c_int,
+    ) -> Result<Mapping, std::io::Error> {
+        if mapped_len == 0 {
+            return Ok(Mapping {
+                ptr: std::ptr::NonNull::dangling().as_ptr(),
+                len: 0,
+                mapped_len: 0,
+                page_size,
+                guard_len: 0,
+                segment: None,
+            });
+        }
+        // SAFETY: an anonymous mapping at an address picked by the kernel doesn't alias
+        // any existing memory.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_len + guard_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: the guard lies within the mapping just created.
+        if guard_len > 0 && unsafe { libc::mprotect(ptr.cast::<u8>().add(mapped_len).cast(), guard_len, libc::PROT_NONE) } != 0 {
+            let err = std::io::Error::last_os_error();
+            // SAFETY: the mapping was just created and nothing refers to it.
+            unsafe {
+                libc::munmap(ptr, mapped_len + guard_len);
+            }
+            return Err(err);
+        }
+        Ok(Mapping {
+            ptr: ptr.cast(),
+            len,
+            mapped_len,
+            page_size,
+            guard_len,
+            segment: None,
+        })
+    }
+
+    /// Creates the shared memory segment `name` with `mapped_len` bytes and maps it, of which
+    /// the first `len` bytes are usable. Fails if a segment of that name exists already.
+    fn shared(len: usize, mapped_len: usize, page_size: usize, name: &str) -> Result<Mapping, std::io::Error> {
+        let name = CString::new(name).map_err(std::io::Error::other)?;
+        // SAFETY: the name is a valid C string and O_EXCL makes sure the segment is a new one.
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o600 as libc::c_uint) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just opened and nothing else owns it. From here on the
+        // segment is unlinked again if anything fails.
+        let segment = Segment {
+            name,
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let size = libc::off_t::try_from(mapped_len).map_err(std::io::Error::other)?;
+        // SAFETY: ftruncate only resizes the segment behind the open descriptor.
+        if unsafe { libc::ftruncate(segment.fd.as_raw_fd(), size) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let ptr = if mapped_len == 0 {
+            std::ptr::NonNull::dangling().as_ptr()
+        } else {
+            // SAFETY: a shared mapping of the whole new segment at an address picked by the kernel
+            // doesn't alias any existing memory of this process.
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    mapped_len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    segment.fd.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
+            ptr.cast()
+        };
+        Ok(Mapping {
+            ptr,
+            len,
+            mapped_len,
+            page_size,
+            guard_len: 0,
+            segment: Some(segment),
+        })
+    }
+
+    /// Shrinks the usable length to `len` and unmaps the whole pages after it.
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+        let mapped_len = self.len.next_multiple_of(self.page_size);
+        if mapped_len < self.mapped_len {
+            // The guard moves to the new end and the pages after it, including the old guard, go
+            let guard_len = if mapped_len == 0 { 0 } else { self.guard_len };
+            // SAFETY: the ranges are page aligned and lie within the mapping and its guard, past
+            // the usable length.
+            unsafe {
+                if guard_len > 0 {
+                    libc::mprotect(self.ptr.add(mapped_len).cast(), guard_len, libc::PROT_NONE);
+                }
+                libc::munmap(
+                    self.ptr.add(mapped_len + guard_len).cast(),
+                    self.mapped_len + self.guard_len - mapped_len - guard_len,
+                );
+            }
+            self.mapped_len = mapped_len;
+            self.guard_len = guard_len;
+            // The pages of a segment outlive the mapping, so they're only freed by shrinking it
+            if let Some(segment) = &self.segment {
+                // SAFETY: ftruncate only resizes the segment behind the open descriptor.
+                unsafe {
+                    libc::ftruncate(segment.fd.as_raw_fd(), mapped_len as libc::off_t);
+                }
+            }
+        }
+    }
+
+    fn as_uninit_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        // SAFETY: the mapping is valid for `len` bytes and MaybeUninit<u8> has the layout of u8.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.cast(), self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is valid for `len` bytes and zero initialized by the kernel.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in deref, and the mapping is exclusively owned.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.mapped_len > 0 {
+            // SAFETY: the pointer and length describe a mapping created by `anonymous` or
+            // `shared` and its guard.
+            unsafe {
+                libc::munmap(self.ptr.cast(), self.mapped_len + self.guard_len);
+            }
+        }
+    }
+}
+
+/// Address space reserved with `VirtualAlloc`, of which the pages up to the usable length are
+/// committed, released with `VirtualFree` when dropped.
+#[cfg(windows)]
+pub(crate) struct VirtualMemory {
+    ptr: *mut u8,
+    /// Usable bytes from the start, all of them committed.
+    len: usize,
+    /// Bytes of address space reserved, a multiple of the page size.
+    reserved_len: usize,
+    /// Bytes committed from the start, a multiple of the page size.
+    committed: usize,
+    /// Size of the pages the memory is committed in, the large page size with large pages.
+    page_size: usize,
+    large_pages: bool,
+}
+
+// SAFETY: the reserved memory is exclusively owned, like the memory of a Vec.
+#[cfg(windows)]
+unsafe impl Send for VirtualMemory {}
+
+#[cfg(windows)]
+impl VirtualMemory {
+    /// Reserves `bytes` and, unless the commit is deferred, commits all of them, usable right
+    /// away. A deferred commit starts out empty until it's extended.
+    fn new(bytes: usize, flags: VirtualAllocFlags) -> Result<VirtualMemory, WeightError> {
+        use windows_sys::Win32::System::Memory::{
+            GetLargePageMinimum, MEM_COMMIT, MEM_LARGE_PAGES, MEM_RESERVE, PAGE_READWRITE, VirtualAlloc,
+        };
+
+        let page_size = if flags.large_pages {
+            // SAFETY: GetLargePageMinimum has no preconditions.
+            match unsafe { GetLargePageMinimum() } {
+                0 => return Err(WeightError::Unsupported("Large pages aren't supported on this system".to_string())),
+                size => size,
+            }
+        } else {
+            crate::system::page_size()
+        };
+        let reserved_len = bytes.checked_next_multiple_of(page_size).ok_or(WeightError::Overflow)?;
+        let mut memory = VirtualMemory {
+            ptr: std::ptr::NonNull::dangling().as_ptr(),
+            len: 0,
+            reserved_len: 0,
+            committed: 0,
+            page_size,
+            large_pages: flags.large_pages,
+        };
+        if reserved_len == 0 {
+            return Ok(memory);
+        }
+        let allocation_type = match (flags.defer_commit, flags.large_pages) {
+            (true, _) => MEM_RESERVE,
+            (false, false) => MEM_RESERVE | MEM_COMMIT,
+            // Large pages can only be reserved and committed at once
+            (false, true) => MEM_RESERVE | MEM_COMMIT | MEM_LARGE_PAGES,
+        };
+        // SAFETY: memory at an address picked by the system doesn't alias any existing memory.
+        let ptr = unsafe { VirtualAlloc(std::ptr::null(), reserved_len, allocation_type, PAGE_READWRITE) };
+        if ptr.is_null() {
+            let err = std::io::Error::last_os_error();
+            if flags.large_pages {
+                return Err(WeightError::Unsupported(format!(
+                    "Failed to allocate {} bytes of large pages: {}. The process needs the \"Lock pages in memory\" privilege",
+                    reserved_len, err
+                )));
+            }
+            debug!("VirtualAlloc of {} bytes failed: {}", reserved_len, err);
+            return Err(WeightError::AllocationFailed(bytes));
+        }
+        memory.ptr = ptr.cast();
+        memory.reserved_len = reserved_len;
+        if !flags.defer_commit {
+            memory.len = bytes;
+            memory.committed = reserved_len;
+        }
+        Ok(memory)
+    }
+
+    /// Commits the pages up to `len` and makes them usable, zeroing what was used before.
+    fn extend_to(&mut self, len: usize) -> Result<(), WeightError> {
+        use windows_sys::Win32::System::Memory::{MEM_COMMIT, PAGE_READWRITE, VirtualAlloc};
+
+        if len <= self.len {
+            return Ok(());
+        }
+        if len > self.reserved_len {
+            return Err(WeightError::AllocationFailed(len));
+        }
+        let committed = len.next_multiple_of(self.page_size).min(self.reserved_len);
+        if committed > self.committed {
+            // SAFETY: the range is page aligned and lies within the reserved address space.
+            let ptr = unsafe {
+                VirtualAlloc(self.ptr.add(self.committed).cast(), committed - self.committed, MEM_COMMIT, PAGE_READWRITE)
+            };
+            if ptr.is_null() {
+                debug!("Committing {} bytes failed: {}", committed - self.committed, std::io::Error::last_os_error());
+                return Err(WeightError::AllocationFailed(len));
+            }
+        }
+        // Freshly committed pages are zero, but the rest of the last page kept may not be
+        let old_committed = self.committed;
+        let start = self.len;
+        self.committed = self.committed.max(committed);
+        self.len = len;
+        zero(&mut self[start..len.min(old_committed)]);
+        Ok(())
+    }
+
+    /// Shrinks the usable length to `len` and decommits the whole pages after it. Large pages
+    /// stay committed until the memory is released.
+    fn truncate(&mut self, len: usize) {
+        use windows_sys::Win32::System::Memory::{MEM_DECOMMIT, VirtualFree};
+
+        self.len = self.len.min(len);
+        let committed = self.len.next_multiple_of(self.page_size);
+        if committed < self.committed && !self.large_pages {
+            // SAFETY: the range is page aligned and lies within the committed memory, past the
+            // usable length.
+            unsafe {
+                VirtualFree(self.ptr.add(committed).cast(), self.committed - committed, MEM_DECOMMIT);
+            }
+            self.committed = committed;
+        }
+    }
+
+    fn as_uninit_mut(&mut self) -> &mut [MaybeUninit<u8>] {
+        // SAFETY: the memory is committed for `len` bytes and MaybeUninit<u8> has the layout of u8.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.cast(), self.len) }
+    }
+}
+
+#[cfg(windows)]
+impl Deref for VirtualMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the memory is committed for `len` bytes, which the system zeroes when
+        // committing them.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+#[cfg(windows)]
+impl DerefMut for VirtualMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in deref, and the memory is exclusively owned.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for VirtualMemory {
+    fn drop(&mut self) {
+        use windows_sys::Win32::System::Memory::{MEM_RELEASE, VirtualFree};
+
+        if self.reserved_len > 0 {
+            // SAFETY: the pointer is the base of the reservation made by `new`, which releases
+            // all of it including its committed pages.
+            unsafe {
+                VirtualFree(self.ptr.cast(), 0, MEM_RELEASE);
+            }
+        }
+    }
+}
+
+/// The mmap(2) flags that set up a mapping as described by `flags`.
+#[cfg(target_os = "linux")]
+fn map_flags(flags: MmapFlags) -> libc::c_int {
+    let mut bits = 0;
+    if flags.populate {
+        bits |= libc::MAP_POPULATE;
+    }
+    if flags.locked {
+        bits |= libc::MAP_LOCKED;
+    }
+    bits
+}
+
+/// Populating and locking are Linux extensions, which the config rejects on other platforms.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn map_flags(_flags: MmapFlags) -> libc::c_int {
+    0
+}
+
+/// Size of the default huge page in bytes, read from /proc/meminfo.
+#[cfg(target_os = "linux")]
+fn huge_page_size() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("Hugepagesize:"))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    kilobytes.checked_mul(1024)
+}
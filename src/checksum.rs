@@ -0,0 +1,65 @@
+//! Checksums of memory contents, to tell whether an allocation still holds what it did when an
+//! earlier checksum was taken, see [`Allocation::checksum`](crate::Allocation::checksum).
+
+/// Reflected polynomial of CRC-32 as used by zlib, gzip and PNG.
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// Lookup tables for processing 8 bytes at a time, table `n` holds the CRC of each byte value
+/// followed by `n` zero bytes.
+static CRC32_TABLES: [[u32; 256]; 8] = crc32_tables();
+
+const fn crc32_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC32_POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        tables[0][byte] = crc;
+        byte += 1;
+    }
+    let mut table = 1;
+    while table < 8 {
+        let mut byte = 0;
+        while byte < 256 {
+            let previous = tables[table - 1][byte];
+            tables[table][byte] = (previous >> 8) ^ tables[0][(previous & 0xFF) as usize];
+            byte += 1;
+        }
+        table += 1;
+    }
+    tables
+}
+
+/// CRC-32 of `data` with the polynomial used by zlib, gzip and PNG (CRC-32/ISO-HDLC), so it
+/// can be compared with the checksum of a dump computed by other tools, e.g. `crc32` or
+/// Python's `zlib.crc32`. It detects every burst error of up to 32 bits, which covers flipped
+/// bits, but isn't meant to withstand deliberate tampering.
+///
+/// ```
+/// assert_eq!(weight::checksum::crc32(b"123456789"), 0xCBF4_3926);
+/// assert_eq!(weight::checksum::crc32(&[]), 0);
+/// ```
+pub fn crc32(data: &[u8]) -> u32 {
+    let tables = &CRC32_TABLES;
+    let mut crc = !0u32;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        let low = crc ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        crc = tables[7][(low & 0xFF) as usize]
+            ^ tables[6][((low >> 8) & 0xFF) as usize]
+            ^ tables[5][((low >> 16) & 0xFF) as usize]
+            ^ tables[4][(low >> 24) as usize]
+            ^ tables[3][word[4] as usize]
+            ^ tables[2][word[5] as usize]
+            ^ tables[1][word[6] as usize]
+            ^ tables[0][word[7] as usize];
+    }
+    for &byte in words.remainder() {
+        crc = (crc >> 8) ^ tables[0][((crc ^ u32::from(byte)) & 0xFF) as usize];
+    }
+    !crc
+}
@@ -0,0 +1,2023 @@
+use std::fmt;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::error::WeightError;
+use crate::alloc::{self, Stats, DEFAULT_INTERVAL};
+use crate::parse::{self, LARGE_ALLOCATION_THRESHOLD};
+use crate::affinity;
+use crate::backend::MemoryBackend;
+use crate::buffer::Buffer;
+use crate::numa;
+use crate::system;
+
+/// Memory pressure in percent at which an [`AdaptiveInterval`] reaches its maximum, measured as
+/// the share of the last 10 seconds in which tasks stalled waiting for memory.
+pub const FULL_MEMORY_PRESSURE: f64 = 10.0;
+
+/// Bounds for adapting the churn interval to the memory pressure Linux reports through PSI,
+/// see [`system::memory_pressure`]. Without pressure the workers sleep for `min` between
+/// passes to keep the pages warm, and the sleep grows linearly towards `max` as the pressure
+/// rises to [`FULL_MEMORY_PRESSURE`], so the churn backs off instead of making thrashing
+/// worse. The pressure is read again before every sleep. While it can't be read, e.g. because
+/// the kernel was built without PSI, the configured interval is used, kept within the bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveInterval {
+    /// Interval while there is no memory pressure.
+    pub min: Duration,
+    /// Interval at or above [`FULL_MEMORY_PRESSURE`].
+    pub max: Duration,
+}
+
+impl AdaptiveInterval {
+    /// The interval for `pressure` in percent, `interval` within the bounds if it's unknown.
+    pub(crate) fn interval(&self, pressure: Option<f64>, interval: Duration) -> Duration {
+        match pressure {
+            Some(pressure) => {
+                let share = (pressure / FULL_MEMORY_PRESSURE).clamp(0.0, 1.0);
+                self.min + (self.max - self.min).mul_f64(share)
+            }
+            None => interval.clamp(self.min, self.max),
+        }
+    }
+}
+
+/// Grows an allocation gradually in a number of steps instead of all at once,
+/// so the system can be watched responding as memory usage climbs.
+pub struct Ramp {
+    /// Number of equally sized steps to allocate the memory in.
+    pub steps: usize,
+    /// Time to wait after each step before allocating the next one.
+    pub delay: Duration,
+    /// Called after each step with the number of bytes allocated so far.
+    pub on_step: Option<Box<dyn FnMut(usize) + Send>>,
+}
+
+impl fmt::Debug for Ramp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ramp")
+            .field("steps", &self.steps)
+            .field("delay", &self.delay)
+            .field("on_step", &self.on_step.is_some())
+            .finish()
+    }
+}
+
+/// Periodically checks that an allocation is still resident and touches every page again
+/// if it isn't, for long running pressure tests where the OS is expected to reclaim memory
+/// now and then despite the churn.
+///
+/// The resident part of the allocation is estimated from the growth of the process' resident
+/// set size since allocating, so the watchdog only works on platforms where that can be
+/// determined and is skewed by other memory the process allocates or frees meanwhile.
+///
+/// For a size that leaves memory free, such as `leave:1GB`, the watchdog also shrinks the
+/// allocation whenever the available memory drops below that headroom, e.g. because other
+/// processes allocated more. It never grows the allocation again, since growing may have to
+/// copy the memory and briefly take up twice as much.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchdog {
+    /// Time between two checks.
+    pub interval: Duration,
+    /// Fraction of the allocation that must be resident, e.g. 0.9 to touch the memory again
+    /// once more than 10% of it has been reclaimed.
+    pub threshold: f64,
+}
+
+/// Periodically writes a snapshot of the start of an allocation to a temporary file and
+/// syncs it to disk, to generate real disk I/O alongside the memory churn for whole system
+/// stress tests. The file is deleted when the allocation is released.
+///
+/// Each snapshot is copied out of the allocation first so the churn only pauses for the copy,
+/// which takes `bytes` of extra memory while it's being written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskFlush {
+    /// Time between two snapshots.
+    pub interval: Duration,
+    /// Number of bytes written per snapshot, at most the size of the allocation.
+    pub bytes: usize,
+}
+
+/// Appends a row with the statistics of an allocation to a CSV file every `interval`, for
+/// plotting how its memory and CPU use develop over a test run. The columns are
+/// `unix_time_ms`, `elapsed_ms` since the allocation was made, `allocated_bytes`,
+/// `resident_bytes` of the process (empty where it can't be determined), `cycles_completed`
+/// and `cpu_fraction`, see [`Stats`]. The file is replaced if it exists.
+///
+/// Rows are flushed to the file about once a second and when the allocation is released.
+/// Failing to write stops the trace with a warning, the memory keeps being churned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvTrace {
+    /// File the rows are written to.
+    pub path: PathBuf,
+    /// Time between two rows.
+    pub interval: Duration,
+}
+
+/// Retries obtaining the memory when it fails, e.g. when several instances start at once on a
+/// machine that is short on memory for a moment. Each retry waits twice as long as the one
+/// before, starting at `backoff`. Only getting the memory is retried, an invalid config or a
+/// size above a resource limit fails right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Attempts in total including the first one, at least 1.
+    pub attempts: usize,
+    /// Time to wait before the first retry.
+    pub backoff: Duration,
+}
+
+impl Retry {
+    /// Time to wait after `failed` attempts have failed.
+    pub(crate) fn delay(&self, failed: usize) -> Duration {
+        self.backoff.saturating_mul(1 << (failed - 1).min(16))
+    }
+}
+
+impl Default for Retry {
+    /// A single attempt without retrying.
+    fn default() -> Self {
+        Retry {
+            attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+/// Callback invoked during the initial fill, see [`WeightConfigBuilder::on_progress`].
+pub(crate) struct Progress(pub(crate) Box<dyn FnMut(usize, usize) + Send>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Callback invoked after each churn cycle, see [`WeightConfigBuilder::on_cycle`].
+pub(crate) struct OnCycle(pub(crate) CycleCallback);
+
+type CycleCallback = Box<dyn FnMut(&Stats) -> ControlFlow<()> + Send>;
+
+impl fmt::Debug for OnCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnCycle")
+    }
+}
+
+/// Memory backend of the caller, see [`WeightConfigBuilder::memory_backend`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CustomBackend {
+    /// Name of the type implementing [`MemoryBackend`].
+    pub(crate) name: &'static str,
+    /// Allocates a buffer of the given size with it.
+    pub(crate) reserve: fn(usize) -> Result<Buffer, WeightError>,
+}
+
+/// Callback invoked for large allocations, see [`WeightConfigBuilder::on_warning`].
+struct OnWarning(WarningCallback);
+
+type WarningCallback = Box<dyn Fn(usize, &str) -> ControlFlow<()> + Send>;
+
+impl fmt::Debug for OnWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnWarning")
+    }
+}
+
+/// How the churn workers touch the memory to keep it resident.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum TouchMode {
+    /// Increment and then decrement every byte. This dirties every page, so pages that do
+    /// get swapped out have to be written to swap.
+    #[default]
+    ReadWrite,
+    /// Only read every byte. This keeps the pages in the working set using less CPU and
+    /// memory bandwidth and without creating dirty pages that must be written back to swap.
+    ReadOnly,
+}
+
+/// In which order the churn workers visit the chunks of their range. Every pattern visits
+/// each chunk exactly once per cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum AccessPattern {
+    /// Walk the chunks from start to end. Hardware prefetchers predict this well, which
+    /// hides the real cost of the memory accesses.
+    #[default]
+    Sequential,
+    /// Walk the chunks from end to start, like a scan running backwards. Most prefetchers
+    /// detect descending streams as well, but with a different warm-up than ascending ones.
+    Reverse,
+    /// Visit every `step`th chunk, then start over one chunk further until all have been
+    /// visited, e.g. chunks 0, 2, 4, ... and then 1, 3, 5, ... for a step of 2. Large steps
+    /// touch a new page on every access, which puts the TLB under pressure. The step must be
+    /// at least 1.
+    Strided(usize),
+    /// Visit the chunks in a different shuffled order every cycle, which defeats prefetchers
+    /// and better simulates real workloads. Seeded with [`WeightConfigBuilder::seed`].
+    Random,
+}
+
+/// How a read-write cycle changes each byte it touches. The second pass of a cycle undoes the
+/// first, so every cycle returns the memory to its fill pattern and
+/// [`Allocation::verify`](crate::Allocation::verify) keeps working.
+///
+/// ```
+/// # use weight::config::ChurnOp;
+/// for op in [ChurnOp::default(), ChurnOp::Add(0x80), ChurnOp::Xor(0xFF), ChurnOp::Xor(0b0101_0101)] {
+///     for byte in 0..=u8::MAX {
+///         assert_ne!(op.apply(byte), byte);
+///         assert_eq!(op.undo(op.apply(byte)), byte);
+///     }
+/// }
+/// assert_eq!(ChurnOp::Xor(0x0F).apply(0xA5), 0xAA);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum ChurnOp {
+    /// Add the value in the first pass and subtract it again in the second, wrapping around.
+    /// The default adds 1.
+    Add(u8),
+    /// XOR with the mask in both passes, which flips the bits set in the mask and then flips
+    /// them back, e.g. to model specific bit flips.
+    Xor(u8),
+}
+
+impl Default for ChurnOp {
+    fn default() -> Self {
+        ChurnOp::Add(1)
+    }
+}
+
+impl ChurnOp {
+    /// The value of `byte` after the first pass of a cycle.
+    pub fn apply(self, byte: u8) -> u8 {
+        match self {
+            ChurnOp::Add(value) => byte.wrapping_add(value),
+            ChurnOp::Xor(mask) => byte ^ mask,
+        }
+    }
+
+    /// The value of `byte` after the second pass of a cycle, which undoes [`apply`](Self::apply).
+    pub fn undo(self, byte: u8) -> u8 {
+        match self {
+            ChurnOp::Add(value) => byte.wrapping_sub(value),
+            ChurnOp::Xor(mask) => byte ^ mask,
+        }
+    }
+}
+
+/// Which bytes of the memory the churn workers touch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Stride {
+    /// Touch every byte. This is bound by memory bandwidth.
+    #[default]
+    Byte,
+    /// Touch one byte per cache line, see [`cache_line_size`](system::cache_line_size). Every cache line is
+    /// still loaded, so the allocation stays resident, but far fewer bytes are processed, which
+    /// scales better to huge regions. A middle ground between touching every byte and
+    /// touching only the pages once.
+    CacheLine,
+}
+
+impl Stride {
+    /// Distance in bytes between two touched bytes.
+    pub(crate) fn bytes(self) -> usize {
+        match self {
+            Stride::Byte => 1,
+            Stride::CacheLine => system::cache_line_size(),
+        }
+    }
+}
+
+/// What the memory is filled with before it's churned. Verification checks that every byte
+/// still holds its fill value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum FillPattern {
+    /// Every byte is `0x00`.
+    Zeros,
+    /// Every byte is `0xFF`.
+    Ones,
+    /// Each byte holds its offset modulo 256.
+    #[default]
+    Index,
+    /// Bytes alternate between `0xAA` and `0x55`, flipping every bit between neighbours.
+    Alternating,
+    /// Pseudo-random bytes generated from the seed, so they can be checked again later.
+    Random(u64),
+    /// Each byte has a single bit set, moving up one bit per byte and starting over at bit 0
+    /// every 8 bytes, which catches bits stuck at zero or coupled to their neighbours.
+    WalkingOnes,
+    /// Each 8-byte word holds its own offset in the allocation, little endian, so memory that
+    /// answers for the wrong address shows up as a mismatch.
+    Address,
+}
+
+/// Where the memory of an allocation comes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Backend {
+    /// A `Vec` from the global allocator, which works everywhere but leaves how the memory
+    /// is obtained up to the allocator.
+    #[default]
+    Heap,
+    /// An anonymous private mapping created with `mmap(2)`, only supported on Unix. Gives
+    /// direct control over how the memory is mapped and is unmapped when the allocation
+    /// is released.
+    Mmap(MmapFlags),
+    /// A named POSIX shared memory segment created with `shm_open(3)` and mapped shared, only
+    /// supported on Unix. Other processes can map the segment by its
+    /// [`shared_memory_name`](crate::Allocation::shared_memory_name), e.g. to verify the memory
+    /// from outside. The segment is removed when the allocation is released.
+    Shared,
+    /// Memory reserved and committed with `VirtualAlloc`, only supported on Windows. Like
+    /// [`Mmap`](Backend::Mmap) on Unix it gives direct control over how the memory is
+    /// obtained, here whether it's committed up front or as it's filled and whether it's
+    /// backed by large pages. It's released with `VirtualFree` along with the allocation.
+    VirtualAlloc(VirtualAllocFlags),
+    /// Picked when the memory is reserved, from the size and what the platform offers:
+    ///
+    /// | Platform | Size                                                 | Backend                       |
+    /// |----------|------------------------------------------------------|-------------------------------|
+    /// | Linux    | at least [`AUTO_HUGE_PAGES_THRESHOLD`], and that many huge pages are [free](crate::system::free_huge_pages) | huge pages |
+    /// | Unix     | at least [`AUTO_MMAP_THRESHOLD`]                     | [`Mmap`](Backend::Mmap)       |
+    /// | any      | smaller, or on Windows                               | [`Heap`](Backend::Heap)       |
+    ///
+    /// Small allocations come from the heap, where the allocator can reuse memory it has.
+    /// Large ones are mapped directly so they go back to the OS as soon as they're released,
+    /// and on Windows the heap already gets them straight from `VirtualAlloc`. Huge pages are
+    /// never picked for [lazy](WeightConfigBuilder::lazy) allocations. If the picked backend
+    /// fails to reserve the memory, the next one down the table is tried, down to the heap,
+    /// and the one that was used is logged.
+    ///
+    /// [`AUTO_HUGE_PAGES_THRESHOLD`]: crate::alloc::AUTO_HUGE_PAGES_THRESHOLD
+    /// [`AUTO_MMAP_THRESHOLD`]: crate::alloc::AUTO_MMAP_THRESHOLD
+    ///
+    /// Mapped memory can't grow, so allocations that will be grown should stay on the heap.
+    Auto,
+}
+
+/// Flags for [`Backend::Mmap`]. Populating and locking are Linux extensions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct MmapFlags {
+    /// Fault all pages in while mapping them (`MAP_POPULATE`), so the memory is resident
+    /// before the first fill.
+    pub populate: bool,
+    /// Lock the pages in physical memory (`MAP_LOCKED`). Unlike
+    /// [`WeightConfigBuilder::lock`] the memory keeps being churned, and failing to lock
+    /// isn't reported by the kernel.
+    pub locked: bool,
+    /// Map one more page after the memory and make it inaccessible (`PROT_NONE`), so code
+    /// writing past the end of the allocation crashes with a segmentation fault instead of
+    /// silently corrupting whatever comes next. The mapping is a page larger than the
+    /// allocation, the guard isn't churned and doesn't count towards its size. When the size
+    /// isn't a multiple of the page size, the rest of the last page stays accessible before
+    /// the guard. Can't be combined with huge pages.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// # if cfg!(unix) {
+    /// use weight::config::{Backend, MmapFlags};
+    ///
+    /// let flags = MmapFlags { guard_page: true, ..MmapFlags::default() };
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MiB").backend(Backend::Mmap(flags)).build()?)?;
+    /// assert_eq!(allocation.bytes(), 1024 * 1024);
+    /// assert!(allocation.verify().is_ok());
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub guard_page: bool,
+}
+
+/// Flags for [`Backend::VirtualAlloc`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct VirtualAllocFlags {
+    /// Only reserve the address space up front (`MEM_RESERVE`) and commit the pages as the
+    /// fill reaches them, so the commit charge of the system grows with the fill, e.g. step by
+    /// step with a [`Ramp`], instead of all of it being charged at once. Without it the whole
+    /// allocation is committed by `VirtualAlloc` (`MEM_COMMIT`) before it's filled. Shrinking
+    /// decommits the pages that are no longer used either way. Can't be combined with large
+    /// pages or [lazy](WeightConfigBuilder::lazy) allocation, which isn't filled.
+    pub defer_commit: bool,
+    /// Back the memory with large pages (`MEM_LARGE_PAGES`), usually 2MB, which are always
+    /// resident and cut TLB misses. The size is rounded up to whole large pages, and the
+    /// process needs the "Lock pages in memory" privilege (`SeLockMemoryPrivilege`).
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// use weight::config::{Backend, VirtualAllocFlags};
+    ///
+    /// let flags = VirtualAllocFlags { large_pages: true, ..VirtualAllocFlags::default() };
+    /// let config = weight::WeightConfig::new("4MiB").backend(Backend::VirtualAlloc(flags)).build();
+    /// if cfg!(windows) {
+    ///     // Fails without the privilege to lock pages in memory
+    ///     if let Ok(allocation) = weight::allocate(config?) {
+    ///         assert!(allocation.verify().is_ok());
+    ///     }
+    /// } else {
+    ///     assert!(matches!(config, Err(weight::WeightError::Unsupported(_))));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub large_pages: bool,
+}
+
+/// Something about a config that is allowed but probably not intended, returned by
+/// [`WeightConfig::warnings`] so the caller can decide whether to go ahead, log it or abort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// The size is above the large allocation threshold, see
+    /// [`WeightConfigBuilder::large_allocation_threshold`].
+    LargeAllocation {
+        /// The requested size in bytes.
+        bytes: usize,
+        /// The threshold it exceeds in bytes.
+        threshold: u64,
+    },
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigWarning::LargeAllocation { bytes, threshold } => write!(
+                f,
+                "Allocating {} bytes is above the large allocation threshold of {} bytes and may cause system instability",
+                bytes, threshold
+            ),
+        }
+    }
+}
+
+/// Validated settings for an allocation, created with [`WeightConfig::new`] and passed to
+/// [`allocate`](crate::alloc::allocate).
+///
+/// ```
+/// # use std::time::Duration;
+/// # use weight::WeightConfig;
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let config = WeightConfig::new("2GB")
+///     .interval(Duration::from_millis(50))
+///     .workers(4)
+///     .build()?;
+/// assert_eq!(config.bytes(), 2_000_000_000);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With the `serde` feature a config can also be loaded from a file, with the memory size
+/// written as a human readable string such as `"2GB"`. Omitted settings use their defaults,
+/// durations are in milliseconds and ramp callbacks aren't serialized. Deserializing
+/// validates the settings like [`WeightConfigBuilder::build`].
+///
+/// ```
+/// # use std::time::Duration;
+/// # use weight::config::FillPattern;
+/// # use weight::WeightConfig;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # #[cfg(feature = "serde")] {
+/// let config: WeightConfig = toml::from_str(
+///     r#"
+///     memory = "2GB"
+///     workers = 4
+///     interval_ms = 50
+///     fill_pattern = "alternating"
+///     lazy = true
+///     "#,
+/// )?;
+/// let built = WeightConfig::new("2GB")
+///     .workers(4)
+///     .interval(Duration::from_millis(50))
+///     .fill_pattern(FillPattern::Alternating)
+///     .lazy(true)
+///     .build()?;
+/// assert_eq!(config.to_json(), built.to_json());
+///
+/// let written = toml::to_string(&built)?;
+/// assert!(written.contains(r#"memory = "2GB""#));
+/// let read: WeightConfig = toml::from_str(&written)?;
+/// assert_eq!(read.to_json(), built.to_json());
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct WeightConfig {
+    pub(crate) memory: String,
+    pub(crate) bytes: usize,
+    pub(crate) workers: usize,
+    pub(crate) interval: Duration,
+    pub(crate) chunk_size: usize,
+    pub(crate) touch_mode: TouchMode,
+    pub(crate) fill_pattern: FillPattern,
+    pub(crate) access_pattern: AccessPattern,
+    pub(crate) churn_op: ChurnOp,
+    pub(crate) stride: Stride,
+    pub(crate) seed: Option<u64>,
+    pub(crate) cpu_budget: Option<f64>,
+    pub(crate) adaptive_interval: Option<AdaptiveInterval>,
+    pub(crate) full_touch_interval: Option<Duration>,
+    pub(crate) align_to_clock: bool,
+    pub(crate) cycle_history: usize,
+    pub(crate) warmup_delay: Duration,
+    pub(crate) lazy: bool,
+    pub(crate) max_runtime: Option<Duration>,
+    pub(crate) best_effort: bool,
+    pub(crate) supervise: bool,
+    pub(crate) lock: bool,
+    pub(crate) raise_lock_limit: bool,
+    pub(crate) huge_pages: bool,
+    pub(crate) disable_thp: bool,
+    pub(crate) backend: Backend,
+    pub(crate) memory_backend: Option<CustomBackend>,
+    pub(crate) zero_on_drop: bool,
+    pub(crate) ramp: Option<Ramp>,
+    /// Bytes per second the initial fill is throttled to.
+    pub(crate) fill_rate: Option<u64>,
+    pub(crate) numa_nodes: Option<Vec<usize>>,
+    pub(crate) cpus: Option<Vec<usize>>,
+    pub(crate) watchdog: Option<Watchdog>,
+    /// Bytes to leave free of the available memory for a `leave:` size.
+    pub(crate) headroom: Option<usize>,
+    pub(crate) disk_flush: Option<DiskFlush>,
+    pub(crate) csv_trace: Option<CsvTrace>,
+    pub(crate) retry: Retry,
+    pub(crate) safety_margin: usize,
+    // Already applied by the builder, only kept to be serialized and reported
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) large_allocation_threshold: u64,
+    pub(crate) strict: bool,
+    pub(crate) warnings: Vec<ConfigWarning>,
+    pub(crate) progress: Option<Progress>,
+    pub(crate) on_cycle: Option<OnCycle>,
+}
+
+impl WeightConfig {
+    /// Starts building a config for allocating `memory`, a size in the format accepted by
+    /// [`parse_memory_string`](crate::parse::parse_memory_string). All other settings
+    /// start out at their defaults.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(memory: impl Into<String>) -> WeightConfigBuilder {
+        WeightConfigBuilder {
+            memory: memory.into(),
+            workers: alloc::default_workers(),
+            interval: DEFAULT_INTERVAL,
+            chunk_size: alloc::default_chunk_size(),
+            touch_mode: TouchMode::default(),
+            fill_pattern: FillPattern::default(),
+            access_pattern: AccessPattern::default(),
+            churn_op: ChurnOp::default(),
+            stride: Stride::default(),
+            seed: None,
+            cpu_budget: None,
+            adaptive_interval: None,
+            full_touch_interval: None,
+            align_to_clock: false,
+            cycle_history: alloc::DEFAULT_CYCLE_HISTORY,
+            warmup_delay: Duration::ZERO,
+            lazy: false,
+            max_runtime: None,
+            best_effort: false,
+            supervise: false,
+            lock: false,
+            raise_lock_limit: false,
+            huge_pages: false,
+            disable_thp: false,
+            backend: Backend::default(),
+            memory_backend: None,
+            zero_on_drop: false,
+            ramp: None,
+            fill_rate: None,
+            numa_nodes: None,
+            cpus: None,
+            watchdog: None,
+            disk_flush: None,
+            csv_trace: None,
+            retry: Retry::default(),
+            safety_margin: system::DEFAULT_SAFETY_MARGIN,
+            max_bytes: None,
+            large_allocation_threshold: LARGE_ALLOCATION_THRESHOLD,
+            strict: false,
+            progress: None,
+            on_cycle: None,
+            on_warning: None,
+        }
+    }
+
+    /// The memory size as it was requested.
+    pub fn memory(&self) -> &str {
+        &self.memory
+    }
+
+    /// The requested memory size in bytes.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Settings that are allowed but probably not intended, e.g. a very large size. Unless an
+    /// [`on_warning`](WeightConfigBuilder::on_warning) hook handles them, building the config
+    /// has logged them as warnings already.
+    ///
+    /// ```
+    /// # use weight::config::ConfigWarning;
+    /// # use weight::WeightConfig;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = WeightConfig::new("2GB").large_allocation_threshold(1_000_000_000).build()?;
+    /// assert_eq!(
+    ///     config.warnings(),
+    ///     [ConfigWarning::LargeAllocation { bytes: 2_000_000_000, threshold: 1_000_000_000 }]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn warnings(&self) -> &[ConfigWarning] {
+        &self.warnings
+    }
+
+    /// The settings the config resolved to as a JSON object, e.g. for an audit log of what an
+    /// allocation actually ran with. Unlike the requested `memory`, `bytes` is the size it was
+    /// resolved to, so a percentage or `leave:` size shows how much that was on this machine.
+    /// Durations are in milliseconds, unset options are `null` and enums are named like in
+    /// config files. Callbacks aren't included.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("1MB+1KiB").workers(2).lock(true).build()?;
+    /// let json = config.to_json();
+    /// assert!(json.starts_with(r#"{"memory":"1MB+1KiB","bytes":1001024,"#));
+    /// assert!(json.contains(r#""workers":2,"interval_ms":100,"#));
+    /// assert!(json.contains(r#""lock":true,"#));
+    /// assert!(json.contains(r#""backend":"heap","#));
+    ///
+    /// let json = weight::WeightConfig::new("1MB").max_bytes(2_000_000).strict(true).build()?.to_json();
+    /// assert!(json.ends_with(r#""max_bytes":2000000,"large_allocation_threshold":107374182400,"strict":true}"#));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let millis = |duration: Duration| u64::try_from(duration.as_millis()).unwrap_or(u64::MAX).to_string();
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let list = |values: &Option<Vec<usize>>| {
+            optional(values.as_ref().map(|values| {
+                format!("[{}]", values.iter().map(usize::to_string).collect::<Vec<_>>().join(","))
+            }))
+        };
+        let members = [
+            ("memory", json_string(&self.memory)),
+            ("bytes", self.bytes.to_string()),
+            ("headroom_bytes", optional(self.headroom.map(|headroom| headroom.to_string()))),
+            ("workers", self.workers.to_string()),
+            ("interval_ms", millis(self.interval)),
+            ("chunk_size", self.chunk_size.to_string()),
+            ("touch_mode", json_string(match self.touch_mode {
+                TouchMode::ReadWrite => "read-write",
+                TouchMode::ReadOnly => "read-only",
+            })),
+            ("fill_pattern", match self.fill_pattern {
+                FillPattern::Zeros => json_string("zeros"),
+                FillPattern::Ones => json_string("ones"),
+                FillPattern::Index => json_string("index"),
+                FillPattern::Alternating => json_string("alternating"),
+                FillPattern::Random(seed) => format!("{{\"random\":{}}}", seed),
+                FillPattern::WalkingOnes => json_string("walking-ones"),
+                FillPattern::Address => json_string("address"),
+            }),
+            ("access_pattern", match self.access_pattern {
+                AccessPattern::Sequential => json_string("sequential"),
+                AccessPattern::Reverse => json_string("reverse"),
+                AccessPattern::Strided(step) => format!("{{\"strided\":{}}}", step),
+                AccessPattern::Random => json_string("random"),
+            }),
+            ("churn_op", match self.churn_op {
+                ChurnOp::Add(value) => format!("{{\"add\":{}}}", value),
+                ChurnOp::Xor(value) => format!("{{\"xor\":{}}}", value),
+            }),
+            ("stride", json_string(match self.stride {
+                Stride::Byte => "byte",
+                Stride::CacheLine => "cache-line",
+            })),
+            ("seed", optional(self.seed.map(|seed| seed.to_string()))),
+            ("cpu_budget", optional(self.cpu_budget.map(|budget| budget.to_string()))),
+            ("adaptive_interval", optional(self.adaptive_interval.map(|adaptive| {
+                format!("{{\"min_ms\":{},\"max_ms\":{}}}", millis(adaptive.min), millis(adaptive.max))
+            }))),
+            ("full_touch_interval_ms", optional(self.full_touch_interval.map(millis))),
+            ("align_to_clock", self.align_to_clock.to_string()),
+            ("cycle_history", self.cycle_history.to_string()),
+            ("warmup_delay_ms", millis(self.warmup_delay)),
+            ("lazy", self.lazy.to_string()),
+            ("max_runtime_ms", optional(self.max_runtime.map(millis))),
+            ("best_effort", self.best_effort.to_string()),
+            ("supervise", self.supervise.to_string()),
+            ("lock", self.lock.to_string()),
+            ("raise_lock_limit", self.raise_lock_limit.to_string()),
+            ("huge_pages", self.huge_pages.to_string()),
+            ("disable_thp", self.disable_thp.to_string()),
+            ("backend", match self.backend {
+                Backend::Heap => json_string("heap"),
+                Backend::Mmap(flags) => {
+                    format!(
+                        "{{\"mmap\":{{\"populate\":{},\"locked\":{},\"guard_page\":{}}}}}",
+                        flags.populate, flags.locked, flags.guard_page
+                    )
+                }
+                Backend::Shared => json_string("shared"),
+                Backend::VirtualAlloc(flags) => {
+                    format!(
+                        "{{\"virtual-alloc\":{{\"defer_commit\":{},\"large_pages\":{}}}}}",
+                        flags.defer_commit, flags.large_pages
+                    )
+                }
+                Backend::Auto => json_string("auto"),
+            }),
+            ("memory_backend", optional(self.memory_backend.map(|custom| json_string(custom.name)))),
+            ("zero_on_drop", self.zero_on_drop.to_string()),
+            ("ramp", optional(self.ramp.as_ref().map(|ramp| {
+                format!("{{\"steps\":{},\"delay_ms\":{}}}", ramp.steps, millis(ramp.delay))
+            }))),
+            ("fill_rate", optional(self.fill_rate.map(|rate| rate.to_string()))),
+            ("numa_nodes", list(&self.numa_nodes)),
+            ("cpus", list(&self.cpus)),
+            ("watchdog", optional(self.watchdog.map(|watchdog| {
+                format!("{{\"interval_ms\":{},\"threshold\":{}}}", millis(watchdog.interval), watchdog.threshold)
+            }))),
+            ("disk_flush", optional(self.disk_flush.map(|disk_flush| {
+                format!("{{\"interval_ms\":{},\"bytes\":{}}}", millis(disk_flush.interval), disk_flush.bytes)
+            }))),
+            ("csv_trace", optional(self.csv_trace.as_ref().map(|trace| {
+                format!(
+                    "{{\"path\":{},\"interval_ms\":{}}}",
+                    json_string(&trace.path.to_string_lossy()),
+                    millis(trace.interval)
+                )
+            }))),
+            ("retry", format!(
+                "{{\"attempts\":{},\"backoff_ms\":{}}}",
+                self.retry.attempts,
+                millis(self.retry.backoff)
+            )),
+            ("safety_margin", self.safety_margin.to_string()),
+            ("max_bytes", optional(self.max_bytes.map(|max_bytes| max_bytes.to_string()))),
+            ("large_allocation_threshold", self.large_allocation_threshold.to_string()),
+            ("strict", self.strict.to_string()),
+        ];
+        let members: Vec<String> = members.iter().map(|(key, value)| format!("\"{}\":{}", key, value)).collect();
+        format!("{{{}}}", members.join(","))
+    }
+}
+
+/// `value` as a JSON string literal, with quotes, backslashes and control characters escaped.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Builder for [`WeightConfig`].
+#[derive(Debug)]
+pub struct WeightConfigBuilder {
+    memory: String,
+    workers: usize,
+    interval: Duration,
+    chunk_size: usize,
+    touch_mode: TouchMode,
+    fill_pattern: FillPattern,
+    access_pattern: AccessPattern,
+    churn_op: ChurnOp,
+    stride: Stride,
+    seed: Option<u64>,
+    cpu_budget: Option<f64>,
+    adaptive_interval: Option<AdaptiveInterval>,
+    full_touch_interval: Option<Duration>,
+    align_to_clock: bool,
+    cycle_history: usize,
+    warmup_delay: Duration,
+    lazy: bool,
+    max_runtime: Option<Duration>,
+    best_effort: bool,
+    supervise: bool,
+    lock: bool,
+    raise_lock_limit: bool,
+    huge_pages: bool,
+    disable_thp: bool,
+    backend: Backend,
+    memory_backend: Option<CustomBackend>,
+    zero_on_drop: bool,
+    ramp: Option<Ramp>,
+    fill_rate: Option<u64>,
+    numa_nodes: Option<Vec<usize>>,
+    cpus: Option<Vec<usize>>,
+    watchdog: Option<Watchdog>,
+    disk_flush: Option<DiskFlush>,
+    csv_trace: Option<CsvTrace>,
+    retry: Retry,
+    safety_margin: usize,
+    max_bytes: Option<usize>,
+    large_allocation_threshold: u64,
+    strict: bool,
+    progress: Option<Progress>,
+    on_cycle: Option<OnCycle>,
+    on_warning: Option<OnWarning>,
+}
+
+impl WeightConfigBuilder {
+    /// Number of worker threads that keep the memory occupied. Defaults to one per logical CPU.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// How long the workers sleep between passes over the memory. A shorter interval keeps the
+    /// pages hotter at the cost of CPU, a longer one uses less CPU for memory that is rarely
+    /// under pressure. Zero is allowed and churns the memory continuously, which keeps one CPU
+    /// busy per worker. Defaults to [`DEFAULT_INTERVAL`].
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How many bytes a worker processes at a time. It must be a power of two, the best value
+    /// depends on the CPU's cache hierarchy. Defaults to the page size, see
+    /// [`default_chunk_size`](alloc::default_chunk_size).
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// How the workers touch the memory, see [`TouchMode`]. Defaults to [`TouchMode::ReadWrite`].
+    pub fn touch_mode(mut self, touch_mode: TouchMode) -> Self {
+        self.touch_mode = touch_mode;
+        self
+    }
+
+    /// What the memory is filled with, see [`FillPattern`]. Defaults to [`FillPattern::Index`].
+    pub fn fill_pattern(mut self, fill_pattern: FillPattern) -> Self {
+        self.fill_pattern = fill_pattern;
+        self
+    }
+
+    /// In which order the workers visit the memory, see [`AccessPattern`]. Defaults to
+    /// [`AccessPattern::Sequential`].
+    pub fn access_pattern(mut self, access_pattern: AccessPattern) -> Self {
+        self.access_pattern = access_pattern;
+        self
+    }
+
+    /// How read-write cycles change the bytes, see [`ChurnOp`]. Defaults to adding and then
+    /// subtracting 1. The value or mask must not be 0, which wouldn't change the memory.
+    pub fn churn_op(mut self, churn_op: ChurnOp) -> Self {
+        self.churn_op = churn_op;
+        self
+    }
+
+    /// How many bytes the workers skip between the bytes they touch, see [`Stride`]. Defaults
+    /// to [`Stride::Byte`].
+    pub fn stride(mut self, stride: Stride) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    /// Seed for the shuffled order of [`AccessPattern::Random`], so runs are reproducible.
+    /// Defaults to a random seed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Limit churning to this fraction of one CPU, e.g. 0.1 for 10% of one core, shared by all
+    /// workers. The workers sleep longer than the interval when needed to stay within the
+    /// budget, based on how long their last pass took. Without a budget CPU usage grows with
+    /// the allocation size.
+    pub fn cpu_budget(mut self, cpu_budget: f64) -> Self {
+        self.cpu_budget = Some(cpu_budget);
+        self
+    }
+
+    /// Adapt the interval to the memory pressure of the system within bounds, see
+    /// [`AdaptiveInterval`]. Replaces the [`interval`](Self::interval), which is only used
+    /// while the pressure can't be read. Only supported on Linux.
+    pub fn adaptive_interval(mut self, adaptive_interval: AdaptiveInterval) -> Self {
+        self.adaptive_interval = Some(adaptive_interval);
+        self
+    }
+
+    /// Make a cycle a full read-write pass over every byte whenever this much time has passed
+    /// since the last one, e.g. every 60s, whatever the [`touch_mode`](Self::touch_mode) and
+    /// [`stride`](Self::stride). The lightweight cycles in between keep the average CPU use
+    /// low, while every page is still written at least this often so none of it goes stale
+    /// and gets reclaimed. The [`interval`](Self::interval) between cycles stays the same.
+    pub fn full_touch_interval(mut self, interval: Duration) -> Self {
+        self.full_touch_interval = Some(interval);
+        self
+    }
+
+    /// Start every pass over the memory on a multiple of the [`interval`](Self::interval) of
+    /// wall-clock time since the Unix epoch, e.g. on every 100ms boundary, instead of sleeping
+    /// the interval after each pass. Passes then don't drift by the time they take and stay in
+    /// phase with the clock, so churn activity lines up with samples of other time-series
+    /// tools. A pass that takes longer than the interval skips the ticks it missed and waits
+    /// for the next one. A read-write cycle is two passes, so it takes two ticks.
+    ///
+    /// An [adaptive interval](Self::adaptive_interval) changes the tick length with the
+    /// pressure, and a [CPU budget](Self::cpu_budget) that asks for a longer pause waits for
+    /// the first tick after it. With a zero interval there are no ticks to align to.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("64KB")
+    ///     .interval(Duration::from_millis(10))
+    ///     .align_to_clock(true)
+    ///     .build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// // Each cycle starts on a 10ms boundary of the system clock
+    /// while allocation.stats().cycles_completed < 2 {
+    ///     std::thread::sleep(Duration::from_millis(10));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn align_to_clock(mut self, align_to_clock: bool) -> Self {
+        self.align_to_clock = align_to_clock;
+        self
+    }
+
+    /// How many of the most recent cycle durations are kept for
+    /// [`cycle_percentiles`](crate::alloc::Allocation::cycle_percentiles), which bounds the
+    /// memory that takes to 16 bytes per cycle. Zero turns the history off. Defaults to
+    /// [`DEFAULT_CYCLE_HISTORY`](alloc::DEFAULT_CYCLE_HISTORY).
+    pub fn cycle_history(mut self, cycles: usize) -> Self {
+        self.cycle_history = cycles;
+        self
+    }
+
+    /// Leave the memory alone for this long after it was filled before the workers start
+    /// churning it, e.g. to measure the cost of faulting it in without the churn getting in the
+    /// way. [`Duration::MAX`] waits until
+    /// [`Allocation::start_churn`](alloc::Allocation::start_churn) is called. Defaults to zero,
+    /// which starts churning right away.
+    ///
+    /// The fill touches every page, so the memory starts out resident, but nothing keeps it
+    /// resident until churning starts: under memory pressure the OS can reclaim it during the
+    /// warmup just like while [paused](alloc::Allocation::pause), and the
+    /// [`Watchdog`] and [`DiskFlush`] wait for the warmup to end as well. Has no effect on
+    /// locked memory and deterministic runs.
+    pub fn warmup_delay(mut self, delay: Duration) -> Self {
+        self.warmup_delay = delay;
+        self
+    }
+
+    /// Reserve the memory without touching it, so it reads as zeros but isn't backed by
+    /// physical pages until it's written, e.g. to measure what faulting it in costs with
+    /// [`Allocation::fault_range`](alloc::Allocation::fault_range). The fill pattern is
+    /// [`FillPattern::Zeros`] and churning, which would fault everything in, waits until
+    /// [`Allocation::start_churn`](alloc::Allocation::start_churn) is called unless a
+    /// [`warmup_delay`](WeightConfigBuilder::warmup_delay) is set.
+    ///
+    /// How untouched memory behaves depends on the platform:
+    ///
+    /// - On Linux reading an untouched page maps the kernel's shared zero page, which doesn't
+    ///   add to the resident set, and the first write takes a copy-on-write fault that gives the
+    ///   page its own physical memory. With transparent huge pages a single write can back a
+    ///   whole 2MB page.
+    /// - On macOS pages are zero-filled on demand as well, but reads already back them with
+    ///   memory. Untouched memory the OS compresses doesn't count as resident either.
+    /// - On Windows the heap commits the memory upfront, which counts against the commit
+    ///   limit, but pages only join the working set when first touched, reads included.
+    ///
+    /// The [heap backend](Backend::Heap) gets zeroed memory from the allocator, which for large
+    /// allocations comes straight from the OS untouched, smaller ones may reuse memory that's
+    /// resident already. Can't be combined with locking, huge pages, populated mappings,
+    /// ramping or NUMA nodes, which all fault the memory in while allocating.
+    pub fn lazy(mut self, lazy: bool) -> Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Stop holding the memory once `max_runtime` has elapsed since it was allocated, e.g. to
+    /// hold 4GB for five minutes in a time-boxed CI step, then exit cleanly. Ends
+    /// [`allocate_memory_blocking`](alloc::allocate_memory_blocking) and
+    /// [`Allocation::hold`](alloc::Allocation::hold), which report it as
+    /// [`Termination::MaxRuntime`](alloc::Termination::MaxRuntime) so it can be told apart from
+    /// being stopped early. Allocations that aren't held keep running until they're dropped.
+    pub fn max_runtime(mut self, max_runtime: Duration) -> Self {
+        self.max_runtime = Some(max_runtime);
+        self
+    }
+
+    /// Allocate as much of the requested memory as possible instead of failing when all of it
+    /// can't be reserved, e.g. to fill whatever a container has left. The largest size the
+    /// backend can reserve is searched for, down to a page, and the resource limits of the
+    /// process aren't checked upfront. How much is missing is logged and reported by
+    /// [`Allocation::shortfall`](alloc::Allocation::shortfall), allocating only fails if not
+    /// even a page can be reserved.
+    ///
+    /// Only reserving the memory can fall short. Where the OS overcommits, as Linux does by
+    /// default, reserving can succeed for more than fits in RAM, and filling it can still get
+    /// the process killed.
+    pub fn best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Restart churn workers that panic instead of letting the memory go unrefreshed, for
+    /// unattended long-running deployments. A panicking worker is logged, its range is filled
+    /// with the fill pattern again since the panic may have left it halfway through a cycle,
+    /// and it starts churning again after a short delay. How often that happened is counted
+    /// in [`Stats::worker_restarts`](alloc::Stats::worker_restarts).
+    ///
+    /// Without it a panicking worker ends the churn of the whole allocation, which keeps
+    /// holding its memory but no longer touches it. The tasks of
+    /// [async allocations](crate::asynchronous) aren't supervised.
+    ///
+    /// ```
+    /// # use std::ops::ControlFlow;
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("64KB")
+    ///     .workers(1)
+    ///     .interval(Duration::from_millis(1))
+    ///     .supervise(true)
+    ///     .on_cycle(|stats| {
+    ///         assert_ne!(stats.cycles_completed, 2, "simulated bug");
+    ///         ControlFlow::Continue(())
+    ///     })
+    ///     .build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// while allocation.stats().worker_restarts == 0 {
+    ///     std::thread::yield_now();
+    /// }
+    /// // The restarted worker keeps churning
+    /// let cycles = allocation.stats().cycles_completed;
+    /// while allocation.stats().cycles_completed < cycles + 3 {
+    ///     std::thread::yield_now();
+    /// }
+    /// assert_eq!(allocation.stats().worker_restarts, 1);
+    /// assert!(allocation.verify().is_ok());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn supervise(mut self, supervise: bool) -> Self {
+        self.supervise = supervise;
+        self
+    }
+
+    /// Lock the memory in physical RAM instead of churning it, using `mlock(2)` on Unix and
+    /// `VirtualLock` on Windows. This guarantees it isn't swapped out so no churn threads are
+    /// started.
+    ///
+    /// If all of it would exceed `RLIMIT_MEMLOCK` on Unix or the working set can't be grown
+    /// to fit it on Windows, as much of the start of the memory as the limit allows is locked
+    /// and the whole allocation is churned like an unlocked one to keep the rest resident.
+    /// [`Stats::locked_bytes`](alloc::Stats::locked_bytes) tells how much was locked. Locking
+    /// only fails if not even a single page can be locked.
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// Raise the soft `RLIMIT_MEMLOCK` of the process before locking so the memory fits, as far
+    /// as it's permitted: up to the hard limit without privileges, or beyond it with
+    /// `CAP_SYS_RESOURCE`. The limit stays raised for the rest of the process. Only has an effect
+    /// with [`lock`](WeightConfigBuilder::lock) on Unix.
+    pub fn raise_lock_limit(mut self, raise: bool) -> Self {
+        self.raise_lock_limit = raise;
+        self
+    }
+
+    /// Back the memory with huge pages (2MB on most systems) instead of regular pages, which
+    /// reduces TLB pressure and page fault overhead for large allocations. Only supported on
+    /// Linux and requires huge pages to be reserved in the kernel first, e.g. with
+    /// `sysctl vm.nr_hugepages=1024` to reserve 1024 huge pages.
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Keep transparent huge pages (THP) from backing the memory on Linux with
+    /// `madvise(MADV_NOHUGEPAGE)`, applied before the memory is filled and again when growing
+    /// moves it. With THP enabled the kernel may back the memory with 2MB pages when it's
+    /// faulted in, or collapse its pages into them in the background later, which changes how
+    /// it faults and how much of it is reclaimed mid-test. Disabling them keeps the behavior
+    /// page by page and reproducible, see [`Allocation::thp_bytes`](alloc::Allocation::thp_bytes).
+    /// Other platforms don't have THP, so it does nothing there. Can't be combined with
+    /// [`huge_pages`](Self::huge_pages).
+    pub fn disable_thp(mut self, disable_thp: bool) -> Self {
+        self.disable_thp = disable_thp;
+        self
+    }
+
+    /// Where the memory comes from, see [`Backend`]. Defaults to [`Backend::Heap`]. Huge pages
+    /// are always mapped directly, with the [`MmapFlags`] of [`Backend::Mmap`] if it's set.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Get the memory from the [`MemoryBackend`] `B` instead of one of the built-in
+    /// [backends](Self::backend), e.g. GPU pinned memory or persistent memory, and fill, churn
+    /// and verify it like any other allocation. See [`backend`](crate::backend) for an example.
+    ///
+    /// The memory of a custom backend can't grow, and shrinking it only stops using the rest,
+    /// since the backend can't be asked to give it back. It can't be combined with another
+    /// backend, huge pages or lazy allocation, which depend on how the memory is obtained.
+    pub fn memory_backend<B: MemoryBackend + 'static>(mut self) -> Self {
+        self.memory_backend = Some(CustomBackend {
+            name: std::any::type_name::<B>(),
+            reserve: Buffer::custom::<B>,
+        });
+        self
+    }
+
+    /// Overwrite the memory with zeros before it's released, including the part given back when
+    /// an allocation is [shrunk](crate::alloc::Allocation::shrink), e.g. when it held sensitive
+    /// data during a test. The zeros are written with volatile writes that aren't optimized
+    /// away, but this is only best-effort: growing an allocation may move it and leave the old
+    /// copy to the allocator, and the OS may have swapped pages out before they were cleared.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use weight::backend::{HeapMemory, MemoryBackend};
+    ///
+    /// /// Heap memory that records whether it was all zeros when it was freed.
+    /// struct Checked(HeapMemory);
+    ///
+    /// static FREED_ZEROED: AtomicBool = AtomicBool::new(false);
+    ///
+    /// impl MemoryBackend for Checked {
+    ///     fn allocate(bytes: usize) -> Result<Self, weight::WeightError> {
+    ///         HeapMemory::allocate(bytes).map(Checked)
+    ///     }
+    ///     fn as_slice(&self) -> &[u8] {
+    ///         self.0.as_slice()
+    ///     }
+    ///     fn as_mut_slice(&mut self) -> &mut [u8] {
+    ///         self.0.as_mut_slice()
+    ///     }
+    ///     fn free(&mut self) {
+    ///         FREED_ZEROED.store(self.as_slice().iter().all(|&byte| byte == 0), Ordering::SeqCst);
+    ///     }
+    /// }
+    ///
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let config = weight::WeightConfig::new("1MB").memory_backend::<Checked>();
+    /// drop(weight::allocate(config.zero_on_drop(true).build()?)?);
+    /// assert!(FREED_ZEROED.load(Ordering::SeqCst));
+    ///
+    /// // Otherwise the memory is freed holding its fill pattern
+    /// let config = weight::WeightConfig::new("1MB").memory_backend::<Checked>();
+    /// drop(weight::allocate(config.build()?)?);
+    /// assert!(!FREED_ZEROED.load(Ordering::SeqCst));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn zero_on_drop(mut self, zero_on_drop: bool) -> Self {
+        self.zero_on_drop = zero_on_drop;
+        self
+    }
+
+    /// Allocate the memory gradually, see [`Ramp`]. Without a ramp it's allocated in one go.
+    pub fn ramp(mut self, ramp: Ramp) -> Self {
+        self.ramp = Some(ramp);
+        self
+    }
+
+    /// Throttle the initial fill to this many bytes per second by sleeping whenever it gets
+    /// ahead, e.g. to push more than the physical memory out to swap gradually instead of in
+    /// an I/O storm that leaves the machine unresponsive. Without a rate the memory is filled
+    /// as fast as possible.
+    ///
+    /// With a [`Ramp`] the rate applies while each step is filled, and the delays between
+    /// steps come on top of it: the time spent waiting isn't made up for with a burst
+    /// afterwards. Lazy allocations aren't filled, so they can't have a rate.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let started = std::time::Instant::now();
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MB").fill_rate(10_000_000).build()?)?;
+    /// assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+    /// assert_eq!(allocation.bytes(), 1_000_000);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fill_rate(mut self, bytes_per_second: u64) -> Self {
+        self.fill_rate = Some(bytes_per_second);
+        self
+    }
+
+    /// Split the memory evenly across these NUMA nodes, one range per node, each churned by
+    /// a worker pinned to the CPUs of its node. This replaces the [`workers`](Self::workers)
+    /// setting. Only supported on Linux.
+    pub fn numa_nodes(mut self, nodes: Vec<usize>) -> Self {
+        self.numa_nodes = Some(nodes);
+        self
+    }
+
+    /// Pin the workers to these CPU cores, one worker per core, e.g. to keep them on the same
+    /// socket as the memory they touch. This replaces the [`workers`](Self::workers) setting
+    /// and can't be combined with [`numa_nodes`](Self::numa_nodes). Only supported on Linux
+    /// and Windows, elsewhere the workers run unpinned after a warning.
+    pub fn cpus(mut self, cpus: Vec<usize>) -> Self {
+        self.cpus = Some(cpus);
+        self
+    }
+
+    /// Watch the allocation and touch the memory again if it's reclaimed, see [`Watchdog`].
+    /// Has no effect on locked memory, which can't be reclaimed.
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Write snapshots of the memory to disk, see [`DiskFlush`]. Has no effect on locked memory.
+    pub fn disk_flush(mut self, disk_flush: DiskFlush) -> Self {
+        self.disk_flush = Some(disk_flush);
+        self
+    }
+
+    /// Sample the statistics of the allocation into a CSV file for as long as it's held, see
+    /// [`CsvTrace`]. Allocating fails if the file can't be created.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let path = std::env::temp_dir().join(format!("weight-trace-{}.csv", std::process::id()));
+    /// let trace = weight::config::CsvTrace { path: path.clone(), interval: Duration::from_millis(10) };
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MB").csv_trace(trace).build()?)?;
+    /// std::thread::sleep(Duration::from_millis(100));
+    /// drop(allocation);
+    ///
+    /// let csv = std::fs::read_to_string(&path).unwrap();
+    /// # std::fs::remove_file(&path).unwrap();
+    /// let mut lines = csv.lines();
+    /// assert_eq!(lines.next(), Some("unix_time_ms,elapsed_ms,allocated_bytes,resident_bytes,cycles_completed,cpu_fraction"));
+    /// let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+    /// assert_eq!(row[2], "1000000");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn csv_trace(mut self, csv_trace: CsvTrace) -> Self {
+        self.csv_trace = Some(csv_trace);
+        self
+    }
+
+    /// Retry obtaining the memory if it fails, see [`Retry`]. If every attempt fails, the
+    /// allocation fails with [`WeightError::RetriesExhausted`]. Defaults to a single attempt.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use weight::config::Retry;
+    /// # use weight::WeightError;
+    /// # fn main() -> Result<(), WeightError> {
+    /// # #[cfg(target_pointer_width = "64")] {
+    /// let retry = Retry { attempts: 3, backoff: Duration::from_millis(1) };
+    /// let config = weight::WeightConfig::new("500PB").strict(false).large_allocation_threshold(u64::MAX).safety_margin(0).retry(retry).build()?;
+    /// let Err(WeightError::RetriesExhausted { attempts, error }) = weight::allocate(config) else {
+    ///     panic!("expected the allocation to fail");
+    /// };
+    /// assert_eq!(attempts, 3);
+    /// assert_eq!(*error, WeightError::AllocationFailed(500_000_000_000_000_000));
+    /// # }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Bytes of [available memory](crate::system::effective_available_memory) to always leave
+    /// free. Allocating a size that would leave less fails with
+    /// [`WeightError::WouldExhaustMemory`] before anything is reserved, so an automated job
+    /// can't run the machine into the OOM killer by accident. Unlike the
+    /// [large allocation threshold](Self::large_allocation_threshold), which only warns about
+    /// sizes, this checks what is available when allocating. With
+    /// [`best_effort`](Self::best_effort) the size is reduced to what leaves the margin instead.
+    ///
+    /// Defaults to [`DEFAULT_SAFETY_MARGIN`](crate::system::DEFAULT_SAFETY_MARGIN). 0 turns the
+    /// check off, for filling all of the memory on purpose. It isn't applied on platforms where
+    /// the available memory can't be determined. [`Allocation::grow`](crate::Allocation::grow)
+    /// checks the memory it adds against the same margin.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// if let Some(available) = weight::system::effective_available_memory() {
+    ///     let config = weight::WeightConfig::new("1MB").safety_margin(available).build()?;
+    ///     let Err(weight::WeightError::WouldExhaustMemory { bytes, margin, .. }) = weight::allocate(config) else {
+    ///         panic!("expected the allocation to be refused");
+    ///     };
+    ///     assert_eq!((bytes, margin), (1_000_000, available));
+    /// }
+    ///
+    /// // Without a margin only the system limits apply
+    /// let allocation = weight::allocate(weight::WeightConfig::new("1MB").safety_margin(0).build()?)?;
+    /// assert_eq!(allocation.bytes(), 1_000_000);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn safety_margin(mut self, bytes: usize) -> Self {
+        self.safety_margin = bytes;
+        self
+    }
+
+    /// Refuse sizes above this many bytes with [`WeightError::ExceedsLimit`], see
+    /// [`parse_memory_string_with_limit`](parse::parse_memory_string_with_limit). There's no
+    /// limit by default.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sizes above this many bytes get a [`ConfigWarning::LargeAllocation`], or fail to build
+    /// when [`strict`](Self::strict) is set. Defaults to [`LARGE_ALLOCATION_THRESHOLD`].
+    pub fn large_allocation_threshold(mut self, threshold: u64) -> Self {
+        self.large_allocation_threshold = threshold;
+        self
+    }
+
+    /// Called by [`build`](Self::build) instead of logging a warning when the size is above the
+    /// [large allocation threshold](Self::large_allocation_threshold), with the size in bytes
+    /// and the warning message, e.g. to log it through the caller's own channel. Returning
+    /// [`ControlFlow::Break`] refuses the allocation, failing the build with
+    /// [`WeightError::TooLarge`] like [`strict`](Self::strict) does. The warning is still
+    /// returned by [`WeightConfig::warnings`] when the build goes ahead.
+    ///
+    /// ```
+    /// # use std::ops::ControlFlow;
+    /// # use std::sync::{Arc, Mutex};
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let seen = Arc::new(Mutex::new(Vec::new()));
+    /// let log = Arc::clone(&seen);
+    /// let config = weight::WeightConfig::new("2MB")
+    ///     .large_allocation_threshold(1_000_000)
+    ///     .on_warning(move |bytes, message| {
+    ///         log.lock().unwrap().push((bytes, message.to_string()));
+    ///         ControlFlow::Continue(())
+    ///     })
+    ///     .build()?;
+    /// assert_eq!(config.warnings().len(), 1);
+    /// assert_eq!(seen.lock().unwrap()[0].0, 2_000_000);
+    ///
+    /// let refused = weight::WeightConfig::new("2MB")
+    ///     .large_allocation_threshold(1_000_000)
+    ///     .on_warning(|_, _| ControlFlow::Break(()))
+    ///     .build();
+    /// assert_eq!(refused.err(), Some(weight::WeightError::TooLarge { bytes: 2_000_000, threshold: 1_000_000 }));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_warning(mut self, callback: impl Fn(usize, &str) -> ControlFlow<()> + Send + 'static) -> Self {
+        self.on_warning = Some(OnWarning(Box::new(callback)));
+        self
+    }
+
+    /// Turn warnings into errors, so [`build`](Self::build) fails with
+    /// [`WeightError::TooLarge`] for sizes above the large allocation threshold.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Called periodically during the initial fill with the number of bytes filled so far and
+    /// the total, e.g. to drive a progress bar for large allocations that take a while.
+    pub fn on_progress(mut self, callback: impl FnMut(usize, usize) + Send + 'static) -> Self {
+        self.progress = Some(Progress(Box::new(callback)));
+        self
+    }
+
+    /// Called after every churn cycle with the allocation's current statistics, e.g. to sample
+    /// the resident set size or push a metric. Returning [`ControlFlow::Break`] stops churning
+    /// and releases the memory, like [`Allocation::stop`](crate::Allocation::stop).
+    ///
+    /// Each worker completes its own cycles, so with several workers the callback is called
+    /// once per worker and cycle, one call at a time. The statistics are gathered before the
+    /// callback is entered and no other lock is held while it runs, but a slow callback does
+    /// hold up the workers waiting to call it.
+    ///
+    /// ```
+    /// # use std::ops::ControlFlow;
+    /// # use std::sync::mpsc;
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<(), weight::WeightError> {
+    /// let (done, finished) = mpsc::channel();
+    /// let config = weight::WeightConfig::new("1MB")
+    ///     .workers(1)
+    ///     .interval(Duration::from_millis(1))
+    ///     .on_cycle(move |stats| {
+    ///         if stats.cycles_completed < 3 {
+    ///             return ControlFlow::Continue(());
+    ///         }
+    ///         let _ = done.send(stats.cycles_completed);
+    ///         ControlFlow::Break(())
+    ///     })
+    ///     .build()?;
+    /// let allocation = weight::allocate(config)?;
+    /// assert_eq!(finished.recv().unwrap(), 3);
+    /// # drop(allocation);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_cycle(mut self, callback: impl FnMut(&Stats) -> ControlFlow<()> + Send + 'static) -> Self {
+        self.on_cycle = Some(OnCycle(Box::new(callback)));
+        self
+    }
+
+    /// Parses the memory size and validates all settings.
+    pub fn build(self) -> Result<WeightConfig, WeightError> {
+        if self.workers == 0 {
+            return Err(WeightError::InvalidConfig("Worker count must be at least 1".to_string()));
+        }
+        if !self.chunk_size.is_power_of_two() {
+            return Err(WeightError::InvalidConfig(format!(
+                "Invalid chunk size: {}. Expected a power of two e.g. 4096",
+                self.chunk_size
+            )));
+        }
+        if let Some(budget) = self.cpu_budget
+            && !(budget.is_finite() && budget > 0.0)
+        {
+            return Err(WeightError::InvalidConfig(format!(
+                "Invalid CPU budget: {}. Expected a fraction of one CPU greater than 0 e.g. 0.1",
+                budget
+            )));
+        }
+        if let Some(adaptive) = self.adaptive_interval {
+            if !cfg!(target_os = "linux") {
+                return Err(WeightError::Unsupported(
+                    "Adapting the interval to memory pressure is only supported on Linux".to_string(),
+                ));
+            }
+            if adaptive.min > adaptive.max {
+                return Err(WeightError::InvalidConfig(format!(
+                    "Invalid adaptive interval: the minimum of {:?} is above the maximum of {:?}",
+                    adaptive.min, adaptive.max
+                )));
+            }
+        }
+        if self.full_touch_interval.is_some_and(|interval| interval.is_zero()) {
+            return Err(WeightError::InvalidConfig("Full touch interval must be greater than 0".to_string()));
+        }
+        if self.access_pattern == AccessPattern::Strided(0) {
+            return Err(WeightError::InvalidConfig("Strided access step must be at least 1".to_string()));
+        }
+        if matches!(self.churn_op, ChurnOp::Add(0) | ChurnOp::Xor(0)) {
+            return Err(WeightError::InvalidConfig(
+                "Churn op value must not be 0, as it wouldn't change the memory".to_string(),
+            ));
+        }
+        if self.ramp.as_ref().is_some_and(|ramp| ramp.steps == 0) {
+            return Err(WeightError::InvalidConfig("Ramp step count must be at least 1".to_string()));
+        }
+        if self.fill_rate == Some(0) {
+            return Err(WeightError::InvalidConfig("Fill rate must be at least 1 byte per second".to_string()));
+        }
+        if let Some(watchdog) = self.watchdog {
+            if watchdog.interval.is_zero() {
+                return Err(WeightError::InvalidConfig("Watchdog interval must be greater than 0".to_string()));
+            }
+            if !(watchdog.threshold > 0.0 && watchdog.threshold <= 1.0) {
+                return Err(WeightError::InvalidConfig(format!(
+                    "Invalid watchdog threshold: {}. Expected a fraction between 0 and 1 e.g. 0.9",
+                    watchdog.threshold
+                )));
+            }
+        }
+        if self.max_runtime.is_some_and(|max_runtime| max_runtime.is_zero()) {
+            return Err(WeightError::InvalidConfig("Max runtime must be greater than 0".to_string()));
+        }
+        if self.retry.attempts == 0 {
+            return Err(WeightError::InvalidConfig("Retry attempts must be at least 1".to_string()));
+        }
+        if let Some(disk_flush) = self.disk_flush {
+            if disk_flush.interval.is_zero() {
+                return Err(WeightError::InvalidConfig("Disk flush interval must be greater than 0".to_string()));
+            }
+            if disk_flush.bytes == 0 {
+                return Err(WeightError::InvalidConfig("Disk flush size must be at least 1 byte".to_string()));
+            }
+        }
+        if self.disable_thp && self.huge_pages {
+            return Err(WeightError::InvalidConfig(
+                "Huge pages can't be combined with disabling transparent huge pages".to_string(),
+            ));
+        }
+        if self.memory_backend.is_some() && (self.backend != Backend::Heap || self.huge_pages || self.lazy) {
+            return Err(WeightError::InvalidConfig(
+                "A custom memory backend can't be combined with another backend, huge pages or lazy allocation"
+                    .to_string(),
+            ));
+        }
+        if self.csv_trace.as_ref().is_some_and(|trace| trace.interval.is_zero()) {
+            return Err(WeightError::InvalidConfig("CSV trace interval must be greater than 0".to_string()));
+        }
+        if self.backend == Backend::Shared {
+            if !cfg!(unix) {
+                return Err(WeightError::Unsupported("The shared memory backend is only supported on Unix".to_string()));
+            }
+            if self.huge_pages {
+                return Err(WeightError::Unsupported("Huge pages can't back shared memory".to_string()));
+            }
+        }
+        if let Backend::Mmap(flags) = self.backend {
+            if !cfg!(unix) {
+                return Err(WeightError::Unsupported("The mmap backend is only supported on Unix".to_string()));
+            }
+            if (flags.populate || flags.locked) && !cfg!(target_os = "linux") {
+                return Err(WeightError::Unsupported(
+                    "Populating and locking mappings is only supported on Linux".to_string(),
+                ));
+            }
+            if flags.guard_page && self.huge_pages {
+                return Err(WeightError::InvalidConfig("Huge pages can't have a guard page".to_string()));
+            }
+        }
+        if let Backend::VirtualAlloc(flags) = self.backend {
+            if !cfg!(windows) {
+                return Err(WeightError::Unsupported("The VirtualAlloc backend is only supported on Windows".to_string()));
+            }
+            if self.huge_pages {
+                return Err(WeightError::InvalidConfig(
+                    "Huge pages are Linux only, use the large pages of the VirtualAlloc backend instead".to_string(),
+                ));
+            }
+            if flags.defer_commit && (flags.large_pages || self.lazy) {
+                return Err(WeightError::InvalidConfig(
+                    "Deferred commits can't be combined with large pages or lazy allocation".to_string(),
+                ));
+            }
+        }
+        if self.lazy {
+            let populated = matches!(self.backend, Backend::Mmap(flags) if flags.populate || flags.locked);
+            if self.lock
+                || self.huge_pages
+                || populated
+                || self.ramp.is_some()
+                || self.fill_rate.is_some()
+                || self.numa_nodes.is_some()
+            {
+                return Err(WeightError::InvalidConfig(
+                    "Lazy allocations can't be locked, use huge pages, populated mappings, ramps, fill rates or NUMA nodes"
+                        .to_string(),
+                ));
+            }
+        }
+        let mut workers = self.workers;
+        if let Some(nodes) = &self.numa_nodes {
+            if nodes.is_empty() {
+                return Err(WeightError::InvalidConfig("At least one NUMA node is required".to_string()));
+            }
+            numa::check_nodes(nodes)?;
+            workers = nodes.len();
+        }
+        if let Some(cpus) = &self.cpus {
+            if self.numa_nodes.is_some() {
+                return Err(WeightError::InvalidConfig(
+                    "Workers can be pinned to NUMA nodes or CPU cores, but not both".to_string(),
+                ));
+            }
+            affinity::check_cpus(cpus)?;
+            workers = cpus.len();
+        }
+        let bytes = match self.max_bytes {
+            Some(max_bytes) => parse::parse_memory_string_with_limit(&self.memory, max_bytes)?,
+            None => parse::parse_memory_string(&self.memory)?,
+        }
+        .bytes();
+        let headroom = parse::headroom(&self.memory);
+        let mut warnings = Vec::new();
+        if parse::exceeds_threshold(bytes, self.large_allocation_threshold) {
+            let threshold = self.large_allocation_threshold;
+            if self.strict {
+                return Err(WeightError::TooLarge { bytes, threshold });
+            }
+            let warning = ConfigWarning::LargeAllocation { bytes, threshold };
+            match &self.on_warning {
+                Some(on_warning) => {
+                    if (on_warning.0)(bytes, &warning.to_string()).is_break() {
+                        return Err(WeightError::TooLarge { bytes, threshold });
+                    }
+                }
+                None => warn!("{}.", warning),
+            }
+            warnings.push(warning);
+        }
+
+        Ok(WeightConfig {
+            memory: self.memory,
+            bytes,
+            workers,
+            interval: self.interval,
+            chunk_size: self.chunk_size,
+            touch_mode: self.touch_mode,
+            fill_pattern: if self.lazy { FillPattern::Zeros } else { self.fill_pattern },
+            access_pattern: self.access_pattern,
+            churn_op: self.churn_op,
+            stride: self.stride,
+            seed: self.seed,
+            cpu_budget: self.cpu_budget,
+            adaptive_interval: self.adaptive_interval,
+            full_touch_interval: self.full_touch_interval,
+            align_to_clock: self.align_to_clock,
+            cycle_history: self.cycle_history,
+            // Churning would fault in all of a lazy allocation right away
+            warmup_delay: if self.lazy && self.warmup_delay.is_zero() {
+                Duration::MAX
+            } else {
+                self.warmup_delay
+            },
+            lazy: self.lazy,
+            max_runtime: self.max_runtime,
+            best_effort: self.best_effort,
+            supervise: self.supervise,
+            lock: self.lock,
+            raise_lock_limit: self.raise_lock_limit,
+            huge_pages: self.huge_pages,
+            disable_thp: self.disable_thp,
+            backend: self.backend,
+            memory_backend: self.memory_backend,
+            zero_on_drop: self.zero_on_drop,
+            ramp: self.ramp,
+            fill_rate: self.fill_rate,
+            numa_nodes: self.numa_nodes,
+            cpus: self.cpus,
+            watchdog: self.watchdog,
+            headroom,
+            disk_flush: self.disk_flush,
+            csv_trace: self.csv_trace,
+            retry: self.retry,
+            safety_margin: self.safety_margin,
+            max_bytes: self.max_bytes,
+            large_allocation_threshold: self.large_allocation_threshold,
+            strict: self.strict,
+            warnings,
+            progress: self.progress,
+            on_cycle: self.on_cycle,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{AccessPattern, AdaptiveInterval, Backend, ChurnOp, CsvTrace, DiskFlush, FillPattern, Ramp, Retry, Stride, TouchMode, Watchdog, WeightConfig};
+
+    /// The serialized form of a [`WeightConfig`].
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ConfigFile {
+        memory: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        workers: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        interval_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_size: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        touch_mode: Option<TouchMode>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fill_pattern: Option<FillPattern>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access_pattern: Option<AccessPattern>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        churn_op: Option<ChurnOp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stride: Option<Stride>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seed: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cpu_budget: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        adaptive_interval: Option<AdaptiveIntervalFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        full_touch_interval_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        align_to_clock: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cycle_history: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        warmup_delay_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lazy: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_runtime_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        best_effort: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        supervise: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lock: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raise_lock_limit: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        huge_pages: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        disable_thp: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backend: Option<Backend>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        zero_on_drop: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ramp: Option<RampFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fill_rate: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        numa_nodes: Option<Vec<usize>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cpus: Option<Vec<usize>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        watchdog: Option<WatchdogFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        disk_flush: Option<DiskFlushFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        csv_trace: Option<CsvTraceFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<RetryFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        safety_margin: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        large_allocation_threshold: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        strict: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RampFile {
+        steps: usize,
+        delay_ms: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct AdaptiveIntervalFile {
+        min_ms: u64,
+        max_ms: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct WatchdogFile {
+        interval_ms: u64,
+        threshold: f64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct DiskFlushFile {
+        interval_ms: u64,
+        bytes: usize,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct CsvTraceFile {
+        path: PathBuf,
+        interval_ms: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RetryFile {
+        attempts: usize,
+        backoff_ms: u64,
+    }
+
+    impl Serialize for WeightConfig {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            ConfigFile {
+                memory: self.memory.clone(),
+                workers: Some(self.workers),
+                interval_ms: Some(self.interval.as_millis() as u64),
+                chunk_size: Some(self.chunk_size),
+                touch_mode: Some(self.touch_mode),
+                fill_pattern: Some(self.fill_pattern),
+                access_pattern: Some(self.access_pattern),
+                churn_op: Some(self.churn_op),
+                stride: Some(self.stride),
+                seed: self.seed,
+                cpu_budget: self.cpu_budget,
+                adaptive_interval: self.adaptive_interval.map(|adaptive| AdaptiveIntervalFile {
+                    min_ms: adaptive.min.as_millis() as u64,
+                    max_ms: adaptive.max.as_millis() as u64,
+                }),
+                full_touch_interval_ms: self.full_touch_interval.map(|interval| interval.as_millis() as u64),
+                align_to_clock: Some(self.align_to_clock),
+                cycle_history: Some(self.cycle_history),
+                // A lazy allocation implies its endless warmup, which TOML's signed integers can't hold
+                warmup_delay_ms: Some(self.warmup_delay)
+                    .filter(|&delay| !(self.lazy && delay == Duration::MAX))
+                    .map(|delay| delay.as_millis().try_into().unwrap_or(u64::MAX)),
+                lazy: Some(self.lazy),
+                max_runtime_ms: self
+                    .max_runtime
+                    .map(|max_runtime| max_runtime.as_millis().try_into().unwrap_or(u64::MAX)),
+                best_effort: Some(self.best_effort),
+                supervise: Some(self.supervise),
+                lock: Some(self.lock),
+                raise_lock_limit: Some(self.raise_lock_limit),
+                huge_pages: Some(self.huge_pages),
+                disable_thp: Some(self.disable_thp),
+                backend: Some(self.backend),
+                zero_on_drop: Some(self.zero_on_drop),
+                ramp: self.ramp.as_ref().map(|ramp| RampFile {
+                    steps: ramp.steps,
+                    delay_ms: ramp.delay.as_millis() as u64,
+                }),
+                fill_rate: self.fill_rate,
+                numa_nodes: self.numa_nodes.clone(),
+                cpus: self.cpus.clone(),
+                watchdog: self.watchdog.map(|watchdog| WatchdogFile {
+                    interval_ms: watchdog.interval.as_millis() as u64,
+                    threshold: watchdog.threshold,
+                }),
+                disk_flush: self.disk_flush.map(|disk_flush| DiskFlushFile {
+                    interval_ms: disk_flush.interval.as_millis() as u64,
+                    bytes: disk_flush.bytes,
+                }),
+                csv_trace: self.csv_trace.as_ref().map(|trace| CsvTraceFile {
+                    path: trace.path.clone(),
+                    interval_ms: trace.interval.as_millis() as u64,
+                }),
+                // A single attempt is the default, so it's left out
+                retry: (self.retry != Retry::default()).then_some(RetryFile {
+                    attempts: self.retry.attempts,
+                    backoff_ms: self.retry.backoff.as_millis() as u64,
+                }),
+                safety_margin: Some(self.safety_margin),
+                max_bytes: self.max_bytes,
+                large_allocation_threshold: Some(self.large_allocation_threshold),
+                strict: Some(self.strict),
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for WeightConfig {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<WeightConfig, D::Error> {
+            let file = ConfigFile::deserialize(deserializer)?;
+            let mut config = WeightConfig::new(file.memory);
+            if let Some(workers) = file.workers {
+                config = config.workers(workers);
+            }
+            if let Some(interval) = file.interval_ms {
+                config = config.interval(Duration::from_millis(interval));
+            }
+            if let Some(chunk_size) = file.chunk_size {
+                config = config.chunk_size(chunk_size);
+            }
+            if let Some(touch_mode) = file.touch_mode {
+                config = config.touch_mode(touch_mode);
+            }
+            if let Some(fill_pattern) = file.fill_pattern {
+                config = config.fill_pattern(fill_pattern);
+            }
+            if let Some(access_pattern) = file.access_pattern {
+                config = config.access_pattern(access_pattern);
+            }
+            if let Some(churn_op) = file.churn_op {
+                config = config.churn_op(churn_op);
+            }
+            if let Some(stride) = file.stride {
+                config = config.stride(stride);
+            }
+            if let Some(seed) = file.seed {
+                config = config.seed(seed);
+            }
+            if let Some(cpu_budget) = file.cpu_budget {
+                config = config.cpu_budget(cpu_budget);
+            }
+            if let Some(cycle_history) = file.cycle_history {
+                config = config.cycle_history(cycle_history);
+            }
+            if let Some(delay) = file.warmup_delay_ms {
+                config = config.warmup_delay(Duration::from_millis(delay));
+            }
+            if let Some(lazy) = file.lazy {
+                config = config.lazy(lazy);
+            }
+            if let Some(max_runtime) = file.max_runtime_ms {
+                config = config.max_runtime(Duration::from_millis(max_runtime));
+            }
+            if let Some(best_effort) = file.best_effort {
+                config = config.best_effort(best_effort);
+            }
+            if let Some(supervise) = file.supervise {
+                config = config.supervise(supervise);
+            }
+            if let Some(interval) = file.full_touch_interval_ms {
+                config = config.full_touch_interval(Duration::from_millis(interval));
+            }
+            if let Some(align_to_clock) = file.align_to_clock {
+                config = config.align_to_clock(align_to_clock);
+            }
+            if let Some(adaptive) = file.adaptive_interval {
+                config = config.adaptive_interval(AdaptiveInterval {
+                    min: Duration::from_millis(adaptive.min_ms),
+                    max: Duration::from_millis(adaptive.max_ms),
+                });
+            }
+            if let Some(lock) = file.lock {
+                config = config.lock(lock);
+            }
+            if let Some(raise) = file.raise_lock_limit {
+                config = config.raise_lock_limit(raise);
+            }
+            if let Some(huge_pages) = file.huge_pages {
+                config = config.huge_pages(huge_pages);
+            }
+            if let Some(disable_thp) = file.disable_thp {
+                config = config.disable_thp(disable_thp);
+            }
+            if let Some(backend) = file.backend {
+                config = config.backend(backend);
+            }
+            if let Some(zero_on_drop) = file.zero_on_drop {
+                config = config.zero_on_drop(zero_on_drop);
+            }
+            if let Some(ramp) = file.ramp {
+                config = config.ramp(Ramp {
+                    steps: ramp.steps,
+                    delay: Duration::from_millis(ramp.delay_ms),
+                    on_step: None,
+                });
+            }
+            if let Some(fill_rate) = file.fill_rate {
+                config = config.fill_rate(fill_rate);
+            }
+            if let Some(nodes) = file.numa_nodes {
+                config = config.numa_nodes(nodes);
+            }
+            if let Some(cpus) = file.cpus {
+                config = config.cpus(cpus);
+            }
+            if let Some(watchdog) = file.watchdog {
+                config = config.watchdog(Watchdog {
+                    interval: Duration::from_millis(watchdog.interval_ms),
+                    threshold: watchdog.threshold,
+                });
+            }
+            if let Some(disk_flush) = file.disk_flush {
+                config = config.disk_flush(DiskFlush {
+                    interval: Duration::from_millis(disk_flush.interval_ms),
+                    bytes: disk_flush.bytes,
+                });
+            }
+            if let Some(trace) = file.csv_trace {
+                config = config.csv_trace(CsvTrace {
+                    path: trace.path,
+                    interval: Duration::from_millis(trace.interval_ms),
+                });
+            }
+            if let Some(retry) = file.retry {
+                config = config.retry(Retry {
+                    attempts: retry.attempts,
+                    backoff: Duration::from_millis(retry.backoff_ms),
+                });
+            }
+            if let Some(margin) = file.safety_margin {
+                config = config.safety_margin(margin);
+            }
+            if let Some(max_bytes) = file.max_bytes {
+                config = config.max_bytes(max_bytes);
+            }
+            if let Some(threshold) = file.large_allocation_threshold {
+                config = config.large_allocation_threshold(threshold);
+            }
+            if let Some(strict) = file.strict {
+                config = config.strict(strict);
+            }
+            // Parses the memory size and validates the settings
+            config.build().map_err(serde::de::Error::custom)
+        }
+    }
+}
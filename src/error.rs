@@ -0,0 +1,154 @@
+use core::fmt;
+
+use crate::alloc_crate::boxed::Box;
+use crate::alloc_crate::string::String;
+use crate::parse::ByteSize;
+
+/// Errors returned when parsing memory sizes or allocating memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WeightError {
+    /// The memory string doesn't match the expected `<number>[unit]` format.
+    InvalidFormat(String),
+    /// The memory string uses a unit that isn't supported.
+    InvalidUnit(String),
+    /// A percentage of total or available memory outside of the supported range.
+    InvalidPercentage(String),
+    /// The requested size doesn't fit in the address space of the platform.
+    Overflow,
+    /// A size expression such as `1GB-2GB` subtracts more than it adds up to.
+    Underflow,
+    /// The memory allocator couldn't provide the requested number of bytes.
+    AllocationFailed(usize),
+    /// The memory couldn't be locked in physical RAM.
+    LockFailed(String),
+    /// The size is above the large allocation threshold of a
+    /// [`strict`](crate::config::WeightConfigBuilder::strict) config.
+    TooLarge {
+        /// The requested size in bytes.
+        bytes: usize,
+        /// The threshold it exceeds in bytes.
+        threshold: u64,
+    },
+    /// The size is above the maximum passed to
+    /// [`parse_memory_string_with_limit`](crate::parse::parse_memory_string_with_limit).
+    ExceedsLimit {
+        /// The requested size in bytes.
+        bytes: usize,
+        /// The maximum size in bytes.
+        limit: usize,
+    },
+    /// The size is above what's left of a resource limit of the process, such as `RLIMIT_AS`
+    /// set with `ulimit -v`, after what the process has mapped already, so allocating it would
+    /// fail.
+    ResourceLimit {
+        /// Name of the limit, e.g. `RLIMIT_AS`.
+        resource: &'static str,
+        /// The requested size in bytes.
+        bytes: usize,
+        /// The limit in bytes.
+        limit: u64,
+    },
+    /// Allocating the size would leave less available memory than the
+    /// [`safety_margin`](crate::config::WeightConfigBuilder::safety_margin), so it was refused
+    /// before anything was reserved.
+    WouldExhaustMemory {
+        /// The requested size in bytes.
+        bytes: usize,
+        /// Memory that was available when allocating, in bytes.
+        available: usize,
+        /// Bytes of available memory to keep.
+        margin: usize,
+    },
+    /// An entry of a comma-separated list of sizes is invalid, see
+    /// [`allocate_memory_list`](crate::alloc::allocate_memory_list).
+    InvalidEntry {
+        /// Position of the entry in the list, starting at 0.
+        index: usize,
+        /// The entry as it was given.
+        entry: String,
+        /// Why the entry is invalid.
+        error: Box<WeightError>,
+    },
+    /// Every attempt to get the memory failed, see
+    /// [`WeightConfigBuilder::retry`](crate::config::WeightConfigBuilder::retry).
+    RetriesExhausted {
+        /// Number of attempts made.
+        attempts: usize,
+        /// Why the last attempt failed.
+        error: Box<WeightError>,
+    },
+    /// A background thread couldn't be started, e.g. because the process is at its thread limit.
+    SpawnFailed(String),
+    /// Reading or writing a file or socket failed, e.g. when dumping an allocation with
+    /// [`dump_to`](crate::alloc::Allocation::dump_to) or binding the metrics server to an
+    /// address that's in use.
+    Io(String),
+    /// An allocation option has an invalid value.
+    InvalidConfig(String),
+    /// The operation isn't supported on the current platform.
+    Unsupported(String),
+}
+
+impl fmt::Display for WeightError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeightError::InvalidFormat(input) => write!(
+                f,
+                "Invalid memory string format: '{}'. Expected format: <number>[unit], <number>%, avail:<number>%, leave:<number>[unit] or <number>x-l<1-3> (e.g., 4096, 1B, 1.5GB, 2GiB, 80%, avail:70%, leave:1GB, 2x-l3)",
+                input
+            ),
+            WeightError::InvalidUnit(unit) => write!(
+                f,
+                "Invalid memory unit: '{}'. Valid units: B, KB, MB, GB, TB, PB, KiB, MiB, GiB, TiB, PiB, the shorthands K, M, G, T, P or p for pages",
+                unit
+            ),
+            WeightError::InvalidPercentage(percentage) => write!(
+                f,
+                "Invalid percentage: '{}%'. Expected a value between 0% and 100%",
+                percentage
+            ),
+            WeightError::Overflow => write!(f, "Memory size overflow"),
+            WeightError::Underflow => write!(f, "Memory size underflow, the result is below zero"),
+            WeightError::AllocationFailed(bytes) => write!(f, "Failed to allocate {} bytes", bytes),
+            WeightError::LockFailed(reason) => write!(f, "{}", reason),
+            WeightError::TooLarge { bytes, threshold } => write!(
+                f,
+                "Refusing to allocate {} bytes, which is above the large allocation threshold of {} bytes",
+                bytes, threshold
+            ),
+            WeightError::ExceedsLimit { bytes, limit } => write!(
+                f,
+                "Memory size of {} bytes exceeds the limit of {} bytes",
+                bytes, limit
+            ),
+            WeightError::ResourceLimit { resource, bytes, limit } => write!(
+                f,
+                "Requested {} exceeds what's left of {} of {}",
+                ByteSize(*bytes),
+                resource,
+                ByteSize(usize::try_from(*limit).unwrap_or(usize::MAX))
+            ),
+            WeightError::WouldExhaustMemory { bytes, available, margin } => write!(
+                f,
+                "Refusing to allocate {} with {} of memory available, which would leave less than the safety margin of {}",
+                ByteSize(*bytes),
+                ByteSize(*available),
+                ByteSize(*margin)
+            ),
+            WeightError::InvalidEntry { index, entry, error } => write!(
+                f,
+                "Invalid size '{}' at position {} of the list: {}",
+                entry,
+                index + 1,
+                error
+            ),
+            WeightError::RetriesExhausted { attempts, error } => write!(f, "{} after {} attempts", error, attempts),
+            WeightError::SpawnFailed(reason) => write!(f, "{}", reason),
+            WeightError::Io(reason) => write!(f, "{}", reason),
+            WeightError::InvalidConfig(reason) => write!(f, "{}", reason),
+            WeightError::Unsupported(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl core::error::Error for WeightError {}
@@ -0,0 +1,64 @@
+//! Writing snapshots of an allocation to disk to put the storage path under load as well.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Distinguishes the files of several allocations in the same process.
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary file that snapshots are written to, deleted when dropped.
+pub(crate) struct FlushFile {
+    // Fields are dropped in order, so the file is closed before it's deleted, which
+    // Windows requires
+    file: File,
+    path: TempPath,
+}
+
+/// Path of a file that is deleted when dropped.
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+impl FlushFile {
+    /// Creates a new empty file in the system's temporary directory.
+    pub(crate) fn create() -> io::Result<FlushFile> {
+        let path = env::temp_dir().join(format!(
+            "weight-{}-{}.bin",
+            process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok(FlushFile { file, path: TempPath(path) })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path.0
+    }
+
+    /// Replaces the contents of the file with `snapshot` and waits for it to reach the disk.
+    /// If that fails, e.g. because the disk is full, the file is emptied to give the space
+    /// back before the error is returned.
+    pub(crate) fn write(&mut self, snapshot: &[u8]) -> io::Result<()> {
+        let result = self.overwrite(snapshot);
+        if result.is_err() {
+            let _ = self.file.set_len(0);
+        }
+        result
+    }
+
+    fn overwrite(&mut self, snapshot: &[u8]) -> io::Result<()> {
+        self.file.rewind()?;
+        self.file.write_all(snapshot)?;
+        self.file.set_len(snapshot.len() as u64)?;
+        // Without syncing the snapshot could sit in the page cache and never hit the disk
+        self.file.sync_data()
+    }
+}
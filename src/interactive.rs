@@ -0,0 +1,150 @@
+//! Allocating and releasing memory from commands typed on a terminal, to add pressure step by
+//! step and watch how the system reacts.
+
+use std::io::{BufRead, Write};
+
+use crate::config::WeightConfigBuilder;
+use crate::error::WeightError;
+use crate::parse::ByteSize;
+use crate::registry::AllocationRegistry;
+use crate::system;
+
+/// Commands [`interactive`] understands besides sizes, printed by `help`.
+const HELP: &str = "\
+Commands:
+  <size>      allocate a size such as 512MB, 1GiB or 10%
+  free        release the most recent allocation
+  free <n>    release allocation #n
+  free all    release every allocation
+  stats       show every allocation and the resident memory
+  help        show this help
+  quit        release everything and exit";
+
+/// Reads commands from `input` line by line and answers each on `output`, until `quit` or the
+/// end of the input. A line holding a size such as `512MB` allocates it with the settings
+/// `config` returns for that size, `free` releases the most recent allocation, `free 2` the
+/// second one, `free all` all of them, and `stats` lists them along with the resident memory
+/// of the process. After every command the running total is printed.
+///
+/// A size that fails to parse or allocate is reported on `output` and the loop goes on, only
+/// failing to read or write is returned as an error. Every allocation is released before
+/// returning.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let input = "1MB\n2MB\nfree 1\nstats\nquit\n";
+/// let mut output = Vec::new();
+/// weight::interactive::interactive(input.as_bytes(), &mut output, |size| weight::WeightConfig::new(size))?;
+/// let output = String::from_utf8(output).unwrap();
+/// assert!(output.contains("Allocated #2: 2000000B"));
+/// assert!(output.contains("Released #1"));
+/// assert!(output.contains("Total: 2000000B (1.91 MiB) in 1 allocation\n"));
+/// assert!(output.ends_with("Total: 0B (0 B) in 0 allocations\n"));
+/// # Ok(())
+/// # }
+/// ```
+pub fn interactive(
+    input: impl BufRead,
+    mut output: impl Write,
+    config: impl Fn(&str) -> WeightConfigBuilder,
+) -> Result<(), WeightError> {
+    let io_error = |err: std::io::Error| WeightError::Io(format!("Interactive session failed: {}", err));
+    let mut registry = AllocationRegistry::new();
+    // Allocations in the order they were made, the registry sorts them by name
+    let mut names: Vec<String> = Vec::new();
+    let mut next = 1;
+
+    writeln!(output, "Enter a size to allocate, or help for the other commands.").map_err(io_error)?;
+    for line in input.lines() {
+        let line = line.map_err(io_error)?;
+        let mut words = line.split_whitespace();
+        let message = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("quit" | "exit"), None) => break,
+            (Some("help"), None) => HELP.to_string(),
+            (Some("stats"), None) => stats(&registry, &names),
+            (Some("free"), None) => match names.pop() {
+                Some(name) => release(&mut registry, &name),
+                None => "Nothing to release".to_string(),
+            },
+            (Some("free"), Some("all")) => {
+                let released = names.len();
+                for name in names.drain(..) {
+                    drop(registry.remove(&name));
+                }
+                format!("Released {} allocations", released)
+            }
+            (Some("free"), Some(number)) => match names.iter().position(|name| name == number) {
+                Some(index) => {
+                    let name = names.remove(index);
+                    release(&mut registry, &name)
+                }
+                None => format!("No allocation #{}, see stats", number),
+            },
+            (Some(size), None) => match config(size).build().and_then(|config| {
+                let name = next.to_string();
+                registry.add(name.clone(), config).map(|()| name)
+            }) {
+                Ok(name) => {
+                    next += 1;
+                    let bytes = registry.get(&name).map_or(0, |allocation| allocation.bytes());
+                    names.push(name.clone());
+                    format!("Allocated #{}: {}B ({})", name, bytes, ByteSize(bytes))
+                }
+                Err(err) => format!("Failed to allocate {}: {}", size, err),
+            },
+            _ => format!("Unknown command: '{}', see help", line.trim()),
+        };
+        writeln!(output, "{}", message).map_err(io_error)?;
+        writeln!(output, "{}", total(&registry)).map_err(io_error)?;
+    }
+
+    for name in names.drain(..) {
+        drop(registry.remove(&name));
+    }
+    writeln!(output, "Released everything.").map_err(io_error)?;
+    writeln!(output, "{}", total(&registry)).map_err(io_error)
+}
+
+/// Releases the allocation `name` and says so.
+fn release(registry: &mut AllocationRegistry, name: &str) -> String {
+    let bytes = registry.remove(name).map_or(0, |allocation| allocation.bytes());
+    format!("Released #{}: {}B ({})", name, bytes, ByteSize(bytes))
+}
+
+/// One line per allocation in `names` with its size and churn cycles, then the resident memory.
+fn stats(registry: &AllocationRegistry, names: &[String]) -> String {
+    let mut lines: Vec<String> = names
+        .iter()
+        .filter_map(|name| registry.get(name).map(|allocation| (name, allocation.stats())))
+        .map(|(name, stats)| {
+            format!(
+                "#{}: {}B ({}), {} churn cycles",
+                name,
+                stats.allocated_bytes,
+                ByteSize(stats.allocated_bytes),
+                stats.cycles_completed
+            )
+        })
+        .collect();
+    if let Some(resident) = system::resident_memory() {
+        lines.push(format!("Resident: {}B ({})", resident, ByteSize(resident)));
+    }
+    if lines.is_empty() {
+        return "No allocations".to_string();
+    }
+    lines.join("\n")
+}
+
+/// The running total of `registry`.
+fn total(registry: &AllocationRegistry) -> String {
+    let bytes = registry.total_bytes();
+    let count = registry.len();
+    format!(
+        "Total: {}B ({}) in {} allocation{}",
+        bytes,
+        ByteSize(bytes),
+        count,
+        if count == 1 { "" } else { "s" }
+    )
+}
@@ -0,0 +1,70 @@
+//! Without the default `std` feature only [`parse`] and [`error`] are built, which work under
+//! `no_std` with an allocator.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Named so it doesn't clash with the alloc module
+extern crate alloc as alloc_crate;
+
+#[macro_use]
+#[cfg_attr(not(feature = "std"), allow(unused_macros))]
+mod macros;
+
+#[cfg(feature = "std")]
+pub mod advice;
+#[cfg(feature = "std")]
+mod affinity;
+#[cfg(feature = "std")]
+pub mod alloc;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+#[cfg(feature = "std")]
+pub mod backend;
+#[cfg(feature = "std")]
+pub mod benchmark;
+#[cfg(feature = "std")]
+mod buffer;
+pub mod checksum;
+#[cfg(feature = "std")]
+pub mod config;
+pub mod error;
+#[cfg(feature = "std")]
+mod flush;
+#[cfg(feature = "std")]
+pub mod interactive;
+#[cfg(feature = "std")]
+mod lock;
+#[cfg(feature = "std")]
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod memtest;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod mirror;
+#[cfg(feature = "std")]
+mod numa;
+pub mod parse;
+#[cfg(feature = "std")]
+pub mod probe;
+#[cfg(all(feature = "fork", unix))]
+pub mod process;
+#[cfg(feature = "std")]
+pub mod registry;
+#[cfg(all(feature = "signal", unix))]
+pub mod shutdown;
+#[cfg(feature = "std")]
+pub mod system;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+pub mod wait;
+
+#[cfg(feature = "std")]
+pub use config::WeightConfig;
+pub use error::WeightError;
+#[cfg(feature = "std")]
+pub use registry::AllocationRegistry;
+#[cfg(feature = "std")]
+pub use alloc::{allocate, allocate_deterministic, allocate_for, allocate_from_env, allocate_memory, allocate_memory_blocking, allocate_memory_list, allocate_target_rss, allocate_with_progress, total_allocated_bytes, Allocation, BlockingRun, ChunksMut, Stats, StatsHandle, StopHandle, TargetRss, Termination, TimedAllocation, Verification};
+pub use parse::{parse_memory_string, ByteSize};
@@ -0,0 +1,226 @@
+//! Pinning memory in physical RAM so the OS can't swap it out.
+
+use crate::buffer::Buffer;
+use crate::error::WeightError;
+
+/// Buffer that is locked in physical memory. It's unlocked again when dropped.
+pub(crate) struct LockedBuffer {
+    data: Buffer,
+}
+
+impl LockedBuffer {
+    /// The locked memory.
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The locked memory, mutably.
+    pub(crate) fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for LockedBuffer {
+    fn drop(&mut self) {
+        if !self.data.is_empty() {
+            unlock(&self.data);
+        }
+    }
+}
+
+/// Outcome of [`lock_buffer`].
+pub(crate) enum Locked {
+    /// All of the memory is locked.
+    Full(LockedBuffer),
+    /// Only the start of the memory could be locked, see [`Buffer::locked_len`]. The rest has
+    /// to be churned to stay resident.
+    Partial(Buffer),
+}
+
+impl Locked {
+    /// The memory, whether locked in full or in part.
+    pub(crate) fn data_mut(&mut self) -> &mut [u8] {
+        match self {
+            Locked::Full(locked) => locked.data_mut(),
+            Locked::Partial(data) => data,
+        }
+    }
+}
+
+/// Locks all pages of `data` in physical memory, first raising the lock limit of the process
+/// to fit them if `raise_limit` is set. If the limit is too low to lock everything, as much
+/// of the start of the memory as fits is locked instead. Only fails if nothing at all could
+/// be locked.
+pub(crate) fn lock_buffer(mut data: Buffer, raise_limit: bool) -> Result<Locked, WeightError> {
+    if data.is_empty() {
+        return Ok(Locked::Full(LockedBuffer { data }));
+    }
+    if raise_limit {
+        raise_lock_limit(data.len());
+    }
+    let err = match lock(&data) {
+        Ok(()) => return Ok(Locked::Full(LockedBuffer { data })),
+        Err(err) => err,
+    };
+    // Searching for the most pages that can be locked at the start stays within a few dozen
+    // attempts, and also accounts for memory the process locked before and for the start
+    // of the memory not being aligned to a page. Locking a longer start includes the pages
+    // of a shorter one, so they don't have to be unlocked in between.
+    let page_size = crate::system::page_size();
+    let (mut low, mut high) = (0, lock_limit().unwrap_or(usize::MAX).min(data.len() - 1) / page_size);
+    while low < high {
+        let pages = low + (high - low).div_ceil(2);
+        if lock(&data[..pages * page_size]).is_ok() {
+            low = pages;
+        } else {
+            high = pages - 1;
+        }
+    }
+    let len = low * page_size;
+    if len == 0 {
+        return Err(err);
+    }
+    warn!(
+        "Only {} of {} bytes could be locked, the memory is churned to keep the rest resident: {}.",
+        len,
+        data.len(),
+        err
+    );
+    data.set_locked_len(len);
+    Ok(Locked::Partial(data))
+}
+
+/// Most memory the process may lock, `None` if it's unlimited or unknown.
+#[cfg(unix)]
+fn lock_limit() -> Option<usize> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes to the rlimit it's given.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    usize::try_from(limit.rlim_cur).ok()
+}
+
+#[cfg(not(unix))]
+fn lock_limit() -> Option<usize> {
+    None
+}
+
+/// Raises `RLIMIT_MEMLOCK` so `bytes` fit, as far as the process is permitted to. Without
+/// privileges only the soft limit can be raised, up to the hard limit.
+#[cfg(unix)]
+fn raise_lock_limit(bytes: usize) {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes to the rlimit it's given.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return;
+    }
+    let wanted = limit.rlim_cur.saturating_add(bytes as libc::rlim_t);
+    let raised = libc::rlimit {
+        rlim_cur: wanted,
+        rlim_max: limit.rlim_max.max(wanted),
+    };
+    let soft = libc::rlimit {
+        rlim_cur: limit.rlim_max.min(wanted),
+        rlim_max: limit.rlim_max,
+    };
+    for limit in [raised, soft] {
+        // SAFETY: setrlimit only reads the rlimit it's given.
+        if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) } == 0 {
+            info!("Raised RLIMIT_MEMLOCK to {} bytes", limit.rlim_cur);
+            return;
+        }
+    }
+    debug!("Failed to raise RLIMIT_MEMLOCK: {}", std::io::Error::last_os_error());
+}
+
+/// On Windows locking grows the working set by itself, elsewhere there is no limit to raise.
+#[cfg(not(unix))]
+fn raise_lock_limit(_bytes: usize) {}
+
+#[cfg(unix)]
+pub(crate) fn lock(data: &[u8]) -> Result<(), WeightError> {
+    // SAFETY: the pointer and length describe memory owned by `data`.
+    if unsafe { libc::mlock(data.as_ptr().cast(), data.len()) } != 0 {
+        return Err(WeightError::LockFailed(format!(
+            "Failed to lock {} bytes of memory: {} (the limit can be checked with `ulimit -l`)",
+            data.len(),
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn unlock(data: &[u8]) {
+    // SAFETY: the pointer and length describe memory owned by `data` that was locked by `lock`.
+    unsafe {
+        libc::munlock(data.as_ptr().cast(), data.len());
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn lock(data: &[u8]) -> Result<(), WeightError> {
+    use windows_sys::Win32::Foundation::ERROR_WORKING_SET_QUOTA;
+    use windows_sys::Win32::System::Memory::VirtualLock;
+
+    // SAFETY: the pointer and length describe memory owned by `data`.
+    if unsafe { VirtualLock(data.as_ptr().cast(), data.len()) } != 0 {
+        return Ok(());
+    }
+
+    // VirtualLock can only lock as much memory as fits in the process working set,
+    // so grow the working set by the size of the buffer and try once more.
+    let mut error = std::io::Error::last_os_error();
+    if error.raw_os_error() == Some(ERROR_WORKING_SET_QUOTA as i32) {
+        match grow_working_set(data.len()) {
+            // SAFETY: as above.
+            Ok(()) if unsafe { VirtualLock(data.as_ptr().cast(), data.len()) } != 0 => return Ok(()),
+            Ok(()) => error = std::io::Error::last_os_error(),
+            Err(grow_error) => error = grow_error,
+        }
+        return Err(WeightError::LockFailed(format!(
+            "Failed to lock {} bytes of memory: the process working set is too small and couldn't be grown: {}",
+            data.len(),
+            error
+        )));
+    }
+    Err(WeightError::LockFailed(format!("Failed to lock {} bytes of memory: {}", data.len(), error)))
+}
+
+#[cfg(windows)]
+fn grow_working_set(bytes: usize) -> Result<(), std::io::Error> {
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, GetProcessWorkingSetSize, SetProcessWorkingSetSize};
+
+    let mut minimum = 0;
+    let mut maximum = 0;
+    // SAFETY: GetCurrentProcess returns a pseudo handle that is always valid and the
+    // out pointers point to live locals.
+    unsafe {
+        let process = GetCurrentProcess();
+        if GetProcessWorkingSetSize(process, &mut minimum, &mut maximum) == 0
+            || SetProcessWorkingSetSize(process, minimum.saturating_add(bytes), maximum.saturating_add(bytes)) == 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn unlock(data: &[u8]) {
+    use windows_sys::Win32::System::Memory::VirtualUnlock;
+
+    // SAFETY: the pointer and length describe memory owned by `data` that was locked by `lock`.
+    unsafe {
+        VirtualUnlock(data.as_ptr().cast(), data.len());
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn lock(_data: &[u8]) -> Result<(), WeightError> {
+    Err(WeightError::Unsupported("Locking memory is not supported on this platform".to_string()))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn unlock(_data: &[u8]) {}
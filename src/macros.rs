@@ -0,0 +1,60 @@
+//! Logging macros that forward to the `log` crate when the `log` feature is enabled, or else to
+//! `tracing` when the `tracing` feature is. Without either warnings are printed to stderr, or
+//! discarded under `no_std`, and everything else is discarded.
+
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::warn!($($arg)*);
+        #[cfg(all(feature = "tracing", not(feature = "log")))]
+        tracing::warn!($($arg)*);
+        #[cfg(all(feature = "std", not(any(feature = "log", feature = "tracing"))))]
+        eprintln!("Warning: {}", format_args!($($arg)*));
+        #[cfg(not(any(feature = "std", feature = "log")))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::info!($($arg)*);
+        #[cfg(all(feature = "tracing", not(feature = "log")))]
+        tracing::info!($($arg)*);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::debug!($($arg)*);
+        #[cfg(all(feature = "tracing", not(feature = "log")))]
+        tracing::debug!($($arg)*);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+/// Enters a `tracing` span with the given fields until the end of the enclosing block when the
+/// `tracing` feature is enabled.
+macro_rules! span {
+    ($name:literal $(, $field:ident = $value:expr)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name $(, $field = $value)*).entered();
+        #[cfg(not(feature = "tracing"))]
+        let _ = ($(&$value,)*);
+    };
+}
+
+/// Emits a `tracing` event with the given fields at debug level when the `tracing` feature
+/// is enabled.
+macro_rules! event {
+    ($name:literal $(, $field:ident = $value:expr)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(name: $name, $($field = $value),*);
+        #[cfg(not(feature = "tracing"))]
+        let _ = ($(&$value,)*);
+    };
+}
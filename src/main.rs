@@ -1,34 +1,802 @@
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use weight::config::{AccessPattern, AdaptiveInterval, Backend, ChurnOp, CsvTrace, DiskFlush, FillPattern, MmapFlags, Ramp, Retry, Stride, TouchMode, VirtualAllocFlags, Watchdog, WeightConfigBuilder};
+use weight::{alloc, benchmark, interactive, memtest, mirror, parse, probe, system, wait, ByteSize, WeightConfig, WeightError};
 
-mod memory;
-mod wait;
-
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Amount of memory to occupy in string format e.g. 1B, 1KB, 1MB, 1GB, 1TB, 1PB
+    /// Amount of memory to occupy, the same as --memory
+    #[arg(conflicts_with = "memory")]
+    size: Option<String>,
+
+    /// Amount of memory to occupy in string format e.g. 1B, 1.5KB, 1MB, 1GB, 1TB, 1PB (powers of 1000)
+    /// or 1KiB, 1MiB, 1GiB, 1TiB, 1PiB (powers of 1024), or a number of pages e.g. 1024p, or a percentage of total memory e.g. 80%
+    /// or of available memory e.g. avail:70%, or a multiple of a CPU cache size e.g. 2x-l3.
+    /// Read from WEIGHT_SIZE when neither this nor a size argument is given
     #[arg(short, long, default_value_t = String::from("1B"))]
     memory: String,
 
-    /// Amount of time in seconds wait before exiting the program. 0 means wait forever
-    #[arg(short, long, default_value_t = 60)]
+    /// Amount of time in seconds wait before exiting the program. 0 means wait until CTRL + C is pressed
+    #[arg(short, long, visible_alias = "duration", default_value_t = 60)]
     wait: u32,
+
+    /// Only check that the memory size is valid and print it in bytes, without allocating anything
+    #[arg(long)]
+    check: bool,
+
+    /// Print the settings the allocation resolved to as a JSON line before allocating, e.g. for
+    /// audit logs, with the size in bytes
+    #[arg(long)]
+    print_config: bool,
+
+    /// Print an estimate of how much memory can be allocated without running out of memory,
+    /// without allocating anything
+    #[arg(long)]
+    max_allocatable: bool,
+
+    /// Measure the memory bandwidth with this many passes over the memory instead of holding it,
+    /// then exit
+    #[arg(long)]
+    benchmark: Option<usize>,
+
+    /// Test the memory for faults with this many passes of the zeros, ones, walking ones and
+    /// address patterns instead of holding it, then exit. Exits with 2 if any byte reads back wrong
+    #[arg(long, conflicts_with = "benchmark")]
+    memtest: Option<usize>,
+
+    /// Read sizes to allocate and commands such as free and stats from stdin line by line,
+    /// printing the running total after each. All other options apply to every allocation
+    #[arg(long, conflicts_with_all = ["benchmark", "memtest"])]
+    interactive: bool,
+
+    /// Allocate the size again and again, churning every step, until allocating fails or the
+    /// system runs low on memory, then print the most memory held and exit
+    #[arg(long, conflicts_with_all = ["benchmark", "memtest", "interactive", "mirror", "target_rss"])]
+    grow_until_fail: bool,
+
+    /// Available memory to leave when growing with --grow-until-fail, e.g. 1GB. Defaults to 10%
+    /// of the total memory
+    #[arg(long, value_parser = parse_size, requires = "grow_until_fail")]
+    keep_available: Option<usize>,
+
+    /// Number of worker threads that keep the memory occupied. Defaults to the number of logical CPUs
+    #[arg(long, default_value_t = alloc::default_workers())]
+    workers: usize,
+
+    /// Time to wait between passes over the memory e.g. 50ms or 2s, a plain number is in milliseconds.
+    /// Lower values keep the memory hotter but use more CPU, 0 modifies the memory continuously
+    #[arg(long, value_parser = parse_interval, default_value = "100ms")]
+    interval: Duration,
+
+    /// Start every pass on a multiple of the interval of wall-clock time, e.g. on every 100ms
+    /// boundary, instead of sleeping the interval after each pass
+    #[arg(long)]
+    align_to_clock: bool,
+
+    /// Leave the memory alone for this long after filling it before churning starts e.g. 5s, to
+    /// measure the state right after allocating
+    #[arg(long, value_parser = parse_interval, default_value = "0")]
+    warmup_delay: Duration,
+
+    /// Reserve the memory without touching it, so it isn't resident until written. Nothing is
+    /// churned unless --warmup-delay is given, after which all of it is faulted in
+    #[arg(long, conflicts_with_all = ["lock", "huge_pages", "ramp_steps"])]
+    lazy: bool,
+
+    /// Treat the size as the resident memory to reach and grow or shrink the allocation until
+    /// the process has grown by that much, within 1% or 1MiB, in at most 8 adjustments
+    #[arg(long, conflicts_with_all = ["lock", "huge_pages", "lazy"])]
+    target_rss: bool,
+
+    /// Allocate as much of the size as can be reserved instead of failing when all of it can't,
+    /// and report the shortfall
+    #[arg(long)]
+    best_effort: bool,
+
+    /// Restart churn threads that panic, after refilling their part of the memory, instead of
+    /// leaving the memory untouched
+    #[arg(long)]
+    supervise: bool,
+
+    /// Number of bytes processed at a time while modifying the memory, must be a power of two.
+    /// Defaults to the page size
+    #[arg(long, default_value_t = alloc::default_chunk_size())]
+    chunk_size: usize,
+
+    /// Only read the memory instead of modifying it. Uses less CPU and doesn't create dirty pages
+    /// that have to be written to swap
+    #[arg(long)]
+    read_only: bool,
+
+    /// Visit the memory in a shuffled order every cycle instead of sequentially, which defeats
+    /// hardware prefetchers
+    #[arg(long, conflicts_with_all = ["reverse_access", "strided_access"])]
+    random_access: bool,
+
+    /// Visit the memory from end to start every cycle instead of from start to end
+    #[arg(long, conflicts_with = "strided_access")]
+    reverse_access: bool,
+
+    /// Visit every this many chunks of the memory, then start over one chunk further, which
+    /// puts the TLB under pressure
+    #[arg(long)]
+    strided_access: Option<usize>,
+
+    /// Add this much to every byte and subtract it again in each cycle instead of 1, from 1 to 255
+    #[arg(long, value_parser = parse_byte, conflicts_with = "churn_xor")]
+    churn_add: Option<u8>,
+
+    /// XOR every byte with this mask twice in each cycle instead of adding and subtracting 1,
+    /// e.g. 0xFF to flip all bits
+    #[arg(long, value_parser = parse_byte)]
+    churn_xor: Option<u8>,
+
+    /// Touch only one byte per cache line instead of every byte, which keeps the memory resident
+    /// using far less memory bandwidth
+    #[arg(long)]
+    cache_line_stride: bool,
+
+    /// Limit churning to this fraction of one CPU, e.g. 0.1 for 10% of one core
+    #[arg(long)]
+    cpu_budget: Option<f64>,
+
+    /// What to fill the memory with before churning it
+    #[arg(long, value_enum, default_value_t = Pattern::Index)]
+    fill_pattern: Pattern,
+
+    /// Seed for the shuffled order of --random-access and the random fill pattern, for reproducible runs
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Lock the memory in physical RAM so it can't be swapped out instead of continuously modifying it.
+    /// Limited by `ulimit -l` on Linux and the process working set size on Windows
+    #[arg(long)]
+    lock: bool,
+
+    /// Raise the soft `ulimit -l` up to the hard limit, or beyond it with CAP_SYS_RESOURCE,
+    /// before locking with --lock. If the memory still doesn't fit, as much as the limit
+    /// allows is locked and the memory is churned
+    #[arg(long, requires = "lock")]
+    raise_lock_limit: bool,
+
+    /// Back the memory with huge pages. Linux only and requires huge pages to be reserved with
+    /// `sysctl vm.nr_hugepages=<count>`
+    #[arg(long)]
+    huge_pages: bool,
+
+    /// Keep transparent huge pages from backing the memory (MADV_NOHUGEPAGE), so it's faulted in
+    /// and reclaimed page by page. Linux only
+    #[arg(long, conflicts_with = "huge_pages")]
+    disable_thp: bool,
+
+    /// Map the memory directly with mmap instead of getting it from the global allocator. Unix only
+    #[arg(long)]
+    mmap: bool,
+
+    /// Fault the mapped pages in while mapping them (MAP_POPULATE). Linux only
+    #[arg(long, requires = "mmap")]
+    mmap_populate: bool,
+
+    /// Lock the mapped pages in physical memory (MAP_LOCKED) while they keep being modified. Linux only
+    #[arg(long, requires = "mmap")]
+    mmap_locked: bool,
+
+    /// Map an inaccessible page after the memory, so writing past its end crashes instead of
+    /// corrupting other memory. Unix only
+    #[arg(long, requires = "mmap")]
+    mmap_guard_page: bool,
+
+    /// Reserve and commit the memory with VirtualAlloc instead of getting it from the global
+    /// allocator. Windows only
+    #[arg(long, conflicts_with_all = ["mmap", "huge_pages"])]
+    virtual_alloc: bool,
+
+    /// Only reserve the address space up front and commit the pages as the fill reaches them.
+    /// Windows only
+    #[arg(long, requires = "virtual_alloc", conflicts_with = "virtual_alloc_large_pages")]
+    virtual_alloc_defer_commit: bool,
+
+    /// Back the memory with large pages (MEM_LARGE_PAGES), which needs the "Lock pages in
+    /// memory" privilege. Windows only
+    #[arg(long, requires = "virtual_alloc")]
+    virtual_alloc_large_pages: bool,
+
+    /// Back the memory with a named POSIX shared memory segment that other processes can map to
+    /// inspect it, instead of getting it from the global allocator. Unix only
+    #[arg(long, conflicts_with_all = ["mmap", "virtual_alloc"])]
+    shared_memory: bool,
+
+    /// Pick where the memory comes from by its size and the platform: huge pages for 1GiB or
+    /// more where enough are reserved on Linux, mmap from 64MiB on Unix, the heap otherwise
+    #[arg(long, conflicts_with_all = ["mmap", "shared_memory", "virtual_alloc"])]
+    auto_backend: bool,
+
+    /// Overwrite the memory with zeros before releasing it. Best-effort, the OS may have swapped
+    /// some of it out before
+    #[arg(long)]
+    zero_on_drop: bool,
+
+    /// Comma separated list of NUMA nodes to split the memory across, with one worker pinned to each
+    /// node. Linux only
+    #[arg(long, value_delimiter = ',')]
+    numa_nodes: Option<Vec<usize>>,
+
+    /// Comma separated list of CPU cores to pin the workers to, one worker per core. Linux and
+    /// Windows only
+    #[arg(long, value_delimiter = ',', conflicts_with = "numa_nodes")]
+    cpus: Option<Vec<usize>>,
+
+    /// Allocate the size once on every NUMA node, each with its own workers pinned to the node,
+    /// to compare local and remote access. A single allocation without NUMA nodes
+    #[arg(long, conflicts_with_all = ["numa_nodes", "cpus", "interactive", "target_rss"])]
+    mirror: bool,
+
+    /// Allocate the memory gradually in this many steps instead of all at once
+    #[arg(long)]
+    ramp_steps: Option<usize>,
+
+    /// Milliseconds to wait between ramp steps
+    #[arg(long, default_value_t = 1000, requires = "ramp_steps")]
+    ramp_delay: u64,
+
+    /// Fill the memory at most this fast per second e.g. 100MB, so pushing it out to swap
+    /// happens gradually. Ramp delays come on top
+    #[arg(long, value_parser = parse_size, conflicts_with = "lazy")]
+    fill_rate: Option<usize>,
+
+    /// Make a cycle a full read-write pass over every byte this often e.g. 60s, whatever touch
+    /// mode and stride the cycles in between use
+    #[arg(long, value_parser = parse_interval)]
+    full_touch_interval: Option<Duration>,
+
+    /// Adapt the interval to the memory pressure on Linux, churning every this often e.g. 10ms
+    /// while there is none
+    #[arg(long, value_parser = parse_interval, requires = "adaptive_max_interval")]
+    adaptive_min_interval: Option<Duration>,
+
+    /// Longest interval when adapting it to memory pressure e.g. 5s, reached once tasks are
+    /// stalled on memory 10% of the time
+    #[arg(long, value_parser = parse_interval, requires = "adaptive_min_interval")]
+    adaptive_max_interval: Option<Duration>,
+
+    /// Check this often whether the memory is still resident e.g. 10s, and touch it all again
+    /// if the OS has reclaimed part of it
+    #[arg(long, value_parser = parse_interval)]
+    watchdog: Option<Duration>,
+
+    /// Fraction of the memory that must stay resident before the watchdog touches it again
+    #[arg(long, default_value_t = 0.9, requires = "watchdog")]
+    watchdog_threshold: f64,
+
+    /// Periodically write this much of the memory to a temporary file e.g. 64MB, to generate disk
+    /// I/O alongside the memory churn
+    #[arg(long, value_parser = parse_size)]
+    disk_flush: Option<usize>,
+
+    /// Time between two writes of --disk-flush e.g. 500ms or 2s
+    #[arg(long, value_parser = parse_interval, default_value = "1s", requires = "disk_flush")]
+    disk_flush_interval: Duration,
+
+    /// Append the resident memory, churn cycles and CPU use to this CSV file over time, for
+    /// plotting the run
+    #[arg(long)]
+    csv_trace: Option<PathBuf>,
+
+    /// Time between two rows of --csv-trace e.g. 100ms or 5s
+    #[arg(long, value_parser = parse_interval, default_value = "1s", requires = "csv_trace")]
+    csv_trace_interval: Duration,
+
+    /// Try getting the memory up to this many times before giving up, e.g. when other processes
+    /// are briefly holding on to theirs
+    #[arg(long, default_value_t = 1)]
+    retry_attempts: usize,
+
+    /// Time to wait before the first retry of --retry-attempts e.g. 100ms, doubled for each
+    /// further retry
+    #[arg(long, value_parser = parse_interval, default_value = "100ms")]
+    retry_backoff: Duration,
+
+    /// Sizes above this e.g. 200GB print a warning that they may cause system instability.
+    /// Defaults to 100GiB
+    #[arg(long, value_parser = parse_size)]
+    large_allocation_threshold: Option<usize>,
+
+    /// Refuse sizes above this e.g. 64GB outright, to catch typos before they exhaust the memory
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<usize>,
+
+    /// Refuse to allocate sizes above the large allocation threshold instead of warning
+    #[arg(long)]
+    strict: bool,
+
+    /// Refuse to allocate a size that would leave less than this much memory available e.g.
+    /// 2GB. Defaults to 512MB
+    #[arg(long, value_parser = parse_size)]
+    safety_margin: Option<usize>,
+
+    /// Allow allocating all of the available memory, without keeping a safety margin free
+    #[arg(long, conflicts_with = "safety_margin")]
+    no_safety_margin: bool,
+
+    /// Serve the allocation's statistics in the Prometheus text format on this address, e.g. 0.0.0.0:9184
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_addr: Option<String>,
+}
+
+/// Environment variable with the size to allocate when none is given on the command line.
+const SIZE_VAR: &str = "WEIGHT_SIZE";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum Pattern {
+    /// Every byte is 0x00
+    Zeros,
+    /// Every byte is 0xFF
+    Ones,
+    /// Each byte holds its offset modulo 256
+    Index,
+    /// Bytes alternate between 0xAA and 0x55
+    Alternating,
+    /// Pseudo-random bytes, seeded with --seed
+    Random,
+    /// Each byte has a single bit set, moving up one bit per byte
+    WalkingOnes,
+    /// Each 8-byte word holds its own offset
+    Address,
 }
 
 fn main() {
-    let args = Args::parse();
-    allocate_memory(&args.memory);
-    wait::wait(args.wait);
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if let Some(size) = args.size.take() {
+        args.memory = size;
+    } else if matches.value_source("memory") == Some(ValueSource::DefaultValue)
+        && let Ok(size) = std::env::var(SIZE_VAR)
+    {
+        args.memory = size.trim().to_string();
+    }
+    if args.check {
+        check_memory(&args.memory);
+        return;
+    }
+    if args.max_allocatable {
+        match system::max_allocatable() {
+            Some(max) => println!("About {}B ({}) can be allocated safely.", max.bytes(), max),
+            None => {
+                eprintln!("Unable to determine the memory of this machine.");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(passes) = args.benchmark {
+        run_benchmark(&args, passes);
+        return;
+    }
+    if let Some(passes) = args.memtest {
+        run_memtest(&args, passes);
+        return;
+    }
+    if args.interactive {
+        let config = |size: &str| {
+            let mut args = args.clone();
+            args.memory = size.to_string();
+            weight_config(&args)
+        };
+        if let Err(err) = interactive::interactive(std::io::stdin().lock(), std::io::stdout(), config) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.mirror {
+        run_mirror(&args);
+        return;
+    }
+    if args.grow_until_fail {
+        run_grow_until_fail(&args);
+        return;
+    }
+    let allocation = allocate_memory(&args);
+    #[cfg(feature = "metrics")]
+    let _metrics = args.metrics_addr.as_ref().map(|addr| {
+        weight::metrics::metrics_server(addr.as_str(), allocation.stats_handle()).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    let (interrupt, interrupted) = mpsc::channel();
+    if let Err(err) = ctrlc::set_handler(move || {
+        let _ = interrupt.send(());
+    }) {
+        eprintln!("Failed to handle CTRL + C, it will exit without releasing the memory first: {}", err);
+    }
+    wait::wait(args.wait, &interrupted);
+    if !allocation.is_locked() {
+        let stats = allocation.stats();
+        println!("Completed {} churn cycles.", stats.cycles_completed);
+        if let Some(faults) = stats.churn_page_faults {
+            println!("Churning took {} minor and {} major page faults.", faults.minor, faults.major);
+        }
+    }
+}
+
+/// Parses an interval such as `50ms` or `2s`, a plain number is in milliseconds.
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.strip_suffix("ms") {
+        Some(number) => (number, 1),
+        None => match value.strip_suffix('s') {
+            Some(number) => (number, 1000),
+            None => (value, 1),
+        },
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("Invalid interval: '{}'. Expected e.g. 50ms, 2s or 100", value))
+}
+
+/// Parses a byte value such as `128` or `0x80`.
+fn parse_byte(value: &str) -> Result<u8, String> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("Invalid byte value: '{}'. Expected e.g. 1, 255 or 0xFF", value))
+}
+
+/// Parses a memory size such as `64MB` into bytes.
+fn parse_size(value: &str) -> Result<usize, String> {
+    parse::parse_memory_string(value)
+        .map(ByteSize::bytes)
+        .map_err(|err| err.to_string())
+}
+
+fn check_memory(memory: &str) {
+    match parse::validate(memory) {
+        Ok(validation) => {
+            println!("{} = {}B ({})", memory, validation.bytes, ByteSize::from(validation.bytes));
+            if validation.large {
+                println!("Allocating this much memory may cause system instability.");
+            }
+        }
+        Err(err) => {
+            eprintln!("Invalid memory size: {} use -h to see examples", err);
+            // Point at where the size stops making sense
+            if let Err(located) = parse::parse_memory_string_with_position(memory) {
+                eprintln!("  {}\n  {}^", memory, " ".repeat(located.column - 1));
+            }
+            std::process::exit(1);
+        }
+    }
 }
 
-fn allocate_memory(memory: &str) {
-    println!("Writing {} of memory...", memory);
-    match memory::allocate_memory(memory) {
-        Ok(bytes) => {
-            println!("Continuously modifying {} = {}B of memory.", memory, bytes);
+fn run_benchmark(args: &Args, passes: usize) {
+    println!("Benchmarking {} of memory with {} passes...", args.memory, passes);
+    match build_config(args).and_then(|config| benchmark::benchmark(config, passes)) {
+        Ok(result) => {
+            for (phase, throughput) in [("Increment", result.increment), ("Decrement", result.decrement)] {
+                println!(
+                    "{}: min {:.2} GB/s, max {:.2} GB/s, mean {:.2} GB/s",
+                    phase, throughput.min, throughput.max, throughput.mean
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("Error benchmarking memory: {} use -h to see examples", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_mirror(args: &Args) {
+    println!("Writing {} of memory on every NUMA node...", args.memory);
+    let mirror = mirror::allocate_mirrored(|| weight_config(args)).unwrap_or_else(|err| {
+        eprintln!("Error allocating memory: {} use -h to see examples", err);
+        std::process::exit(1);
+    });
+    for region in mirror.regions() {
+        let bytes = region.allocation.bytes();
+        match region.node {
+            Some(node) => println!("Node {}: {}B ({}) of memory.", node, bytes, ByteSize::from(bytes)),
+            None => println!("No NUMA nodes, holding a single region of {}B ({}).", bytes, ByteSize::from(bytes)),
+        }
+    }
+    let total = mirror.total_bytes();
+    println!("Holding {}B ({}) in total.", total, ByteSize::from(total));
+    let (interrupt, interrupted) = mpsc::channel();
+    if let Err(err) = ctrlc::set_handler(move || {
+        let _ = interrupt.send(());
+    }) {
+        eprintln!("Failed to handle CTRL + C, it will exit without releasing the memory first: {}", err);
+    }
+    wait::wait(args.wait, &interrupted);
+    for region in mirror.regions() {
+        let cycles = region.allocation.stats().cycles_completed;
+        match region.node {
+            Some(node) => println!("Node {} completed {} churn cycles.", node, cycles),
+            None => println!("Completed {} churn cycles.", cycles),
+        }
+    }
+}
+
+fn run_grow_until_fail(args: &Args) {
+    let mut limits = probe::GrowLimits::default();
+    if let Some(keep_available) = args.keep_available {
+        limits.keep_available = keep_available;
+    }
+    println!(
+        "Growing in steps of {} until allocating fails or less than {} is available...",
+        args.memory,
+        ByteSize::from(limits.keep_available)
+    );
+    match probe::grow_until_fail(|| weight_config(args), limits) {
+        Ok(threshold) => {
+            println!(
+                "Held at most {}B ({}) in {} steps.",
+                threshold.max_bytes,
+                ByteSize::from(threshold.max_bytes),
+                threshold.steps
+            );
+            match threshold.reason {
+                probe::StopReason::AllocationFailed(err) => println!("Stopped because allocating failed: {}", err),
+                probe::StopReason::LowMemory { available } => {
+                    println!("Stopped with {} available, before running out of memory.", ByteSize::from(available))
+                }
+                probe::StopReason::MemoryPressure(pressure) => println!("Stopped at a memory pressure of {:.2}%.", pressure),
+                probe::StopReason::MaxBytes => println!("Stopped at the maximum size."),
+            }
+        }
+        Err(err) => {
+            eprintln!("Error allocating memory: {} use -h to see examples", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_memtest(args: &Args, passes: usize) {
+    println!("Testing {} of memory with {} passes...", args.memory, passes);
+    match build_config(args).and_then(|config| memtest::memtest(config, passes)) {
+        Ok(report) => {
+            for result in &report.results {
+                let verification = &result.verification;
+                match verification.mismatches.first() {
+                    None => println!("Pass {}, {:?}: ok", result.pass + 1, result.pattern),
+                    Some(first) => println!(
+                        "Pass {}, {:?}: {} bad bytes, the first at offset {:#x}",
+                        result.pass + 1,
+                        result.pattern,
+                        verification.mismatch_count,
+                        first
+                    ),
+                }
+            }
+            if !report.is_ok() {
+                std::process::exit(2);
+            }
+        }
+        Err(err) => {
+            eprintln!("Error testing memory: {} use -h to see examples", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// A random seed for when --seed isn't given.
+fn rand_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new().build_hasher().finish()
+}
+
+/// Builds the allocation settings from the command line arguments, which prints their warnings.
+fn build_config(args: &Args) -> Result<WeightConfig, WeightError> {
+    weight_config(args).build()
+}
+
+fn weight_config(args: &Args) -> WeightConfigBuilder {
+    let mut config = WeightConfig::new(&args.memory)
+        .workers(args.workers)
+        .interval(args.interval)
+        .align_to_clock(args.align_to_clock)
+        .warmup_delay(args.warmup_delay)
+        .lazy(args.lazy)
+        .best_effort(args.best_effort)
+        .supervise(args.supervise)
+        .chunk_size(args.chunk_size)
+        .touch_mode(if args.read_only { TouchMode::ReadOnly } else { TouchMode::ReadWrite })
+        .fill_pattern(match args.fill_pattern {
+            Pattern::Zeros => FillPattern::Zeros,
+            Pattern::Ones => FillPattern::Ones,
+            Pattern::Index => FillPattern::Index,
+            Pattern::Alternating => FillPattern::Alternating,
+            Pattern::Random => FillPattern::Random(args.seed.unwrap_or_else(rand_seed)),
+            Pattern::WalkingOnes => FillPattern::WalkingOnes,
+            Pattern::Address => FillPattern::Address,
+        })
+        .churn_op(match (args.churn_add, args.churn_xor) {
+            (_, Some(mask)) => ChurnOp::Xor(mask),
+            (Some(value), None) => ChurnOp::Add(value),
+            (None, None) => ChurnOp::default(),
+        })
+        .access_pattern(match (args.random_access, args.reverse_access, args.strided_access) {
+            (true, _, _) => AccessPattern::Random,
+            (_, true, _) => AccessPattern::Reverse,
+            (_, _, Some(step)) => AccessPattern::Strided(step),
+            _ => AccessPattern::Sequential,
+        })
+        .stride(if args.cache_line_stride { Stride::CacheLine } else { Stride::Byte })
+        .lock(args.lock)
+        .raise_lock_limit(args.raise_lock_limit)
+        .huge_pages(args.huge_pages)
+        .disable_thp(args.disable_thp)
+        .zero_on_drop(args.zero_on_drop)
+        .strict(args.strict);
+    if args.mmap {
+        config = config.backend(Backend::Mmap(MmapFlags {
+            populate: args.mmap_populate,
+            locked: args.mmap_locked,
+            guard_page: args.mmap_guard_page,
+        }));
+    }
+    if args.virtual_alloc {
+        config = config.backend(Backend::VirtualAlloc(VirtualAllocFlags {
+            defer_commit: args.virtual_alloc_defer_commit,
+            large_pages: args.virtual_alloc_large_pages,
+        }));
+    }
+    if args.shared_memory {
+        config = config.backend(Backend::Shared);
+    }
+    if args.auto_backend {
+        config = config.backend(Backend::Auto);
+    }
+    if let Some(nodes) = &args.numa_nodes {
+        config = config.numa_nodes(nodes.clone());
+    }
+    if let Some(cpus) = &args.cpus {
+        config = config.cpus(cpus.clone());
+    }
+    if let Some(budget) = args.cpu_budget {
+        config = config.cpu_budget(budget);
+    }
+    if let Some(seed) = args.seed {
+        config = config.seed(seed);
+    }
+    if let Some(max_bytes) = args.max_size {
+        config = config.max_bytes(max_bytes);
+    }
+    if let Some(margin) = args.safety_margin {
+        config = config.safety_margin(margin);
+    }
+    if args.no_safety_margin {
+        config = config.safety_margin(0);
+    }
+    if let Some(threshold) = args.large_allocation_threshold {
+        config = config.large_allocation_threshold(threshold as u64);
+    }
+    if let Some(steps) = args.ramp_steps {
+        config = config.ramp(Ramp {
+            steps,
+            delay: Duration::from_millis(args.ramp_delay),
+            on_step: Some(Box::new(|bytes| println!("Allocated {}B...", bytes))),
+        });
+    }
+    if let Some(rate) = args.fill_rate {
+        config = config.fill_rate(rate as u64);
+    }
+    if let Some(interval) = args.full_touch_interval {
+        config = config.full_touch_interval(interval);
+    }
+    if let (Some(min), Some(max)) = (args.adaptive_min_interval, args.adaptive_max_interval) {
+        config = config.adaptive_interval(AdaptiveInterval { min, max });
+    }
+    if let Some(interval) = args.watchdog {
+        config = config.watchdog(Watchdog {
+            interval,
+            threshold: args.watchdog_threshold,
+        });
+    }
+    if let Some(path) = &args.csv_trace {
+        config = config.csv_trace(CsvTrace {
+            path: path.clone(),
+            interval: args.csv_trace_interval,
+        });
+    }
+    if let Some(bytes) = args.disk_flush {
+        config = config.disk_flush(DiskFlush {
+            interval: args.disk_flush_interval,
+            bytes,
+        });
+    }
+    if args.retry_attempts != 1 {
+        config = config.retry(Retry {
+            attempts: args.retry_attempts,
+            backoff: args.retry_backoff,
+        });
+    }
+    config
+}
+
+fn allocate_memory(args: &Args) -> alloc::Allocation {
+    println!("Writing {} of memory...", args.memory);
+    let allocate = |config: WeightConfig| {
+        if args.print_config {
+            println!("{}", config.to_json());
+        }
+        if !args.target_rss {
+            return alloc::allocate(config);
+        }
+        alloc::allocate_target_rss(config).map(|target| {
+            println!(
+                "{}B ({}) became resident {} {} adjustments.",
+                target.resident_bytes,
+                ByteSize::from(target.resident_bytes),
+                if target.converged { "after" } else { "and missed the target after" },
+                target.iterations
+            );
+            target.allocation
+        })
+    };
+    match build_config(args).and_then(allocate) {
+        Ok(allocation) => {
+            let size = ByteSize::from(allocation.bytes());
+            if allocation.is_locked() {
+                println!("Locked {} = {}B ({}) of memory.", args.memory, allocation.bytes(), size);
+            } else if args.read_only {
+                println!("Continuously reading {} = {}B ({}) of memory.", args.memory, allocation.bytes(), size);
+            } else {
+                println!("Continuously modifying {} = {}B ({}) of memory.", args.memory, allocation.bytes(), size);
+            }
+            if allocation.shortfall() > 0 {
+                println!(
+                    "Only {}B of the requested {}B could be reserved, {} short.",
+                    allocation.bytes(),
+                    allocation.bytes() + allocation.shortfall(),
+                    ByteSize::from(allocation.shortfall())
+                );
+            }
+            if let Some(committed) = allocation.committed_bytes() {
+                println!("{}B of {}B committed to physical memory.", committed, allocation.bytes());
+            }
+            if let Some(huge) = allocation.thp_bytes() {
+                println!("{}B of {}B backed by transparent huge pages.", huge, allocation.bytes());
+            }
+            let locked = allocation.stats().locked_bytes;
+            if args.lock && !allocation.is_locked() {
+                println!("Only {}B of {}B could be locked, the rest is kept resident by churning it.", locked, allocation.bytes());
+            }
+            if let Some(name) = allocation.shared_memory_name() {
+                println!("Memory is shared as {}.", name);
+            }
+            if let Some(faults) = allocation.stats().fill_page_faults {
+                println!("Filling took {} minor and {} major page faults.", faults.minor, faults.major);
+            }
+            if let Some(process) = system::process_memory() {
+                println!(
+                    "Process is using {} resident and {} virtual memory.",
+                    ByteSize::from(process.resident_bytes),
+                    ByteSize::from(process.virtual_bytes)
+                );
+            }
+            match system::swap_info() {
+                Some(swap) if swap.is_configured() => println!(
+                    "Swap is {} of which {} is in use.",
+                    ByteSize::from(swap.total_bytes),
+                    ByteSize::from(swap.used_bytes)
+                ),
+                Some(_) => println!("No swap is configured, so memory can't be swapped out."),
+                None => {}
+            }
+            allocation
         }
         Err(err) => {
-            eprintln!("Error parsing memory string: {} use -h to see examples", err);
+            eprintln!("Error allocating memory: {} use -h to see examples", err);
             std::process::exit(1);
         }
     }
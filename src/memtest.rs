@@ -0,0 +1,145 @@
+//! Testing RAM for faults by writing patterns to an allocation and reading them back.
+
+use std::thread;
+
+use crate::alloc::{self, ChurnSettings, Placement, Verification};
+use crate::config::{FillPattern, WeightConfig};
+use crate::error::WeightError;
+use crate::lock;
+
+/// Patterns written and checked by [`memtest`] in every pass, in order.
+pub const MEMTEST_PATTERNS: [FillPattern; 4] = [
+    FillPattern::Zeros,
+    FillPattern::Ones,
+    FillPattern::WalkingOnes,
+    FillPattern::Address,
+];
+
+/// Outcome of writing one pattern to the whole allocation and reading it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternResult {
+    /// Pass the pattern was checked in, starting at 0.
+    pub pass: usize,
+    /// The pattern that was written.
+    pub pattern: FillPattern,
+    /// Bytes that didn't read back as written, by their offset in the allocation.
+    pub verification: Verification,
+}
+
+impl PatternResult {
+    /// Whether every byte read back as written.
+    pub fn is_ok(&self) -> bool {
+        self.verification.is_ok()
+    }
+}
+
+/// Result of [`memtest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Memtest {
+    /// Number of bytes that were tested.
+    pub bytes: usize,
+    /// Number of passes over all patterns.
+    pub passes: usize,
+    /// One result per pattern and pass, in the order they were run.
+    pub results: Vec<PatternResult>,
+}
+
+impl Memtest {
+    /// Whether every pattern read back as written in every pass.
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(PatternResult::is_ok)
+    }
+
+    /// The patterns that didn't read back as written.
+    pub fn failures(&self) -> impl Iterator<Item = &PatternResult> {
+        self.results.iter().filter(|result| !result.is_ok())
+    }
+}
+
+/// Allocates the memory described by `config` and tests it for faults the way memtest does
+/// from userspace: each of `passes` passes writes every pattern of [`MEMTEST_PATTERNS`] to the
+/// whole allocation and then reads it back. The workers and their NUMA nodes or CPU cores
+/// split the memory between them, and with
+/// [`lock`](crate::config::WeightConfigBuilder::lock) the memory is locked first so it stays
+/// in the same physical pages for the whole test. The memory is released again before
+/// returning.
+///
+/// Mismatches are reported by their offset in the allocation. The operating system decides
+/// which physical pages back it, so this narrows a fault down to an allocation and an offset
+/// rather than a physical address.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let report = weight::memtest::memtest(weight::WeightConfig::new("1MB").build()?, 2)?;
+/// assert_eq!(report.results.len(), 2 * weight::memtest::MEMTEST_PATTERNS.len());
+/// assert!(report.is_ok(), "bad RAM: {:?}", report.failures().collect::<Vec<_>>());
+/// # Ok(())
+/// # }
+/// ```
+pub fn memtest(mut config: WeightConfig, passes: usize) -> Result<Memtest, WeightError> {
+    if passes == 0 {
+        return Err(WeightError::InvalidConfig("Memtest pass count must be at least 1".to_string()));
+    }
+    let settings = ChurnSettings::new(&config);
+    let (mut buffer, _) = alloc::allocate_buffer(&mut config)?;
+    let mut locked;
+    let data: &mut [u8] = if config.lock {
+        locked = lock::lock_buffer(buffer, config.raise_lock_limit)?;
+        locked.data_mut()
+    } else {
+        &mut buffer
+    };
+    let placement = Placement::new(config.workers, config.numa_nodes.take(), config.cpus.take());
+    let placement = &placement;
+    let bytes = data.len();
+    let range_len = alloc::range_len(bytes, config.workers, settings.chunk_size);
+
+    let mut results = Vec::with_capacity(passes * MEMTEST_PATTERNS.len());
+    for pass in 0..passes {
+        for pattern in MEMTEST_PATTERNS {
+            // The whole allocation is written before anything is read back, so the bytes
+            // come from RAM rather than from the CPU caches
+            thread::scope(|scope| {
+                for (i, range) in data.chunks_mut(range_len).enumerate() {
+                    scope.spawn(move || {
+                        placement.pin(i);
+                        alloc::write_pattern(range, i * range_len, pattern);
+                    });
+                }
+            });
+            let verification = thread::scope(|scope| {
+                let checks: Vec<_> = data
+                    .chunks(range_len)
+                    .enumerate()
+                    .map(|(i, range)| {
+                        scope.spawn(move || {
+                            placement.pin(i);
+                            alloc::verify_pattern(range, i * range_len, pattern)
+                        })
+                    })
+                    .collect();
+                checks.into_iter().fold(Verification::default(), |mut verification, check| {
+                    verification.merge(check.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)));
+                    verification
+                })
+            });
+            if !verification.is_ok() {
+                warn!(
+                    "Pass {} with the {:?} pattern found {} bad bytes, the first at offset {:#x}.",
+                    pass + 1,
+                    pattern,
+                    verification.mismatch_count,
+                    verification.mismatches[0]
+                );
+            }
+            results.push(PatternResult {
+                pass,
+                pattern,
+                verification,
+            });
+        }
+    }
+    debug!("Tested {} bytes with {} passes", bytes, passes);
+
+    Ok(Memtest { bytes, passes, results })
+}
@@ -0,0 +1,204 @@
+//! Exporting the statistics of an allocation in the Prometheus text format over HTTP.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::alloc::{self, Stats, StatsHandle};
+use crate::error::WeightError;
+
+/// Path the metrics are served on, every other path gets a 404.
+pub const METRICS_PATH: &str = "/metrics";
+/// How often the listener checks whether the server was dropped while no scrape comes in.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a scraper may take to send its request before the connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request head that is read, anything past it is ignored.
+const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+
+/// HTTP listener serving the statistics of an allocation on [`METRICS_PATH`], started by
+/// [`metrics_server`]. Scrapes are answered one at a time on a background thread.
+///
+/// Dropping the server stops listening and waits for the thread to exit.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Address the server is listening on, useful when it was bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts serving the statistics behind `stats` on `addr` in the Prometheus text format, so
+/// a long-running allocation can be scraped and graphed next to the system metrics it's
+/// putting pressure on. Fails with [`WeightError::InvalidConfig`] if `addr` isn't a valid
+/// address, with [`WeightError::Io`] if it can't be bound, e.g. because it's in use, and with
+/// [`WeightError::SpawnFailed`] if the thread answering scrapes can't be started.
+///
+/// ```
+/// # use std::io::{Read, Write};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let allocation = weight::allocate(weight::WeightConfig::new("1MB").build()?)?;
+/// let server = weight::metrics::metrics_server("127.0.0.1:0", allocation.stats_handle())?;
+///
+/// let mut stream = std::net::TcpStream::connect(server.local_addr())?;
+/// stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+/// let mut response = String::new();
+/// stream.read_to_string(&mut response)?;
+/// assert!(response.contains("weight_allocated_bytes 1000000"));
+///
+/// let taken = weight::metrics::metrics_server(server.local_addr(), allocation.stats_handle());
+/// assert!(matches!(taken, Err(weight::WeightError::Io(_))));
+/// let invalid = weight::metrics::metrics_server("not an address", allocation.stats_handle());
+/// assert!(matches!(invalid, Err(weight::WeightError::InvalidConfig(_))));
+/// # Ok(())
+/// # }
+/// ```
+pub fn metrics_server(addr: impl ToSocketAddrs, stats: StatsHandle) -> Result<MetricsServer, WeightError> {
+    let failed = |err: io::Error| {
+        let reason = format!("Failed to start the metrics server: {}", err);
+        // An address that doesn't parse is a bad config, one that's in use or not allowed isn't
+        match err.kind() {
+            io::ErrorKind::InvalidInput => WeightError::InvalidConfig(reason),
+            _ => WeightError::Io(reason),
+        }
+    };
+    let listener = TcpListener::bind(addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+        .map_err(failed)?;
+    let addr = listener.local_addr().map_err(failed)?;
+    let running = Arc::new(AtomicBool::new(true));
+
+    let thread_running = Arc::clone(&running);
+    let thread = thread::Builder::new()
+        .spawn(move || {
+            while thread_running.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(err) = respond(stream, &stats) {
+                            debug!("Failed to answer a metrics request: {}", err);
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                    Err(err) => {
+                        warn!("Failed to accept a metrics connection: {}", err);
+                        thread::sleep(ACCEPT_POLL_INTERVAL);
+                    }
+                }
+            }
+        })
+        .map_err(|err| alloc::spawn_failed("the metrics server", err))?;
+
+    info!("Serving metrics on http://{}{}", addr, METRICS_PATH);
+    Ok(MetricsServer {
+        addr,
+        running,
+        thread: Some(thread),
+    })
+}
+
+/// Reads the request head from `stream` and answers it with the metrics or an error status.
+fn respond(mut stream: TcpStream, stats: &StatsHandle) -> io::Result<()> {
+    // Accepted sockets can inherit non-blocking mode from the listener on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers don't matter, but are read so the client doesn't see a reset
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(METRICS_PATH)) => ("200 OK", render(&stats.stats())),
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Formats `stats` in the Prometheus text exposition format.
+fn render(stats: &Stats) -> String {
+    let mut body = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+        let _ = write!(body, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n");
+    };
+    metric("weight_allocated_bytes", "gauge", "Number of bytes held by the allocation.", &stats.allocated_bytes);
+    metric(
+        "weight_cycles_completed_total",
+        "counter",
+        "Number of churn cycles completed, summed over all worker threads.",
+        &stats.cycles_completed,
+    );
+    if let Some(resident) = stats.resident_bytes {
+        metric("weight_resident_bytes", "gauge", "Resident set size of the whole process in bytes.", &resident);
+    }
+    metric(
+        "weight_locked_bytes",
+        "gauge",
+        "Number of bytes of the allocation locked in physical memory.",
+        &stats.locked_bytes,
+    );
+    if let Some(huge) = stats.thp_bytes {
+        metric(
+            "weight_thp_bytes",
+            "gauge",
+            "Number of bytes of the allocation backed by transparent huge pages.",
+            &huge,
+        );
+    }
+    metric(
+        "weight_worker_restarts_total",
+        "counter",
+        "Number of times a churn worker panicked and was restarted.",
+        &stats.worker_restarts,
+    );
+    metric(
+        "weight_cpu_fraction",
+        "gauge",
+        "Fraction of one CPU spent churning since the allocation was made.",
+        &stats.cpu_fraction,
+    );
+    if let Some(faults) = stats.churn_page_faults {
+        metric(
+            "weight_minor_page_faults_total",
+            "counter",
+            "Minor page faults of the whole process since the memory was filled.",
+            &faults.minor,
+        );
+        metric(
+            "weight_major_page_faults_total",
+            "counter",
+            "Major page faults of the whole process since the memory was filled, e.g. from swap.",
+            &faults.major,
+        );
+    }
+    body
+}
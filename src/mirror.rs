@@ -0,0 +1,112 @@
+//! Allocating the same region once on every NUMA node (Linux only), to compare local and remote
+//! memory access from the same process.
+
+use crate::alloc::{self, Allocation};
+use crate::config::WeightConfigBuilder;
+use crate::error::WeightError;
+use crate::numa;
+
+/// One of the regions of a [`Mirror`].
+pub struct MirrorRegion {
+    /// NUMA node the memory and its workers are placed on, `None` for the single region of a
+    /// machine without NUMA nodes.
+    pub node: Option<usize>,
+    /// The region itself.
+    pub allocation: Allocation,
+}
+
+/// Identical regions on every NUMA node, returned by [`allocate_mirrored`].
+///
+/// Dropping it stops and releases all of them.
+pub struct Mirror {
+    regions: Vec<MirrorRegion>,
+}
+
+impl Mirror {
+    /// The regions in the order of their nodes.
+    pub fn regions(&self) -> &[MirrorRegion] {
+        &self.regions
+    }
+
+    /// The region placed on `node`, if there is one.
+    pub fn get(&self, node: usize) -> Option<&Allocation> {
+        self.regions
+            .iter()
+            .find(|region| region.node == Some(node))
+            .map(|region| &region.allocation)
+    }
+
+    /// Whether the regions are spread over NUMA nodes rather than a single unplaced region.
+    pub fn is_mirrored(&self) -> bool {
+        self.regions.iter().any(|region| region.node.is_some())
+    }
+
+    /// Total number of bytes held by all regions.
+    pub fn total_bytes(&self) -> usize {
+        self.regions.iter().map(|region| region.allocation.bytes()).sum()
+    }
+
+    /// Hands out the regions, which keep running on their own.
+    pub fn into_regions(self) -> Vec<MirrorRegion> {
+        self.regions
+    }
+}
+
+/// Allocates the region `config` describes once on every online NUMA node, each with its
+/// memory bound to the node and as many workers as the config asks for pinned to the CPUs of
+/// the node, e.g. to measure bandwidth and latency from CPUs of one node to the memory of
+/// every other node. `config` is called once per node, so every region can get its own
+/// callbacks.
+///
+/// On machines with fewer than two NUMA nodes and on platforms other than Linux there is
+/// nothing to mirror, and a single region is allocated without placing it. Choosing the nodes
+/// or the CPU cores in the config fails, since the regions are placed on all of them. Fails
+/// if any region can't be allocated, releasing those that were.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let mirror = weight::mirror::allocate_mirrored(|| weight::WeightConfig::new("1MB").workers(2))?;
+/// for region in mirror.regions() {
+///     println!("Node {:?} holds {} bytes", region.node, region.allocation.bytes());
+/// }
+/// assert_eq!(mirror.total_bytes(), mirror.regions().len() * 1_000_000);
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_mirrored(config: impl Fn() -> WeightConfigBuilder) -> Result<Mirror, WeightError> {
+    let template = config().build()?;
+    if template.numa_nodes.is_some() || template.cpus.is_some() {
+        return Err(WeightError::InvalidConfig(
+            "Mirrored regions are placed on every NUMA node, so they can't choose nodes or CPU cores".to_string(),
+        ));
+    }
+    let workers = template.workers;
+    drop(template);
+
+    let nodes = numa::online_nodes();
+    if nodes.len() < 2 {
+        info!("There are no NUMA nodes to mirror the allocation on, allocating a single region");
+        let allocation = alloc::allocate(config().build()?)?;
+        return Ok(Mirror {
+            regions: vec![MirrorRegion { node: None, allocation }],
+        });
+    }
+    let regions = nodes
+        .into_iter()
+        .map(|node| {
+            let allocation = alloc::allocate(config().numa_nodes(vec![node; workers]).build()?)?;
+            Ok(MirrorRegion {
+                node: Some(node),
+                allocation,
+            })
+        })
+        .collect::<Result<Vec<_>, WeightError>>()?;
+    let mirror = Mirror { regions };
+    info!(
+        "Mirrored {} bytes on {} NUMA nodes, {} bytes in total",
+        mirror.regions[0].allocation.bytes(),
+        mirror.regions.len(),
+        mirror.total_bytes()
+    );
+    Ok(mirror)
+}
@@ -0,0 +1,123 @@
+//! Placing memory and worker threads on specific NUMA nodes (Linux only).
+
+use std::mem::MaybeUninit;
+
+use crate::error::WeightError;
+
+/// Checks that all `nodes` exist on this machine.
+#[cfg(target_os = "linux")]
+pub(crate) fn check_nodes(nodes: &[usize]) -> Result<(), WeightError> {
+    for &node in nodes {
+        if node >= MAX_NODES {
+            return Err(WeightError::InvalidConfig(format!("NUMA node {} is out of range", node)));
+        }
+        if node_cpus(node).is_none() {
+            return Err(WeightError::Unsupported(format!(
+                "NUMA node {} doesn't exist on this system (see /sys/devices/system/node)",
+                node
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that all `nodes` exist on this machine.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn check_nodes(_nodes: &[usize]) -> Result<(), WeightError> {
+    Err(WeightError::Unsupported("NUMA placement is only supported on Linux".to_string()))
+}
+
+/// Highest number of nodes that fit in the node mask passed to `mbind`.
+#[cfg(target_os = "linux")]
+const MAX_NODES: usize = 1024;
+
+/// Makes the kernel back the pages of `range` with memory from `node` once they're first
+/// touched. Only whole pages inside the range are bound. The range is expected to be
+/// not yet initialized so none of its pages have been faulted in.
+#[cfg(target_os = "linux")]
+pub(crate) fn bind(range: &mut [MaybeUninit<u8>], node: usize) -> Result<(), WeightError> {
+    const MPOL_BIND: libc::c_int = 2;
+    const BITS: usize = libc::c_ulong::BITS as usize;
+
+    let page_size = crate::system::page_size();
+    let start = (range.as_ptr() as usize).next_multiple_of(page_size);
+    let end = (range.as_ptr() as usize + range.len()) / page_size * page_size;
+    if end <= start {
+        return Ok(());
+    }
+
+    let mut mask = [0 as libc::c_ulong; MAX_NODES / BITS];
+    mask[node / BITS] |= 1 << (node % BITS);
+    // SAFETY: the address range lies within `range` and the mask holds MAX_NODES bits.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            start,
+            end - start,
+            MPOL_BIND,
+            mask.as_ptr(),
+            MAX_NODES,
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(WeightError::Unsupported(format!(
+            "Failed to bind memory to NUMA node {}: {}",
+            node,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn bind(_range: &mut [MaybeUninit<u8>], _node: usize) -> Result<(), WeightError> {
+    Err(WeightError::Unsupported("NUMA placement is only supported on Linux".to_string()))
+}
+
+/// Restricts the calling thread to the CPUs of `node`.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(node: usize) -> Result<(), WeightError> {
+    let cpus = node_cpus(node)
+        .ok_or_else(|| WeightError::Unsupported(format!("NUMA node {} doesn't exist on this system", node)))?;
+    crate::affinity::pin_current_thread(&cpus)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_current_thread(_node: usize) -> Result<(), WeightError> {
+    Err(WeightError::Unsupported("NUMA placement is only supported on Linux".to_string()))
+}
+
+/// NUMA nodes that are online, read from sysfs. Empty if the kernel doesn't expose them.
+#[cfg(target_os = "linux")]
+pub(crate) fn online_nodes() -> Vec<usize> {
+    std::fs::read_to_string("/sys/devices/system/node/online")
+        .ok()
+        .and_then(|online| parse_cpu_list(online.trim()))
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn online_nodes() -> Vec<usize> {
+    Vec::new()
+}
+
+/// CPUs that belong to `node`, read from sysfs. `None` if the node doesn't exist.
+#[cfg(target_os = "linux")]
+fn node_cpus(node: usize) -> Option<Vec<usize>> {
+    let cpulist = std::fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node)).ok()?;
+    parse_cpu_list(cpulist.trim())
+}
+
+/// Parses a CPU or node list in the kernel's format, e.g. `0-3,8,10-11`.
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<usize>().ok()?..=last.parse::<usize>().ok()?),
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
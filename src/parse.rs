@@ -0,0 +1,808 @@
+//! Parsing human readable memory sizes such as `2GB` or `80%`, usable on its own without
+//! allocating anything.
+
+use core::fmt;
+use core::ops::{Add, Sub};
+
+use crate::alloc_crate::format;
+use crate::alloc_crate::string::ToString;
+use crate::error::WeightError;
+#[cfg(feature = "std")]
+use crate::system;
+
+/// Sizes above this (100GiB) may cause system instability, so building a config for them
+/// reports a [`ConfigWarning::LargeAllocation`] unless another threshold is set with
+/// [`WeightConfigBuilder::large_allocation_threshold`].
+///
+/// [`ConfigWarning::LargeAllocation`]: crate::config::ConfigWarning::LargeAllocation
+/// [`WeightConfigBuilder::large_allocation_threshold`]: crate::config::WeightConfigBuilder::large_allocation_threshold
+pub const LARGE_ALLOCATION_THRESHOLD: u64 = 100 * 1024 * 1024 * 1024;
+
+/// Whether allocating `bytes` is dangerously large by the policy the library uses itself,
+/// i.e. above [`LARGE_ALLOCATION_THRESHOLD`], so callers can pre-flight sizes the same way.
+///
+/// ```
+/// # use weight::parse::{is_large_allocation, LARGE_ALLOCATION_THRESHOLD};
+/// assert!(!is_large_allocation(1_000_000_000));
+/// # #[cfg(target_pointer_width = "64")] {
+/// assert!(!is_large_allocation(LARGE_ALLOCATION_THRESHOLD as usize));
+/// assert!(is_large_allocation(LARGE_ALLOCATION_THRESHOLD as usize + 1));
+/// # }
+/// ```
+pub fn is_large_allocation(bytes: usize) -> bool {
+    exceeds_threshold(bytes, LARGE_ALLOCATION_THRESHOLD)
+}
+
+/// Whether `bytes` is above `threshold`, the check behind [`is_large_allocation`] for
+/// thresholds that were changed.
+pub(crate) fn exceeds_threshold(bytes: usize, threshold: u64) -> bool {
+    bytes as u64 > threshold
+}
+// Percentage based requests are capped at this fraction of total or available memory to leave
+// room for the OS
+const MAX_MEMORY_FRACTION: f64 = 0.9;
+
+/// Marks a percentage of available instead of total memory, as in `avail:70%`.
+const AVAILABLE_PREFIX: &str = "avail:";
+/// Marks a size to leave free of the available memory, as in `leave:1GB`.
+const HEADROOM_PREFIX: &str = "leave:";
+/// Separates a multiple from the CPU cache it's a multiple of, as in `2x-l3`.
+const CACHE_SEPARATOR: &str = "x-l";
+
+/// Size units [`parse_memory_string`] accepts, with the number of bytes each stands for. This
+/// is the only list of units, both to recognize a unit after a number and to convert it, so
+/// the two can't disagree. Units are matched case-insensitively, except that a lowercase `p`
+/// is a memory page, whose size depends on the system and which isn't listed.
+///
+/// ```
+/// # use weight::parse::SIZE_UNITS;
+/// for &(unit, multiplier) in SIZE_UNITS {
+///     let bytes = |size: &str| weight::parse_memory_string(size).map(|size| size.bytes() as u64);
+///     assert_eq!(bytes(&format!("1{}", unit)), Ok(multiplier), "{}", unit);
+///     assert_eq!(bytes(&format!("2{}", unit.to_ascii_uppercase())), Ok(2 * multiplier), "{}", unit);
+/// }
+/// ```
+pub const SIZE_UNITS: &[(&str, u64)] = &[
+    ("B", 1),
+    ("K", 1000),
+    ("KB", 1000),
+    ("M", 1000 * 1000),
+    ("MB", 1000 * 1000),
+    ("G", 1000 * 1000 * 1000),
+    ("GB", 1000 * 1000 * 1000),
+    ("T", 1000 * 1000 * 1000 * 1000),
+    ("TB", 1000 * 1000 * 1000 * 1000),
+    ("P", 1000 * 1000 * 1000 * 1000 * 1000),
+    ("PB", 1000 * 1000 * 1000 * 1000 * 1000),
+    ("KiB", 1024),
+    ("MiB", 1024 * 1024),
+    ("GiB", 1024 * 1024 * 1024),
+    ("TiB", 1024 * 1024 * 1024 * 1024),
+    ("PiB", 1024 * 1024 * 1024 * 1024 * 1024),
+];
+
+/// The unit that stands for a memory page, see [`SIZE_UNITS`].
+const PAGE_UNIT: &str = "p";
+
+/// A number of bytes, displayed using the largest binary unit that keeps the value at least 1,
+/// e.g. `ByteSize(1073741824)` is displayed as `1.00 GiB`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub usize);
+
+impl ByteSize {
+    /// Number of bytes.
+    pub fn bytes(self) -> usize {
+        self.0
+    }
+
+    /// The sum of both sizes, `None` if it doesn't fit in a `usize`. `+` saturates instead.
+    pub fn checked_add(self, other: ByteSize) -> Option<ByteSize> {
+        self.0.checked_add(other.0).map(ByteSize)
+    }
+
+    /// The difference of both sizes, `None` if `other` is larger. `-` saturates at zero
+    /// instead.
+    ///
+    /// ```
+    /// use weight::parse::ByteSize;
+    ///
+    /// assert_eq!(ByteSize(3).checked_sub(ByteSize(2)), Some(ByteSize(1)));
+    /// assert_eq!(ByteSize(1).checked_sub(ByteSize(2)), None);
+    /// assert_eq!(ByteSize(1) - ByteSize(2), ByteSize(0));
+    /// assert_eq!(ByteSize(usize::MAX) + ByteSize(1), ByteSize(usize::MAX));
+    /// ```
+    pub fn checked_sub(self, other: ByteSize) -> Option<ByteSize> {
+        self.0.checked_sub(other.0).map(ByteSize)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64;
+        let mut unit = UNITS[0];
+        for next in UNITS {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next;
+        }
+        write!(f, "{:.2} {}", value, unit)
+    }
+}
+
+impl From<usize> for ByteSize {
+    fn from(bytes: usize) -> Self {
+        ByteSize(bytes)
+    }
+}
+
+impl From<ByteSize> for usize {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+/// Saturates at `usize::MAX`, see [`ByteSize::checked_add`] to detect that.
+impl Add for ByteSize {
+    type Output = ByteSize;
+
+    fn add(self, other: ByteSize) -> ByteSize {
+        ByteSize(self.0.saturating_add(other.0))
+    }
+}
+
+/// Saturates at zero, see [`ByteSize::checked_sub`] to detect that.
+impl Sub for ByteSize {
+    type Output = ByteSize;
+
+    fn sub(self, other: ByteSize) -> ByteSize {
+        ByteSize(self.0.saturating_sub(other.0))
+    }
+}
+
+/// Parses a human readable memory size such as `100KB`, `1.5GiB` or `80%` into a number of bytes.
+///
+/// `KB`, `MB`, `GB`, `TB` and `PB` are powers of 1000, `KiB`, `MiB`, `GiB`, `TiB` and `PiB` are
+/// powers of 1024 and a percentage is a share of the total physical memory. A percentage
+/// prefixed with `avail:`, e.g. `avail:70%`, is a share of the memory that is available right
+/// now instead, which doesn't count memory already in use by other processes. A size prefixed
+/// with `leave:`, e.g. `leave:1GB`, is how much of the available memory to leave free, so it
+/// allocates the rest, or nothing if less than that is available. A multiple of a CPU cache
+/// size such as `2x-l3` is that many times the size of the level 1, 2 or 3 data cache, e.g.
+/// for cache thrashing tests that adapt to the host, see [`CacheSizes`]. The single letters
+/// `K`, `M`, `G`, `T` and `P` are short for the decimal units, so `1g` is `1GB`. A lowercase
+/// `p` is a number of memory pages of the system instead, e.g. `1024p` is 4MiB with 4KiB pages
+/// and 16MiB with 16KiB pages, which is the only unit where case matters. A number
+/// without a unit is a number of bytes. Underscores may separate digits, e.g. `1_000_000B`. Sizes can
+/// be added and subtracted, e.g. `2GB+512MB` or `4GB-256MB`, as long as the result isn't
+/// below zero.
+///
+/// Percentages range from `0%`, which is an empty allocation, to `100%`. Shares above 90% are
+/// capped at 90% with a warning, so a misconfigured percentage doesn't take all memory and
+/// bring the OOM killer down on the host. Percentages outside of that range, including
+/// negative ones, are an [`InvalidPercentage`](WeightError::InvalidPercentage) error.
+///
+/// Available memory is a moving target: it's read when the size is parsed, so the same string
+/// can give a different size a moment later, and it's an error on platforms where it can't
+/// be determined.
+///
+/// Parsing doesn't judge whether a size is reasonable, large sizes are reported when building
+/// a [`WeightConfig`](crate::WeightConfig) instead.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// assert_eq!(weight::parse_memory_string("2GiB")?.bytes(), 2 * 1024 * 1024 * 1024);
+/// assert_eq!(weight::parse_memory_string("2GB")?.bytes(), 2 * 1000 * 1000 * 1000);
+/// assert_eq!(weight::parse_memory_string("1g")?.bytes(), 1000 * 1000 * 1000);
+/// assert_eq!(weight::parse_memory_string("512m")?.bytes(), 512 * 1000 * 1000);
+/// assert_eq!(weight::parse_memory_string("4k")?.bytes(), 4000);
+/// assert_eq!(weight::parse_memory_string("4KiB")?.bytes(), 4096);
+/// assert!(weight::parse_memory_string("4Ki").is_err());
+/// assert_eq!(weight::parse_memory_string("1_000B")?.bytes(), 1000);
+/// assert!(weight::parse_memory_string("_1000B").is_err());
+/// assert!(weight::parse_memory_string("1__000B").is_err());
+/// # #[cfg(feature = "std")] {
+/// let page_size = weight::system::page_size();
+/// assert_eq!(weight::parse_memory_string("1p")?.bytes(), page_size);
+/// assert_eq!(weight::parse_memory_string("1024p")?.bytes(), 1024 * page_size);
+/// # }
+/// # #[cfg(target_pointer_width = "64")]
+/// assert_eq!(weight::parse_memory_string("1P")?.bytes(), 1000 * 1000 * 1000 * 1000 * 1000);
+/// assert_eq!(weight::parse_memory_string("2GB+512MB")?.bytes(), 2_512_000_000);
+/// assert_eq!(weight::parse_memory_string("1GB-1GB")?.bytes(), 0);
+/// assert_eq!(weight::parse_memory_string("1GB-2GB"), Err(weight::WeightError::Underflow));
+/// # #[cfg(feature = "std")]
+/// if weight::system::available_memory().is_some() {
+///     assert!(weight::parse_memory_string("avail:50%")?.bytes() > 0);
+///     assert_eq!(weight::parse_memory_string("leave:1PB")?.bytes(), 0);
+/// }
+/// assert!(weight::parse_memory_string("avail:1GB").is_err());
+/// # #[cfg(feature = "std")]
+/// if let Some(l3) = weight::system::cache_sizes().l3 {
+///     assert_eq!(weight::parse_memory_string("2x-l3")?.bytes(), 2 * l3);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The boundaries of percentages:
+///
+/// ```
+/// # use weight::WeightError;
+/// # fn main() -> Result<(), WeightError> {
+/// assert_eq!(weight::parse_memory_string("0%")?.bytes(), 0);
+/// # #[cfg(feature = "std")]
+/// if let Some(total) = weight::system::total_memory() {
+///     assert_eq!(weight::parse_memory_string("100%")?, weight::parse_memory_string("90%")?);
+///     assert!(weight::parse_memory_string("100%")?.bytes() < total);
+/// }
+/// assert_eq!(weight::parse_memory_string("150%"), Err(WeightError::InvalidPercentage("150".to_string())));
+/// assert_eq!(weight::parse_memory_string("-5%"), Err(WeightError::InvalidPercentage("-5".to_string())));
+/// assert_eq!(weight::parse_memory_string("avail:101%"), Err(WeightError::InvalidPercentage("101".to_string())));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Malformed sizes are told apart by what is wrong with them. Letters after the number that
+/// aren't a unit are an invalid unit, which names the unit and lists the valid ones, while a
+/// size without a number is an invalid format:
+///
+/// ```
+/// # use weight::WeightError;
+/// let unit = |unit: &str| Err(WeightError::InvalidUnit(unit.to_string()));
+/// assert_eq!(weight::parse_memory_string("12XB"), unit("XB"));
+/// assert_eq!(weight::parse_memory_string("1.5GIGS"), unit("GIGS"));
+/// assert_eq!(weight::parse_memory_string("1GB+2ZiB"), unit("ZiB"));
+/// assert_eq!(
+///     weight::parse_memory_string("12XB").unwrap_err().to_string(),
+///     "Invalid memory unit: 'XB'. Valid units: B, KB, MB, GB, TB, PB, KiB, MiB, GiB, TiB, PiB, the shorthands K, M, G, T, P or p for pages"
+/// );
+///
+/// let format = |input: &str| Err(WeightError::InvalidFormat(input.to_string()));
+/// assert_eq!(weight::parse_memory_string("GB"), format("GB"));
+/// assert_eq!(weight::parse_memory_string("1.GB"), format("1.GB"));
+/// assert_eq!(weight::parse_memory_string("12 GB"), format("12 GB"));
+/// assert_eq!(weight::parse_memory_string("1.5.5GB"), format("1.5.5GB"));
+/// assert!(weight::parse_memory_string("GB").unwrap_err().to_string().starts_with("Invalid memory string format: 'GB'"));
+/// ```
+///
+/// Sizes that don't fit in the address space are an overflow error, so on 32-bit targets
+/// `4GB` can't be allocated:
+///
+/// ```
+/// # use weight::WeightError;
+/// let result = weight::parse_memory_string("4GB");
+/// if cfg!(target_pointer_width = "32") {
+///     assert_eq!(result, Err(WeightError::Overflow));
+/// } else {
+///     assert_eq!(result.map(|size| size.bytes() as u64), Ok(4_000_000_000));
+/// }
+/// ```
+pub fn parse_memory_string(memory_str: &str) -> Result<ByteSize, WeightError> {
+    parse_bytes(memory_str, &cache_sizes()).map(ByteSize)
+}
+
+/// Sizes of the data caches of a CPU in bytes, which sizes such as `2x-l3` are multiples of.
+/// Levels that don't exist or can't be determined are `None`. The caches of the machine are
+/// returned by [`system::cache_sizes`](crate::system::cache_sizes).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheSizes {
+    /// Size of the level 1 data cache of a core.
+    pub l1: Option<usize>,
+    /// Size of the level 2 cache.
+    pub l2: Option<usize>,
+    /// Size of the level 3 cache, the last level on most CPUs, which is shared by several cores.
+    pub l3: Option<usize>,
+}
+
+impl CacheSizes {
+    /// Size of the cache at `level`, `None` for levels other than 1 to 3.
+    pub fn level(&self, level: u8) -> Option<usize> {
+        match level {
+            1 => self.l1,
+            2 => self.l2,
+            3 => self.l3,
+            _ => None,
+        }
+    }
+}
+
+/// Parses `memory_str` like [`parse_memory_string`], but takes multiples of cache sizes such
+/// as `2x-l3` from `caches` instead of the CPU of the machine, e.g. to plan sizes for another
+/// machine. A multiple of a cache that `caches` doesn't know is an
+/// [`Unsupported`](WeightError::Unsupported) error.
+///
+/// ```
+/// # use weight::parse::{parse_memory_string_with_caches, CacheSizes};
+/// # use weight::WeightError;
+/// # fn main() -> Result<(), WeightError> {
+/// let caches = CacheSizes { l1: Some(48 * 1024), l2: Some(2 * 1024 * 1024), l3: Some(32 * 1024 * 1024) };
+/// assert_eq!(parse_memory_string_with_caches("2x-l3", caches)?.bytes(), 64 * 1024 * 1024);
+/// assert_eq!(parse_memory_string_with_caches("0.5X-L2", caches)?.bytes(), 1024 * 1024);
+/// assert_eq!(parse_memory_string_with_caches("1x-l1+1KiB", caches)?.bytes(), 49 * 1024);
+/// assert_eq!(parse_memory_string_with_caches("4x-l3-64MiB", caches)?.bytes(), 64 * 1024 * 1024);
+/// assert_eq!(
+///     parse_memory_string_with_caches("2x-l3", CacheSizes { l3: None, ..caches }),
+///     Err(WeightError::Unsupported("Unable to determine the size of the L3 cache on this platform".to_string()))
+/// );
+/// assert_eq!(parse_memory_string_with_caches("2x-l4", caches), Err(WeightError::InvalidFormat("2x-l4".to_string())));
+/// assert_eq!(parse_memory_string_with_caches("x-l3", caches), Err(WeightError::InvalidFormat("x-l3".to_string())));
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_memory_string_with_caches(memory_str: &str, caches: CacheSizes) -> Result<ByteSize, WeightError> {
+    parse_bytes(memory_str, &caches).map(ByteSize)
+}
+
+/// Parses `memory_str` like [`parse_memory_string`], but refuses sizes above `max_bytes` with
+/// [`WeightError::ExceedsLimit`]. Unlike the large allocation threshold, which only warns,
+/// this is a hard limit that catches typos such as `20TB` instead of `20GB` before anything
+/// is allocated.
+///
+/// ```
+/// # use weight::parse::parse_memory_string_with_limit;
+/// # use weight::WeightError;
+/// # fn main() -> Result<(), WeightError> {
+/// assert_eq!(parse_memory_string_with_limit("2GB", 3_000_000_000)?.bytes(), 2_000_000_000);
+/// assert_eq!(
+///     parse_memory_string_with_limit("2GB", 1_000_000_000),
+///     Err(WeightError::ExceedsLimit { bytes: 2_000_000_000, limit: 1_000_000_000 })
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_memory_string_with_limit(memory_str: &str, max_bytes: usize) -> Result<ByteSize, WeightError> {
+    let bytes = parse_bytes(memory_str, &cache_sizes())?;
+    if bytes > max_bytes {
+        return Err(WeightError::ExceedsLimit { bytes, limit: max_bytes });
+    }
+    Ok(ByteSize(bytes))
+}
+
+/// Result of checking a memory size with [`validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validation {
+    /// The size in bytes.
+    pub bytes: usize,
+    /// Whether the size is above [`LARGE_ALLOCATION_THRESHOLD`], which may cause system
+    /// instability.
+    pub large: bool,
+}
+
+/// Checks that `memory` is a valid size in the format accepted by [`parse_memory_string`]
+/// without allocating anything or spawning threads, e.g. to verify a batch of sizes up front.
+/// Unlike parsing it also reports whether the size is large.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// # #[cfg(target_pointer_width = "64")] {
+/// let validation = weight::parse::validate("200GiB")?;
+/// assert_eq!(validation.bytes, 200 * 1024 * 1024 * 1024);
+/// assert!(validation.large);
+/// # }
+/// # Ok(())
+/// # }
+/// ```
+pub fn validate(memory: &str) -> Result<Validation, WeightError> {
+    let bytes = parse_bytes(memory, &cache_sizes())?;
+    Ok(Validation {
+        bytes,
+        large: is_large_allocation(bytes),
+    })
+}
+
+/// A size that [`parse_memory_string_with_position`] couldn't parse, with where in the string
+/// it went wrong. It displays as the [`error`](Self::error) alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset of the first character that couldn't be parsed, or the length of the string
+    /// when it ended where more was expected, e.g. after a trailing `+`.
+    pub position: usize,
+    /// Column of that character counted in characters from 1, for pointing at it in a UI.
+    pub column: usize,
+    /// What was wrong, as [`parse_memory_string`] reports it.
+    pub error: WeightError,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+impl From<ParseError> for WeightError {
+    fn from(error: ParseError) -> WeightError {
+        error.error
+    }
+}
+
+/// Parses `memory_str` like [`parse_memory_string`], but reports where the first invalid
+/// character is along with the error, e.g. to underline it in an editor or on the command
+/// line. A size that parses but can't be used, such as a percentage above 100% or one that
+/// overflows, points at the start of its number, and a subtraction that goes below zero at the
+/// minus sign.
+///
+/// ```
+/// # use weight::parse::parse_memory_string_with_position;
+/// # use weight::WeightError;
+/// # fn main() -> Result<(), weight::parse::ParseError> {
+/// assert_eq!(parse_memory_string_with_position("2GB+512MB")?.bytes(), 2_512_000_000);
+///
+/// let error = parse_memory_string_with_position("10 GB").unwrap_err();
+/// assert_eq!((error.position, error.column), (2, 3));
+/// assert_eq!(error.error, WeightError::InvalidFormat("10 GB".to_string()));
+/// assert_eq!(error.to_string(), WeightError::InvalidFormat("10 GB".to_string()).to_string());
+///
+/// assert_eq!(parse_memory_string_with_position("10XB").unwrap_err().position, 2);
+/// assert_eq!(parse_memory_string_with_position("1GB + 2ZiB").unwrap_err().position, 7);
+/// assert_eq!(parse_memory_string_with_position("1.GB").unwrap_err().position, 2);
+/// assert_eq!(parse_memory_string_with_position("1GB+").unwrap_err().position, 4);
+/// assert_eq!(parse_memory_string_with_position("1GB-2GB").unwrap_err().position, 3);
+/// assert_eq!(parse_memory_string_with_position("avail:150%").unwrap_err().position, 6);
+/// // A non-breaking space takes two bytes but one column
+/// let error = parse_memory_string_with_position("1GB+\u{a0}2XB").unwrap_err();
+/// assert_eq!((error.position, error.column), (7, 7));
+/// # Ok(())
+/// # }
+/// ```
+pub fn parse_memory_string_with_position(memory_str: &str) -> Result<ByteSize, ParseError> {
+    parse_bytes_at(memory_str, &cache_sizes())
+        .map(ByteSize)
+        .map_err(|(position, error)| ParseError {
+            position,
+            column: memory_str[..position].chars().count() + 1,
+            error,
+        })
+}
+
+/// Parses `memory_str` into a number of bytes, see [`parse_memory_string`].
+fn parse_bytes(memory_str: &str, caches: &CacheSizes) -> Result<usize, WeightError> {
+    parse_bytes_at(memory_str, caches).map_err(|(_, error)| error)
+}
+
+/// An error along with the byte offset it's at in the string that was being parsed.
+type Located = (usize, WeightError);
+
+/// Moves the offset of a [`Located`] error in a part of a string to the whole string, where
+/// the part starts at `offset`.
+fn shift(offset: usize) -> impl Fn(Located) -> Located {
+    move |(position, error)| (offset + position, error)
+}
+
+/// Parses `memory_str` into a number of bytes, failing with the offset of the first invalid
+/// character, see [`parse_memory_string_with_position`].
+fn parse_bytes_at(memory_str: &str, caches: &CacheSizes) -> Result<usize, Located> {
+    // A leading minus would otherwise be read as subtracting from an empty term
+    let leading = memory_str.len() - memory_str.trim_start().len();
+    if let Some(negative) = memory_str.trim_start().strip_prefix('-')
+        && let Some(number) = negative.trim().strip_suffix('%')
+    {
+        return Err((leading, WeightError::InvalidPercentage(format!("-{}", number))));
+    }
+    // Sizes can be combined into expressions such as 2GB+512MB, which are summed term by term.
+    // No unit ends in x, so a minus after one is part of a cache multiple such as 2x-l3
+    let mut previous = ' ';
+    let mut terms = memory_str.split_inclusive(|char: char| {
+        let operator = char == '+' || (char == '-' && !previous.eq_ignore_ascii_case(&'x'));
+        previous = char;
+        operator
+    });
+    let mut term = terms.next().unwrap_or_default();
+    let mut start = 0;
+    let mut bytes = 0usize;
+    let mut subtract = false;
+    loop {
+        let operator = term.chars().last().filter(|char| matches!(char, '+' | '-'));
+        let body = &term[..term.len() - operator.map_or(0, char::len_utf8)];
+        let offset = start + body.len() - body.trim_start().len();
+        let size = parse_term(body.trim(), caches).map_err(|(position, err)| match err {
+            WeightError::InvalidFormat(_) => (offset + position, WeightError::InvalidFormat(memory_str.to_string())),
+            err => (offset + position, err),
+        })?;
+        bytes = if subtract {
+            // The operator is just before the term
+            bytes.checked_sub(size).ok_or((start - 1, WeightError::Underflow))?
+        } else {
+            bytes.checked_add(size).ok_or((offset, WeightError::Overflow))?
+        };
+        match (operator, terms.next()) {
+            (Some(operator), Some(next)) => {
+                subtract = operator == '-';
+                start += term.len();
+                term = next;
+            }
+            (None, _) => return Ok(bytes),
+            // A trailing operator without a term after it
+            (Some(_), None) => return Err((memory_str.len(), WeightError::InvalidFormat(memory_str.to_string()))),
+        }
+    }
+}
+
+/// Parses a single size such as `512MB`, `80%`, `avail:70%` or `2x-l3` into a number of bytes.
+fn parse_term(term: &str, caches: &CacheSizes) -> Result<usize, Located> {
+    if let Some(percentage) = strip_prefix_ignore_case(term, AVAILABLE_PREFIX) {
+        return percentage_of_available_memory(percentage).map_err(shift(AVAILABLE_PREFIX.len()));
+    }
+    if let Some(headroom) = strip_prefix_ignore_case(term, HEADROOM_PREFIX) {
+        let offset = term.len() - headroom.trim_start().len();
+        return parse_size_at(headroom.trim())
+            .and_then(|headroom| leave_free(headroom).map_err(|err| (0, err)))
+            .map_err(shift(offset));
+    }
+    if let Some(separator) = term.to_ascii_lowercase().find(CACHE_SEPARATOR) {
+        return multiple_of_cache(&term[..separator], &term[separator + CACHE_SEPARATOR.len()..], caches);
+    }
+    parse_size_at(term)
+}
+
+/// Converts a multiple such as `2` of the cache at `level`, such as `3`, into bytes. Errors are
+/// located in the whole multiple such as `2x-l3`.
+fn multiple_of_cache(number: &str, level: &str, caches: &CacheSizes) -> Result<usize, Located> {
+    let invalid = |position| (position, WeightError::InvalidFormat(format!("{}{}{}", number, CACHE_SEPARATOR, level)));
+    let level_offset = number.len() + CACHE_SEPARATOR.len();
+    let (number, unit) = split_term(number).map_err(|(position, _)| invalid(position))?;
+    if !unit.is_empty() {
+        return Err(invalid(number.len()));
+    }
+    let level = match level {
+        "1" => 1,
+        "2" => 2,
+        "3" => 3,
+        _ => return Err(invalid(level_offset)),
+    };
+    let size = caches.level(level).ok_or_else(|| {
+        (
+            level_offset,
+            WeightError::Unsupported(format!("Unable to determine the size of the L{} cache on this platform", level)),
+        )
+    })?;
+    bytes_for_multiplier(&number.replace('_', ""), size as u64).map_err(|err| (0, err))
+}
+
+/// The headroom of `memory` if it's a single `leave:` size such as `leave:1GB`, which the
+/// [`Watchdog`](crate::config::Watchdog) keeps free.
+#[cfg(feature = "std")]
+pub(crate) fn headroom(memory: &str) -> Option<usize> {
+    let headroom = strip_prefix_ignore_case(memory.trim(), HEADROOM_PREFIX)?;
+    parse_size_at(headroom.trim()).ok()
+}
+
+/// Parses a size without a prefix, such as `512MB` or `80%`, into a number of bytes. Errors
+/// other than a malformed size are located at the start of the number.
+fn parse_size_at(term: &str) -> Result<usize, Located> {
+    let (number, unit) = split_term(term)?;
+
+    let number = number.replace('_', "");
+
+    // A number without a unit is a byte count
+    let unit = if unit.is_empty() { "B" } else { unit };
+
+    if unit == "%" {
+        percentage_of_total_memory(&number).map_err(|err| (0, err))
+    } else {
+        bytes_for_unit(&number, unit).map_err(|err| (0, err))
+    }
+}
+
+/// Splits `term` into its number, such as `1_000` or `.5`, and its unit, which is empty if
+/// there is none. Letters after the number that aren't a valid unit are reported as an
+/// [`InvalidUnit`](WeightError::InvalidUnit) located at the unit, anything else that isn't a
+/// number followed by a unit or `%` as an [`InvalidFormat`](WeightError::InvalidFormat)
+/// located at the first character that doesn't fit.
+fn split_term(term: &str) -> Result<(&str, &str), Located> {
+    let invalid = |position| (position, WeightError::InvalidFormat(term.to_string()));
+    let mut len = digits_len(term);
+    if let Some(fraction) = term[len..].strip_prefix('.') {
+        // Digits are optional before the point but not after it
+        let fraction_len = digits_len(fraction);
+        if fraction_len == 0 {
+            return Err(invalid(len + 1));
+        }
+        len += 1 + fraction_len;
+    } else if len == 0 {
+        return Err(invalid(0));
+    }
+    let (number, unit) = term.split_at(len);
+    if unit.is_empty() || unit == "%" || is_unit(unit) {
+        Ok((number, unit))
+    } else if unit.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+        Err((len, WeightError::InvalidUnit(unit.to_string())))
+    } else {
+        // Past a percent sign anything is too much, otherwise the first character that can't
+        // be part of a unit
+        let offset = match unit.strip_prefix('%') {
+            Some(_) => 1,
+            None => unit.find(|char: char| !char.is_ascii_alphabetic()).unwrap_or_default(),
+        };
+        Err(invalid(len + offset))
+    }
+}
+
+/// Length of the digits at the start of `input`. Single underscores are allowed between digits,
+/// like in Rust integer literals.
+fn digits_len(input: &str) -> usize {
+    let bytes = input.as_bytes();
+    let mut len = 0;
+    while let Some(&byte) = bytes.get(len) {
+        let separator = byte == b'_'
+            && len > 0
+            && bytes[len - 1].is_ascii_digit()
+            && bytes.get(len + 1).is_some_and(u8::is_ascii_digit);
+        if !byte.is_ascii_digit() && !separator {
+            break;
+        }
+        len += 1;
+    }
+    len
+}
+
+/// The multiplier of `unit` in [`SIZE_UNITS`], matched case-insensitively.
+fn unit_multiplier(unit: &str) -> Option<u64> {
+    SIZE_UNITS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        .map(|&(_, multiplier)| multiplier)
+}
+
+/// Whether `unit` is one of the size units or a page.
+fn is_unit(unit: &str) -> bool {
+    unit == PAGE_UNIT || unit_multiplier(unit).is_some()
+}
+
+/// Converts a number with a size unit such as `KB` or `GiB` into bytes.
+fn bytes_for_unit(number: &str, unit: &str) -> Result<usize, WeightError> {
+    // The multipliers are u64 so the larger units are correct on 32-bit targets as well.
+    // Pages are told apart from petabytes by case
+    let multiplier = if unit == PAGE_UNIT {
+        page_size()
+            .ok_or_else(|| WeightError::Unsupported("Unable to determine the page size on this platform".to_string()))?
+            as u64
+    } else {
+        unit_multiplier(unit).ok_or_else(|| WeightError::InvalidUnit(unit.to_string()))?
+    };
+    bytes_for_multiplier(number, multiplier)
+}
+
+/// Converts a number of units of `multiplier` bytes each into bytes.
+fn bytes_for_multiplier(number: &str, multiplier: u64) -> Result<usize, WeightError> {
+    if number.contains('.') {
+        // Fractional sizes are rounded to the nearest whole byte
+        let value: f64 = number
+            .parse()
+            .map_err(|_| WeightError::InvalidFormat(number.to_string()))?;
+        let bytes = round(value * multiplier as f64);
+        if bytes >= usize::MAX as f64 {
+            return Err(WeightError::Overflow);
+        }
+        Ok(bytes as usize)
+    } else {
+        // split_term only lets digits through, so parsing can only fail when the
+        // number doesn't fit in a u64. The product must also fit in the address space.
+        let bytes = number
+            .parse::<u64>()
+            .map_err(|_| WeightError::Overflow)?
+            .checked_mul(multiplier)
+            .ok_or(WeightError::Overflow)?;
+        usize::try_from(bytes).map_err(|_| WeightError::Overflow)
+    }
+}
+
+/// `term` without `prefix`, compared case-insensitively, or `None` if it doesn't start with it.
+fn strip_prefix_ignore_case<'a>(term: &'a str, prefix: &str) -> Option<&'a str> {
+    let (start, rest) = term.split_at_checked(prefix.len())?;
+    start.eq_ignore_ascii_case(prefix).then_some(rest)
+}
+
+/// The number of bytes that leaves `headroom` bytes of the currently available memory free,
+/// zero if less than that is available already.
+fn leave_free(headroom: usize) -> Result<usize, WeightError> {
+    let available = available_memory()
+        .ok_or_else(|| WeightError::Unsupported("Unable to determine available memory on this platform".to_string()))?;
+    Ok(available.saturating_sub(headroom))
+}
+
+/// Converts a percentage such as `70%` into that share of the currently available memory
+/// in bytes.
+fn percentage_of_available_memory(percentage: &str) -> Result<usize, Located> {
+    let invalid = |position| (position, WeightError::InvalidFormat(percentage.to_string()));
+    let (number, unit) = split_term(percentage).map_err(|(position, _)| invalid(position))?;
+    if unit != "%" {
+        return Err(invalid(number.len()));
+    }
+    share_of_memory(&number.replace('_', ""), "available", available_memory).map_err(|err| (0, err))
+}
+
+/// Converts a percentage such as `80` into that share of total physical memory in bytes.
+fn percentage_of_total_memory(number: &str) -> Result<usize, WeightError> {
+    share_of_memory(number, "total", total_memory)
+}
+
+/// Converts a percentage such as `80` into that share of the `kind` of memory returned by
+/// `memory`, capped at [`MAX_MEMORY_FRACTION`]. `0` is always zero bytes, even where the
+/// memory can't be determined.
+fn share_of_memory(number: &str, kind: &str, memory: fn() -> Option<usize>) -> Result<usize, WeightError> {
+    let percentage: f64 = number
+        .parse()
+        .map_err(|_| WeightError::InvalidPercentage(number.to_string()))?;
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err(WeightError::InvalidPercentage(number.to_string()));
+    }
+    if percentage == 0.0 {
+        return Ok(0);
+    }
+
+    let memory = memory()
+        .ok_or_else(|| WeightError::Unsupported(format!("Unable to determine {} memory on this platform", kind)))?;
+
+    let mut fraction = percentage / 100.0;
+    if fraction > MAX_MEMORY_FRACTION {
+        warn!(
+            "{}% of {} memory requested, capping at {}% to leave room for the system.",
+            number,
+            kind,
+            MAX_MEMORY_FRACTION * 100.0
+        );
+        fraction = MAX_MEMORY_FRACTION;
+    }
+
+    Ok(round(memory as f64 * fraction) as usize)
+}
+
+#[cfg(feature = "std")]
+fn total_memory() -> Option<usize> {
+    system::effective_total_memory()
+}
+
+/// Without `std` there's no way to query the system, so percentages can't be resolved.
+#[cfg(not(feature = "std"))]
+fn total_memory() -> Option<usize> {
+    None
+}
+
+#[cfg(feature = "std")]
+fn page_size() -> Option<usize> {
+    Some(system::page_size())
+}
+
+/// Without `std` the page size isn't known, so sizes in pages can't be resolved.
+#[cfg(not(feature = "std"))]
+fn page_size() -> Option<usize> {
+    None
+}
+
+#[cfg(feature = "std")]
+fn cache_sizes() -> CacheSizes {
+    system::cache_sizes()
+}
+
+/// Without `std` the caches can't be queried, so sizes in multiples of them can't be resolved.
+#[cfg(not(feature = "std"))]
+fn cache_sizes() -> CacheSizes {
+    CacheSizes::default()
+}
+
+#[cfg(feature = "std")]
+fn available_memory() -> Option<usize> {
+    system::effective_available_memory()
+}
+
+#[cfg(not(feature = "std"))]
+fn available_memory() -> Option<usize> {
+    None
+}
+
+/// Rounds a non-negative `value` to the nearest whole number, halfway cases away from zero,
+/// like `f64::round`, which isn't available under `no_std`.
+fn round(value: f64) -> f64 {
+    // Values too large for a u64 saturate, which still leaves them above usize::MAX
+    let whole = value as u64 as f64;
+    if value - whole >= 0.5 { whole + 1.0 } else { whole }
+}
@@ -0,0 +1,142 @@
+//! Growing memory step by step until allocating fails or the system runs low on memory, to
+//! find how much a process can hold before the machine or its container runs out.
+
+use crate::alloc::{self, Allocation};
+use crate::config::WeightConfigBuilder;
+use crate::error::WeightError;
+use crate::system;
+
+/// Memory pressure in percent at which [`GrowLimits::default`] stops growing, a share of the
+/// last 10 seconds in which tasks stalled waiting for memory that means the kernel is
+/// struggling to reclaim it.
+pub const DEFAULT_MAX_PRESSURE: f64 = 10.0;
+
+/// When [`grow_until_fail`] stops growing, besides allocating failing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrowLimits {
+    /// Bytes of [available memory](system::effective_available_memory) to leave, growing stops
+    /// before a step would go below it. It keeps the probe away from the OOM killer, which steps
+    /// in once the memory that can be reclaimed runs out.
+    pub keep_available: usize,
+    /// [Memory pressure](system::memory_pressure) in percent at which growing stops.
+    pub max_pressure: f64,
+    /// Bytes to grow to at most, `None` to grow until one of the other limits is reached.
+    pub max_bytes: Option<usize>,
+}
+
+impl Default for GrowLimits {
+    /// Leaves [`ALLOCATABLE_MARGIN`](system::ALLOCATABLE_MARGIN) of the
+    /// [total memory](system::effective_total_memory) available, stops at
+    /// [`DEFAULT_MAX_PRESSURE`] and grows without a maximum.
+    fn default() -> GrowLimits {
+        let total = system::effective_total_memory().unwrap_or(0);
+        GrowLimits {
+            keep_available: (total as f64 * system::ALLOCATABLE_MARGIN) as usize,
+            max_pressure: DEFAULT_MAX_PRESSURE,
+            max_bytes: None,
+        }
+    }
+}
+
+/// Why [`grow_until_fail`] stopped growing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// Allocating the next step failed with this error, e.g. because a resource limit or the
+    /// commit limit of the system was reached.
+    AllocationFailed(WeightError),
+    /// The next step would have left less than [`keep_available`](GrowLimits::keep_available)
+    /// bytes of this much available memory.
+    LowMemory {
+        /// Available memory in bytes before the step.
+        available: usize,
+    },
+    /// Memory pressure reached [`max_pressure`](GrowLimits::max_pressure), it was this much in
+    /// percent.
+    MemoryPressure(f64),
+    /// The next step would have gone past [`max_bytes`](GrowLimits::max_bytes).
+    MaxBytes,
+}
+
+/// Result of [`grow_until_fail`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrowthThreshold {
+    /// Most bytes held at once, the sum of all steps that were allocated.
+    pub max_bytes: usize,
+    /// Number of steps that were allocated.
+    pub steps: usize,
+    /// Why growing stopped.
+    pub reason: StopReason,
+}
+
+/// Allocates the memory `config` describes again and again, each step filled and churned like
+/// any other allocation, until allocating a step fails or one of the `limits` is reached, then
+/// reports the most memory held at once and why it stopped. `config` is called once per step,
+/// so every step can get its own callbacks. All steps are released before returning.
+///
+/// Available memory and pressure are checked before every step, so the probe stops short of
+/// the point where the kernel starts killing processes rather than finding it by getting
+/// killed. Where they can't be determined, e.g. pressure on platforms other than Linux, only
+/// the other limits apply. With overcommit, as on Linux by default, allocating only fails at
+/// resource limits or when a single step is larger than the system could ever provide, so the
+/// available memory is what stops it there. Fails without growing if `config` is invalid or
+/// describes zero bytes, and with the error of any step that fails for other reasons than
+/// running out of memory.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// use weight::probe::{GrowLimits, StopReason};
+///
+/// let limits = GrowLimits { max_bytes: Some(5_000_000), ..GrowLimits::default() };
+/// let threshold = weight::probe::grow_until_fail(|| weight::WeightConfig::new("1MB").workers(1), limits)?;
+/// println!("Held {} bytes in {} steps, stopped by {:?}", threshold.max_bytes, threshold.steps, threshold.reason);
+/// if threshold.reason == StopReason::MaxBytes {
+///     assert_eq!(threshold.max_bytes, 5_000_000);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn grow_until_fail(config: impl Fn() -> WeightConfigBuilder, limits: GrowLimits) -> Result<GrowthThreshold, WeightError> {
+    let step = config().build()?.bytes;
+    if step == 0 {
+        return Err(WeightError::InvalidConfig("Growing needs a step of at least 1 byte".to_string()));
+    }
+
+    let mut steps: Vec<Allocation> = Vec::new();
+    let mut held = 0usize;
+    let reason = loop {
+        if limits.max_bytes.is_some_and(|max| held.saturating_add(step) > max) {
+            break StopReason::MaxBytes;
+        }
+        if let Some(available) = system::effective_available_memory()
+            && available < limits.keep_available.saturating_add(step)
+        {
+            break StopReason::LowMemory { available };
+        }
+        if let Some(pressure) = system::memory_pressure()
+            && pressure >= limits.max_pressure
+        {
+            break StopReason::MemoryPressure(pressure);
+        }
+        match alloc::allocate(config().build()?) {
+            Ok(allocation) => {
+                held += allocation.bytes();
+                steps.push(allocation);
+                debug!("Grew to {} bytes in {} steps", held, steps.len());
+            }
+            Err(
+                err @ (WeightError::AllocationFailed(_)
+                | WeightError::RetriesExhausted { .. }
+                | WeightError::ResourceLimit { .. }
+                | WeightError::WouldExhaustMemory { .. }
+                | WeightError::LockFailed(_)),
+            ) => break StopReason::AllocationFailed(err),
+            Err(err) => return Err(err),
+        }
+    };
+    info!("Stopped growing at {} bytes after {} steps: {:?}", held, steps.len(), reason);
+    Ok(GrowthThreshold {
+        max_bytes: held,
+        steps: steps.len(),
+        reason,
+    })
+}
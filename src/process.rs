@@ -0,0 +1,237 @@
+//! Holding allocations in forked child processes (Unix only), so that one of them being
+//! killed by the OOM killer doesn't take the others or the caller down with it.
+//!
+//! # Caveats
+//!
+//! [`allocate_in_child`] forks the calling process, and only the forking thread exists in the
+//! child. If another thread held a lock at that moment, e.g. inside the memory allocator, the
+//! child can deadlock as soon as it needs that lock. Fork before starting other threads where
+//! possible, e.g. at the start of a test harness and before any in-process allocation, whose
+//! worker threads count as well. The child also starts with a copy of everything the parent
+//! has mapped, which is copied on write and doesn't add to its resident memory until touched.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::alloc;
+use crate::config::WeightConfig;
+use crate::error::WeightError;
+
+/// Held while forking, so a child forked from another thread doesn't inherit the end of a
+/// pipe the parent waits to be closed.
+static FORKING: Mutex<()> = Mutex::new(());
+/// How often a child checks whether its parent is still alive while it waits to be stopped.
+const PARENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Allocation held and churned by a child process, started with [`allocate_in_child`].
+///
+/// Dropping the handle tells the child to release the memory and waits for it to exit, like
+/// [`stop`](ChildAllocation::stop). If the parent dies without doing so, the child notices
+/// within a second and exits on its own.
+pub struct ChildAllocation {
+    pid: libc::pid_t,
+    bytes: usize,
+    stop: File,
+    exited: bool,
+}
+
+impl ChildAllocation {
+    /// Process ID of the child holding the memory.
+    pub fn pid(&self) -> u32 {
+        self.pid as u32
+    }
+
+    /// Number of bytes the child allocated.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Resident set size of the child in bytes, `None` once it has exited or on platforms
+    /// other than Linux.
+    pub fn resident_bytes(&self) -> Option<usize> {
+        if self.exited {
+            return None;
+        }
+        child_resident_memory(self.pid)
+    }
+
+    /// Whether the child is still running. `false` once it was killed, e.g. by the OOM killer.
+    pub fn is_running(&mut self) -> bool {
+        if !self.exited {
+            let mut status = 0;
+            // SAFETY: the pid belongs to a child of this process that hasn't been reaped yet.
+            self.exited = unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) } != 0;
+        }
+        !self.exited
+    }
+
+    /// Tells the child to release the memory and waits for it to exit.
+    pub fn stop(mut self) -> Result<(), WeightError> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<(), WeightError> {
+        if self.exited {
+            return Ok(());
+        }
+        // A child that is gone already can't be written to, waiting reaps it all the same
+        let _ = self.stop.write_all(&[0]);
+        let mut status = 0;
+        // SAFETY: the pid belongs to a child of this process that hasn't been reaped yet.
+        let result = unsafe { libc::waitpid(self.pid, &mut status, 0) };
+        self.exited = true;
+        if result < 0 {
+            return Err(WeightError::Io(format!(
+                "Failed to wait for child process {}: {}",
+                self.pid,
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChildAllocation {
+    fn drop(&mut self) {
+        if let Err(err) = self.shut_down() {
+            warn!("{}", err);
+        }
+    }
+}
+
+/// Forks a child process that allocates memory according to `config` and keeps it occupied
+/// like [`allocate`](crate::allocate) until the returned handle is stopped or dropped. Read
+/// the [caveats](self#caveats) of forking first.
+///
+/// Returns once the child has allocated the memory. Fails with [`WeightError::SpawnFailed`] if
+/// the child couldn't be started or couldn't allocate, with the child's error in the message.
+///
+/// ```
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let regions = ["1MB", "2MB"]
+///     .into_iter()
+///     .map(|size| weight::process::allocate_in_child(weight::WeightConfig::new(size).build()?))
+///     .collect::<Result<Vec<_>, _>>()?;
+/// for region in &regions {
+///     println!("PID {} holds {} bytes, {:?} resident", region.pid(), region.bytes(), region.resident_bytes());
+///     assert_ne!(region.pid(), std::process::id());
+/// }
+/// assert_eq!(regions[1].bytes(), 2_000_000);
+/// # Ok(())
+/// # }
+/// ```
+pub fn allocate_in_child(config: WeightConfig) -> Result<ChildAllocation, WeightError> {
+    let spawn_failed = |err: io::Error| WeightError::SpawnFailed(format!("Failed to fork a child process: {}", err));
+    let forking = FORKING.lock().unwrap_or_else(|err| err.into_inner());
+    let (stop_reader, stop_writer) = pipe().map_err(spawn_failed)?;
+    let (report_reader, report_writer) = pipe().map_err(spawn_failed)?;
+
+    // SAFETY: getpid has no preconditions.
+    let parent = unsafe { libc::getpid() };
+    // SAFETY: the child only continues with the caveats documented above and never returns
+    // from this function, it exits with _exit.
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(spawn_failed(io::Error::last_os_error()));
+    }
+    if pid == 0 {
+        drop(stop_writer);
+        drop(report_reader);
+        run_child(config, parent, stop_reader, File::from(report_writer));
+    }
+    drop(stop_reader);
+    drop(report_writer);
+    drop(forking);
+
+    let mut child = ChildAllocation {
+        pid,
+        bytes: 0,
+        stop: File::from(stop_writer),
+        exited: false,
+    };
+    // The child closes its end once it has allocated, or by exiting if it couldn't
+    let mut report = Vec::new();
+    File::from(report_reader)
+        .read_to_end(&mut report)
+        .map_err(|err| WeightError::Io(format!("Failed to read from child process {}: {}", pid, err)))?;
+    match report.split_first() {
+        Some((b'+', bytes)) => {
+            child.bytes = std::str::from_utf8(bytes).ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(0);
+            info!("Child process {} holds {} bytes.", pid, child.bytes);
+            Ok(child)
+        }
+        Some((b'-', message)) => Err(WeightError::SpawnFailed(format!(
+            "Child process {} failed to allocate: {}",
+            pid,
+            String::from_utf8_lossy(message)
+        ))),
+        _ => Err(WeightError::SpawnFailed(format!("Child process {} exited before allocating", pid))),
+    }
+}
+
+/// Body of the child: allocates, reports the outcome through `report` and holds the memory
+/// until `stop` becomes readable or the `parent` is gone.
+fn run_child(config: WeightConfig, parent: libc::pid_t, stop: OwnedFd, mut report: File) -> ! {
+    let allocation = match alloc::allocate(config) {
+        Ok(allocation) => allocation,
+        Err(err) => {
+            let _ = write!(report, "-{}", err);
+            // SAFETY: _exit skips the parent's atexit handlers and stdio buffers the child copied.
+            unsafe { libc::_exit(1) }
+        }
+    };
+    let _ = write!(report, "+{}", allocation.bytes());
+    drop(report);
+
+    // Either a byte or the end of the pipe means stop. Children forked later inherit the write
+    // end, so the pipe can't be relied on to close when the parent dies and its parent is
+    // checked as well, which changes once the child is orphaned
+    let mut poll = libc::pollfd {
+        fd: stop.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: poll only writes revents of the single pollfd it is given, getppid has no preconditions.
+    while unsafe { libc::poll(&mut poll, 1, PARENT_POLL_INTERVAL.as_millis() as libc::c_int) } == 0
+        && unsafe { libc::getppid() } == parent
+    {}
+    drop(allocation);
+    // SAFETY: see above.
+    unsafe { libc::_exit(0) }
+}
+
+/// Creates a pipe whose ends are closed when the process execs, so other programs started
+/// later don't keep it open.
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe writes.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just opened and nothing else owns them.
+    let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in fds {
+        // SAFETY: fd is open, F_SETFD only changes its flags.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((reader, writer))
+}
+
+/// Resident set size of the process `pid` in bytes.
+#[cfg(target_os = "linux")]
+fn child_resident_memory(pid: libc::pid_t) -> Option<usize> {
+    // The second field of statm is the number of resident pages
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    pages.checked_mul(crate::system::page_size())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn child_resident_memory(_pid: libc::pid_t) -> Option<usize> {
+    None
+}
@@ -0,0 +1,90 @@
+//! Managing several independent allocations at once.
+
+use std::collections::BTreeMap;
+
+use crate::config::WeightConfig;
+use crate::error::WeightError;
+use crate::alloc::{self, Allocation};
+
+/// Tracks multiple named allocations, each with its own config, so stress scenarios can mix
+/// regions with different sizes and churn settings without juggling individual handles.
+///
+/// Every region has its own workers, which follow only the interval, worker count, fill
+/// pattern and other churn settings of its own config, so hot and cold data can be modelled
+/// side by side:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use weight::config::FillPattern;
+/// # fn main() -> Result<(), weight::WeightError> {
+/// let mut registry = weight::AllocationRegistry::new();
+/// let hot = weight::WeightConfig::new("64KB").workers(2).interval(Duration::from_millis(1));
+/// let cold = weight::WeightConfig::new("64KB").workers(1).interval(Duration::from_millis(100));
+/// registry.add("hot", hot.fill_pattern(FillPattern::Ones).build()?)?;
+/// registry.add("cold", cold.fill_pattern(FillPattern::Alternating).build()?)?;
+/// std::thread::sleep(Duration::from_millis(500));
+///
+/// let cycles = |name| registry.get(name).unwrap().stats().cycles_completed;
+/// let (hot, cold) = (cycles("hot"), cycles("cold"));
+/// // The cold region sleeps 100 times as long between cycles, with half the workers
+/// assert!(cold <= 8, "cold region ran {} cycles", cold);
+/// assert!(hot > 10 * cold, "hot region ran {} cycles, cold {}", hot, cold);
+/// assert!(registry.get("hot").unwrap().verify().is_ok() && registry.get("cold").unwrap().verify().is_ok());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Dropping the registry stops and releases every allocation it holds.
+#[derive(Default)]
+pub struct AllocationRegistry {
+    allocations: BTreeMap<String, Allocation>,
+}
+
+impl AllocationRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> AllocationRegistry {
+        AllocationRegistry::default()
+    }
+
+    /// Allocates memory as described by `config` and tracks it under `name`.
+    pub fn add(&mut self, name: impl Into<String>, config: WeightConfig) -> Result<(), WeightError> {
+        let name = name.into();
+        if self.allocations.contains_key(&name) {
+            return Err(WeightError::InvalidConfig(format!("An allocation named '{}' already exists", name)));
+        }
+        let allocation = alloc::allocate(config)?;
+        self.allocations.insert(name, allocation);
+        Ok(())
+    }
+
+    /// Stops tracking the allocation named `name` and hands it back to the caller. Dropping
+    /// the returned handle releases the memory; the other allocations keep running.
+    pub fn remove(&mut self, name: &str) -> Option<Allocation> {
+        self.allocations.remove(name)
+    }
+
+    /// Returns the allocation named `name`, if it's tracked.
+    pub fn get(&self, name: &str) -> Option<&Allocation> {
+        self.allocations.get(name)
+    }
+
+    /// Names of the tracked allocations, in sorted order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.allocations.keys().map(String::as_str)
+    }
+
+    /// Number of tracked allocations.
+    pub fn len(&self) -> usize {
+        self.allocations.len()
+    }
+
+    /// Whether the registry holds no allocations.
+    pub fn is_empty(&self) -> bool {
+        self.allocations.is_empty()
+    }
+
+    /// Total number of bytes held by all tracked allocations.
+    pub fn total_bytes(&self) -> usize {
+        self.allocations.values().map(Allocation::bytes).sum()
+    }
+}
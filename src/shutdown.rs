@@ -0,0 +1,158 @@
+//! Releasing allocations gracefully when the process is asked to terminate.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use once_cell::sync::Lazy;
+use signal_hook::SigId;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::error::WeightError;
+use crate::alloc::{self, Allocation};
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+    allocations: Vec<Allocation>,
+    callbacks: Vec<Callback>,
+}
+
+/// Handles SIGTERM and SIGINT by running the registered shutdown callbacks, stopping and
+/// releasing all held allocations and then exiting the process with status `128 + signal`.
+///
+/// This gives orchestrators that send SIGTERM on shutdown a clean teardown instead of the
+/// process being killed while it's churning memory.
+#[derive(Clone)]
+pub struct ShutdownHandler {
+    state: Arc<Mutex<State>>,
+}
+
+impl ShutdownHandler {
+    /// Installs the signal handlers. They stay installed for the rest of the process. Fails with
+    /// [`WeightError::SpawnFailed`] if the thread waiting for the signals can't be started.
+    pub fn install() -> Result<ShutdownHandler, WeightError> {
+        // Never released, the handler terminates the process itself
+        handle_signals();
+        let mut signals = Signals::new([SIGTERM, SIGINT]).map_err(|err| {
+            release_signals();
+            WeightError::Unsupported(format!("Failed to install signal handlers: {}", err))
+        })?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let handler_state = Arc::clone(&state);
+        let spawned = thread::Builder::new().spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Received signal {}, releasing allocations", signal);
+                let state = std::mem::take(&mut *handler_state.lock().unwrap_or_else(PoisonError::into_inner));
+                for callback in state.callbacks {
+                    callback();
+                }
+                // Dropping the allocations stops their churn threads and frees the memory
+                drop(state.allocations);
+                std::process::exit(128 + signal);
+            }
+        });
+        if let Err(err) = spawned {
+            // Dropping the signals with the thread's closure unregistered them
+            release_signals();
+            return Err(alloc::spawn_failed("the shutdown signal handler", err));
+        }
+
+        Ok(ShutdownHandler { state })
+    }
+
+    /// Keeps `allocation` alive until the process receives a shutdown signal, at which
+    /// point it's stopped and released.
+    pub fn hold(&self, allocation: Allocation) {
+        self.lock().allocations.push(allocation);
+    }
+
+    /// Registers a callback to run when a shutdown signal is received, before the held
+    /// allocations are released.
+    pub fn on_shutdown(&self, callback: impl FnOnce() + Send + 'static) {
+        self.lock().callbacks.push(Box::new(callback));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // A panicking callback shouldn't prevent registering more
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Holds and shutdown handlers that keep SIGTERM and SIGINT from terminating the process
+/// themselves, and the flag that has the signals terminate it while there are none.
+struct Handlers {
+    count: Mutex<usize>,
+    terminate: Arc<AtomicBool>,
+}
+
+static HANDLERS: Lazy<Handlers> = Lazy::new(|| {
+    let terminate = Arc::new(AtomicBool::new(true));
+    for signal in [SIGTERM, SIGINT] {
+        // Registered before the actions of the handlers, so those don't run when it terminates
+        if let Err(err) = signal_hook::flag::register_conditional_default(signal, Arc::clone(&terminate)) {
+            warn!("Failed to restore the default action of signal {}: {}", signal, err);
+        }
+    }
+    Handlers {
+        count: Mutex::new(0),
+        terminate,
+    }
+});
+
+/// Counts one more handler of the signals, which no longer terminate the process by default.
+fn handle_signals() {
+    let mut count = HANDLERS.count.lock().unwrap_or_else(PoisonError::into_inner);
+    *count += 1;
+    HANDLERS.terminate.store(false, Ordering::SeqCst);
+}
+
+/// Counts one handler less, the signals terminate the process again once there are none.
+fn release_signals() {
+    let mut count = HANDLERS.count.lock().unwrap_or_else(PoisonError::into_inner);
+    *count -= 1;
+    HANDLERS.terminate.store(*count == 0, Ordering::SeqCst);
+}
+
+/// SIGTERM and SIGINT ending an [`Allocation::hold`] instead of terminating the process, for
+/// as long as this exists. Signals arriving meanwhile end every hold in progress.
+pub(crate) struct HoldSignals {
+    received: Arc<AtomicBool>,
+    ids: Vec<SigId>,
+}
+
+impl HoldSignals {
+    pub(crate) fn register() -> HoldSignals {
+        handle_signals();
+        let received = Arc::new(AtomicBool::new(false));
+        let ids = [SIGTERM, SIGINT]
+            .into_iter()
+            .filter_map(|signal| match signal_hook::flag::register(signal, Arc::clone(&received)) {
+                Ok(id) => Some(id),
+                Err(err) => {
+                    warn!("Failed to handle signal {}, it won't end holding the allocation: {}", signal, err);
+                    None
+                }
+            })
+            .collect();
+        HoldSignals { received, ids }
+    }
+
+    /// Whether SIGTERM or SIGINT arrived since registering.
+    pub(crate) fn received(&self) -> bool {
+        self.received.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for HoldSignals {
+    fn drop(&mut self) {
+        // Released first, so a signal arriving in between terminates instead of being lost
+        release_signals();
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+}
@@ -0,0 +1,754 @@
+//! Small platform helpers for querying the machine weight is running on.
+
+use std::ops::Range;
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+
+use crate::error::WeightError;
+use crate::parse::{ByteSize, CacheSizes};
+
+/// Total physical memory of the machine in bytes, or `None` if it can't be
+/// determined on the current platform.
+#[cfg(unix)]
+pub fn total_memory() -> Option<usize> {
+    // SAFETY: sysconf has no preconditions and only reads system configuration.
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages <= 0 || page_size <= 0 {
+        return None;
+    }
+    (pages as usize).checked_mul(page_size as usize)
+}
+
+/// Total physical memory of the machine in bytes, or `None` if it can't be
+/// determined on the current platform.
+#[cfg(windows)]
+pub fn total_memory() -> Option<usize> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    // SAFETY: MEMORYSTATUSEX is plain data and dwLength is set as the API requires.
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        return None;
+    }
+    usize::try_from(status.ullTotalPhys).ok()
+}
+
+/// Total physical memory of the machine in bytes, or `None` if it can't be
+/// determined on the current platform.
+#[cfg(not(any(unix, windows)))]
+pub fn total_memory() -> Option<usize> {
+    None
+}
+
+/// Physical memory that is available for new allocations without swapping in bytes, or `None`
+/// if it can't be determined on the current platform. Unlike [`total_memory`] this changes
+/// as other processes allocate and free memory.
+#[cfg(target_os = "linux")]
+pub fn available_memory() -> Option<usize> {
+    // MemAvailable also counts caches the kernel can drop, unlike MemFree
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    kilobytes.checked_mul(1024)
+}
+
+/// Physical memory that is available for new allocations without swapping in bytes, or `None`
+/// if it can't be determined on the current platform. Unlike [`total_memory`] this changes
+/// as other processes allocate and free memory.
+#[cfg(windows)]
+pub fn available_memory() -> Option<usize> {
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    // SAFETY: MEMORYSTATUSEX is plain data and dwLength is set as the API requires.
+    let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+    status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+    if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+        return None;
+    }
+    usize::try_from(status.ullAvailPhys).ok()
+}
+
+/// Physical memory that is available for new allocations without swapping in bytes, or `None`
+/// if it can't be determined on the current platform. Unlike [`total_memory`] this changes
+/// as other processes allocate and free memory.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn available_memory() -> Option<usize> {
+    None
+}
+
+/// Version of the cgroup hierarchy a [`CgroupMemory`] limit was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    /// The legacy per-controller hierarchy, limited by `memory.limit_in_bytes`.
+    V1,
+    /// The unified hierarchy, limited by `memory.max`.
+    V2,
+}
+
+/// Memory limit of the cgroup the process runs in, e.g. the memory limit of a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CgroupMemory {
+    /// Version of the cgroup hierarchy the limit was read from.
+    pub version: CgroupVersion,
+    /// Bytes the processes in the cgroup may use before they are reclaimed from or killed.
+    pub limit: usize,
+    /// Bytes the processes in the cgroup use right now, `None` if it couldn't be read.
+    pub usage: Option<usize>,
+}
+
+/// cgroup v1 reports no limit as the largest page aligned `i64`, which depends on the page size.
+const CGROUP_V1_UNLIMITED: u64 = i64::MAX as u64 & !0xFFFF;
+
+impl CgroupMemory {
+    /// Bytes left below the limit, `None` if the usage couldn't be read.
+    pub fn available(&self) -> Option<usize> {
+        self.usage.map(|usage| self.limit.saturating_sub(usage))
+    }
+
+    /// Reads the memory limit of the process from the cgroup filesystem mounted at `root`,
+    /// given the contents of `/proc/self/cgroup`. The memory controller of cgroup v1 is
+    /// preferred where both versions are mounted, since v2 then has no memory controller.
+    /// The cgroup and all its ancestors are checked and the lowest limit wins, as a parent's
+    /// limit applies to everything below it. `None` if none of them is limited.
+    ///
+    /// ```
+    /// # use weight::system::{CgroupMemory, CgroupVersion};
+    /// # fn main() -> std::io::Result<()> {
+    /// let root = std::env::temp_dir().join(format!("weight-cgroup-doc-{}", std::process::id()));
+    /// std::fs::create_dir_all(root.join("parent/container"))?;
+    /// std::fs::write(root.join("parent/memory.max"), "1073741824\n")?;
+    /// std::fs::write(root.join("parent/container/memory.max"), "max\n")?;
+    /// std::fs::write(root.join("parent/memory.current"), "268435456\n")?;
+    ///
+    /// let memory = CgroupMemory::read_from(&root, "0::/parent/container\n").unwrap();
+    /// assert_eq!(memory.version, CgroupVersion::V2);
+    /// assert_eq!(memory.limit, 1 << 30);
+    /// assert_eq!(memory.available(), Some(768 << 20));
+    ///
+    /// std::fs::create_dir_all(root.join("memory/docker"))?;
+    /// std::fs::write(root.join("memory/docker/memory.limit_in_bytes"), "9223372036854771712\n")?;
+    /// assert_eq!(CgroupMemory::read_from(&root, "4:memory:/docker\n0::/\n"), None);
+    /// std::fs::write(root.join("memory/docker/memory.limit_in_bytes"), "536870912\n")?;
+    /// let memory = CgroupMemory::read_from(&root, "4:memory:/docker\n0::/\n").unwrap();
+    /// assert_eq!((memory.version, memory.limit, memory.usage), (CgroupVersion::V1, 512 << 20, None));
+    /// # std::fs::remove_dir_all(&root)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn read_from(root: &Path, self_cgroup: &str) -> Option<CgroupMemory> {
+        let entries = self_cgroup.lines().filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            Some((fields.next()?, fields.next()?, fields.next()?))
+        });
+        let mut v2_path = None;
+        for (id, controllers, path) in entries {
+            if controllers.split(',').any(|controller| controller == "memory") {
+                return Self::read_hierarchy(&root.join("memory"), path, CgroupVersion::V1);
+            }
+            if id == "0" && controllers.is_empty() {
+                v2_path = Some(path);
+            }
+        }
+        Self::read_hierarchy(root, v2_path?, CgroupVersion::V2)
+    }
+
+    /// Lowest limit of the cgroup at `path` below `mount` and its ancestors.
+    fn read_hierarchy(mount: &Path, path: &str, version: CgroupVersion) -> Option<CgroupMemory> {
+        let (limit_file, usage_file) = match version {
+            CgroupVersion::V1 => ("memory.limit_in_bytes", "memory.usage_in_bytes"),
+            CgroupVersion::V2 => ("memory.max", "memory.current"),
+        };
+        let read = |dir: &Path, file: &str| -> Option<u64> {
+            std::fs::read_to_string(dir.join(file)).ok()?.trim().parse().ok()
+        };
+        // Inside a cgroup namespace the path can name a cgroup that isn't visible in the mount,
+        // the ancestors still include the root of the mount, which is the container's cgroup
+        let mut dir = mount.join(path.trim_start_matches('/'));
+        let mut lowest: Option<CgroupMemory> = None;
+        loop {
+            // "max" for v2 doesn't parse, and neither limit applies when it is unlimited
+            if let Some(limit) = read(&dir, limit_file)
+                .filter(|&limit| limit < CGROUP_V1_UNLIMITED)
+                .map(|limit| usize::try_from(limit).unwrap_or(usize::MAX))
+                && lowest.is_none_or(|lowest| limit < lowest.limit)
+            {
+                lowest = Some(CgroupMemory {
+                    version,
+                    limit,
+                    usage: read(&dir, usage_file).map(|usage| usize::try_from(usage).unwrap_or(usize::MAX)),
+                });
+            }
+            if dir == mount || !dir.pop() || !dir.starts_with(mount) {
+                return lowest;
+            }
+        }
+    }
+}
+
+/// Memory limit of the cgroup the process runs in, read from `/sys/fs/cgroup`. `None` if the
+/// process isn't limited by a cgroup or on platforms other than Linux.
+#[cfg(target_os = "linux")]
+pub fn cgroup_memory() -> Option<CgroupMemory> {
+    let self_cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    CgroupMemory::read_from(Path::new("/sys/fs/cgroup"), &self_cgroup)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cgroup_memory() -> Option<CgroupMemory> {
+    None
+}
+
+/// Memory the process can use in total in bytes: the [`total_memory`] of the machine, or the
+/// [`cgroup_memory`] limit where that is lower, like in a container with a memory limit.
+/// Sizes given as a percentage of the total memory are a share of this.
+pub fn effective_total_memory() -> Option<usize> {
+    let total = total_memory()?;
+    Some(cgroup_memory().map_or(total, |cgroup| total.min(cgroup.limit)))
+}
+
+/// Memory that is available to the process in bytes: the [`available_memory`] of the machine,
+/// or what's left below the [`cgroup_memory`] limit where that is lower. Going past the limit
+/// of a container gets the process killed even while the host has memory to spare.
+pub fn effective_available_memory() -> Option<usize> {
+    let available = available_memory()?;
+    Some(
+        cgroup_memory()
+            .and_then(|cgroup| cgroup.available())
+            .map_or(available, |cgroup| available.min(cgroup)),
+    )
+}
+
+/// Size of a memory page in bytes, e.g. 4KB on most x86 systems and 16KB on Apple Silicon.
+/// Falls back to 4KB if it can't be determined.
+pub fn page_size() -> usize {
+    static PAGE_SIZE: Lazy<usize> = Lazy::new(|| query_page_size().unwrap_or(4096));
+    *PAGE_SIZE
+}
+
+#[cfg(unix)]
+fn query_page_size() -> Option<usize> {
+    // SAFETY: sysconf has no preconditions and only reads system configuration.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(page_size).ok().filter(|&size| size > 0)
+}
+
+#[cfg(windows)]
+fn query_page_size() -> Option<usize> {
+    use windows_sys::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
+
+    // SAFETY: SYSTEM_INFO is plain data that GetSystemInfo fills in.
+    let mut info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+    unsafe { GetSystemInfo(&mut info) };
+    usize::try_from(info.dwPageSize).ok().filter(|&size| size > 0)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn query_page_size() -> Option<usize> {
+    None
+}
+
+/// Memory pressure in percent, the share of the last 10 seconds in which at least one task
+/// stalled waiting for memory, e.g. because its pages had to be reclaimed or read back from
+/// swap. This is `some avg10` from `/proc/pressure/memory`. `None` on platforms other than
+/// Linux and on kernels without pressure stall information.
+#[cfg(target_os = "linux")]
+pub fn memory_pressure() -> Option<f64> {
+    let pressure = std::fs::read_to_string("/proc/pressure/memory").ok()?;
+    let some = pressure.lines().find_map(|line| line.strip_prefix("some "))?;
+    some.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn memory_pressure() -> Option<f64> {
+    None
+}
+
+/// Bytes of huge pages reserved in the kernel that no process is using yet, i.e.
+/// `HugePages_Free` times `Hugepagesize` from `/proc/meminfo`. That much can be mapped with
+/// [`huge_pages`](crate::config::WeightConfigBuilder::huge_pages). `None` on platforms other
+/// than Linux.
+#[cfg(target_os = "linux")]
+pub fn free_huge_pages() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<usize> {
+        let line = meminfo.lines().find(|line| line.starts_with(name))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    };
+    let kilobytes = field("Hugepagesize:")?;
+    field("HugePages_Free:")?.checked_mul(kilobytes)?.checked_mul(1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn free_huge_pages() -> Option<usize> {
+    None
+}
+
+/// Swap space of the machine, returned by [`swap_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapInfo {
+    /// Bytes of swap configured in total, 0 if there is none.
+    pub total_bytes: usize,
+    /// Bytes of swap in use, by any process.
+    pub used_bytes: usize,
+}
+
+impl SwapInfo {
+    /// Whether the machine has any swap. Without it memory nobody touches can't be swapped
+    /// out, so pressure ends with the OOM killer instead of with pages being swapped.
+    pub fn is_configured(&self) -> bool {
+        self.total_bytes > 0
+    }
+
+    /// Bytes of swap that are still free.
+    pub fn free_bytes(&self) -> usize {
+        self.total_bytes.saturating_sub(self.used_bytes)
+    }
+}
+
+/// Total and used swap space of the machine, to tell how a pressure test will behave: with
+/// plenty of swap idle memory gets swapped out, without any there's nowhere for it to go.
+/// Read from `SwapTotal` and `SwapFree` in `/proc/meminfo` on Linux, which sums up the
+/// devices in `/proc/swaps`, and from `vm.swapusage` on macOS, where swap grows on demand so
+/// the total is only what's been set up so far. `None` on other platforms, where swap can't
+/// be queried, which is not the same as a machine without swap.
+///
+/// ```
+/// match weight::system::swap_info() {
+///     Some(swap) if swap.is_configured() => println!("{} of {} bytes of swap in use", swap.used_bytes, swap.total_bytes),
+///     Some(_) => println!("No swap is configured"),
+///     None => println!("Swap can't be queried on this platform"),
+/// }
+/// ```
+#[cfg(target_os = "linux")]
+pub fn swap_info() -> Option<SwapInfo> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| -> Option<usize> {
+        let line = meminfo.lines().find(|line| line.starts_with(name))?;
+        let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+        kilobytes.checked_mul(1024)
+    };
+    let total_bytes = field("SwapTotal:")?;
+    Some(SwapInfo {
+        total_bytes,
+        used_bytes: total_bytes.saturating_sub(field("SwapFree:")?),
+    })
+}
+
+#[cfg(target_os = "macos")]
+pub fn swap_info() -> Option<SwapInfo> {
+    // SAFETY: xsw_usage is plain data.
+    let mut usage: libc::xsw_usage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::xsw_usage>();
+    // SAFETY: the name is nul-terminated, usage is valid for writes of len bytes and no new
+    // value is passed.
+    let result = unsafe {
+        libc::sysctlbyname(
+            c"vm.swapusage".as_ptr(),
+            (&mut usage as *mut libc::xsw_usage).cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    Some(SwapInfo {
+        total_bytes: usize::try_from(usage.xsu_total).ok()?,
+        used_bytes: usize::try_from(usage.xsu_used).ok()?,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn swap_info() -> Option<SwapInfo> {
+    None
+}
+
+/// Size of a CPU cache line in bytes, the unit memory is moved between RAM and the caches in.
+/// Falls back to 64 bytes, the size on most x86 and ARM CPUs, if it can't be determined.
+pub fn cache_line_size() -> usize {
+    static CACHE_LINE_SIZE: Lazy<usize> = Lazy::new(|| query_cache_line_size().unwrap_or(64));
+    *CACHE_LINE_SIZE
+}
+
+#[cfg(target_os = "linux")]
+fn query_cache_line_size() -> Option<usize> {
+    let size = std::fs::read_to_string("/sys/devices/system/cpu/cpu0/cache/index0/coherency_line_size").ok()?;
+    size.trim().parse().ok().filter(|&size: &usize| size.is_power_of_two())
+}
+
+#[cfg(target_os = "macos")]
+fn query_cache_line_size() -> Option<usize> {
+    let mut size: i64 = 0;
+    let mut len = std::mem::size_of::<i64>();
+    // SAFETY: the name is nul-terminated, size is valid for writes of len bytes and no new
+    // value is passed.
+    let result = unsafe {
+        libc::sysctlbyname(
+            c"hw.cachelinesize".as_ptr(),
+            (&mut size as *mut i64).cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    usize::try_from(size).ok().filter(|size| size.is_power_of_two())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn query_cache_line_size() -> Option<usize> {
+    None
+}
+
+/// Sizes of the level 1 data cache and the level 2 and 3 caches of the first CPU core, read
+/// from `/sys/devices/system/cpu/cpu0/cache` on Linux and from the `hw.l1dcachesize`,
+/// `hw.l2cachesize` and `hw.l3cachesize` sysctls on macOS. Sizes such as `2x-l3` are
+/// multiples of them. Levels the CPU doesn't have, such as L3 on Apple Silicon, and every
+/// level on other platforms are `None`.
+///
+/// ```
+/// let caches = weight::system::cache_sizes();
+/// if let Some(l3) = caches.l3 {
+///     println!("Allocating 2x-l3 takes {} bytes", 2 * l3);
+/// }
+/// ```
+pub fn cache_sizes() -> CacheSizes {
+    static CACHE_SIZES: Lazy<CacheSizes> = Lazy::new(query_cache_sizes);
+    *CACHE_SIZES
+}
+
+#[cfg(target_os = "linux")]
+fn query_cache_sizes() -> CacheSizes {
+    let mut caches = CacheSizes::default();
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/cpu/cpu0/cache") else {
+        return caches;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let read = |name: &str| std::fs::read_to_string(path.join(name)).ok();
+        // Level 1 is split into an instruction and a data cache, only the data cache holds memory
+        if read("type").is_none_or(|kind| kind.trim() == "Instruction") {
+            continue;
+        }
+        let level = read("level").and_then(|level| level.trim().parse().ok());
+        // The size is given in kilobytes, e.g. "32K"
+        let size = read("size").and_then(|size| {
+            let size = size.trim();
+            let (number, multiplier) = match size.strip_suffix('K') {
+                Some(number) => (number, 1024),
+                None => match size.strip_suffix('M') {
+                    Some(number) => (number, 1024 * 1024),
+                    None => (size, 1),
+                },
+            };
+            number.parse::<usize>().ok()?.checked_mul(multiplier)
+        });
+        match level {
+            Some(1) => caches.l1 = size,
+            Some(2) => caches.l2 = size,
+            Some(3) => caches.l3 = size,
+            _ => {}
+        }
+    }
+    caches
+}
+
+#[cfg(target_os = "macos")]
+fn query_cache_sizes() -> CacheSizes {
+    let sysctl = |name: &std::ffi::CStr| -> Option<usize> {
+        let mut size: i64 = 0;
+        let mut len = std::mem::size_of::<i64>();
+        // SAFETY: the name is nul-terminated, size is valid for writes of len bytes and no new
+        // value is passed.
+        let result = unsafe {
+            libc::sysctlbyname(name.as_ptr(), (&mut size as *mut i64).cast(), &mut len, std::ptr::null_mut(), 0)
+        };
+        if result != 0 {
+            return None;
+        }
+        usize::try_from(size).ok().filter(|&size| size > 0)
+    };
+    CacheSizes {
+        l1: sysctl(c"hw.l1dcachesize"),
+        l2: sysctl(c"hw.l2cachesize"),
+        l3: sysctl(c"hw.l3cachesize"),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn query_cache_sizes() -> CacheSizes {
+    CacheSizes::default()
+}
+
+/// Page faults of the current process, returned by [`page_faults`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageFaults {
+    /// Faults served without I/O, e.g. the first touch of a freshly allocated page.
+    pub minor: u64,
+    /// Faults that had to read the page from disk, e.g. from swap. Many of them while churning
+    /// mean the memory isn't staying resident.
+    pub major: u64,
+}
+
+impl PageFaults {
+    /// Faults that happened after `earlier` was taken.
+    pub fn since(&self, earlier: PageFaults) -> PageFaults {
+        PageFaults {
+            minor: self.minor.saturating_sub(earlier.minor),
+            major: self.major.saturating_sub(earlier.major),
+        }
+    }
+}
+
+/// Page faults of the whole process since it started, from `getrusage`. `None` on platforms
+/// other than Unix.
+///
+/// ```
+/// if let Some(before) = weight::system::page_faults() {
+///     let allocation = weight::allocate_memory("16MB").unwrap();
+///     let faults = weight::system::page_faults().unwrap().since(before);
+///     println!("Allocating took {} minor and {} major faults", faults.minor, faults.major);
+///     # drop(allocation);
+/// }
+/// ```
+#[cfg(unix)]
+pub fn page_faults() -> Option<PageFaults> {
+    // SAFETY: rusage is plain data that getrusage fills in.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    Some(PageFaults {
+        minor: usage.ru_minflt as u64,
+        major: usage.ru_majflt as u64,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn page_faults() -> Option<PageFaults> {
+    None
+}
+
+/// Page faults since `before`, `None` if either count is missing.
+pub(crate) fn page_faults_since(before: Option<PageFaults>) -> Option<PageFaults> {
+    before.zip(page_faults()).map(|(before, now)| now.since(before))
+}
+
+/// Memory usage of the current process, returned by [`process_memory`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessMemory {
+    /// Bytes backed by physical memory, the resident set size on Linux and the working set
+    /// on Windows.
+    pub resident_bytes: usize,
+    /// Bytes of virtual memory reserved by the process on Linux, and committed by it on
+    /// Windows. Memory that is only reserved this way may not be backed by physical memory.
+    pub virtual_bytes: usize,
+}
+
+/// Resident and virtual memory usage of the current process, or `None` if it can't be
+/// determined on the current platform. Comparing it before and after allocating shows
+/// whether the memory is actually backed by physical RAM.
+#[cfg(target_os = "linux")]
+pub fn process_memory() -> Option<ProcessMemory> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    // Both fields are given in kB, e.g. "VmRSS:     1234 kB"
+    let field = |name: &str| -> Option<usize> {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        let kilobytes: usize = line[name.len()..].split_whitespace().next()?.parse().ok()?;
+        kilobytes.checked_mul(1024)
+    };
+    Some(ProcessMemory {
+        resident_bytes: field("VmRSS:")?,
+        virtual_bytes: field("VmSize:")?,
+    })
+}
+
+/// Resident and virtual memory usage of the current process, or `None` if it can't be
+/// determined on the current platform. Comparing it before and after allocating shows
+/// whether the memory is actually backed by physical RAM.
+#[cfg(windows)]
+pub fn process_memory() -> Option<ProcessMemory> {
+    use windows_sys::Win32::System::ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+    // SAFETY: PROCESS_MEMORY_COUNTERS is plain data and its size is passed as the API requires.
+    let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    if unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) } == 0 {
+        return None;
+    }
+    Some(ProcessMemory {
+        resident_bytes: counters.WorkingSetSize,
+        virtual_bytes: counters.PagefileUsage,
+    })
+}
+
+/// Resident and virtual memory usage of the current process, or `None` if it can't be
+/// determined on the current platform.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn process_memory() -> Option<ProcessMemory> {
+    None
+}
+
+/// Bytes of the memory at the addresses in `region` that are backed by transparent huge
+/// pages, summed from `AnonHugePages` of the mappings it overlaps in `/proc/self/smaps`.
+/// A mapping the region only partly covers counts in proportion to the overlap. `None` on
+/// platforms other than Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn transparent_huge_pages(region: Range<usize>) -> Option<usize> {
+    if region.is_empty() {
+        return Some(0);
+    }
+    let smaps = std::fs::read_to_string("/proc/self/smaps").ok()?;
+    let mut bytes = 0;
+    // The overlap of the mapping whose fields are being read with the region, and its length
+    let mut mapping: Option<(usize, usize)> = None;
+    for line in smaps.lines() {
+        // Mappings start with a line like "7f0000000000-7f0000200000 rw-p 00000000 00:00 0"
+        if let Some((start, end)) = line
+            .split_whitespace()
+            .next()
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(start, end)| Some((usize::from_str_radix(start, 16).ok()?, usize::from_str_radix(end, 16).ok()?)))
+        {
+            let overlap = end.min(region.end).saturating_sub(start.max(region.start));
+            mapping = (overlap > 0).then_some((overlap, end - start));
+        } else if let Some((overlap, len)) = mapping
+            && let Some(huge) = line.strip_prefix("AnonHugePages:")
+        {
+            let kilobytes: usize = huge.split_whitespace().next()?.parse().ok()?;
+            bytes += (kilobytes as u128 * 1024 * overlap as u128 / len as u128) as usize;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn transparent_huge_pages(_region: Range<usize>) -> Option<usize> {
+    None
+}
+
+/// Resident set size of the current process in bytes, or `None` if it can't be
+/// determined on the current platform.
+#[cfg(target_os = "linux")]
+pub(crate) fn resident_memory() -> Option<usize> {
+    // The second field of statm is the number of resident pages
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    pages.checked_mul(page_size())
+}
+
+/// Resident set size of the current process in bytes, or `None` if it can't be
+/// determined on the current platform.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn resident_memory() -> Option<usize> {
+    process_memory().map(|memory| memory.resident_bytes)
+}
+
+/// Fails with [`WeightError::ResourceLimit`] if allocating `bytes` would exceed the address
+/// space (`RLIMIT_AS`) or data segment (`RLIMIT_DATA`) limit of the process, as set with
+/// `ulimit -v` or `ulimit -d`. What the process has mapped already counts towards the limits
+/// like in [`max_allocatable`]. Limits that are unlimited or can't be read are ignored.
+#[cfg(unix)]
+pub(crate) fn check_resource_limits(bytes: usize) -> Result<(), WeightError> {
+    let used = mapped_bytes();
+    for (resource, limit) in resource_limits() {
+        if bytes as u64 > limit.saturating_sub(used) {
+            return Err(WeightError::ResourceLimit { resource, bytes, limit });
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of address space the process has mapped, 0 where that can't be determined.
+fn mapped_bytes() -> u64 {
+    process_memory().map_or(0, |memory| memory.virtual_bytes) as u64
+}
+
+/// The address space and data segment limits of the process that are set, by name.
+#[cfg(unix)]
+fn resource_limits() -> impl Iterator<Item = (&'static str, u64)> {
+    [(libc::RLIMIT_AS, "RLIMIT_AS"), (libc::RLIMIT_DATA, "RLIMIT_DATA")]
+        .into_iter()
+        .filter_map(|(resource, name)| {
+            let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            // SAFETY: getrlimit only writes to the rlimit it's given.
+            if unsafe { libc::getrlimit(resource, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+                return None;
+            }
+            // rlim_t is only 32 bits wide on some 32-bit targets
+            #[allow(clippy::useless_conversion)]
+            Some((name, u64::from(limit.rlim_cur)))
+        })
+}
+
+#[cfg(not(unix))]
+fn resource_limits() -> impl Iterator<Item = (&'static str, u64)> {
+    std::iter::empty()
+}
+
+/// Resource limits only exist on Unix, so there's nothing to check.
+#[cfg(not(unix))]
+pub(crate) fn check_resource_limits(_bytes: usize) -> Result<(), WeightError> {
+    Ok(())
+}
+
+/// Share of the total memory that [`max_allocatable`] leaves free for the operating system and
+/// other processes.
+pub const ALLOCATABLE_MARGIN: f64 = 0.1;
+
+/// Bytes of available memory an allocation leaves unless configured otherwise, see
+/// [`WeightConfigBuilder::safety_margin`](crate::config::WeightConfigBuilder::safety_margin).
+/// Enough for the operating system and a shell to keep working on a small machine.
+pub const DEFAULT_SAFETY_MARGIN: usize = 512_000_000;
+
+/// Fails with [`WeightError::WouldExhaustMemory`] if allocating `bytes` would leave less than
+/// `margin` bytes of [available memory](effective_available_memory). A margin of 0 and
+/// platforms where the available memory can't be determined pass.
+pub(crate) fn check_safety_margin(bytes: usize, margin: usize) -> Result<(), WeightError> {
+    if margin == 0 {
+        return Ok(());
+    }
+    match effective_available_memory() {
+        Some(available) if available.saturating_sub(bytes) < margin => Err(WeightError::WouldExhaustMemory { bytes, available, margin }),
+        _ => Ok(()),
+    }
+}
+
+/// Recommended upper bound for how much this process can allocate without running the machine
+/// out of memory, to avoid finding it by trial and error. This is the available memory minus
+/// [`ALLOCATABLE_MARGIN`] of the total memory, or all but that margin of the total memory
+/// where the available memory can't be determined. Inside a cgroup with a memory limit, like
+/// most containers, both are taken from the limit where it is lower than what the machine has.
+/// On Unix it's also kept within what's left of the address space and data segment limits of
+/// the process.
+///
+/// The estimate is advisory and not a guarantee: other processes can take the memory at any
+/// time, and memory the kernel could reclaim, such as caches, isn't always freed fast enough.
+/// `None` if the total memory can't be determined on this platform.
+///
+/// ```
+/// if let Some(max) = weight::system::max_allocatable() {
+///     assert!(max.bytes() < weight::system::total_memory().unwrap_or(usize::MAX));
+/// }
+/// ```
+pub fn max_allocatable() -> Option<ByteSize> {
+    let total = effective_total_memory()?;
+    let margin = (total as f64 * ALLOCATABLE_MARGIN) as usize;
+    let mut bytes = effective_available_memory().unwrap_or(total).saturating_sub(margin);
+    // The address space limit also counts what the process has mapped already
+    let used = mapped_bytes();
+    for (_, limit) in resource_limits() {
+        bytes = bytes.min(usize::try_from(limit.saturating_sub(used)).unwrap_or(usize::MAX));
+    }
+    Some(ByteSize(bytes))
+}
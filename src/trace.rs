@@ -0,0 +1,55 @@
+//! Writing the statistics of an allocation to a CSV file over time, for plotting a test run.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::alloc::Stats;
+
+/// Columns of the CSV file, written as its first line.
+const HEADER: &str = "unix_time_ms,elapsed_ms,allocated_bytes,resident_bytes,cycles_completed,cpu_fraction";
+/// How often buffered rows are written out, so a crash loses at most this much of the trace.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// CSV file that samples are appended to, flushed every [`FLUSH_INTERVAL`] and when dropped.
+pub(crate) struct CsvFile {
+    file: BufWriter<File>,
+    started: Instant,
+    flushed: Instant,
+}
+
+impl CsvFile {
+    /// Creates the file at `path`, replacing it if it exists, and writes the header.
+    pub(crate) fn create(path: &Path) -> io::Result<CsvFile> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}", HEADER)?;
+        file.flush()?;
+        let now = Instant::now();
+        Ok(CsvFile {
+            file,
+            started: now,
+            flushed: now,
+        })
+    }
+
+    /// Appends a row for `stats`. The resident size is left empty where it's unknown.
+    pub(crate) fn sample(&mut self, stats: &Stats) -> io::Result<()> {
+        let unix_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(
+            self.file,
+            "{},{},{},{},{},{:.6}",
+            unix_time.as_millis(),
+            self.started.elapsed().as_millis(),
+            stats.allocated_bytes,
+            stats.resident_bytes.map(|resident| resident.to_string()).unwrap_or_default(),
+            stats.cycles_completed,
+            stats.cpu_fraction
+        )?;
+        if self.flushed.elapsed() >= FLUSH_INTERVAL {
+            self.file.flush()?;
+            self.flushed = Instant::now();
+        }
+        Ok(())
+    }
+}
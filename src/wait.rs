@@ -1,15 +1,28 @@
-use std::thread;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::time::Duration;
 
-pub fn wait(seconds: u32) {
+/// Waits for `seconds`, or until CTRL + C is pressed when `seconds` is 0. Returns early
+/// as soon as a message arrives on `interrupted`.
+pub fn wait(seconds: u32, interrupted: &Receiver<()>) {
     if seconds == 0 {
         println!("CTRL + C to exit.");
-        loop {
-            thread::sleep(Duration::from_secs(10));
+        if interrupted.recv().is_ok() {
+            println!("Interrupted, releasing memory.");
+        } else {
+            // Nothing can interrupt the wait anymore, so wait forever
+            loop {
+                std::thread::park();
+            }
         }
     } else {
         println!("Waiting for {} seconds before exiting.", seconds);
-        thread::sleep(Duration::from_secs(seconds as u64));
-        println!("Exiting after waiting for {} seconds.", seconds);
+        match interrupted.recv_timeout(Duration::from_secs(seconds as u64)) {
+            Ok(()) => println!("Interrupted, releasing memory."),
+            Err(RecvTimeoutError::Timeout) => println!("Exiting after waiting for {} seconds.", seconds),
+            Err(RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(Duration::from_secs(seconds as u64));
+                println!("Exiting after waiting for {} seconds.", seconds);
+            }
+        }
     }
-}
\ No newline at end of file
+}